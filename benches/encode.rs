@@ -0,0 +1,68 @@
+//!Compares encoding message directly into socket against encoding into buffer first.
+//!
+//!Run with `cargo bench --bench encode`
+use tracing_fluentd::fluent;
+
+use std::io::{Read, Write};
+use std::time::Instant;
+
+const ITERATIONS: usize = 2_000;
+const RECORDS: usize = 100;
+
+fn message() -> fluent::Message {
+    let mut msg = fluent::Message::new("rust");
+    for idx in 0..RECORDS {
+        let mut record = fluent::Record::now();
+        let mut metadata = fluent::Map::new();
+        metadata.insert("name".into(), "event benches/encode.rs:20".into());
+        metadata.insert("target".into(), "encode".into());
+        metadata.insert("level".into(), tracing::Level::INFO.into());
+        record.insert("metadata".into(), metadata.into());
+        record.insert("message".into(), "benchmark message".into());
+        record.insert("idx".into(), (idx as u64).into());
+        msg.add(record);
+    }
+    msg
+}
+
+fn connect() -> std::net::TcpStream {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    std::thread::spawn(move || {
+        let (mut conn, _) = listener.accept().expect("accept");
+        let mut buffer = [0u8; 64 * 1024];
+        while let Ok(size) = conn.read(&mut buffer) {
+            if size == 0 {
+                break;
+            }
+        }
+    });
+    std::net::TcpStream::connect(addr).expect("connect")
+}
+
+fn bench<F: FnMut(&mut std::net::TcpStream)>(name: &str, mut fun: F) {
+    let mut conn = connect();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        fun(&mut conn);
+    }
+    let elapsed = start.elapsed();
+    println!("{}: {:?} per message", name, elapsed / ITERATIONS as u32);
+}
+
+fn main() {
+    let msg = message();
+
+    bench("direct", |conn| {
+        rmp_serde::encode::write(conn, &msg).expect("encode");
+        conn.flush().expect("flush");
+    });
+
+    let mut buffer = Vec::new();
+    bench("buffered", |conn| {
+        buffer.clear();
+        rmp_serde::encode::write(&mut buffer, &msg).expect("encode");
+        conn.write_all(&buffer).expect("write");
+        conn.flush().expect("flush");
+    });
+}
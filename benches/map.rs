@@ -0,0 +1,43 @@
+//!Measures building, looking up and encoding records with 4, 8 and 32 fields.
+//!
+//!Records with up to `fluent::INLINE_LEN` fields are stored as vector of pairs, while bigger ones
+//!are spilled into hash map.
+//!
+//!Run with `cargo bench --bench map`
+use tracing_fluentd::fluent;
+
+use std::time::Instant;
+
+const RECORDS: usize = 200_000;
+const ROUNDS: usize = 5;
+
+fn bench(fields: usize) {
+    let keys = (0..fields).map(|idx| format!("field{}", idx)).collect::<Vec<_>>();
+    let keys = keys.into_iter().map(|key| &*Box::leak(key.into_boxed_str())).collect::<Vec<&'static str>>();
+    let mut buffer = Vec::new();
+
+    //Best of rounds, to reduce noise.
+    let elapsed = (0..ROUNDS).map(|_| {
+        let start = Instant::now();
+        for idx in 0..RECORDS {
+            let mut record = fluent::Record::now();
+            for key in keys.iter() {
+                record.insert((*key).into(), (idx as u64).into());
+            }
+            for key in keys.iter() {
+                assert!(record.contains_key(key));
+            }
+            buffer.clear();
+            rmp_serde::encode::write(&mut buffer, &record).expect("encode");
+        }
+        start.elapsed()
+    }).min().expect("rounds");
+
+    println!("{} fields: {:?} per record", fields, elapsed / RECORDS as u32);
+}
+
+fn main() {
+    bench(4);
+    bench(8);
+    bench(32);
+}
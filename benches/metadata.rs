@@ -0,0 +1,36 @@
+//!Measures cost of formatting events of the same callsite, which metadata is cached per callsite.
+//!
+//!Records are discarded via `Builder::disabled_layer`, so only formatting is measured.
+//!
+//!Run with `cargo bench --bench metadata`
+use tracing_subscriber::layer::SubscriberExt;
+
+use std::time::Instant;
+
+const EVENTS: usize = 200_000;
+const ROUNDS: usize = 5;
+
+fn bench<F: tracing_fluentd::FieldFormatter + Send + Sync>(name: &str, builder: tracing_fluentd::Builder<F>) {
+    let layer = builder.disabled_layer();
+    let dispatch = tracing::Dispatch::new(tracing_subscriber::Registry::default().with(layer));
+
+    //Best of rounds, to reduce noise.
+    let elapsed = (0..ROUNDS).map(|_| {
+        let start = Instant::now();
+        tracing::dispatcher::with_default(&dispatch, || {
+            for idx in 0..EVENTS {
+                tracing::info!(idx, "benchmark message");
+            }
+        });
+        start.elapsed()
+    }).min().expect("rounds");
+
+    println!("{}: {:?} per event", name, elapsed / EVENTS as u32);
+}
+
+fn main() {
+    let builder = tracing_fluentd::Builder::new("rust");
+
+    bench("nested", builder.clone());
+    bench("flatten", builder.flatten());
+}
@@ -0,0 +1,66 @@
+//!Compares throughput of encoding records within worker against encoding them within threads,
+//!that emit events (`Builder::with_packed_forward`).
+//!
+//!Run with `cargo bench --bench pipeline`
+use tracing_subscriber::layer::SubscriberExt;
+
+use std::io::Read;
+use std::time::Instant;
+
+const THREADS: usize = 4;
+const EVENTS: usize = 50_000;
+
+fn sink() -> std::net::SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    std::thread::spawn(move || {
+        for conn in listener.incoming() {
+            let mut conn = match conn {
+                Ok(conn) => conn,
+                Err(_) => break,
+            };
+            std::thread::spawn(move || {
+                let mut buffer = [0u8; 64 * 1024];
+                while let Ok(size) = conn.read(&mut buffer) {
+                    if size == 0 {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    addr
+}
+
+fn bench(name: &str, builder: tracing_fluentd::Builder<tracing_fluentd::NestedFmt, std::net::SocketAddr>) {
+    let layer = builder.layer().expect("Create layer");
+    let dispatch = tracing::Dispatch::new(tracing_subscriber::Registry::default().with(layer));
+
+    let start = Instant::now();
+    let threads = (0..THREADS).map(|thread| {
+        let dispatch = dispatch.clone();
+        std::thread::spawn(move || tracing::dispatcher::with_default(&dispatch, || {
+            for idx in 0..EVENTS {
+                tracing::info!(thread, idx, text = "benchmark field", "benchmark message");
+            }
+        }))
+    }).collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().expect("join");
+    }
+    //Layer is dropped with the last dispatch, awaiting worker to send all records.
+    drop(dispatch);
+
+    let elapsed = start.elapsed();
+    let records = (THREADS * EVENTS) as f64;
+    println!("{}: {:?} ({:.0} records/s)", name, elapsed, records / elapsed.as_secs_f64());
+}
+
+fn main() {
+    let addr = sink();
+    let builder = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1000).unwrap())
+                                                       .with_writer(addr);
+
+    bench("worker encoding", builder.clone());
+    bench("caller encoding", builder.with_packed_forward());
+}
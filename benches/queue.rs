@@ -0,0 +1,73 @@
+//!Counts allocations per event with unbounded queue and ring queue (`Builder::with_queue`).
+//!
+//!Both include allocations of records themselves, hence difference is due to queue, which
+//!allocates its blocks as records are queued, unless it is ring.
+//!
+//!Run with `cargo bench --bench queue`
+use tracing_subscriber::layer::SubscriberExt;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::time::Instant;
+
+const EVENTS: usize = 100_000;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+struct Sink;
+
+impl std::io::Write for Sink {
+    #[inline(always)]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    #[inline(always)]
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn bench(name: &str, queue: tracing_fluentd::QueueKind) {
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(|| Ok(Sink))
+                                                     .with_queue(queue)
+                                                     .layer()
+                                                     .expect("Create layer");
+    let dispatch = tracing::Dispatch::new(tracing_subscriber::Registry::default().with(layer));
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    tracing::dispatcher::with_default(&dispatch, || {
+        for idx in 0..EVENTS {
+            tracing::info!(idx, "benchmark message");
+        }
+    });
+    //Layer is dropped with the last dispatch, awaiting worker to send all records.
+    drop(dispatch);
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    println!("{}: {:?}, {:.3} allocations per event", name, elapsed, allocations as f64 / EVENTS as f64);
+}
+
+fn main() {
+    bench("unbounded queue", tracing_fluentd::QueueKind::Unbounded);
+    bench("ring queue", tracing_fluentd::QueueKind::Ring(core::num::NonZeroUsize::new(EVENTS).unwrap()));
+}
@@ -0,0 +1,51 @@
+//!Compares emitting events with records allocated for every event against records reused via pool.
+//!
+//!Run with `cargo bench --bench record_pool`
+use tracing_subscriber::layer::SubscriberExt;
+
+use std::time::Instant;
+
+const EVENTS: usize = 200_000;
+
+struct Sink;
+
+impl std::io::Write for Sink {
+    #[inline(always)]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    #[inline(always)]
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn bench(name: &str, pool: bool) {
+    let builder = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(100).unwrap())
+                                                     .with_writer(|| Ok(Sink))
+                                                     .flatten();
+    let builder = match pool {
+        true => builder.with_record_pool(core::num::NonZeroUsize::new(1024).unwrap()),
+        false => builder,
+    };
+    let layer = builder.layer().expect("Create layer");
+    let dispatch = tracing::Dispatch::new(tracing_subscriber::Registry::default().with(layer));
+
+    let start = Instant::now();
+    tracing::dispatcher::with_default(&dispatch, || {
+        for idx in 0..EVENTS {
+            tracing::info!(idx, user_id = 42u64, route = "/api/v1/orders", status = 200u16, "benchmark message");
+        }
+    });
+    //Worker sends remaining records once layer is dropped.
+    drop(dispatch);
+    let elapsed = start.elapsed();
+
+    println!("{}: {:?} ({:?} per event)", name, elapsed, elapsed / EVENTS as u32);
+}
+
+fn main() {
+    bench("allocated records", false);
+    bench("pooled records", true);
+}
@@ -0,0 +1,81 @@
+//!Counts allocations per event within span, which has large string fields.
+//!
+//!Fields recorded as `&str` are shared between events, while fields recorded via `Display` are
+//!copied into every event.
+//!
+//!Run with `cargo bench --bench span_fields`
+use tracing_subscriber::layer::SubscriberExt;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::time::Instant;
+
+const EVENTS: usize = 100_000;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    #[inline(always)]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    #[inline(always)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+struct Sink;
+
+impl std::io::Write for Sink {
+    #[inline(always)]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    #[inline(always)]
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn bench(name: &str, text: &str, shared: bool) {
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(|| Ok(Sink)).layer().expect("Create layer");
+    let dispatch = tracing::Dispatch::new(tracing_subscriber::Registry::default().with(layer));
+
+    tracing::dispatcher::with_default(&dispatch, || {
+        let span = match shared {
+            true => tracing::info_span!("request", query = text, body = text),
+            false => tracing::info_span!("request", query = %text, body = %text),
+        };
+        let _entered = span.enter();
+
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let allocated = ALLOCATED.load(Ordering::Relaxed);
+        let start = Instant::now();
+        for idx in 0..EVENTS {
+            tracing::info!(idx, "benchmark message");
+        }
+        let elapsed = start.elapsed();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+        let allocated = ALLOCATED.load(Ordering::Relaxed) - allocated;
+        println!("{}: {:?}, {:.1} allocations ({} bytes) per event",
+                 name, elapsed, allocations as f64 / EVENTS as f64, allocated / EVENTS);
+    });
+}
+
+fn main() {
+    let text = "x".repeat(4096);
+
+    bench("shared span fields", &text, true);
+    bench("copied span fields", &text, false);
+}
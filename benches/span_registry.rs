@@ -0,0 +1,60 @@
+//!Compares bytes written per event within span, which has large fields, with and without span registry.
+//!
+//!Run with `cargo bench --bench span_registry`
+use tracing_subscriber::layer::SubscriberExt;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+const EVENTS: usize = 100_000;
+
+struct Sink(Arc<AtomicUsize>);
+
+impl std::io::Write for Sink {
+    #[inline(always)]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.fetch_add(buf.len(), Ordering::Relaxed);
+        Ok(buf.len())
+    }
+
+    #[inline(always)]
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn bench(name: &str, registry: bool) {
+    let written = Arc::new(AtomicUsize::new(0));
+    let sink = written.clone();
+    let builder = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(100).unwrap())
+                                                     .with_writer(move || Ok(Sink(sink.clone())))
+                                                     .flatten();
+    let builder = match registry {
+        true => builder.with_span_registry(),
+        false => builder,
+    };
+    let layer = builder.layer().expect("Create layer");
+    let dispatch = tracing::Dispatch::new(tracing_subscriber::Registry::default().with(layer));
+
+    let start = Instant::now();
+    tracing::dispatcher::with_default(&dispatch, || {
+        let headers = "accept: */*; user-agent: bench; ".repeat(8);
+        let span = tracing::info_span!("request", user_id = 42u64, route = "/api/v1/orders", headers = headers.as_str());
+        let _entered = span.enter();
+
+        for idx in 0..EVENTS {
+            tracing::info!(idx, "benchmark message");
+        }
+    });
+    //Worker sends remaining records once layer is dropped.
+    drop(dispatch);
+    let elapsed = start.elapsed();
+
+    println!("{}: {:?}, {} bytes per event", name, elapsed, written.load(Ordering::Relaxed) / EVENTS);
+}
+
+fn main() {
+    bench("span attributes within every record", false);
+    bench("span attributes once per message", true);
+}
@@ -0,0 +1,274 @@
+//!Worker running as task of async runtime.
+//!
+//!Worker is built around minimal abstraction of runtime:
+//!
+//!- `AsyncMakeWriter` - Creates connection to fluentd, e.g. any closure returning future of `futures_io::AsyncWrite`.
+//!- `Runtime` - Spawns worker task and provides timer for delays between attempts to connect.
+//!
+//!Hence worker can be used with any runtime, while `async-std` feature provides `AsyncStd` runtime
+//!and `tcp` writer.
+//!
+//!Use `Builder::layer_async` to create layer.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time;
+use std::io;
+use std::sync::Arc;
+
+use futures_util::io::AsyncWriteExt;
+
+use crate::{fluent, Codec, QueueKind};
+use crate::worker::{self, Batch, CloseOnDrop, Consumer, DropHandler, Encode, Message, Opts};
+
+///Boxed future, used by `AsyncMakeWriter` and `Runtime`.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+///Describes how to create async writer.
+///
+///Implemented for any closure returning future of writer, e.g. `move || TcpStream::connect(addr)`.
+pub trait AsyncMakeWriter: 'static + Send + Sync {
+    ///Writer type
+    type Writer: futures_io::AsyncWrite + Unpin + Send;
+
+    ///Creates instance of `Writer`.
+    ///
+    ///Same as with `MakeWriter`, writer is cached and re-created only if writing fails.
+    fn make(&self) -> BoxFuture<'_, io::Result<Self::Writer>>;
+}
+
+impl<W: futures_io::AsyncWrite + Unpin + Send, R: Future<Output = io::Result<W>> + Send + 'static, T: 'static + Send + Sync + Fn() -> R> AsyncMakeWriter for T {
+    type Writer = W;
+
+    #[inline(always)]
+    fn make(&self) -> BoxFuture<'_, io::Result<Self::Writer>> {
+        Box::pin((self)())
+    }
+}
+
+///Async runtime, used by worker.
+pub trait Runtime: 'static + Send + Sync {
+    ///Spawns worker `task`.
+    fn spawn(&self, task: BoxFuture<'static, ()>);
+
+    ///Returns future that completes after `duration`.
+    fn sleep(&self, duration: time::Duration) -> BoxFuture<'static, ()>;
+}
+
+///Worker task, owned by layer created via `Builder::layer_async`.
+///
+///Worker is stopped once layer is dropped, sending remaining records in background.
+pub struct AsyncWorker {
+    sender: async_channel::Sender<Message>,
+    closed: Arc<AtomicBool>,
+    packed: bool,
+    on_drop: Option<DropHandler>,
+}
+
+impl AsyncWorker {
+    #[inline(always)]
+    ///Returns handler of dropped records, that layers should use.
+    pub(crate) fn on_drop(&self) -> Option<DropHandler> {
+        self.on_drop.clone()
+    }
+}
+
+impl Consumer for AsyncWorker {
+    #[inline]
+    fn record(&self, record: fluent::Record) {
+        let message = match worker::record_message(record, self.packed) {
+            Some(message) => message,
+            None => return if let Some(on_drop) = self.on_drop.as_ref() {
+                on_drop(crate::DropReason::EncodeFailed, 1);
+            },
+        };
+
+        match self.sender.try_send(message) {
+            Ok(()) => (),
+            Err(async_channel::TrySendError::Full(message)) => if let Some(on_drop) = self.on_drop.as_ref() {
+                on_drop(crate::DropReason::QueueFull, message.records());
+            },
+            Err(async_channel::TrySendError::Closed(_)) => self.closed.store(true, Ordering::Release),
+        }
+    }
+
+    #[inline(always)]
+    fn flush(&self) {
+        let _ = self.sender.try_send(Message::Flush);
+    }
+
+    #[inline(always)]
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+///Creates writers, retrying once after delay, same as thread worker.
+async fn make_with_retry<MW: AsyncMakeWriter, R: Runtime>(writer: &MW, runtime: &R, error_handler: Option<&worker::ErrorHandler>) -> io::Result<MW::Writer> {
+    let mut attempt = 0;
+    loop {
+        match writer.make().await {
+            Ok(writer) => break Ok(writer),
+            Err(error) => {
+                if let Some(handler) = error_handler {
+                    handler(&error);
+                }
+                attempt += 1;
+                if attempt > 1 {
+                    break Err(error);
+                }
+                runtime.sleep(time::Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+async fn write<W: futures_io::AsyncWrite + Unpin>(writer: &mut W, buffer: &mut Vec<u8>, msg: &Batch, codec: Codec) -> io::Result<()> {
+    buffer.clear();
+    msg.encode(buffer, codec)?;
+    writer.write_all(buffer).await?;
+    writer.flush().await
+}
+
+async fn run<MW: AsyncMakeWriter, R: Runtime>(tag: &'static str, writer: MW, runtime: Arc<R>, opts: Opts, recv: async_channel::Receiver<Message>, close_on_drop: CloseOnDrop) {
+    let _close_on_drop = close_on_drop;
+    let mut msg = Batch::new(tag, opts.option_section, opts.codec, false);
+    let mut ongoing_writer = None;
+    //Kept across messages to avoid re-allocating it every time.
+    let mut buffer = Vec::new();
+    let mut is_terminated = false;
+
+    while !is_terminated {
+        //Fetch up to max_msg_record
+        while msg.len() < opts.max_msg_record {
+            match recv.recv().await {
+                Ok(Message::Flush) => if msg.len() > 0 {
+                    break
+                },
+                Ok(Message::Terminate) | Err(async_channel::RecvError) => {
+                    is_terminated = true;
+                    break;
+                },
+                Ok(message) => msg.add(message),
+            }
+        }
+
+        //Get every extra record we can get at the current moment.
+        loop {
+            match recv.try_recv() {
+                Ok(Message::Flush) | Err(async_channel::TryRecvError::Empty) => break,
+                Ok(Message::Terminate) | Err(async_channel::TryRecvError::Closed) => {
+                    is_terminated = true;
+                    break;
+                },
+                Ok(message) => msg.add(message),
+            }
+        }
+
+        if msg.len() == 0 {
+            continue;
+        }
+
+        //Last records are attempted multiple times, but without waiting too much.
+        let attempts = match is_terminated {
+            true => 3,
+            false => 1,
+        };
+        for _ in 0..attempts {
+            let mut writer = match ongoing_writer.take() {
+                Some(writer) => writer,
+                None => match make_with_retry(&writer, &*runtime, opts.error_handler.as_ref()).await {
+                    Ok(writer) => writer,
+                    Err(error) => {
+                        tracing::event!(tracing::Level::DEBUG, "Failed to create fluent writer {}", error);
+                        continue;
+                    }
+                },
+            };
+
+            if let Some(option_fields_fn) = opts.option_fields_fn.as_ref() {
+                msg.set_option_fields(option_fields_fn);
+            }
+
+            match write(&mut writer, &mut buffer, &msg, opts.codec).await {
+                Ok(()) => {
+                    msg.clear();
+                    ongoing_writer = Some(writer);
+                    break;
+                },
+                //Writer is still usable, as message is encoded before it is written.
+                Err(error) if worker::is_encode_error(&error) => {
+                    let error_handler = opts.error_handler.as_ref();
+                    let dropped = msg.drop_unencodable(&mut buffer, opts.codec, |error| if let Some(handler) = error_handler {
+                        handler(error);
+                    });
+                    if let Some(on_drop) = opts.on_drop.as_ref() {
+                        on_drop(crate::DropReason::EncodeFailed, dropped);
+                    }
+                    ongoing_writer = Some(writer);
+                    if msg.len() == 0 {
+                        break;
+                    }
+                },
+                Err(error) => {
+                    if let Some(handler) = opts.error_handler.as_ref() {
+                        handler(&error);
+                    }
+                    tracing::event!(tracing::Level::INFO, "Failed to send records to fluent server {}", error);
+                },
+            }
+        }
+    }
+
+    if let (true, Some(on_drop)) = (msg.len() > 0, opts.on_drop.as_ref()) {
+        on_drop(crate::DropReason::ShutdownTimeout, msg.len());
+    }
+}
+
+///Spawns worker task within `runtime`.
+pub(crate) fn spawn<MW: AsyncMakeWriter, R: Runtime>(tag: &'static str, writer: MW, runtime: R, opts: Opts) -> AsyncWorker {
+    let (sender, recv) = match opts.queue {
+        QueueKind::Unbounded => async_channel::unbounded(),
+        QueueKind::Ring(capacity) => async_channel::bounded(capacity.get()),
+    };
+    let closed = Arc::new(AtomicBool::new(false));
+    let close_on_drop = CloseOnDrop(closed.clone());
+    let packed = opts.packed && matches!(opts.codec, Codec::Msgpack);
+    let on_drop = opts.on_drop.clone();
+
+    let runtime = Arc::new(runtime);
+    runtime.spawn(Box::pin(run(tag, writer, runtime.clone(), opts, recv, close_on_drop)));
+
+    AsyncWorker {
+        sender,
+        closed,
+        packed,
+        on_drop,
+    }
+}
+
+#[cfg(feature = "async-std")]
+#[derive(Clone, Copy, Debug, Default)]
+///`Runtime` of `async-std`.
+pub struct AsyncStd;
+
+#[cfg(feature = "async-std")]
+impl Runtime for AsyncStd {
+    #[inline(always)]
+    fn spawn(&self, task: BoxFuture<'static, ()>) {
+        async_std::task::spawn(task);
+    }
+
+    #[inline(always)]
+    fn sleep(&self, duration: time::Duration) -> BoxFuture<'static, ()> {
+        Box::pin(async_std::task::sleep(duration))
+    }
+}
+
+#[cfg(feature = "async-std")]
+#[inline(always)]
+///Creates writer, connecting to `addr` via `async-std` TCP stream.
+pub fn tcp(addr: std::net::SocketAddr) -> impl AsyncMakeWriter<Writer = async_std::net::TcpStream> {
+    move || async_std::net::TcpStream::connect(addr)
+}
@@ -0,0 +1,141 @@
+use core::any::TypeId;
+use core::mem::ManuallyDrop;
+use std::sync::Arc;
+
+use tracing_subscriber::Registry;
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+use tracing_core::subscriber::Subscriber as Collect;
+use tracing_subscriber::layer::Context;
+use tracing_core::span::{Id, Attributes, Record};
+use tracing_core::Event;
+
+use crate::{fluent, writer, Builder, FieldFormatter, FmtOpts};
+
+///Builder with type-erased formatter and writer, produced by `Builder::boxed`.
+///
+///`S` is type of subscriber to which layer is added, which is `Registry` by default.
+pub type BoxedBuilder<S = Registry> = Builder<BoxedFmt<S>, writer::BoxMakeWriter>;
+
+//Object-safe part of `FieldFormatter`, specialized for subscriber `S`.
+trait DynFieldFormatter<S: for<'a> LookupSpan<'a>>: Send + Sync {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>, opts: &FmtOpts);
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>, opts: &FmtOpts);
+    fn on_event(&self, record: &mut fluent::Record, event: &Event<'_>, current_span: Option<SpanRef<'_, S>>, opts: &FmtOpts);
+}
+
+impl<F: FieldFormatter + Send + Sync, S: Collect + for<'a> LookupSpan<'a>> DynFieldFormatter<S> for F {
+    #[inline(always)]
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>, opts: &FmtOpts) {
+        FieldFormatter::on_new_span_with_opts(self, attrs, id, ctx, opts)
+    }
+
+    #[inline(always)]
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>, opts: &FmtOpts) {
+        FieldFormatter::on_record_with_opts(self, id, values, ctx, opts)
+    }
+
+    #[inline(always)]
+    fn on_event(&self, record: &mut fluent::Record, event: &Event<'_>, current_span: Option<SpanRef<'_, S>>, opts: &FmtOpts) {
+        FieldFormatter::on_event_with_opts(self, record, event, current_span, opts)
+    }
+}
+
+#[inline(always)]
+///Converts context of subscriber `C` into context of subscriber `S`, if it is the same type.
+fn downcast_context<'a, C: Collect, S: Collect>(ctx: Context<'a, C>) -> Option<Context<'a, S>> {
+    match TypeId::of::<C>() == TypeId::of::<S>() {
+        //Types are the same, hence it is no-op.
+        true => Some(unsafe {
+            let ctx = ManuallyDrop::new(ctx);
+            core::ptr::read(&*ctx as *const Context<'a, C> as *const Context<'a, S>)
+        }),
+        false => None,
+    }
+}
+
+#[inline(always)]
+///Converts reference to context of subscriber `C` into context of subscriber `S`, if it is the same type.
+fn downcast_context_ref<'b, 'a, C: Collect, S: Collect>(ctx: &'b Context<'a, C>) -> Option<&'b Context<'a, S>> {
+    match TypeId::of::<C>() == TypeId::of::<S>() {
+        //Types are the same, hence it is no-op.
+        true => Some(unsafe {
+            &*(ctx as *const Context<'a, C> as *const Context<'a, S>)
+        }),
+        false => None,
+    }
+}
+
+///Type-erased formatter, produced by `Builder::boxed`.
+///
+///Formatter is specialized for subscriber `S`, to which layer is added.
+///Within other subscribers, span attributes are not recorded and events are formatted without
+///span.
+///
+///Every call to formatter is dynamically dispatched, which is negligible in comparison with
+///creation of record.
+pub struct BoxedFmt<S: for<'a> LookupSpan<'a> = Registry> {
+    inner: Arc<dyn DynFieldFormatter<S>>,
+}
+
+impl<S: Collect + for<'a> LookupSpan<'a>> BoxedFmt<S> {
+    #[inline]
+    ///Wraps `fmt` into box.
+    pub fn new<F: FieldFormatter + Send + Sync>(fmt: F) -> Self {
+        Self {
+            inner: Arc::new(fmt),
+        }
+    }
+}
+
+impl<S: for<'a> LookupSpan<'a>> Clone for BoxedFmt<S> {
+    #[inline(always)]
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S: Collect + for<'a> LookupSpan<'a>> FieldFormatter for BoxedFmt<S> {
+    #[inline(always)]
+    fn on_new_span<C: Collect + for<'a> LookupSpan<'a>>(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, C>) {
+        self.on_new_span_with_opts(attrs, id, ctx, &FmtOpts::new());
+    }
+
+    #[inline(always)]
+    fn on_record<C: Collect + for<'a> LookupSpan<'a>>(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, C>) {
+        self.on_record_with_opts(id, values, ctx, &FmtOpts::new());
+    }
+
+    #[inline(always)]
+    ///Formats event without span, as span cannot be passed to type-erased formatter.
+    fn on_event<'a, R: LookupSpan<'a>>(&self, record: &mut fluent::Record, event: &Event<'_>, current_span: Option<SpanRef<'a, R>>) {
+        self.on_event_with_opts(record, event, current_span, &FmtOpts::new());
+    }
+
+    #[inline(always)]
+    fn on_new_span_with_opts<C: Collect + for<'a> LookupSpan<'a>>(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, C>, opts: &FmtOpts) {
+        if let Some(ctx) = downcast_context(ctx) {
+            self.inner.on_new_span(attrs, id, ctx, opts);
+        }
+    }
+
+    #[inline(always)]
+    fn on_record_with_opts<C: Collect + for<'a> LookupSpan<'a>>(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, C>, opts: &FmtOpts) {
+        if let Some(ctx) = downcast_context(ctx) {
+            self.inner.on_record(id, values, ctx, opts);
+        }
+    }
+
+    #[inline(always)]
+    fn on_event_with<C: Collect + for<'a> LookupSpan<'a>>(&self, record: &mut fluent::Record, event: &Event<'_>, ctx: &Context<'_, C>, opts: &FmtOpts) {
+        let current_span = downcast_context_ref::<C, S>(ctx).and_then(|ctx| ctx.event_span(event));
+        self.inner.on_event(record, event, current_span, opts);
+    }
+
+    #[inline(always)]
+    ///Formats event without span, as span cannot be passed to type-erased formatter.
+    fn on_event_with_opts<'a, R: LookupSpan<'a>>(&self, record: &mut fluent::Record, event: &Event<'_>, _: Option<SpanRef<'a, R>>, opts: &FmtOpts) {
+        self.inner.on_event(record, event, None, opts);
+    }
+}
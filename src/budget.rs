@@ -0,0 +1,80 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::OverflowPolicy;
+use crate::worker::{DropHandler, Message};
+
+///Limit of total size of records, that are not yet delivered.
+pub(crate) struct Budget {
+    limit: usize,
+    used: AtomicUsize,
+    //Used to discard the oldest records within queue, if policy is `OverflowPolicy::DropOldest`.
+    oldest: Option<crossbeam_channel::Receiver<Message>>,
+}
+
+impl Budget {
+    pub(crate) fn new(limit: usize, policy: OverflowPolicy, recv: &crossbeam_channel::Receiver<Message>) -> Self {
+        Self {
+            limit,
+            used: AtomicUsize::new(0),
+            oldest: match policy {
+                OverflowPolicy::DropNewest => None,
+                OverflowPolicy::DropOldest => Some(recv.clone()),
+            },
+        }
+    }
+
+    #[inline(always)]
+    ///Returns size of records, that are not yet delivered.
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    #[inline(always)]
+    pub(crate) fn release(&self, size: usize) {
+        self.used.fetch_sub(size, Ordering::AcqRel);
+    }
+
+    #[inline(always)]
+    fn try_reserve(&self, size: usize) -> bool {
+        let limit = self.limit;
+        self.used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| used.checked_add(size).filter(|used| *used <= limit)).is_ok()
+    }
+
+    ///Reserves `size` for new record, returning whether it can be queued.
+    ///
+    ///Depending on policy, either new record is discarded or the oldest records within queue.
+    ///Records, that are already received by worker, cannot be discarded, hence new record is
+    ///discarded if there is nothing else to discard.
+    pub(crate) fn reserve(&self, size: usize, sender: &crossbeam_channel::Sender<Message>, on_drop: Option<&DropHandler>) -> bool {
+        let mut dropped = 0;
+        let is_reserved = loop {
+            if self.try_reserve(size) {
+                break true;
+            }
+
+            let oldest = match self.oldest.as_ref() {
+                Some(oldest) => oldest,
+                None => break false,
+            };
+            match oldest.try_recv() {
+                Ok(Message::Flush) | Ok(Message::Sync(_)) => (),
+                //Worker must still receive it.
+                Ok(Message::Terminate) => {
+                    let _ = sender.send(Message::Terminate);
+                    break false;
+                },
+                Ok(message) => {
+                    self.release(message.estimated_size());
+                    dropped += message.records();
+                },
+                Err(_) => break false,
+            }
+        };
+
+        let dropped = dropped + !is_reserved as usize;
+        if let (true, Some(on_drop)) = (dropped > 0, on_drop) {
+            on_drop(crate::DropReason::QueueFull, dropped);
+        }
+        is_reserved
+    }
+}
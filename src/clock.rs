@@ -0,0 +1,161 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use crate::worker::{self, ErrorHandler};
+
+///Source of time for worker, used for retry delays and periodic actions.
+///
+///Configured via `Builder::with_clock`, while default is `SystemClock`.
+pub trait Clock: 'static + Send + Sync {
+    ///Returns current time.
+    fn now(&self) -> Instant;
+
+    #[inline(always)]
+    ///Returns current wall-clock time, used for timestamps of records.
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    ///Blocks current thread until `deadline`.
+    fn sleep_until(&self, deadline: Instant);
+
+    #[inline(always)]
+    ///Blocks current thread for `duration`.
+    fn sleep(&self, duration: Duration) {
+        self.sleep_until(self.now() + duration);
+    }
+
+    #[inline]
+    ///Returns for how long worker may wait for records, before checking whether `deadline` is
+    ///reached.
+    ///
+    ///By default it is time remaining till `deadline`.
+    fn recv_timeout(&self, deadline: Instant) -> Duration {
+        deadline.saturating_duration_since(self.now())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+///`Clock` using system time.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline(always)]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    #[inline(always)]
+    fn sleep_until(&self, deadline: Instant) {
+        std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+    }
+}
+
+///Receives message from `recv`, waiting no longer than `deadline` of `clock`.
+pub(crate) fn recv_deadline<T>(clock: &dyn Clock, recv: &crossbeam_channel::Receiver<T>, deadline: Instant) -> Result<T, crossbeam_channel::RecvTimeoutError> {
+    loop {
+        match recv.recv_timeout(clock.recv_timeout(deadline)) {
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) if clock.now() < deadline => continue,
+            result => break result,
+        }
+    }
+}
+
+///Returns `time` as duration since UNIX epoch, clamped to zero if `time` is before epoch.
+pub(crate) fn unix_time(time: SystemTime) -> Duration {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or(Duration::ZERO)
+}
+
+///Interval after which monotonic timestamps are anchored to wall-clock time again.
+const REANCHOR_INTERVAL: Duration = Duration::from_secs(60);
+///Lag behind wall-clock time, after which monotonic timestamps are anchored immediately.
+const MAX_LAG: Duration = Duration::from_secs(1);
+
+struct Anchor {
+    time: Duration,
+    instant: Instant,
+}
+
+///Source of timestamps of records.
+pub(crate) struct Timestamps {
+    clock: Arc<dyn Clock>,
+    anchor: Option<Mutex<Anchor>>,
+    error_handler: Option<ErrorHandler>,
+    //Whether time before UNIX epoch is reported already.
+    reported: AtomicBool,
+    //Whether event's field, conflicting with time field, is reported already.
+    conflict_reported: AtomicBool,
+}
+
+impl Timestamps {
+    pub(crate) fn new(opts: &worker::Opts, monotonic: bool) -> Self {
+        let clock = opts.clock.clone();
+        let anchor = match monotonic {
+            true => Some(Mutex::new(Anchor {
+                time: unix_time(clock.system_time()),
+                instant: clock.now(),
+            })),
+            false => None,
+        };
+
+        Self {
+            clock,
+            anchor,
+            error_handler: opts.error_handler.clone(),
+            reported: AtomicBool::new(false),
+            conflict_reported: AtomicBool::new(false),
+        }
+    }
+
+    ///Reports once that event already contains time field `key`, hence it is not inserted.
+    pub(crate) fn report_conflict(&self, key: &str) {
+        if !self.conflict_reported.swap(true, Ordering::Relaxed) {
+            if let Some(handler) = self.error_handler.as_ref() {
+                let error = io::Error::other(format!("Event contains field '{}', which is kept instead of record's time", key));
+                handler(&error);
+            }
+        }
+    }
+
+    ///Returns wall-clock time as duration since UNIX epoch, clamping it to zero and reporting
+    ///error once, if system time is before epoch.
+    fn wall(&self) -> Duration {
+        match self.clock.system_time().duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(time) => time,
+            Err(error) => {
+                if !self.reported.swap(true, Ordering::Relaxed) {
+                    if let Some(handler) = self.error_handler.as_ref() {
+                        let error = io::Error::other(format!("System time is before UNIX epoch by {:?}, using epoch as timestamp", error.duration()));
+                        handler(&error);
+                    }
+                }
+                Duration::ZERO
+            }
+        }
+    }
+
+    ///Returns current timestamp as duration since UNIX epoch.
+    pub(crate) fn now(&self) -> Duration {
+        let wall = self.wall();
+        let anchor = match self.anchor.as_ref() {
+            Some(anchor) => anchor,
+            None => return wall,
+        };
+
+        let instant = self.clock.now();
+        let mut anchor = anchor.lock().unwrap_or_else(|error| error.into_inner());
+        let elapsed = instant.saturating_duration_since(anchor.instant);
+        let time = anchor.time + elapsed;
+        if elapsed >= REANCHOR_INTERVAL || wall > time + MAX_LAG {
+            //Never move backwards, even if wall-clock did.
+            anchor.time = core::cmp::max(wall, time);
+            anchor.instant = instant;
+            return anchor.time;
+        }
+
+        time
+    }
+}
@@ -0,0 +1,99 @@
+use core::hash::Hasher;
+use core::time::Duration;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::hash_map::DefaultHasher;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use tracing_core::callsite::Identifier;
+
+use crate::fluent;
+
+struct HashWriter(DefaultHasher);
+
+impl std::io::Write for HashWriter {
+    #[inline(always)]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    #[inline(always)]
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+///Returns fingerprint of record's entries, ignoring its time.
+fn fingerprint(record: &fluent::Map) -> u64 {
+    let mut hasher = HashWriter(DefaultHasher::new());
+    let _ = rmp_serde::encode::write(&mut hasher, record);
+    hasher.0.finish()
+}
+
+struct Last {
+    fingerprint: u64,
+    emitted_at: Instant,
+    repeated: u64,
+}
+
+pub(crate) enum Outcome {
+    ///Record is the same as last one within window.
+    Suppressed,
+    ///Record should be sent, with number of suppressed repetitions of the same record.
+    Repeated(u64),
+    ///Record is different from the last one, which was suppressed number of times.
+    Changed(u64),
+}
+
+///Tracks last record of every callsite.
+pub(crate) struct Dedup {
+    window: Duration,
+    last: Mutex<HashMap<Identifier, Last>>,
+}
+
+impl Dedup {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn check(&self, callsite: Identifier, record: &fluent::Map) -> Outcome {
+        let fingerprint = fingerprint(record);
+        let now = Instant::now();
+
+        let mut last = self.last.lock().unwrap_or_else(|error| error.into_inner());
+        let last = match last.entry(callsite) {
+            Entry::Occupied(last) => last.into_mut(),
+            Entry::Vacant(last) => {
+                last.insert(Last {
+                    fingerprint,
+                    emitted_at: now,
+                    repeated: 0,
+                });
+                return Outcome::Repeated(0);
+            }
+        };
+
+        if last.fingerprint != fingerprint {
+            let repeated = last.repeated;
+            *last = Last {
+                fingerprint,
+                emitted_at: now,
+                repeated: 0,
+            };
+            Outcome::Changed(repeated)
+        } else if now.duration_since(last.emitted_at) < self.window {
+            last.repeated += 1;
+            Outcome::Suppressed
+        } else {
+            let repeated = last.repeated;
+            last.emitted_at = now;
+            last.repeated = 0;
+            Outcome::Repeated(repeated)
+        }
+    }
+}
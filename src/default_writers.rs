@@ -1,14 +1,45 @@
-use crate::MakeWriter;
+use crate::{MakeWriter, ProbeWrite};
 
-use std::net::ToSocketAddrs;
+use core::time::Duration;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
 
-impl MakeWriter for std::vec::IntoIter<std::net::SocketAddr> {
-    type Writer = std::net::TcpStream;
+#[derive(Clone, Copy, Debug)]
+struct ConnectOpts {
+    timeout: Duration,
+    attempts: usize,
+    delay: Duration,
+}
 
-    #[inline(always)]
-    fn make(&self) -> std::io::Result<Self::Writer> {
-        for addr in self.as_slice().iter() {
-            match std::net::TcpStream::connect_timeout(addr, core::time::Duration::from_secs(1)) {
+impl ConnectOpts {
+    //Matches behavior of default writers: single attempt per address, with timeout of 1s.
+    const DEFAULT: Self = Self {
+        timeout: Duration::from_secs(1),
+        attempts: 1,
+        delay: Duration::from_millis(0),
+    };
+
+    fn connect_addr(&self, addr: &SocketAddr) -> std::io::Result<TcpStream> {
+        let mut attempt = 1;
+        loop {
+            match TcpStream::connect_timeout(addr, self.timeout) {
+                Ok(socket) => return Ok(socket),
+                Err(error) if attempt >= self.attempts => return Err(error),
+                Err(_) => {
+                    attempt += 1;
+                    std::thread::sleep(self.delay);
+                }
+            }
+        }
+    }
+
+    ///Connects to the first available address out of `addrs`.
+    fn connect_any(&self, addrs: &[SocketAddr]) -> std::io::Result<TcpStream> {
+        if addrs.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "no fluentd address provided"));
+        }
+
+        for addr in addrs.iter() {
+            match self.connect_addr(addr) {
                 Ok(socket) => return Ok(socket),
                 Err(_) => continue,
             }
@@ -16,18 +47,16 @@ impl MakeWriter for std::vec::IntoIter<std::net::SocketAddr> {
 
         Err(std::io::Error::new(std::io::ErrorKind::NotFound, "cannot connect to fluentd"))
     }
-}
 
-///Creates writer by resolving address from provided string.
-impl MakeWriter for &'static str {
-    type Writer = std::net::TcpStream;
-
-    #[inline(always)]
-    fn make(&self) -> std::io::Result<Self::Writer> {
-        let addrs = self.to_socket_addrs()?;
+    ///Resolves `addr` and connects to the first available address.
+    fn connect_resolved<A: ToSocketAddrs + ?Sized>(&self, addr: &A) -> std::io::Result<TcpStream> {
+        let addrs = match addr.to_socket_addrs() {
+            Ok(addrs) => addrs,
+            Err(error) => return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("cannot resolve fluentd address: {}", error))),
+        };
 
-        for addr in addrs.as_slice().iter() {
-            match std::net::TcpStream::connect_timeout(addr, core::time::Duration::from_secs(1)) {
+        for addr in addrs {
+            match self.connect_addr(&addr) {
                 Ok(socket) => return Ok(socket),
                 Err(_) => continue,
             }
@@ -37,67 +66,248 @@ impl MakeWriter for &'static str {
     }
 }
 
+#[inline(always)]
+pub(crate) fn connect_any(addrs: &[SocketAddr]) -> std::io::Result<TcpStream> {
+    ConnectOpts::DEFAULT.connect_any(addrs)
+}
+
+#[inline(always)]
+fn connect_resolved<A: ToSocketAddrs + ?Sized>(addr: &A) -> std::io::Result<TcpStream> {
+    ConnectOpts::DEFAULT.connect_resolved(addr)
+}
+
+///Tcp writer with configurable connection attempts.
+///
+///Wraps any address, that can be resolved via `ToSocketAddrs`, and connects to the first available
+///address, trying each address up to `attempts` times with `delay` in between.
+///
+///Time spent to connect is bounded by `addresses * (attempts * timeout + (attempts - 1) * delay)`.
+///
+///Defaults match other tcp writers: single attempt per address with timeout of 1s.
+pub struct TcpConfig<A> {
+    addr: A,
+    opts: ConnectOpts,
+}
+
+impl<A: ToSocketAddrs> TcpConfig<A> {
+    #[inline(always)]
+    ///Creates new configuration for `addr`.
+    pub const fn new(addr: A) -> Self {
+        Self {
+            addr,
+            opts: ConnectOpts::DEFAULT,
+        }
+    }
+
+    #[inline(always)]
+    ///Sets timeout of every connection attempt.
+    pub const fn timeout(mut self, timeout: Duration) -> Self {
+        self.opts.timeout = timeout;
+        self
+    }
+
+    #[inline(always)]
+    ///Sets number of connection attempts per address.
+    pub const fn attempts(mut self, attempts: core::num::NonZeroUsize) -> Self {
+        self.opts.attempts = attempts.get();
+        self
+    }
+
+    #[inline(always)]
+    ///Sets delay between attempts to connect to the same address.
+    pub const fn delay(mut self, delay: Duration) -> Self {
+        self.opts.delay = delay;
+        self
+    }
+}
+
+impl<A: ToSocketAddrs + Send + 'static> MakeWriter for TcpConfig<A> {
+    type Writer = TcpStream;
+
+    #[inline(always)]
+    fn make(&self) -> std::io::Result<Self::Writer> {
+        self.opts.connect_resolved(&self.addr)
+    }
+
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> std::io::Result<()> {
+        ProbeWrite::probe(writer)
+    }
+}
+
+impl MakeWriter for std::vec::IntoIter<SocketAddr> {
+    type Writer = TcpStream;
+
+    #[inline(always)]
+    fn make(&self) -> std::io::Result<Self::Writer> {
+        connect_any(self.as_slice())
+    }
+
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> std::io::Result<()> {
+        ProbeWrite::probe(writer)
+    }
+}
+
+///Creates writer by connecting to the first available address, trying them in order.
+///
+///For high availability setups prefer `writer::failover`, which keeps using the last successfully
+///connected address.
+impl MakeWriter for Vec<SocketAddr> {
+    type Writer = TcpStream;
+
+    #[inline(always)]
+    fn make(&self) -> std::io::Result<Self::Writer> {
+        connect_any(self.as_slice())
+    }
+
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> std::io::Result<()> {
+        ProbeWrite::probe(writer)
+    }
+}
+
+///Creates writer by connecting to the first available address, trying them in order.
+///
+///For high availability setups prefer `writer::failover`, which keeps using the last successfully
+///connected address.
+impl MakeWriter for &'static [SocketAddr] {
+    type Writer = TcpStream;
+
+    #[inline(always)]
+    fn make(&self) -> std::io::Result<Self::Writer> {
+        connect_any(self)
+    }
+
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> std::io::Result<()> {
+        ProbeWrite::probe(writer)
+    }
+}
+
+///Creates writer by resolving address from provided string.
+impl MakeWriter for &'static str {
+    type Writer = TcpStream;
+
+    #[inline(always)]
+    fn make(&self) -> std::io::Result<Self::Writer> {
+        connect_resolved(self)
+    }
+
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> std::io::Result<()> {
+        ProbeWrite::probe(writer)
+    }
+}
+
 ///Creates writer by resolving address from provided string and port.
 impl MakeWriter for (&'static str, u16) {
-    type Writer = std::net::TcpStream;
+    type Writer = TcpStream;
 
     #[inline(always)]
     fn make(&self) -> std::io::Result<Self::Writer> {
-        let addrs = self.to_socket_addrs()?;
+        connect_resolved(self)
+    }
 
-        for addr in addrs.as_slice().iter() {
-            match std::net::TcpStream::connect_timeout(addr, core::time::Duration::from_secs(1)) {
-                Ok(socket) => return Ok(socket),
-                Err(_) => continue,
-            }
-        }
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> std::io::Result<()> {
+        ProbeWrite::probe(writer)
+    }
+}
 
-        Err(std::io::Error::new(std::io::ErrorKind::NotFound, "cannot connect to fluentd"))
+///Creates writer by resolving address from provided string.
+impl MakeWriter for String {
+    type Writer = TcpStream;
+
+    #[inline(always)]
+    fn make(&self) -> std::io::Result<Self::Writer> {
+        connect_resolved(self.as_str())
+    }
+
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> std::io::Result<()> {
+        ProbeWrite::probe(writer)
     }
 }
 
-impl MakeWriter for std::net::SocketAddr {
-    type Writer = std::net::TcpStream;
+///Creates writer by resolving address from provided string.
+impl MakeWriter for std::borrow::Cow<'static, str> {
+    type Writer = TcpStream;
 
     #[inline(always)]
     fn make(&self) -> std::io::Result<Self::Writer> {
-        match std::net::TcpStream::connect_timeout(self, core::time::Duration::from_secs(1)) {
-            Ok(socket) => Ok(socket),
-            Err(_) => Err(std::io::Error::new(std::io::ErrorKind::NotFound, "cannot connect to fluentd")),
-        }
+        connect_resolved(self.as_ref())
+    }
+
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> std::io::Result<()> {
+        ProbeWrite::probe(writer)
     }
 }
 
-impl MakeWriter for [std::net::SocketAddr; 1] {
-    type Writer = std::net::TcpStream;
+///Creates writer by resolving address from provided string and port.
+impl MakeWriter for (String, u16) {
+    type Writer = TcpStream;
 
     #[inline(always)]
     fn make(&self) -> std::io::Result<Self::Writer> {
-        match std::net::TcpStream::connect_timeout(&self[0], core::time::Duration::from_secs(1)) {
+        connect_resolved(&(self.0.as_str(), self.1))
+    }
+
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> std::io::Result<()> {
+        ProbeWrite::probe(writer)
+    }
+}
+
+impl MakeWriter for SocketAddr {
+    type Writer = TcpStream;
+
+    #[inline(always)]
+    fn make(&self) -> std::io::Result<Self::Writer> {
+        match ConnectOpts::DEFAULT.connect_addr(self) {
             Ok(socket) => Ok(socket),
             Err(_) => Err(std::io::Error::new(std::io::ErrorKind::NotFound, "cannot connect to fluentd")),
         }
     }
+
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> std::io::Result<()> {
+        ProbeWrite::probe(writer)
+    }
+}
+
+impl MakeWriter for [SocketAddr; 1] {
+    type Writer = TcpStream;
+
+    #[inline(always)]
+    fn make(&self) -> std::io::Result<Self::Writer> {
+        self[0].make()
+    }
+
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> std::io::Result<()> {
+        ProbeWrite::probe(writer)
+    }
 }
 
 //While we can use generics, it doesn't really make sense to store addresses in such big arrays.
+//Prefer `Vec<SocketAddr>` or `&'static [SocketAddr]` for arbitrary number of addresses.
 macro_rules! impl_for_socket_addr_array {
     ($($idx:literal),+) => {
 
         $(
-            impl MakeWriter for [std::net::SocketAddr; $idx] {
-                type Writer = std::net::TcpStream;
+            impl MakeWriter for [SocketAddr; $idx] {
+                type Writer = TcpStream;
 
                 #[inline(always)]
                 fn make(&self) -> std::io::Result<Self::Writer> {
-                    for addr in self {
-                        match std::net::TcpStream::connect_timeout(addr, core::time::Duration::from_secs(1)) {
-                            Ok(socket) => return Ok(socket),
-                            Err(_) => continue,
-                        }
-                    }
-
-                    Err(std::io::Error::new(std::io::ErrorKind::NotFound, "cannot connect to fluentd"))
+                    connect_any(self)
+                }
+
+                #[inline(always)]
+                fn probe(&self, writer: &mut Self::Writer) -> std::io::Result<()> {
+                    ProbeWrite::probe(writer)
                 }
             }
         )+
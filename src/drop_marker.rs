@@ -0,0 +1,86 @@
+//!Records, marking dropped records within data stream.
+
+use core::time::Duration;
+use std::sync::{Arc, Mutex};
+
+use crate::{fluent, Clock, DropReason};
+use crate::clock::unix_time;
+use crate::worker::DropHandler;
+
+///Records dropped for the same reason since last marker.
+struct Episode {
+    reason: DropReason,
+    count: usize,
+    from: Duration,
+    to: Duration,
+}
+
+impl Episode {
+    fn record(&self, tag: &Arc<str>) -> fluent::Record {
+        let mut record = fluent::Record::with_time(self.to);
+        record.set_tag(tag.clone());
+        record.insert("message".into(), format!("dropped {} records ({})", self.count, self.reason.as_str()).into());
+        record.insert("reason".into(), self.reason.as_str().into());
+        record.insert("count".into(), (self.count as u64).into());
+        record.insert("from".into(), (self.from.as_millis() as u64).into());
+        record.insert("to".into(), (self.to.as_millis() as u64).into());
+        record
+    }
+}
+
+///Drops, accumulated until worker sends markers, configured via `Builder::with_drop_markers`.
+pub(crate) struct DropMarkers {
+    tag: Arc<str>,
+    clock: Arc<dyn Clock>,
+    episodes: Mutex<Vec<Episode>>,
+}
+
+impl DropMarkers {
+    ///Creates markers with tag `<tag>.<suffix>`.
+    pub(crate) fn new(tag: &'static str, suffix: &'static str, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            tag: Arc::from(format!("{}.{}", tag, suffix)),
+            clock,
+            episodes: Mutex::new(Vec::new()),
+        }
+    }
+
+    ///Wraps `on_drop` to accumulate drops, before calling it.
+    pub(crate) fn counting(self: &Arc<Self>, on_drop: Option<DropHandler>) -> DropHandler {
+        let markers = self.clone();
+        Arc::new(move |reason, count| {
+            markers.on_drop(reason, count);
+            if let Some(on_drop) = on_drop.as_ref() {
+                on_drop(reason, count);
+            }
+        })
+    }
+
+    fn on_drop(&self, reason: DropReason, count: usize) {
+        let now = unix_time(self.clock.system_time());
+        let mut episodes = self.episodes.lock().unwrap_or_else(|error| error.into_inner());
+        match episodes.iter_mut().find(|episode| episode.reason == reason) {
+            Some(episode) => {
+                episode.count += count;
+                episode.to = now;
+            },
+            None => episodes.push(Episode {
+                reason,
+                count,
+                from: now,
+                to: now,
+            }),
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    ///Returns marker of every reason, dropped since previous call.
+    pub(crate) fn take(&self) -> Vec<fluent::Record> {
+        let mut episodes = self.episodes.lock().unwrap_or_else(|error| error.into_inner());
+        episodes.drain(..).map(|episode| episode.record(&self.tag)).collect()
+    }
+}
@@ -0,0 +1,316 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use tracing_core::{Event, Field, Interest, Metadata};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+use crate::{Scope, SampleDecision, fluent};
+
+#[derive(Clone, Debug)]
+struct FilterState {
+    max_level: LevelFilter,
+    targets: Option<Targets>,
+    sample_ratio: f64,
+}
+
+fn read(state: &RwLock<FilterState>) -> RwLockReadGuard<'_, FilterState> {
+    state.read().unwrap_or_else(|error| error.into_inner())
+}
+
+fn write(state: &RwLock<FilterState>) -> RwLockWriteGuard<'_, FilterState> {
+    state.write().unwrap_or_else(|error| error.into_inner())
+}
+
+type DecideFn = Arc<dyn Fn(&fluent::Value) -> SampleDecision + Send + Sync>;
+
+#[derive(Clone)]
+///Field, which value decides whether event is sampled.
+struct SamplingKey {
+    key: &'static str,
+    decide: DecideFn,
+}
+
+impl core::fmt::Debug for SamplingKey {
+    #[inline]
+    fn fmt(&self, fmt: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        fmt.debug_struct("SamplingKey").field("key", &self.key).finish()
+    }
+}
+
+///Visitor looking for value of single field.
+struct KeyVisitor {
+    key: &'static str,
+    value: fluent::Map,
+}
+
+impl tracing_core::field::Visit for KeyVisitor {
+    #[inline(always)]
+    fn record_debug(&mut self, field: &Field, value: &dyn core::fmt::Debug) {
+        if field.name() == self.key {
+            self.value.record_debug(field, value);
+        }
+    }
+
+    #[inline(always)]
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if field.name() == self.key {
+            self.value.record_i64(field, value);
+        }
+    }
+
+    #[inline(always)]
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == self.key {
+            self.value.record_u64(field, value);
+        }
+    }
+
+    #[inline(always)]
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if field.name() == self.key {
+            self.value.record_f64(field, value);
+        }
+    }
+
+    #[inline(always)]
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == self.key {
+            self.value.record_bool(field, value);
+        }
+    }
+
+    #[inline(always)]
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == self.key {
+            self.value.record_str(field, value);
+        }
+    }
+}
+
+impl SamplingKey {
+    ///Returns value of field within `event` or, if event doesn't have it, within the closest span
+    ///that has it.
+    fn value<'a, R: LookupSpan<'a>>(&self, event: &Event<'_>, span: Option<SpanRef<'a, R>>) -> Option<fluent::Value> {
+        //Avoid visiting fields of events that cannot have it.
+        if event.metadata().fields().field(self.key).is_some() {
+            let mut visitor = KeyVisitor {
+                key: self.key,
+                value: fluent::Map::new(),
+            };
+            event.record(&mut visitor);
+            if let Some(value) = visitor.value.swap_remove(self.key) {
+                return Some(value);
+            }
+        }
+
+        for span in Scope::new(span?) {
+            if let Some(value) = span.extensions().get::<fluent::Map>().and_then(|record| record.get(self.key)) {
+                return Some(value.clone());
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug)]
+///Filter of events recorded by `Layer`.
+///
+///Configured via `Builder::with_max_level`, `Builder::with_targets` and `Builder::with_sample_ratio`,
+///and can be changed at runtime via `Handle`.
+///
+///Only events are filtered, while spans are always enabled, so that their attributes are
+///available to recorded events.
+///
+///`Layer` applies it to every event by itself, but as plain layer it cannot disable callsites
+///without disabling them for every other layer too.
+///Use `Layer::filtered` to apply it as per-layer filter instead, so that disabled callsites are
+///never reported to `Layer`.
+///
+///Cloning creates independent filter with the same configuration.
+pub struct LayerFilter {
+    state: Arc<RwLock<FilterState>>,
+    sampled: Arc<AtomicU64>,
+    sampling_key: Option<SamplingKey>,
+}
+
+impl LayerFilter {
+    #[inline(always)]
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Arc::new(RwLock::new(FilterState {
+                max_level: LevelFilter::TRACE,
+                targets: None,
+                sample_ratio: 1.0,
+            })),
+            sampled: Arc::new(AtomicU64::new(0)),
+            sampling_key: None,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn set_max_level(&mut self, max_level: LevelFilter) {
+        write(&self.state).max_level = max_level;
+    }
+
+    #[inline(always)]
+    pub(crate) fn set_targets(&mut self, targets: Targets) {
+        write(&self.state).targets = Some(targets);
+    }
+
+    #[inline(always)]
+    pub(crate) fn set_sample_ratio(&mut self, ratio: f64) {
+        write(&self.state).sample_ratio = ratio;
+    }
+
+    #[inline(always)]
+    pub(crate) fn set_sampling_key(&mut self, key: &'static str, decide: DecideFn) {
+        self.sampling_key = Some(SamplingKey {
+            key,
+            decide,
+        });
+    }
+
+    #[inline(always)]
+    ///Creates filter that shares configuration with this one.
+    pub(crate) fn shared(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            sampled: self.sampled.clone(),
+            sampling_key: self.sampling_key.clone(),
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn handle(&self) -> Handle {
+        Handle {
+            state: self.state.clone(),
+        }
+    }
+
+    #[inline]
+    ///Returns whether event with `metadata` is recorded.
+    pub fn would_enable(&self, metadata: &Metadata<'_>) -> bool {
+        if metadata.is_span() {
+            return true;
+        }
+
+        let state = read(&self.state);
+        if *metadata.level() > state.max_level {
+            return false;
+        }
+
+        match state.targets.as_ref() {
+            Some(targets) => targets.would_enable(metadata.target(), metadata.level()),
+            None => true,
+        }
+    }
+
+    ///Returns whether next enabled event is sampled.
+    ///
+    ///Sampled events are spread evenly, e.g. with ratio `0.25` every fourth event is sampled.
+    pub(crate) fn sample(&self) -> bool {
+        let ratio = read(&self.state).sample_ratio;
+        if ratio >= 1.0 {
+            return true;
+        } else if ratio <= 0.0 {
+            return false;
+        }
+
+        let idx = self.sampled.fetch_add(1, Ordering::Relaxed);
+        (((idx + 1) as f64) * ratio).floor() > ((idx as f64) * ratio).floor()
+    }
+
+    ///Returns whether `event`, within `span`, is sampled.
+    ///
+    ///Applies decision of sampling key, if any, falling back to sample ratio.
+    pub(crate) fn sample_event<'a, R: LookupSpan<'a>>(&self, event: &Event<'_>, span: Option<SpanRef<'a, R>>) -> bool {
+        let decision = match self.sampling_key.as_ref() {
+            Some(sampling_key) => match sampling_key.value(event, span) {
+                Some(value) => (sampling_key.decide)(&value),
+                None => SampleDecision::UseDefaultRatio,
+            },
+            None => SampleDecision::UseDefaultRatio,
+        };
+
+        match decision {
+            SampleDecision::Keep => true,
+            SampleDecision::Drop => false,
+            SampleDecision::UseDefaultRatio => self.sample(),
+        }
+    }
+}
+
+impl Clone for LayerFilter {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            state: Arc::new(RwLock::new(read(&self.state).clone())),
+            sampled: Arc::new(AtomicU64::new(0)),
+            sampling_key: self.sampling_key.clone(),
+        }
+    }
+}
+
+impl<S> tracing_subscriber::layer::Filter<S> for LayerFilter {
+    #[inline(always)]
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: &Context<'_, S>) -> bool {
+        self.would_enable(metadata)
+    }
+
+    #[inline]
+    fn callsite_enabled(&self, metadata: &'static Metadata<'static>) -> Interest {
+        //Filter changes only via `Handle`, which rebuilds interest of all callsites.
+        match self.would_enable(metadata) {
+            true => Interest::always(),
+            false => Interest::never(),
+        }
+    }
+}
+
+#[derive(Clone)]
+///Handle to change filtering of `Layer` at runtime.
+///
+///Created via `Builder::layer_with_handle`.
+///
+///Changes take effect for subsequent events, without need to re-create subscriber.
+pub struct Handle {
+    state: Arc<RwLock<FilterState>>,
+}
+
+impl Handle {
+    ///Sets maximum level of recorded events.
+    pub fn set_max_level(&self, max_level: LevelFilter) {
+        write(&self.state).max_level = max_level;
+        tracing_core::callsite::rebuild_interest_cache();
+    }
+
+    ///Sets targets filter of recorded events, or removes it if `None`.
+    pub fn set_targets(&self, targets: Option<Targets>) {
+        write(&self.state).targets = targets;
+        tracing_core::callsite::rebuild_interest_cache();
+    }
+
+    ///Sets ratio of enabled events to record, from `0.0` to `1.0`.
+    pub fn set_sample_ratio(&self, ratio: f64) {
+        write(&self.state).sample_ratio = ratio;
+    }
+
+    ///Returns current maximum level of recorded events.
+    pub fn max_level(&self) -> LevelFilter {
+        read(&self.state).max_level
+    }
+
+    ///Returns current ratio of enabled events to record.
+    pub fn sample_ratio(&self) -> f64 {
+        read(&self.state).sample_ratio
+    }
+}
+
+impl core::fmt::Debug for Handle {
+    #[inline]
+    fn fmt(&self, fmt: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(&*read(&self.state), fmt)
+    }
+}
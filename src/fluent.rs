@@ -1,69 +1,70 @@
 //!Fluentd forward protocol definitions.
-use serde::ser::{Serialize, Serializer, SerializeTuple, SerializeMap};
+use serde::ser::{Serialize, Serializer, SerializeTuple, SerializeMap, SerializeSeq};
 
 use std::time;
 use core::fmt;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::sync::Arc;
+pub mod decode;
+mod map;
 
-#[derive(Clone)]
-#[repr(transparent)]
-///HashMap object suitable for fluent record.
-pub struct Map(HashMap<Cow<'static, str>, Value>);
+pub use map::{Drain, Entry, IntoIter, Iter, IterMut, Key, Keys, Map, Values, INLINE_LEN};
 
 impl Map {
-    #[inline(always)]
-    ///Creates new empty map.
-    pub fn new() -> Self {
-        Self(HashMap::new())
+    ///Returns approximate size of encoded map in bytes.
+    pub(crate) fn estimated_size(&self) -> usize {
+        self.iter().fold(5, |size, (key, value)| size + key.len() + 5 + value.estimated_size())
     }
 }
 
-impl core::fmt::Debug for Map {
-    #[inline(always)]
-    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::fmt::Result {
-        core::fmt::Debug::fmt(&self.0, fmt)
-    }
-}
-
-impl core::ops::Deref for Map {
-    type Target = HashMap<Cow<'static, str>, Value>;
-
-    #[inline(always)]
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-impl core::ops::DerefMut for Map {
-    #[inline(always)]
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
+#[derive(Clone, Debug)]
+///Content of the option section of forward mode message.
+pub enum OptionSection {
+    ///Omits option section, making message to consist only of tag and entries.
+    Omit,
+    ///Includes only `size`, which is number of entries.
+    ///
+    ///This is default.
+    Size,
+    ///Includes `size` with additional entries.
+    ///
+    ///`size` within map is ignored, as it is always set to number of entries.
+    Custom(Map),
 }
 
 #[derive(Debug)]
 pub(crate) struct Opts {
     size: usize,
+    section: OptionSection,
 }
 
 #[derive(Clone)]
 ///Map value type.
 pub enum Value {
+    ///Null
+    Null,
     ///Boolean
     Bool(bool),
     ///Integer
     Int(i64),
     ///Unsigned integer
     Uint(u64),
+    ///Floating point
+    Float(f64),
     ///String
     Str(&'static str),
     ///Owned string
     String(String),
+    ///Shared string, which is cheap to clone.
+    ///
+    ///Used for string fields of spans, as they are cloned into every event within span.
+    Shared(Arc<str>),
     ///Event level
     EventLevel(tracing_core::Level),
     ///Object
     Object(Map),
+    ///Array
+    Array(Vec<Value>),
 }
 
 impl From<bool> for Value {
@@ -80,6 +81,13 @@ impl From<i64> for Value {
     }
 }
 
+impl From<i32> for Value {
+    #[inline(always)]
+    fn from(val: i32) -> Self {
+        Self::Int(val as _)
+    }
+}
+
 impl From<u32> for Value {
     #[inline(always)]
     fn from(val: u32) -> Self {
@@ -94,6 +102,13 @@ impl From<u64> for Value {
     }
 }
 
+impl From<f64> for Value {
+    #[inline(always)]
+    fn from(val: f64) -> Self {
+        Self::Float(val)
+    }
+}
+
 impl From<&'static str> for Value {
     #[inline(always)]
     fn from(val: &'static str) -> Self {
@@ -108,6 +123,13 @@ impl From<String> for Value {
     }
 }
 
+impl From<Arc<str>> for Value {
+    #[inline(always)]
+    fn from(val: Arc<str>) -> Self {
+        Self::Shared(val)
+    }
+}
+
 impl From<tracing::Level> for Value {
     #[inline(always)]
     fn from(val: tracing::Level) -> Self {
@@ -122,43 +144,328 @@ impl From<Map> for Value {
     }
 }
 
+impl From<Vec<Value>> for Value {
+    #[inline(always)]
+    fn from(val: Vec<Value>) -> Self {
+        Self::Array(val)
+    }
+}
+
+#[cfg(feature = "json")]
+impl From<serde_json::Value> for Value {
+    fn from(val: serde_json::Value) -> Self {
+        match val {
+            serde_json::Value::Null => Self::Null,
+            serde_json::Value::Bool(val) => Self::Bool(val),
+            serde_json::Value::Number(val) => match (val.as_u64(), val.as_i64(), val.as_f64()) {
+                (Some(val), _, _) => Self::Uint(val),
+                (None, Some(val), _) => Self::Int(val),
+                (None, None, Some(val)) => Self::Float(val),
+                (None, None, None) => Self::String(val.to_string()),
+            },
+            serde_json::Value::String(val) => Self::String(val),
+            serde_json::Value::Array(val) => Self::Array(val.into_iter().map(Into::into).collect()),
+            serde_json::Value::Object(val) => {
+                let mut map = Map::new();
+                for (key, value) in val {
+                    map.insert(key.into(), value.into());
+                }
+                Self::Object(map)
+            },
+        }
+    }
+}
+
+impl Value {
+    #[inline]
+    ///Creates object, filled by `fun`.
+    ///
+    ///```rust
+    ///use tracing_fluentd::fluent::Value;
+    ///
+    ///let value = Value::object(|map| {
+    ///    map.insert("zone".into(), "eu-1".into());
+    ///    map.insert("labels".into(), Value::object(|labels| {
+    ///        labels.insert("tier".into(), 1u64.into());
+    ///    }));
+    ///});
+    ///assert!(matches!(value, Value::Object(ref map) if map.len() == 2));
+    ///```
+    pub fn object<F: FnOnce(&mut Map)>(fun: F) -> Self {
+        let mut map = Map::new();
+        fun(&mut map);
+        Value::Object(map)
+    }
+
+    #[inline]
+    ///Returns string, if value is one of string variants.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(val) => Some(val),
+            Value::String(val) => Some(val),
+            Value::Shared(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    ///Returns approximate size of encoded value in bytes.
+    pub(crate) fn estimated_size(&self) -> usize {
+        //Headers of strings and collections take up to 5 bytes, while numbers up to 9.
+        match self {
+            Value::Null | Value::Bool(_) => 1,
+            Value::Int(_) | Value::Uint(_) | Value::Float(_) => 9,
+            Value::EventLevel(_) => 6,
+            Value::Str(val) => val.len() + 5,
+            Value::String(val) => val.len() + 5,
+            Value::Shared(val) => val.len() + 5,
+            Value::Object(val) => val.estimated_size(),
+            Value::Array(val) => val.iter().fold(5, |size, value| size + value.estimated_size()),
+        }
+    }
+}
+
 impl fmt::Debug for Value {
     #[inline(always)]
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            Value::Null => fmt.write_str("null"),
             Value::Bool(val) => fmt::Display::fmt(val, fmt),
             Value::Int(val) => fmt::Display::fmt(val, fmt),
             Value::Uint(val) => fmt::Display::fmt(val, fmt),
+            Value::Float(val) => fmt::Display::fmt(val, fmt),
             Value::EventLevel(val) => fmt::Debug::fmt(val, fmt),
             Value::Str(val) => fmt::Debug::fmt(val, fmt),
             Value::String(val) => fmt::Debug::fmt(val, fmt),
+            Value::Shared(val) => fmt::Debug::fmt(val, fmt),
             Value::Object(val) => fmt::Debug::fmt(val, fmt),
+            Value::Array(val) => fmt::Debug::fmt(val, fmt),
+        }
+    }
+}
+
+#[cfg(all(tracing_unstable, feature = "valuable"))]
+impl From<valuable::Value<'_>> for Value {
+    fn from(val: valuable::Value<'_>) -> Self {
+        use valuable::{Visit, NamedValues, Fields};
+
+        struct ListVisitor(Vec<Value>);
+
+        impl Visit for ListVisitor {
+            #[inline(always)]
+            fn visit_value(&mut self, value: valuable::Value<'_>) {
+                self.0.push(value.into());
+            }
+
+            #[inline]
+            fn visit_unnamed_fields(&mut self, values: &[valuable::Value<'_>]) {
+                self.0.extend(values.iter().map(|value| Value::from(*value)));
+            }
+        }
+
+        struct MapVisitor(Map);
+
+        impl Visit for MapVisitor {
+            #[inline(always)]
+            fn visit_value(&mut self, _: valuable::Value<'_>) {
+            }
+
+            #[inline]
+            fn visit_named_fields(&mut self, values: &NamedValues<'_>) {
+                for (field, value) in values.iter() {
+                    self.0.insert(field.name().to_owned().into(), (*value).into());
+                }
+            }
+
+            #[inline]
+            fn visit_entry(&mut self, key: valuable::Value<'_>, value: valuable::Value<'_>) {
+                let key = match key {
+                    valuable::Value::String(key) => key.to_owned(),
+                    key => format!("{:?}", key),
+                };
+                self.0.insert(key.into(), value.into());
+            }
+        }
+
+        match val {
+            valuable::Value::Bool(val) => Value::Bool(val),
+            valuable::Value::I8(val) => Value::Int(val.into()),
+            valuable::Value::I16(val) => Value::Int(val.into()),
+            valuable::Value::I32(val) => Value::Int(val.into()),
+            valuable::Value::I64(val) => Value::Int(val),
+            valuable::Value::Isize(val) => Value::Int(val as _),
+            valuable::Value::U8(val) => Value::Uint(val.into()),
+            valuable::Value::U16(val) => Value::Uint(val.into()),
+            valuable::Value::U32(val) => Value::Uint(val.into()),
+            valuable::Value::U64(val) => Value::Uint(val),
+            valuable::Value::Usize(val) => Value::Uint(val as _),
+            valuable::Value::F32(val) => Value::Float(val.into()),
+            valuable::Value::F64(val) => Value::Float(val),
+            valuable::Value::Char(val) => Value::String(val.to_string()),
+            valuable::Value::String(val) => Value::String(val.to_owned()),
+            valuable::Value::Path(val) => Value::String(format!("{}", val.display())),
+            valuable::Value::Error(val) => Value::String(format!("{}", val)),
+            valuable::Value::Listable(val) => {
+                let mut visitor = ListVisitor(Vec::with_capacity(val.size_hint().0));
+                val.visit(&mut visitor);
+                Value::Array(visitor.0)
+            },
+            valuable::Value::Tuplable(val) => {
+                let mut visitor = ListVisitor(Vec::new());
+                val.visit(&mut visitor);
+                Value::Array(visitor.0)
+            },
+            valuable::Value::Mappable(val) => {
+                let mut visitor = MapVisitor(Map::new());
+                val.visit(&mut visitor);
+                Value::Object(visitor.0)
+            },
+            valuable::Value::Structable(val) => match val.definition().fields() {
+                Fields::Named(_) => {
+                    let mut visitor = MapVisitor(Map::new());
+                    val.visit(&mut visitor);
+                    Value::Object(visitor.0)
+                },
+                Fields::Unnamed(_) => {
+                    let mut visitor = ListVisitor(Vec::new());
+                    val.visit(&mut visitor);
+                    Value::Array(visitor.0)
+                },
+            },
+            val => Value::String(format!("{:?}", val)),
         }
     }
 }
 
+///Default limit of nested objects and arrays within serialized value.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+const MAX_DEPTH_MARKER: &str = "[max depth exceeded]";
+
 #[derive(Debug)]
 ///Representation of fluent entry within `Message`
 pub struct Record {
     time: time::Duration,
     entries: Map,
+    max_depth: usize,
+    //Attributes of event's span, if enabled via `Builder::with_span_registry`
+    span: Option<crate::span_registry::SpanData>,
+    //Tag of record, if it differs from tag of worker.
+    tag: Option<Arc<str>>,
+    //Key of object with event metadata, which is kept last within record.
+    metadata_key: Option<Cow<'static, str>>,
 }
 
 impl Record {
     #[inline(always)]
     ///Creates record with current timestamp
+    ///
+    ///If system time is before UNIX epoch, timestamp is epoch itself.
     pub fn now() -> Self {
-        let time = match time::SystemTime::now().duration_since(time::SystemTime::UNIX_EPOCH) {
-            Ok(time) => time,
-            Err(_) => panic!("SystemTime is before UNIX!?"),
-        };
+        Self::with_time(crate::clock::unix_time(time::SystemTime::now()))
+    }
 
+    #[inline(always)]
+    ///Creates record with provided timestamp as duration since UNIX epoch.
+    pub fn with_time(time: time::Duration) -> Self {
         Self {
             time,
             entries: Map::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            span: None,
+            tag: None,
+            metadata_key: None,
         }
     }
 
+    #[inline(always)]
+    ///Sets limit of nested objects and arrays within record, which is enforced on serialization.
+    ///
+    ///Object or array, that exceeds limit, is serialized as `"[max depth exceeded]"` string.
+    ///Default is `DEFAULT_MAX_DEPTH`.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    #[inline(always)]
+    ///Returns record's timestamp as duration since UNIX epoch.
+    pub fn time(&self) -> time::Duration {
+        self.time
+    }
+
+    #[inline(always)]
+    ///Returns tag of record, if it overrides tag of worker.
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    #[inline(always)]
+    ///Returns tag of record, shared with encoded record.
+    pub(crate) fn shared_tag(&self) -> Option<Arc<str>> {
+        self.tag.clone()
+    }
+
+    #[inline(always)]
+    ///Overrides tag of worker for this record.
+    ///
+    ///Worker batches records by tag, sending records of every tag as separate message.
+    pub fn set_tag<T: Into<Arc<str>>>(&mut self, tag: T) {
+        self.tag = Some(tag.into());
+    }
+
+    #[inline(always)]
+    ///Returns approximate size of encoded record in bytes.
+    pub(crate) fn estimated_size(&self) -> usize {
+        //Array header and time
+        let span = self.span.as_ref().map_or(0, |span| span.estimated_size());
+        11 + self.entries.estimated_size() + span
+    }
+
+    #[inline(always)]
+    ///Attaches attributes of span, which are inserted into record by worker.
+    pub(crate) fn set_span(&mut self, span: crate::span_registry::SpanData) {
+        self.span = Some(span);
+    }
+
+    #[inline(always)]
+    ///Takes attributes of span, which are not yet inserted into record.
+    pub(crate) fn take_span(&mut self) -> Option<crate::span_registry::SpanData> {
+        self.span.take()
+    }
+
+    #[inline(always)]
+    ///Returns key of object with event metadata, if it is known.
+    pub(crate) fn metadata_key(&self) -> Option<&str> {
+        self.metadata_key.as_deref()
+    }
+
+    #[inline(always)]
+    ///Sets key of object with event metadata, which may differ from configured key if record
+    ///already contains field with such name.
+    pub(crate) fn set_metadata_key(&mut self, key: Cow<'static, str>) {
+        self.metadata_key = Some(key);
+    }
+
+    #[inline(always)]
+    ///Sets timestamp of record, reused via pool.
+    pub(crate) fn set_time(&mut self, time: time::Duration) {
+        self.time = time;
+    }
+
+    #[inline]
+    ///Clears record to be reused, retaining capacity of its entries.
+    pub(crate) fn reset(&mut self) {
+        self.entries.clear();
+        self.max_depth = DEFAULT_MAX_DEPTH;
+        self.span = None;
+        self.tag = None;
+        self.metadata_key = None;
+    }
+
+    #[inline]
+    ///Encodes record as `[time, record]` entry, suitable for `PackedMessage::add_encoded`.
+    pub fn encode(&self) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+        rmp_serde::encode::to_vec(self)
+    }
+
     #[inline(always)]
     ///Merges record entries with provided map
     pub fn update(&mut self, other: &Map) {
@@ -189,7 +496,7 @@ impl core::ops::DerefMut for Record {
 #[derive(Debug)]
 ///Forward mode message.
 pub struct Message {
-    tag: &'static str,
+    tag: Cow<'static, str>,
     entries: Vec<Record>,
     opts: Opts,
     //option
@@ -199,15 +506,55 @@ impl Message {
     #[inline(always)]
     ///Creates new message with provided tag.
     pub const fn new(tag: &'static str) -> Self {
+        Self::with_tag(Cow::Borrowed(tag))
+    }
+
+    #[inline(always)]
+    ///Creates new message with provided tag, which is not necessary static.
+    pub(crate) const fn with_tag(tag: Cow<'static, str>) -> Self {
         Self {
             tag,
             entries: Vec::new(),
             opts: Opts {
                 size: 0,
+                section: OptionSection::Size,
             }
         }
     }
 
+    #[inline(always)]
+    ///Returns tag of the message.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    #[inline(always)]
+    ///Returns records of the message.
+    pub fn records(&self) -> &[Record] {
+        &self.entries
+    }
+
+    #[inline(always)]
+    ///Returns content of option section.
+    pub fn option_section(&self) -> &OptionSection {
+        &self.opts.section
+    }
+
+    #[inline(always)]
+    ///Sets content of option section.
+    pub fn set_option_section(&mut self, section: OptionSection) {
+        self.opts.section = section;
+    }
+
+    #[inline(always)]
+    ///Returns custom entries of option section, if it is `OptionSection::Custom`.
+    pub fn option_fields_mut(&mut self) -> Option<&mut Map> {
+        match &mut self.opts.section {
+            OptionSection::Custom(fields) => Some(fields),
+            _ => None,
+        }
+    }
+
     #[inline(always)]
     ///Adds record to the message.
     pub fn add(&mut self, record: Record) {
@@ -227,6 +574,101 @@ impl Message {
         self.entries.clear();
         self.opts.size = 0;
     }
+
+    #[inline(always)]
+    ///Removes records from the message, returning them.
+    pub(crate) fn drain(&mut self) -> std::vec::Drain<'_, Record> {
+        self.opts.size = 0;
+        self.entries.drain(..)
+    }
+
+    ///Retains only records for which `fun` returns `true`, given index of record and record itself.
+    ///
+    ///Removed records are dropped.
+    pub(crate) fn retain<F: FnMut(usize, &Record) -> bool>(&mut self, mut fun: F) {
+        let mut idx = 0;
+        self.entries.retain(|record| {
+            let is_retained = fun(idx, record);
+            idx += 1;
+            is_retained
+        });
+        self.opts.size = self.entries.len();
+    }
+}
+
+#[derive(Debug)]
+///PackedForward mode message, containing already encoded entries.
+///
+///Entries are encoded the same way as within `Message`, but are sent as single binary, which
+///allows to encode them beforehand.
+pub struct PackedMessage {
+    tag: Cow<'static, str>,
+    entries: Vec<u8>,
+    opts: Opts,
+}
+
+impl PackedMessage {
+    #[inline(always)]
+    ///Creates new message with provided tag.
+    pub const fn new(tag: &'static str) -> Self {
+        Self::with_tag(Cow::Borrowed(tag))
+    }
+
+    #[inline(always)]
+    ///Creates new message with provided tag, which is not necessary static.
+    pub(crate) const fn with_tag(tag: Cow<'static, str>) -> Self {
+        Self {
+            tag,
+            entries: Vec::new(),
+            opts: Opts {
+                size: 0,
+                section: OptionSection::Size,
+            }
+        }
+    }
+
+    #[inline(always)]
+    ///Sets content of option section.
+    pub fn set_option_section(&mut self, section: OptionSection) {
+        self.opts.section = section;
+    }
+
+    #[inline(always)]
+    ///Returns custom entries of option section, if it is `OptionSection::Custom`.
+    pub fn option_fields_mut(&mut self) -> Option<&mut Map> {
+        match &mut self.opts.section {
+            OptionSection::Custom(fields) => Some(fields),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    ///Adds record, encoded via `Record::encode`, to the message.
+    pub fn add_encoded(&mut self, entry: &[u8]) {
+        self.entries.extend_from_slice(entry);
+        self.opts.size += 1;
+    }
+
+    #[inline]
+    ///Encodes and adds record to the message.
+    pub fn add(&mut self, record: &Record) -> Result<(), rmp_serde::encode::Error> {
+        rmp_serde::encode::write(&mut self.entries, record)?;
+        self.opts.size += 1;
+        Ok(())
+    }
+
+    #[inline(always)]
+    ///Returns number of records inside message.
+    pub fn len(&self) -> usize {
+        self.opts.size
+    }
+
+    #[inline(always)]
+    ///Clears records from the message
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.opts.size = 0;
+    }
 }
 
 fn tracing_level_to_str(level: tracing_core::Level) -> &'static str {
@@ -243,44 +685,121 @@ fn tracing_level_to_str(level: tracing_core::Level) -> &'static str {
     }
 }
 
-impl Serialize for Value {
-    #[inline]
+///Formats time since UNIX epoch as RFC3339 UTC timestamp with nanoseconds precision.
+pub(crate) fn rfc3339(time: time::Duration) -> String {
+    const SECS_PER_DAY: u64 = 86400;
+
+    let secs = time.as_secs();
+    let days = secs / SECS_PER_DAY;
+    let secs_of_day = secs % SECS_PER_DAY;
+
+    //Civil date from days since epoch
+    //http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719468;
+    let era = days / 146097;
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+            year, month, day,
+            secs_of_day / 3600, (secs_of_day % 3600) / 60, secs_of_day % 60,
+            time.subsec_nanos())
+}
+
+pub(crate) fn tracing_level_to_lowercase_str(level: tracing_core::Level) -> &'static str {
+    if level == tracing_core::Level::ERROR {
+        "error"
+    } else if level == tracing_core::Level::WARN {
+        "warn"
+    } else if level == tracing_core::Level::INFO {
+        "info"
+    } else if level == tracing_core::Level::DEBUG {
+        "debug"
+    } else {
+        "trace"
+    }
+}
+
+///Serializes value, allowing no more than specified number of nested objects and arrays.
+struct Limited<'a, T>(&'a T, usize);
+
+impl Serialize for Limited<'_, Value> {
     fn serialize<SER: Serializer>(&self, ser: SER) -> Result<SER::Ok, SER::Error> {
-        match self {
+        let Limited(value, depth) = *self;
+        match value {
+            Value::Null => ser.serialize_unit(),
             Value::Bool(val) => ser.serialize_bool(*val),
             Value::Int(val) => ser.serialize_i64(*val),
             Value::Uint(val) => ser.serialize_u64(*val),
+            Value::Float(val) => ser.serialize_f64(*val),
             Value::EventLevel(val) => ser.serialize_str(tracing_level_to_str(*val)),
             Value::Str(val) => ser.serialize_str(val),
             Value::String(val) => ser.serialize_str(val),
-            Value::Object(val) => {
-                let mut map = ser.serialize_map(Some(val.len()))?;
-                for (key, value) in val.iter() {
-                    map.serialize_entry(key, value)?;
+            Value::Shared(val) => ser.serialize_str(val),
+            Value::Object(_) | Value::Array(_) if depth == 0 => ser.serialize_str(MAX_DEPTH_MARKER),
+            Value::Object(val) => Limited(val, depth - 1).serialize(ser),
+            Value::Array(val) => {
+                let mut seq = ser.serialize_seq(Some(val.len()))?;
+                for value in val.iter() {
+                    seq.serialize_element(&Limited(value, depth - 1))?;
                 }
-                map.end()
+                seq.end()
             },
         }
     }
 }
 
+impl Serialize for Limited<'_, Map> {
+    fn serialize<SER: Serializer>(&self, ser: SER) -> Result<SER::Ok, SER::Error> {
+        let Limited(map, depth) = *self;
+        let mut ser_map = ser.serialize_map(Some(map.len()))?;
+        for (key, value) in map.iter() {
+            ser_map.serialize_entry(key, &Limited(value, depth))?;
+        }
+        ser_map.end()
+    }
+}
+
+impl Serialize for Value {
+    #[inline]
+    ///Serializes value, replacing objects and arrays nested deeper than `DEFAULT_MAX_DEPTH`.
+    fn serialize<SER: Serializer>(&self, ser: SER) -> Result<SER::Ok, SER::Error> {
+        Limited(self, DEFAULT_MAX_DEPTH).serialize(ser)
+    }
+}
+
 impl Serialize for Map {
     #[inline]
+    ///Serializes map, replacing objects and arrays nested deeper than `DEFAULT_MAX_DEPTH`.
     fn serialize<SER: Serializer>(&self, ser: SER) -> Result<SER::Ok, SER::Error> {
-        let mut map = ser.serialize_map(Some(self.0.len()))?;
-        for (key, value) in self.0.iter() {
-            map.serialize_entry(key, value)?;
-        }
-        map.end()
+        Limited(self, DEFAULT_MAX_DEPTH).serialize(ser)
     }
 }
 
 impl Serialize for Opts {
     #[inline]
     fn serialize<SER: Serializer>(&self, ser: SER) -> Result<SER::Ok, SER::Error> {
-        let mut map = ser.serialize_map(Some(1))?;
-        map.serialize_entry("size", &self.size)?;
-        map.end()
+        match &self.section {
+            OptionSection::Custom(fields) => {
+                let len = fields.len() + 1 - fields.contains_key("size") as usize;
+                let mut map = ser.serialize_map(Some(len))?;
+                map.serialize_entry("size", &self.size)?;
+                for (key, value) in fields.iter().filter(|(key, _)| key.as_ref() != "size") {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            },
+            _ => {
+                let mut map = ser.serialize_map(Some(1))?;
+                map.serialize_entry("size", &self.size)?;
+                map.end()
+            },
+        }
     }
 }
 
@@ -317,9 +836,12 @@ impl Serialize for Record {
             //
             //Serialize time as EventTime ext
             //https://github.com/fluent/fluentd/wiki/Forward-Protocol-Specification-v1.5#eventtime-ext-format
-            //This is valid up to year 2106
-            let nanos = self.time.subsec_nanos();
+            //Seconds are 32bit, hence time after year 2106 cannot be represented.
+            if seconds > u64::from(u32::MAX) {
+                return Err(serde::ser::Error::custom("time exceeds range of EventTime"));
+            }
             let seconds = (seconds as u32).to_be_bytes();
+            let nanos = self.time.subsec_nanos();
             let nanos = nanos.to_be_bytes();
             let time = [seconds[0], seconds[1], seconds[2], seconds[3], nanos[0], nanos[1], nanos[2], nanos[3]];
             let time = ExtType((0, Int8(time)));
@@ -330,14 +852,62 @@ impl Serialize for Record {
             seq.serialize_element(&seconds)?;
         }
 
-        seq.serialize_element(&self.entries)?;
+        seq.serialize_element(&Limited(&self.entries, self.max_depth))?;
         seq.end()
     }
 }
 
+#[cfg(feature = "json")]
+///Record as JSON object, written on its own line.
+struct Line<'a> {
+    tag: &'a str,
+    record: &'a Record,
+}
+
+#[cfg(feature = "json")]
+impl Serialize for Line<'_> {
+    #[inline]
+    fn serialize<SER: Serializer>(&self, ser: SER) -> Result<SER::Ok, SER::Error> {
+        let mut map = ser.serialize_map(Some(3))?;
+        map.serialize_entry("tag", self.tag)?;
+        map.serialize_entry("time", &self.record.time.as_secs())?;
+        map.serialize_entry("record", &Limited(&self.record.entries, self.record.max_depth))?;
+        map.end()
+    }
+}
+
+#[cfg(feature = "json")]
+impl Record {
+    #[inline]
+    ///Writes record with `tag` as JSON object on its own line.
+    pub(crate) fn write_ndjson<W: std::io::Write>(&self, writer: &mut W, tag: &str) -> std::io::Result<()> {
+        serde_json::to_writer(&mut *writer, &Line { tag, record: self })?;
+        writer.write_all(b"\n")
+    }
+}
+
+#[cfg(feature = "json")]
+impl Message {
+    ///Writes every record as JSON object on its own line.
+    pub(crate) fn write_ndjson<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        for record in self.entries.iter() {
+            record.write_ndjson(writer, &self.tag)?;
+        }
+
+        Ok(())
+    }
+}
+
 impl Serialize for Message {
     #[inline]
     fn serialize<SER: Serializer>(&self, ser: SER) -> Result<SER::Ok, SER::Error> {
+        if let OptionSection::Omit = self.opts.section {
+            let mut seq = ser.serialize_tuple(2)?;
+            seq.serialize_element(&self.tag)?;
+            seq.serialize_element(&self.entries)?;
+            return seq.end();
+        }
+
         let mut seq = ser.serialize_tuple(3)?;
         seq.serialize_element(&self.tag)?;
         seq.serialize_element(&self.entries)?;
@@ -345,3 +915,30 @@ impl Serialize for Message {
         seq.end()
     }
 }
+
+impl Serialize for PackedMessage {
+    #[inline]
+    fn serialize<SER: Serializer>(&self, ser: SER) -> Result<SER::Ok, SER::Error> {
+        struct Entries<'a>(&'a [u8]);
+
+        impl Serialize for Entries<'_> {
+            #[inline(always)]
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_bytes(self.0)
+            }
+        }
+
+        if let OptionSection::Omit = self.opts.section {
+            let mut seq = ser.serialize_tuple(2)?;
+            seq.serialize_element(&self.tag)?;
+            seq.serialize_element(&Entries(&self.entries))?;
+            return seq.end();
+        }
+
+        let mut seq = ser.serialize_tuple(3)?;
+        seq.serialize_element(&self.tag)?;
+        seq.serialize_element(&Entries(&self.entries))?;
+        seq.serialize_element(&self.opts)?;
+        seq.end()
+    }
+}
@@ -0,0 +1,259 @@
+//!Decoding of forward protocol messages.
+//!
+//!Intended for tests and tooling, e.g. to inspect messages written into file.
+//!
+//!```rust
+//!use tracing_fluentd::fluent::{self, decode};
+//!
+//!let mut message = fluent::Message::new("rust");
+//!let mut record = fluent::Record::with_time(core::time::Duration::from_secs(1));
+//!record.insert("message".into(), "test".into());
+//!message.add(record);
+//!
+//!let mut bytes = rmp_serde::to_vec(&message).expect("encode");
+//!//Trailing garbage
+//!bytes.push(0xc1);
+//!
+//!let (frames, error) = decode::read_frames(bytes.as_slice());
+//!assert_eq!(frames.len(), 1);
+//!assert_eq!(frames[0].message.tag(), "rust");
+//!assert_eq!(frames[0].message.records()[0]["message"].as_str(), Some("test"));
+//!assert!(error.is_some());
+//!```
+use super::{Map, Message, OptionSection, Opts, Record, Value};
+
+use core::fmt;
+use core::time::Duration;
+use std::io::{self, Read};
+
+#[derive(Debug)]
+///Error of decoding.
+pub enum Error {
+    ///Failed to read msgpack value.
+    Read(rmpv::decode::Error),
+    ///Value is not valid forward protocol message.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Read(error) => write!(fmt, "Failed to read message: {}", error),
+            Error::Invalid(reason) => write!(fmt, "Invalid message: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    #[inline]
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Read(error) => Some(error),
+            Error::Invalid(_) => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+///Mode of decoded message.
+pub enum Mode {
+    ///`[tag, [[time, record], ...], options]`
+    Forward,
+    ///`[tag, bin, options]`, where `bin` is concatenation of encoded `[time, record]` entries.
+    PackedForward,
+    ///`[tag, time, record, options]`
+    Message,
+}
+
+#[derive(Debug)]
+///Decoded message.
+pub struct Frame {
+    ///Mode of message.
+    pub mode: Mode,
+    ///Message, containing every entry of frame.
+    ///
+    ///Option section is `OptionSection::Omit` if frame has no options, `OptionSection::Size` if
+    ///it contains only `size` and `OptionSection::Custom` otherwise.
+    pub message: Message,
+}
+
+///Message split into parts, without decoding entries.
+pub(crate) struct RawFrame {
+    pub(crate) mode: Mode,
+    pub(crate) tag: String,
+    ///Pairs of time and record.
+    pub(crate) entries: Vec<(rmpv::Value, rmpv::Value)>,
+    pub(crate) options: Option<rmpv::Value>,
+}
+
+///Decodes time of entry, encoded as either integer or `EventTime`.
+pub fn event_time(time: &rmpv::Value) -> Option<Duration> {
+    match time {
+        rmpv::Value::Integer(secs) => secs.as_u64().map(Duration::from_secs),
+        rmpv::Value::Ext(0, bytes) if bytes.len() == 8 => {
+            let secs = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            let nanos = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+            Some(Duration::new(secs.into(), nanos))
+        },
+        _ => None,
+    }
+}
+
+///Reads single msgpack value, returning `None` if `reader` has no more data.
+pub(crate) fn read_value<R: Read>(reader: &mut R) -> Result<Option<rmpv::Value>, Error> {
+    let mut marker = [0u8; 1];
+    loop {
+        match reader.read(&mut marker) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(Error::Read(rmpv::decode::Error::InvalidMarkerRead(error))),
+        }
+    }
+
+    rmpv::decode::read_value(&mut marker.as_slice().chain(reader)).map(Some).map_err(Error::Read)
+}
+
+fn split_entry(entry: rmpv::Value) -> Result<(rmpv::Value, rmpv::Value), Error> {
+    match entry {
+        rmpv::Value::Array(entry) if entry.len() == 2 => {
+            let mut entry = entry.into_iter();
+            match (entry.next(), entry.next()) {
+                (Some(time), Some(record)) => Ok((time, record)),
+                _ => Err(Error::Invalid("entry must be [time, record]")),
+            }
+        },
+        _ => Err(Error::Invalid("entry must be [time, record]")),
+    }
+}
+
+///Splits `frame` into tag, entries and options.
+pub(crate) fn split_frame(frame: rmpv::Value) -> Result<RawFrame, Error> {
+    let mut frame = match frame {
+        rmpv::Value::Array(frame) if frame.len() >= 2 => frame.into_iter(),
+        _ => return Err(Error::Invalid("message must be array of at least 2 elements")),
+    };
+
+    let tag = match frame.next() {
+        Some(rmpv::Value::String(tag)) => tag.into_str().ok_or(Error::Invalid("tag must be UTF-8 string"))?,
+        _ => return Err(Error::Invalid("tag must be string")),
+    };
+    let (mode, entries) = match frame.next() {
+        Some(rmpv::Value::Array(entries)) => (Mode::Forward, entries.into_iter().map(split_entry).collect::<Result<Vec<_>, _>>()?),
+        Some(rmpv::Value::Binary(packed)) => {
+            let mut packed = packed.as_slice();
+            let mut entries = Vec::new();
+            while let Some(entry) = read_value(&mut packed)? {
+                entries.push(split_entry(entry)?);
+            }
+            (Mode::PackedForward, entries)
+        },
+        Some(time) => match frame.next() {
+            Some(record) => (Mode::Message, vec![(time, record)]),
+            None => return Err(Error::Invalid("message mode requires record")),
+        },
+        None => return Err(Error::Invalid("message must be array of at least 2 elements")),
+    };
+    let options = frame.next();
+
+    if let Some(rmpv::Value::Map(options)) = options.as_ref() {
+        if options.iter().any(|(key, _)| key.as_str() == Some("compressed")) {
+            return Err(Error::Invalid("compressed entries are not supported"));
+        }
+    }
+
+    Ok(RawFrame {
+        mode,
+        tag,
+        entries,
+        options,
+    })
+}
+
+fn decode_map(map: Vec<(rmpv::Value, rmpv::Value)>) -> Result<Map, Error> {
+    let mut result = Map::new();
+    for (key, value) in map {
+        let key = match key {
+            rmpv::Value::String(key) => key.into_str().ok_or(Error::Invalid("key must be UTF-8 string"))?,
+            _ => return Err(Error::Invalid("key must be string")),
+        };
+        result.insert(key.into(), decode_value(value)?);
+    }
+    Ok(result)
+}
+
+fn decode_value(value: rmpv::Value) -> Result<Value, Error> {
+    match value {
+        rmpv::Value::Nil => Ok(Value::Null),
+        rmpv::Value::Boolean(value) => Ok(Value::Bool(value)),
+        rmpv::Value::Integer(value) => match value.as_u64() {
+            Some(value) => Ok(Value::Uint(value)),
+            None => value.as_i64().map(Value::Int).ok_or(Error::Invalid("integer out of range")),
+        },
+        rmpv::Value::F32(value) => Ok(Value::Float(value.into())),
+        rmpv::Value::F64(value) => Ok(Value::Float(value)),
+        rmpv::Value::String(value) => value.into_str().map(Value::String).ok_or(Error::Invalid("string must be UTF-8")),
+        rmpv::Value::Array(values) => values.into_iter().map(decode_value).collect::<Result<Vec<_>, _>>().map(Value::Array),
+        rmpv::Value::Map(map) => decode_map(map).map(Value::Object),
+        rmpv::Value::Binary(_) => Err(Error::Invalid("binary values are not supported")),
+        rmpv::Value::Ext(_, _) => Err(Error::Invalid("extension values are not supported")),
+    }
+}
+
+fn decode_frame(frame: RawFrame) -> Result<Frame, Error> {
+    let mut message = Message::new("");
+    message.tag = frame.tag.into();
+
+    for (time, record) in frame.entries {
+        let time = event_time(&time).ok_or(Error::Invalid("time must be integer or EventTime"))?;
+        let mut decoded = Record::with_time(time);
+        match record {
+            rmpv::Value::Map(record) => decoded.entries = decode_map(record)?,
+            _ => return Err(Error::Invalid("record must be map")),
+        }
+        message.add(decoded);
+    }
+
+    message.opts = Opts {
+        size: message.entries.len(),
+        section: match frame.options {
+            None => OptionSection::Omit,
+            Some(rmpv::Value::Map(options)) => {
+                let mut fields = decode_map(options)?;
+                fields.shift_remove("size");
+                match fields.is_empty() {
+                    true => OptionSection::Size,
+                    false => OptionSection::Custom(fields),
+                }
+            },
+            Some(_) => return Err(Error::Invalid("options must be map")),
+        },
+    };
+
+    Ok(Frame {
+        mode: frame.mode,
+        message,
+    })
+}
+
+///Reads next message from `reader`, returning `None` if `reader` has no more data.
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Frame>, Error> {
+    match read_value(reader)? {
+        Some(frame) => split_frame(frame).and_then(decode_frame).map(Some),
+        None => Ok(None),
+    }
+}
+
+///Reads all messages from `reader`.
+///
+///Stops at first error, returning messages decoded so far together with error.
+pub fn read_frames<R: Read>(mut reader: R) -> (Vec<Frame>, Option<Error>) {
+    let mut frames = Vec::new();
+    loop {
+        match read_frame(&mut reader) {
+            Ok(Some(frame)) => frames.push(frame),
+            Ok(None) => break (frames, None),
+            Err(error) => break (frames, Some(error)),
+        }
+    }
+}
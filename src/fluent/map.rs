@@ -0,0 +1,562 @@
+//!Insertion ordered map of record's fields.
+
+use super::Value;
+
+use core::{fmt, ops, slice};
+use core::iter::FromIterator;
+use std::borrow::Cow;
+use std::vec;
+
+use indexmap::IndexMap;
+
+///Key of `Map`.
+pub type Key = Cow<'static, str>;
+
+///Max number of entries, stored as plain vector of pairs.
+///
+///Lookup within such small vector is faster than hashing key, while vector requires single
+///allocation.
+pub const INLINE_LEN: usize = 8;
+
+#[derive(Clone)]
+enum Repr {
+    Inline(Vec<(Key, Value)>),
+    Spilled(IndexMap<Key, Value>),
+}
+
+#[derive(Clone)]
+///Insertion ordered map object suitable for fluent record.
+///
+///Up to `INLINE_LEN` entries are stored as vector of pairs with linear lookup, while map spills
+///into hash map once it grows beyond that.
+///Order of entries is preserved in both cases.
+pub struct Map(Repr);
+
+impl Map {
+    #[inline(always)]
+    ///Creates new empty map.
+    pub const fn new() -> Self {
+        Self(Repr::Inline(Vec::new()))
+    }
+
+    #[inline]
+    ///Creates new empty map with space for at least `capacity` entries.
+    pub fn with_capacity(capacity: usize) -> Self {
+        match capacity > INLINE_LEN {
+            true => Self(Repr::Spilled(IndexMap::with_capacity(capacity))),
+            false => Self(Repr::Inline(Vec::with_capacity(capacity))),
+        }
+    }
+
+    #[inline(always)]
+    ///Returns whether entries are stored as vector of pairs, rather than spilled into hash map.
+    pub fn is_inline(&self) -> bool {
+        matches!(self.0, Repr::Inline(_))
+    }
+
+    #[inline]
+    ///Returns number of entries.
+    pub fn len(&self) -> usize {
+        match &self.0 {
+            Repr::Inline(entries) => entries.len(),
+            Repr::Spilled(entries) => entries.len(),
+        }
+    }
+
+    #[inline(always)]
+    ///Returns whether map has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    ///Returns number of entries map can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        match &self.0 {
+            Repr::Inline(entries) => entries.capacity(),
+            Repr::Spilled(entries) => entries.capacity(),
+        }
+    }
+
+    #[inline]
+    ///Reserves space for at least `additional` entries, spilling map if they would not fit
+    ///inline.
+    pub fn reserve(&mut self, additional: usize) {
+        if self.len() + additional > INLINE_LEN {
+            self.spill().reserve(additional);
+        } else if let Repr::Inline(entries) = &mut self.0 {
+            entries.reserve(additional);
+        }
+    }
+
+    #[inline]
+    ///Removes all entries, retaining allocated memory.
+    pub fn clear(&mut self) {
+        match &mut self.0 {
+            Repr::Inline(entries) => entries.clear(),
+            Repr::Spilled(entries) => entries.clear(),
+        }
+    }
+
+    fn spill(&mut self) -> &mut IndexMap<Key, Value> {
+        if let Repr::Inline(entries) = &mut self.0 {
+            let mut spilled = IndexMap::with_capacity(entries.len() * 2);
+            spilled.extend(entries.drain(..));
+            self.0 = Repr::Spilled(spilled);
+        }
+
+        match &mut self.0 {
+            Repr::Spilled(entries) => entries,
+            Repr::Inline(_) => unreachable!(),
+        }
+    }
+
+    ///Inserts `value` under `key`, returning previous value.
+    ///
+    ///If `key` is already present, its position is kept, otherwise entry is appended.
+    pub fn insert(&mut self, key: Key, value: Value) -> Option<Value> {
+        if let Repr::Inline(entries) = &mut self.0 {
+            if let Some((_, old)) = entries.iter_mut().find(|(name, _)| *name == key) {
+                return Some(core::mem::replace(old, value));
+            }
+            if entries.len() < INLINE_LEN {
+                entries.push((key, value));
+                return None;
+            }
+        }
+
+        self.spill().insert(key, value)
+    }
+
+    #[inline]
+    ///Returns index of entry with `key`.
+    pub fn get_index_of(&self, key: &str) -> Option<usize> {
+        match &self.0 {
+            Repr::Inline(entries) => entries.iter().position(|(name, _)| name == key),
+            Repr::Spilled(entries) => entries.get_index_of(key),
+        }
+    }
+
+    #[inline]
+    ///Returns value of `key`.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match &self.0 {
+            Repr::Inline(entries) => entries.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            Repr::Spilled(entries) => entries.get(key),
+        }
+    }
+
+    #[inline]
+    ///Returns mutable value of `key`.
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        match &mut self.0 {
+            Repr::Inline(entries) => entries.iter_mut().find(|(name, _)| name == key).map(|(_, value)| value),
+            Repr::Spilled(entries) => entries.get_mut(key),
+        }
+    }
+
+    #[inline(always)]
+    ///Returns whether map contains `key`.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get_index_of(key).is_some()
+    }
+
+    #[inline]
+    ///Returns entry at `idx`.
+    pub fn get_index(&self, idx: usize) -> Option<(&Key, &Value)> {
+        match &self.0 {
+            Repr::Inline(entries) => entries.get(idx).map(|(key, value)| (key, value)),
+            Repr::Spilled(entries) => entries.get_index(idx),
+        }
+    }
+
+    #[inline]
+    ///Returns entry at `idx` with mutable value.
+    pub fn get_index_mut(&mut self, idx: usize) -> Option<(&Key, &mut Value)> {
+        match &mut self.0 {
+            Repr::Inline(entries) => entries.get_mut(idx).map(|(key, value)| (&*key, value)),
+            Repr::Spilled(entries) => entries.get_index_mut(idx),
+        }
+    }
+
+    #[inline(always)]
+    ///Returns first entry.
+    pub fn first(&self) -> Option<(&Key, &Value)> {
+        self.get_index(0)
+    }
+
+    #[inline]
+    ///Removes entry with `key`, shifting subsequent entries to keep order.
+    pub fn shift_remove(&mut self, key: &str) -> Option<Value> {
+        match &mut self.0 {
+            Repr::Inline(entries) => {
+                let idx = entries.iter().position(|(name, _)| name == key)?;
+                Some(entries.remove(idx).1)
+            },
+            Repr::Spilled(entries) => entries.shift_remove(key),
+        }
+    }
+
+    #[inline]
+    ///Removes entry with `key`, replacing it with the last entry.
+    pub fn swap_remove(&mut self, key: &str) -> Option<Value> {
+        match &mut self.0 {
+            Repr::Inline(entries) => {
+                let idx = entries.iter().position(|(name, _)| name == key)?;
+                Some(entries.swap_remove(idx).1)
+            },
+            Repr::Spilled(entries) => entries.swap_remove(key),
+        }
+    }
+
+    #[inline]
+    ///Moves entry at index `from` to index `to`, shifting entries in between.
+    ///
+    ///Panics if either index is out of bounds.
+    pub fn move_index(&mut self, from: usize, to: usize) {
+        match &mut self.0 {
+            Repr::Inline(entries) => {
+                let entry = entries.remove(from);
+                entries.insert(to, entry);
+            },
+            Repr::Spilled(entries) => entries.move_index(from, to),
+        }
+    }
+
+    #[inline]
+    ///Retains only entries, for which `fun` returns `true`.
+    pub fn retain<F: FnMut(&Key, &mut Value) -> bool>(&mut self, mut fun: F) {
+        match &mut self.0 {
+            Repr::Inline(entries) => entries.retain_mut(|(key, value)| fun(key, value)),
+            Repr::Spilled(entries) => entries.retain(|key, value| fun(key, value)),
+        }
+    }
+
+    #[inline(always)]
+    ///Returns entry of `key` for in-place manipulation.
+    pub fn entry(&mut self, key: Key) -> Entry<'_> {
+        Entry {
+            map: self,
+            key,
+        }
+    }
+
+    #[inline]
+    ///Returns iterator over entries.
+    pub fn iter(&self) -> Iter<'_> {
+        match &self.0 {
+            Repr::Inline(entries) => Iter(IterRepr::Inline(entries.iter())),
+            Repr::Spilled(entries) => Iter(IterRepr::Spilled(entries.iter())),
+        }
+    }
+
+    #[inline]
+    ///Returns iterator over entries with mutable values.
+    pub fn iter_mut(&mut self) -> IterMut<'_> {
+        match &mut self.0 {
+            Repr::Inline(entries) => IterMut(IterMutRepr::Inline(entries.iter_mut())),
+            Repr::Spilled(entries) => IterMut(IterMutRepr::Spilled(entries.iter_mut())),
+        }
+    }
+
+    #[inline(always)]
+    ///Returns iterator over keys.
+    pub fn keys(&self) -> Keys<'_> {
+        Keys(self.iter())
+    }
+
+    #[inline(always)]
+    ///Returns iterator over values.
+    pub fn values(&self) -> Values<'_> {
+        Values(self.iter())
+    }
+
+    #[inline]
+    ///Removes all entries, returning them as iterator.
+    pub fn drain(&mut self) -> Drain<'_> {
+        match &mut self.0 {
+            Repr::Inline(entries) => Drain(DrainRepr::Inline(entries.drain(..))),
+            Repr::Spilled(entries) => Drain(DrainRepr::Spilled(entries.drain(..))),
+        }
+    }
+}
+
+impl Default for Map {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Map {
+    #[inline]
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl ops::Index<&str> for Map {
+    type Output = Value;
+
+    #[inline]
+    fn index(&self, key: &str) -> &Self::Output {
+        match self.get(key) {
+            Some(value) => value,
+            None => panic!("Map has no key '{}'", key),
+        }
+    }
+}
+
+impl ops::IndexMut<&str> for Map {
+    #[inline]
+    fn index_mut(&mut self, key: &str) -> &mut Self::Output {
+        match self.get_mut(key) {
+            Some(value) => value,
+            None => panic!("Map has no key '{}'", key),
+        }
+    }
+}
+
+impl Extend<(Key, Value)> for Map {
+    #[inline]
+    fn extend<I: IntoIterator<Item = (Key, Value)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl FromIterator<(Key, Value)> for Map {
+    #[inline]
+    fn from_iter<I: IntoIterator<Item = (Key, Value)>>(iter: I) -> Self {
+        let mut map = Self::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<'a> IntoIterator for &'a Map {
+    type Item = (&'a Key, &'a Value);
+    type IntoIter = Iter<'a>;
+
+    #[inline(always)]
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a mut Map {
+    type Item = (&'a Key, &'a mut Value);
+    type IntoIter = IterMut<'a>;
+
+    #[inline(always)]
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+impl IntoIterator for Map {
+    type Item = (Key, Value);
+    type IntoIter = IntoIter;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        match self.0 {
+            Repr::Inline(entries) => IntoIter(IntoIterRepr::Inline(entries.into_iter())),
+            Repr::Spilled(entries) => IntoIter(IntoIterRepr::Spilled(entries.into_iter())),
+        }
+    }
+}
+
+///Entry of `Map`, created via `Map::entry`.
+pub struct Entry<'a> {
+    map: &'a mut Map,
+    key: Key,
+}
+
+impl<'a> Entry<'a> {
+    #[inline(always)]
+    ///Returns key of entry.
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    #[inline(always)]
+    ///Inserts `default` if entry is vacant, returning value of entry.
+    pub fn or_insert(self, default: Value) -> &'a mut Value {
+        self.or_insert_with(|| default)
+    }
+
+    ///Inserts result of `default` if entry is vacant, returning value of entry.
+    pub fn or_insert_with<F: FnOnce() -> Value>(self, default: F) -> &'a mut Value {
+        let map = self.map;
+        let idx = match map.get_index_of(&self.key) {
+            Some(idx) => idx,
+            None => {
+                map.insert(self.key, default());
+                map.len() - 1
+            },
+        };
+
+        match map.get_index_mut(idx) {
+            Some((_, value)) => value,
+            None => unreachable!(),
+        }
+    }
+
+    #[inline]
+    ///Modifies value of entry, if it is occupied.
+    pub fn and_modify<F: FnOnce(&mut Value)>(self, fun: F) -> Self {
+        if let Some(value) = self.map.get_mut(&self.key) {
+            fun(value);
+        }
+        self
+    }
+}
+
+macro_rules! impl_iter {
+    ($name:ident<$($lt:lifetime)?>, $repr:ident => $item:ty, |$entry:ident| $inline:expr) => {
+        impl$(<$lt>)? Iterator for $name$(<$lt>)? {
+            type Item = $item;
+
+            #[inline]
+            fn next(&mut self) -> Option<Self::Item> {
+                match &mut self.0 {
+                    $repr::Inline(iter) => iter.next().map(|$entry| $inline),
+                    $repr::Spilled(iter) => iter.next(),
+                }
+            }
+
+            #[inline]
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                match &self.0 {
+                    $repr::Inline(iter) => iter.size_hint(),
+                    $repr::Spilled(iter) => iter.size_hint(),
+                }
+            }
+        }
+
+        impl$(<$lt>)? DoubleEndedIterator for $name$(<$lt>)? {
+            #[inline]
+            fn next_back(&mut self) -> Option<Self::Item> {
+                match &mut self.0 {
+                    $repr::Inline(iter) => iter.next_back().map(|$entry| $inline),
+                    $repr::Spilled(iter) => iter.next_back(),
+                }
+            }
+        }
+
+        impl$(<$lt>)? ExactSizeIterator for $name$(<$lt>)? {
+        }
+    };
+}
+
+enum IterRepr<'a> {
+    Inline(slice::Iter<'a, (Key, Value)>),
+    Spilled(indexmap::map::Iter<'a, Key, Value>),
+}
+
+///Iterator over entries of `Map`.
+pub struct Iter<'a>(IterRepr<'a>);
+
+impl_iter!(Iter<'a>, IterRepr => (&'a Key, &'a Value), |entry| (&entry.0, &entry.1));
+
+enum IterMutRepr<'a> {
+    Inline(slice::IterMut<'a, (Key, Value)>),
+    Spilled(indexmap::map::IterMut<'a, Key, Value>),
+}
+
+///Iterator over entries of `Map` with mutable values.
+pub struct IterMut<'a>(IterMutRepr<'a>);
+
+impl_iter!(IterMut<'a>, IterMutRepr => (&'a Key, &'a mut Value), |entry| (&entry.0, &mut entry.1));
+
+enum DrainRepr<'a> {
+    Inline(vec::Drain<'a, (Key, Value)>),
+    Spilled(indexmap::map::Drain<'a, Key, Value>),
+}
+
+///Draining iterator over entries of `Map`, created via `Map::drain`.
+pub struct Drain<'a>(DrainRepr<'a>);
+
+impl_iter!(Drain<'a>, DrainRepr => (Key, Value), |entry| entry);
+
+enum IntoIterRepr {
+    Inline(vec::IntoIter<(Key, Value)>),
+    Spilled(indexmap::map::IntoIter<Key, Value>),
+}
+
+///Owning iterator over entries of `Map`.
+pub struct IntoIter(IntoIterRepr);
+
+impl_iter!(IntoIter<>, IntoIterRepr => (Key, Value), |entry| entry);
+
+///Iterator over keys of `Map`.
+pub struct Keys<'a>(Iter<'a>);
+
+impl<'a> Iterator for Keys<'a> {
+    type Item = &'a Key;
+
+    #[inline(always)]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, _)| key)
+    }
+
+    #[inline(always)]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+///Iterator over values of `Map`.
+pub struct Values<'a>(Iter<'a>);
+
+impl<'a> Iterator for Values<'a> {
+    type Item = &'a Value;
+
+    #[inline(always)]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(_, value)| value)
+    }
+
+    #[inline(always)]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+#[macro_export]
+///Creates `fluent::Map` out of `key => value` pairs, converting keys and values via `From`.
+///
+///```rust
+///use tracing_fluentd::{fluent, fluent_map};
+///
+///let map = fluent_map! {
+///    "service" => "api",
+///    "port" => 8080u64,
+///    "labels" => fluent_map! {
+///        "zone" => "eu-1",
+///    },
+///};
+///assert_eq!(map.len(), 3);
+///assert!(matches!(map["labels"], fluent::Value::Object(_)));
+///
+/////Empty map is created via `const fn`, hence it can be used within constant.
+///const EMPTY: fluent::Map = fluent_map! {};
+///assert!(EMPTY.is_empty());
+///```
+macro_rules! fluent_map {
+    () => {
+        $crate::fluent::Map::new()
+    };
+    (@unit $key:expr) => {
+        ()
+    };
+    ($($key:expr => $value:expr),+ $(,)?) => {{
+        let mut map = $crate::fluent::Map::with_capacity(<[()]>::len(&[$($crate::fluent_map!(@unit $key)),+]));
+        $(
+            map.insert($crate::fluent::Key::from($key), $crate::fluent::Value::from($value));
+        )+
+        map
+    }};
+}
@@ -0,0 +1,104 @@
+use core::fmt;
+use core::time::Duration;
+use std::sync::Mutex;
+
+use tracing_subscriber::layer::SubscriberExt;
+
+use crate::{Builder, FieldFormatter, FlushingGuard, MakeWriter};
+
+//Guard of worker, used by global subscriber.
+static GUARD: Mutex<Option<FlushingGuard>> = Mutex::new(None);
+
+#[derive(Debug)]
+///Error of global initialization.
+pub enum InitError {
+    ///Failed to create worker.
+    Worker(std::io::Error),
+    ///Global subscriber is already set.
+    AlreadySet(tracing_core::dispatcher::SetGlobalDefaultError),
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitError::Worker(error) => write!(fmt, "Failed to create fluentd worker: {}", error),
+            InitError::AlreadySet(error) => fmt::Display::fmt(error, fmt),
+        }
+    }
+}
+
+impl std::error::Error for InitError {
+    #[inline]
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InitError::Worker(error) => Some(error),
+            InitError::AlreadySet(error) => Some(error),
+        }
+    }
+}
+
+impl<F: FieldFormatter + Send + Sync, A: MakeWriter> Builder<F, A> {
+    #[inline]
+    ///Sets layer as global subscriber, returning guard of its worker.
+    ///
+    ///Guard should be kept until process exits, as dropping it stops worker, after sending
+    ///remaining records.
+    ///
+    ///```rust
+    ///let _guard = tracing_fluentd::Builder::new("rust").flatten().try_init().expect("Init tracing");
+    ///tracing::info!("initialized");
+    ///```
+    pub fn try_init(self) -> Result<FlushingGuard, InitError> {
+        let (layer, guard) = self.layer_guarded().map_err(InitError::Worker)?;
+        let dispatch = tracing_core::Dispatch::new(tracing_subscriber::Registry::default().with(layer));
+        tracing_core::dispatcher::set_global_default(dispatch).map_err(InitError::AlreadySet)?;
+        Ok(guard)
+    }
+
+    #[inline]
+    ///Sets layer, composed with `extra_layer`, as global subscriber, returning guard of its worker.
+    ///
+    ///Same as `try_init`, except events are also passed to `extra_layer`, e.g. to print them.
+    ///
+    ///```rust
+    ///let _guard = tracing_fluentd::Builder::new("rust").try_init_with(tracing_subscriber::fmt::layer()).expect("Init tracing");
+    ///tracing::info!("initialized");
+    ///```
+    pub fn try_init_with<L: tracing_subscriber::Layer<tracing_subscriber::Registry> + Send + Sync + 'static>(self, extra_layer: L) -> Result<FlushingGuard, InitError> {
+        let (layer, guard) = self.layer_guarded().map_err(InitError::Worker)?;
+        let dispatch = tracing_core::Dispatch::new(tracing_subscriber::Registry::default().with(extra_layer).with(layer));
+        tracing_core::dispatcher::set_global_default(dispatch).map_err(InitError::AlreadySet)?;
+        Ok(guard)
+    }
+}
+
+///Sets layer, created by `builder`, as global subscriber, keeping its worker until `shutdown`.
+///
+///As global subscriber is never dropped, `shutdown` should be called before process exits, in
+///order to send remaining records.
+pub fn init<F: FieldFormatter + Send + Sync, A: MakeWriter>(builder: Builder<F, A>) -> Result<(), InitError> {
+    let guard = builder.try_init()?;
+
+    *GUARD.lock().unwrap_or_else(|error| error.into_inner()) = Some(guard);
+    Ok(())
+}
+
+///Stops worker of global subscriber, created by `init`, waiting up to `timeout` for remaining
+///records to be sent.
+///
+///Only the first call stops worker, while subsequent calls do nothing.
+///Once worker is stopped, events are no longer recorded.
+pub fn shutdown(timeout: Duration) {
+    let guard = match GUARD.lock().unwrap_or_else(|error| error.into_inner()).take() {
+        Some(guard) => guard,
+        None => return,
+    };
+
+    //Guard awaits worker on drop without limit, so it is dropped in background.
+    let (finished, is_finished) = crossbeam_channel::bounded::<()>(0);
+    let _ = std::thread::Builder::new().name("tracing-fluentd-shutdown".to_owned()).spawn(move || {
+        drop(guard);
+        drop(finished);
+    });
+    let _ = is_finished.recv_timeout(timeout);
+}
@@ -0,0 +1,197 @@
+//!Forward protocol handshake.
+//!
+//!See <https://github.com/fluent/fluentd/wiki/Forward-Protocol-Specification-v1#handshake-messages>
+use crate::{MakeWriter, MakeContext};
+
+use std::io::{self, Read, Write};
+use sha2::{Digest, Sha512};
+
+fn hex_digest(parts: &[&[u8]]) -> String {
+    use core::fmt::Write;
+
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+
+    let digest = hasher.finalize();
+    let mut result = String::with_capacity(digest.len() * 2);
+    for byte in digest.iter() {
+        let _ = write!(&mut result, "{:02x}", byte);
+    }
+    result
+}
+
+//Salt doesn't need to be cryptographically secure, only unique.
+fn salt() -> String {
+    use std::hash::{BuildHasher, Hasher};
+    use std::collections::hash_map::RandomState;
+
+    let time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(time.as_nanos());
+    let first = hasher.finish();
+    hasher.write_u64(first);
+    format!("{:016x}{:016x}", first, hasher.finish())
+}
+
+fn hostname() -> String {
+    if let Ok(hostname) = std::fs::read_to_string("/proc/sys/kernel/hostname") {
+        let hostname = hostname.trim();
+        if !hostname.is_empty() {
+            return hostname.to_owned();
+        }
+    }
+
+    std::env::var("HOSTNAME").or_else(|_| std::env::var("COMPUTERNAME")).unwrap_or_else(|_| "localhost".to_owned())
+}
+
+#[inline(always)]
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_message<R: Read>(reader: &mut R, kind: &'static str) -> io::Result<Vec<rmpv::Value>> {
+    let message = match rmpv::decode::read_value(reader) {
+        Ok(rmpv::Value::Array(message)) => message,
+        Ok(_) => return Err(invalid("handshake message is not an array")),
+        Err(rmpv::decode::Error::InvalidMarkerRead(error)) => return Err(error),
+        Err(rmpv::decode::Error::InvalidDataRead(error)) => return Err(error),
+        Err(error) => return Err(io::Error::new(io::ErrorKind::InvalidData, error)),
+    };
+
+    match message.first().and_then(rmpv::Value::as_str) {
+        Some(actual) if actual == kind => Ok(message),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("expected {} message", kind))),
+    }
+}
+
+//Values are allowed to be either string or binary
+fn as_bytes(value: &rmpv::Value) -> Option<&[u8]> {
+    match value {
+        rmpv::Value::String(value) => Some(value.as_bytes()),
+        rmpv::Value::Binary(value) => Some(value.as_slice()),
+        _ => None,
+    }
+}
+
+fn get<'a>(map: &'a [(rmpv::Value, rmpv::Value)], key: &str) -> Option<&'a rmpv::Value> {
+    map.iter().find(|(name, _)| name.as_str() == Some(key)).map(|(_, value)| value)
+}
+
+///`MakeWriter` that performs forward protocol handshake on every new connection.
+///
+///Required to connect to `in_forward` with `<security>` section.
+///Writer must be able to read server's response, hence it is required to implement `Read`.
+///
+///Handshake blocks until server responds, so writer should have read timeout configured to avoid
+///blocking worker forever.
+///
+///Created via `Builder::with_shared_key`.
+pub struct Handshake<MW> {
+    writer: MW,
+    shared_key: String,
+    hostname: String,
+    credentials: Option<(String, String)>,
+}
+
+impl<MW: MakeWriter> Handshake<MW> where MW::Writer: Read {
+    ///Creates new handshake with `shared_key` on top of `writer`.
+    ///
+    ///Hostname is taken from the system by default.
+    pub fn new<K: Into<String>>(writer: MW, shared_key: K) -> Self {
+        Self {
+            writer,
+            shared_key: shared_key.into(),
+            hostname: hostname(),
+            credentials: None,
+        }
+    }
+
+    #[inline]
+    ///Sets hostname to send to the server.
+    pub fn hostname<H: Into<String>>(mut self, hostname: H) -> Self {
+        self.hostname = hostname.into();
+        self
+    }
+
+    #[inline]
+    ///Sets username and password for servers that require user authentication.
+    pub fn credentials<U: Into<String>, P: Into<String>>(mut self, username: U, password: P) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    fn handshake(&self, conn: &mut MW::Writer) -> io::Result<()> {
+        let helo = read_message(conn, "HELO")?;
+        let options = match helo.get(1) {
+            Some(rmpv::Value::Map(options)) => options.as_slice(),
+            _ => return Err(invalid("HELO without options")),
+        };
+        let nonce = match get(options, "nonce").and_then(as_bytes) {
+            Some(nonce) => nonce,
+            None => return Err(invalid("HELO without nonce")),
+        };
+        let auth = get(options, "auth").and_then(as_bytes).unwrap_or_default();
+
+        let shared_key_salt = salt();
+        let shared_key_digest = hex_digest(&[shared_key_salt.as_bytes(), self.hostname.as_bytes(), nonce, self.shared_key.as_bytes()]);
+        let (username, password_digest) = match (auth.is_empty(), self.credentials.as_ref()) {
+            (true, _) => ("", String::new()),
+            (false, Some((username, password))) => (username.as_str(), hex_digest(&[auth, username.as_bytes(), password.as_bytes()])),
+            (false, None) => return Err(io::Error::new(io::ErrorKind::PermissionDenied, "server requires user authentication")),
+        };
+
+        let ping = rmpv::Value::Array(vec![
+            "PING".into(),
+            self.hostname.as_str().into(),
+            shared_key_salt.as_str().into(),
+            shared_key_digest.into(),
+            username.into(),
+            password_digest.into(),
+        ]);
+        let mut buffer = Vec::new();
+        rmpv::encode::write_value(&mut buffer, &ping)?;
+        conn.write_all(&buffer)?;
+        conn.flush()?;
+
+        let pong = read_message(conn, "PONG")?;
+        if pong.len() < 5 {
+            return Err(invalid("PONG is too short"));
+        }
+        if pong[1].as_bool() != Some(true) {
+            let reason = pong[2].as_str().unwrap_or("unknown reason");
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("authentication failed: {}", reason)));
+        }
+
+        let server_hostname = as_bytes(&pong[3]).unwrap_or_default();
+        let expected = hex_digest(&[shared_key_salt.as_bytes(), server_hostname, nonce, self.shared_key.as_bytes()]);
+        match as_bytes(&pong[4]) {
+            Some(digest) if digest == expected.as_bytes() => Ok(()),
+            _ => Err(io::Error::new(io::ErrorKind::PermissionDenied, "server shared key mismatch")),
+        }
+    }
+}
+
+impl<MW: MakeWriter> MakeWriter for Handshake<MW> where MW::Writer: Read {
+    type Writer = MW::Writer;
+
+    #[inline]
+    fn make(&self) -> io::Result<Self::Writer> {
+        let mut conn = self.writer.make()?;
+        self.handshake(&mut conn)?;
+        Ok(conn)
+    }
+
+    #[inline]
+    fn make_with(&self, ctx: &MakeContext) -> io::Result<Self::Writer> {
+        let mut conn = self.writer.make_with(ctx)?;
+        self.handshake(&mut conn)?;
+        Ok(conn)
+    }
+
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> io::Result<()> {
+        self.writer.probe(writer)
+    }
+}
@@ -0,0 +1,142 @@
+//!UDP heartbeat of forward protocol.
+//!
+//!`in_forward` replies to single byte UDP datagram, sent to the same port as tcp listener, which
+//!allows to detect dead servers without trying to connect to them.
+use crate::{MakeWriter, ProbeWrite};
+
+use core::time::Duration;
+use std::io;
+use std::net::{SocketAddr, TcpStream, UdpSocket, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex, Weak, Once};
+use std::time::Instant;
+
+const HEARTBEAT: [u8; 1] = [0];
+
+///Creates writer that connects to the first responsive address out of `addrs`.
+///
+///Heartbeat is sent every second by default and server is considered alive if it responded
+///within last 3 seconds.
+pub fn heartbeat(addrs: Vec<SocketAddr>) -> Heartbeat {
+    Heartbeat {
+        state: Arc::new(HeartbeatState {
+            last_seen: Mutex::new(vec![None; addrs.len()]),
+        }),
+        addrs,
+        interval: Duration::from_secs(1),
+        timeout: Duration::from_secs(3),
+        started: Once::new(),
+    }
+}
+
+struct HeartbeatState {
+    last_seen: Mutex<Vec<Option<Instant>>>,
+}
+
+fn bind(addr: &SocketAddr) -> io::Result<UdpSocket> {
+    let socket = match addr {
+        SocketAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+        SocketAddr::V6(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))?,
+    };
+    socket.connect(addr)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+fn run(weak: Weak<HeartbeatState>, addrs: Vec<SocketAddr>, interval: Duration) {
+    //Address without socket is simply never considered alive
+    let sockets = addrs.iter().map(|addr| bind(addr).ok()).collect::<Vec<_>>();
+    let mut buffer = [0u8; 8];
+
+    while let Some(state) = weak.upgrade() {
+        //Responses to previous heartbeat are already available at this point.
+        let now = Instant::now();
+        let mut last_seen = state.last_seen.lock().unwrap_or_else(|error| error.into_inner());
+        for (idx, socket) in sockets.iter().enumerate() {
+            if let Some(socket) = socket {
+                //Refused heartbeat is reported as error, which only means there is no response.
+                while socket.recv(&mut buffer).is_ok() {
+                    last_seen[idx] = Some(now);
+                }
+                let _ = socket.send(&HEARTBEAT);
+            }
+        }
+        drop(last_seen);
+        drop(state);
+
+        std::thread::sleep(interval);
+    }
+}
+
+///`MakeWriter` that connects to the first address responding to heartbeat.
+///
+///Created via `heartbeat`.
+///
+///Heartbeat is performed by background thread, started on the first attempt to create writer,
+///and stopped once `Heartbeat` is dropped.
+///Addresses that responded within `timeout` are tried first, in the same order as provided, while
+///remaining addresses are tried afterwards, so that writer can be created even if heartbeat is
+///blocked.
+pub struct Heartbeat {
+    addrs: Vec<SocketAddr>,
+    interval: Duration,
+    timeout: Duration,
+    state: Arc<HeartbeatState>,
+    started: Once,
+}
+
+impl Heartbeat {
+    #[inline(always)]
+    ///Sets interval between heartbeats.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    #[inline(always)]
+    ///Sets duration since last response, after which server is no longer considered alive.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    ///Starts heartbeat, unless it is already running.
+    ///
+    ///It is done automatically on first attempt to create writer.
+    pub fn start(&self) {
+        self.started.call_once(|| {
+            let state = Arc::downgrade(&self.state);
+            let addrs = self.addrs.clone();
+            let interval = self.interval;
+            let worker = std::thread::Builder::new().name("tracing-fluentd-heartbeat".to_owned());
+            if let Err(error) = worker.spawn(move || run(state, addrs, interval)) {
+                tracing::event!(tracing::Level::DEBUG, "Failed to start heartbeat {}", error);
+            }
+        });
+    }
+
+    ///Returns addresses that responded to heartbeat within `timeout`.
+    pub fn alive(&self) -> Vec<SocketAddr> {
+        let last_seen = self.state.last_seen.lock().unwrap_or_else(|error| error.into_inner());
+        self.addrs.iter().zip(last_seen.iter()).filter(|(_, last_seen)| match last_seen {
+            Some(last_seen) => last_seen.elapsed() < self.timeout,
+            None => false,
+        }).map(|(addr, _)| *addr).collect()
+    }
+}
+
+impl MakeWriter for Heartbeat {
+    type Writer = TcpStream;
+
+    fn make(&self) -> io::Result<Self::Writer> {
+        self.start();
+
+        let mut addrs = self.alive();
+        addrs.extend(self.addrs.iter().filter(|addr| !addrs.contains(addr)).copied().collect::<Vec<_>>());
+        crate::default_writers::connect_any(&addrs)
+    }
+
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> io::Result<()> {
+        ProbeWrite::probe(writer)
+    }
+}
@@ -3,6 +3,16 @@
 //!## Features
 //!
 //!- `event_time` - Specifies to encode timestamp as EventTime instead of default unix timestamp
+//!- `valuable` - Enables recording of `valuable` values as structured objects. Requires `--cfg tracing_unstable`.
+//!- `proxy` - Enables `proxy` module with writer connecting via SOCKS5 or HTTP proxy.
+//!- `testing` - Enables `testing` module with in-memory writer, mock fluentd server and test clock, recommended for testing logging.
+//!- `tls` - Enables `tls` module with TLS writer, supporting client certificates.
+//!- `json` - Enables `Codec::Ndjson` to write records as newline-delimited JSON, intended for debugging, and `Builder::with_parse_json_fields`.
+//!- `heartbeat` - Enables `writer::heartbeat` to prefer servers responding to UDP heartbeat.
+//!- `async` - Enables `async_worker` module with worker running within async runtime.
+//!- `async-std` - Enables `async-std` runtime for `async_worker`.
+//!- `metrics` - Enables `Builder::with_metrics` to report worker counters via `metrics` facade.
+//!- `opentelemetry` - Enables recording of trace and span ids of `tracing-opentelemetry` spans.
 //!
 //!## Example
 //!
@@ -17,17 +27,52 @@
 #![warn(missing_docs)]
 #![cfg_attr(feature = "cargo-clippy", allow(clippy::style))]
 
-use std::net::{TcpStream, SocketAddrV4, SocketAddr, Ipv4Addr};
+use std::net::{TcpStream, SocketAddrV4, SocketAddrV6, SocketAddr, Ipv4Addr, Ipv6Addr};
 use std::io::Write;
 use core::num;
 
 mod tracing;
+mod filter;
+mod rate_limit;
+mod dedup;
+mod stats;
+mod clock;
+mod budget;
+mod local_buffer;
+mod panic_hook;
+mod global;
+mod boxed;
+mod span_registry;
+mod metadata_cache;
+mod record_pool;
+mod drop_marker;
+#[cfg(feature = "opentelemetry")]
+mod otel;
 pub mod fluent;
 mod worker;
 mod default_writers;
+mod handshake;
+#[cfg(feature = "heartbeat")]
+mod heartbeat;
+pub mod writer;
+#[cfg(feature = "proxy")]
+pub mod proxy;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(feature = "async")]
+pub mod async_worker;
 
-pub use self::tracing::FieldFormatter;
+pub use self::tracing::{FieldFormatter, FieldVisitor, FmtOpts, Scope};
+pub use self::filter::{LayerFilter, Handle};
+pub use self::worker::{Consumer, NullConsumer, ThreadWorker, WorkerChannel};
+pub use self::clock::{Clock, SystemClock};
+pub use self::panic_hook::install_panic_hook;
+pub use self::global::{init, shutdown, InitError};
+pub use self::boxed::{BoxedBuilder, BoxedFmt};
 
+#[derive(Clone, Copy, Debug)]
 ///Policy to insert span data as object.
 ///
 ///Specifically, any span's or event metadata's attributes are associated with its name inside
@@ -35,9 +80,10 @@ pub use self::tracing::FieldFormatter;
 ///For example having span `lolka` would add key `lolka` to the record, with span's attributes as
 ///value.
 ///
-///Special case is event metadata which is always inserted with key `metadata` and contains
-///information such location in code and event level.
+///Special case is event metadata which is inserted with key `metadata`, configurable via
+///`Builder::with_metadata_key`, and contains information such location in code and event level.
 pub struct NestedFmt;
+#[derive(Clone, Copy, Debug)]
 ///Policy to insert span data as flattent object.
 ///
 ///Specifically, any span's or event metadata's attributes are inserted at the root of event
@@ -45,6 +91,122 @@ pub struct NestedFmt;
 ///For example, having span `lolka` with attribute `arg: 1` would result in `arg: 1` to be inserted
 ///alongside `message` and other attributes of the event.
 pub struct FlattenFmt;
+#[derive(Clone, Copy, Debug)]
+///Policy to insert event's attributes at the root of record, while grouping span data under
+///single key.
+///
+///Attributes of all spans within event's scope are merged into one object, inserted under
+///`context` key by default. In case of the same attribute within multiple spans, the innermost
+///span's value is used.
+///Event metadata is inserted at the root of record, same as with `FlattenFmt`, unless configured
+///via `ContextFmt::with_nested_metadata` to be inserted under `metadata` key.
+pub struct ContextFmt {
+    key: &'static str,
+    nested_metadata: bool,
+}
+
+impl ContextFmt {
+    #[inline(always)]
+    ///Creates default formatter, inserting span data under `context` key.
+    pub const fn new() -> Self {
+        Self {
+            key: "context",
+            nested_metadata: false,
+        }
+    }
+
+    #[inline(always)]
+    ///Specifies key under which to insert span data.
+    pub const fn with_key(mut self, key: &'static str) -> Self {
+        self.key = key;
+        self
+    }
+
+    #[inline(always)]
+    ///Configures to insert event metadata under `metadata` key, same as `NestedFmt`.
+    ///
+    ///Key is configured via `Builder::with_metadata_key`.
+    pub const fn with_nested_metadata(mut self) -> Self {
+        self.nested_metadata = true;
+        self
+    }
+
+    #[inline(always)]
+    ///Returns key under which span data is inserted.
+    pub const fn key(&self) -> &'static str {
+        self.key
+    }
+}
+
+impl Default for ContextFmt {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+///Policy to produce records in shape expected by Google Cloud Logging.
+///
+///Span's attributes are inserted at the root of record, same as with `FlattenFmt`, while event
+///metadata is inserted as:
+///
+///- `severity` - Level of event, mapped to `DEBUG`, `INFO`, `WARNING` or `ERROR`.
+///- `logging.googleapis.com/sourceLocation` - Object with `file`, `line` and `function`, which is
+///target of event.
+pub struct GcpFmt;
+#[derive(Clone, Copy, Debug)]
+///Policy to produce records in shape expected by Logstash.
+///
+///Span's attributes are inserted at the root of record, same as with `FlattenFmt`, alongside
+///the following keys:
+///
+///- `@timestamp` - RFC3339 time of record.
+///- `@version` - Version of format, always `"1"`.
+///- `@metadata` - Object with event metadata, same as `metadata` of `NestedFmt`.
+pub struct LogstashFmt;
+#[derive(Clone, Debug)]
+///Policy to produce records in shape of GELF, expected by Graylog.
+///
+///Record consists of:
+///
+///- `version` - Always `"1.1"`.
+///- `host` - Host, specified via `Builder::gelf`.
+///- `short_message` - First line of message, or name of event, if there is no message.
+///- `full_message` - Whole message, if it consists of multiple lines.
+///- `timestamp` - Seconds since UNIX epoch, as floating point number.
+///- `level` - Syslog severity of event.
+///
+///Fields of event, its spans and metadata are inserted as additional fields, prefixed with `_`.
+///Characters, not allowed by GELF, are replaced with `_`, while reserved `id` is renamed to `_id_`.
+pub struct GelfFmt {
+    host: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+///Context in which `MakeWriter::make_with` is called.
+pub struct MakeContext {
+    ///Number of failed attempts to create writer since last successfully created writer.
+    ///
+    ///`0` means it is first attempt.
+    pub attempt: usize,
+    ///Whether previous writer or previous attempt to create it failed.
+    pub previous_failed: bool,
+    ///Kind of the last error, if any.
+    pub last_error: Option<std::io::ErrorKind>,
+}
+
+impl MakeContext {
+    #[inline(always)]
+    pub(crate) const fn new() -> Self {
+        Self {
+            attempt: 0,
+            previous_failed: false,
+            last_error: None,
+        }
+    }
+}
 
 ///Describers creation of sink for `tracing` record.
 pub trait MakeWriter: 'static + Send {
@@ -57,6 +219,77 @@ pub trait MakeWriter: 'static + Send {
     ///
     ///In case of failure working with writer, subscriber shall retry at least once
     fn make(&self) -> std::io::Result<Self::Writer>;
+
+    #[inline(always)]
+    ///Creates instance of `Writer`, providing context of why writer is being created.
+    ///
+    ///This is what worker calls, by default it is the same as `make`.
+    fn make_with(&self, _ctx: &MakeContext) -> std::io::Result<Self::Writer> {
+        self.make()
+    }
+
+    #[inline(always)]
+    ///Checks whether cached `writer` is still usable, before writing next message into it.
+    ///
+    ///Worker calls it only if enabled via `Builder::with_connection_probe`, re-creating writer on
+    ///error.
+    ///
+    ///By default does nothing, while built-in tcp writers use `ProbeWrite`.
+    fn probe(&self, _writer: &mut Self::Writer) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    #[inline(always)]
+    ///Checks whether cached `writer` should no longer be used, as it was created by outdated
+    ///configuration.
+    ///
+    ///Worker always calls it before writing next message, re-creating writer if `true`.
+    ///
+    ///By default returns `false`.
+    fn is_outdated(&self, _writer: &Self::Writer) -> bool {
+        false
+    }
+}
+
+///Writer that is able to check whether it is still usable.
+///
+///Implementation for `TcpStream` assumes socket is in blocking mode, and always leaves it in
+///blocking mode after probing.
+pub trait ProbeWrite: Write {
+    #[inline(always)]
+    ///Checks whether writer is still usable.
+    ///
+    ///By default assumes writer is always usable.
+    fn probe(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+///Checks for pending socket error and whether peer closed connection, without blocking.
+///
+///Socket is temporary switched into nonblocking mode to peek, and switched back into blocking
+///mode afterwards, as `TcpStream` provides no way to query its current mode.
+///Hence it is only suitable for sockets in blocking mode, which is the case for built-in writers.
+impl ProbeWrite for TcpStream {
+    fn probe(&mut self) -> std::io::Result<()> {
+        if let Some(error) = self.take_error()? {
+            return Err(error);
+        }
+
+        self.set_nonblocking(true)?;
+        let result = self.peek(&mut [0u8; 1]);
+        self.set_nonblocking(false)?;
+
+        match result {
+            Ok(0) => Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "connection is closed by peer")),
+            Ok(_) => Ok(()),
+            Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => Ok(()),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl ProbeWrite for std::fs::File {
 }
 
 impl<W: Write, T: 'static + Send + Fn() -> std::io::Result<W>> MakeWriter for T {
@@ -67,17 +300,327 @@ impl<W: Write, T: 'static + Send + Fn() -> std::io::Result<W>> MakeWriter for T
     }
 }
 
-fn default() -> std::io::Result<TcpStream> {
+#[derive(Clone, Copy, Debug)]
+///`MakeWriter` that connects to local `fluentd`, using `localhost`.
+///
+///This is default writer.
+pub struct Localhost;
+
+impl MakeWriter for Localhost {
+    type Writer = TcpStream;
+
+    #[inline(always)]
+    fn make(&self) -> std::io::Result<Self::Writer> {
+        localhost()
+    }
+
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> std::io::Result<()> {
+        ProbeWrite::probe(writer)
+    }
+}
+
+///Creates tcp socket towards local `fluentd` on port `24224`.
+///
+///Tries `127.0.0.1` first and then `[::1]`, making it usable on IPv6-only hosts.
+///Each attempt is limited to 1s, while both attempts together are limited to 2s.
+///
+///Used by default writer `Localhost`, but it can be passed as `MakeWriter` via `Builder::with_writer` too.
+pub fn localhost() -> std::io::Result<TcpStream> {
     use core::time::Duration;
 
-    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 24224));
-    TcpStream::connect_timeout(&addr, Duration::from_secs(1))
+    const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(1);
+    const TOTAL_TIMEOUT: Duration = Duration::from_secs(2);
+
+    let addrs = [
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 24224)),
+        SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 24224, 0, 0)),
+    ];
+
+    let start = std::time::Instant::now();
+    let mut last_error = None;
+    for addr in addrs.iter() {
+        let timeout = match TOTAL_TIMEOUT.checked_sub(start.elapsed()) {
+            Some(remaining) if !remaining.is_zero() => core::cmp::min(remaining, ATTEMPT_TIMEOUT),
+            _ => break,
+        };
+
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(socket) => return Ok(socket),
+            Err(error) => last_error = Some(error),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::TimedOut, "cannot connect to fluentd")))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+///Format of timestamp inserted within record.
+pub enum TimestampStyle {
+    ///RFC3339 UTC string with nanoseconds precision (e.g. `2021-01-01T00:00:00.000000000Z`)
+    Rfc3339,
+    ///Integer number of milliseconds since UNIX epoch.
+    EpochMillis,
+    ///Integer number of nanoseconds since UNIX epoch.
+    EpochNanos,
+}
+
+impl TimestampStyle {
+    pub(crate) fn format(self, time: core::time::Duration) -> fluent::Value {
+        match self {
+            TimestampStyle::Rfc3339 => fluent::rfc3339(time).into(),
+            TimestampStyle::EpochMillis => (time.as_millis() as u64).into(),
+            TimestampStyle::EpochNanos => (time.as_nanos() as u64).into(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+///Unit of numeric time field, inserted via `Builder::with_time_field`.
+pub enum TimeUnit {
+    ///Integer number of seconds since UNIX epoch.
+    Seconds,
+    ///Integer number of milliseconds since UNIX epoch.
+    Millis,
+}
+
+impl TimeUnit {
+    pub(crate) fn format(self, time: core::time::Duration) -> fluent::Value {
+        match self {
+            TimeUnit::Seconds => time.as_secs().into(),
+            TimeUnit::Millis => (time.as_millis() as u64).into(),
+        }
+    }
+}
+
+///Returns environment variables, whose name starts with `prefix`, as `(name without prefix, value)`.
+///
+///Variables that are not valid unicode are skipped.
+fn env_prefixed(prefix: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    std::env::vars_os().filter_map(move |(var, value)| {
+        let (var, value) = match (var.into_string(), value.into_string()) {
+            (Ok(var), Ok(value)) => (var, value),
+            _ => return None,
+        };
+
+        match var.strip_prefix(prefix) {
+            Some(key) if !key.is_empty() => Some((key.to_owned(), value)),
+            _ => None,
+        }
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+///Names of environment variables, used by `Builder::with_kubernetes_fields_from`.
+pub struct KubernetesVars {
+    ///Variable with name of pod. Default is `POD_NAME`.
+    pub pod_name: &'static str,
+    ///Variable with namespace of pod. Default is `POD_NAMESPACE`.
+    pub namespace: &'static str,
+    ///Variable with name of node. Default is `NODE_NAME`.
+    pub node_name: &'static str,
+    ///Prefix of variables with pod's labels, e.g. `POD_LABEL_app` results in label `app`.
+    ///Default is `POD_LABEL_`.
+    pub labels_prefix: &'static str,
+}
+
+impl Default for KubernetesVars {
+    #[inline(always)]
+    fn default() -> Self {
+        Self {
+            pod_name: "POD_NAME",
+            namespace: "POD_NAMESPACE",
+            node_name: "NODE_NAME",
+            labels_prefix: "POD_LABEL_",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+///Prefix of span attributes within flattened record, configured via `Builder::with_span_field_prefix`.
+pub enum SpanFieldPrefix {
+    ///Static prefix, e.g. `ctx_` results in `ctx_<key>`.
+    Static(&'static str),
+    ///Prefix with name of span, resulting in `span.<name>.<key>`.
+    SpanName,
+}
+
+impl SpanFieldPrefix {
+    pub(crate) fn key(self, span_name: &str, key: &str) -> String {
+        match self {
+            SpanFieldPrefix::Static(prefix) => format!("{}{}", prefix, key),
+            SpanFieldPrefix::SpanName => format!("span.{}.{}", span_name, key),
+        }
+    }
+}
+
+impl From<&'static str> for SpanFieldPrefix {
+    #[inline(always)]
+    fn from(prefix: &'static str) -> Self {
+        SpanFieldPrefix::Static(prefix)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+///Reason of discarding records, reported via `Builder::on_drop`.
+pub enum DropReason {
+    ///Queue of worker is full.
+    QueueFull,
+    ///Event exceeds limit, configured via `Builder::with_rate_limit`.
+    RateLimited,
+    ///Message exceeds limit of records.
+    BatchCapExceeded,
+    ///Event cannot be delivered, as worker is no longer running.
+    DeliveryGivenUp,
+    ///Worker failed to send remaining records on shutdown.
+    ShutdownTimeout,
+    ///Record cannot be encoded, e.g. its time is out of range of `EventTime`.
+    ///
+    ///Records, dropped by worker, are also reported to error handler with index within message and tag.
+    EncodeFailed,
+}
+
+impl DropReason {
+    #[inline]
+    ///Returns name of reason in snake case, e.g. `queue_full`.
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::QueueFull => "queue_full",
+            DropReason::RateLimited => "rate_limited",
+            DropReason::BatchCapExceeded => "batch_cap_exceeded",
+            DropReason::DeliveryGivenUp => "delivery_given_up",
+            DropReason::ShutdownTimeout => "shutdown_timeout",
+            DropReason::EncodeFailed => "encode_failed",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+///Decision whether to record event, returned by callback of `Builder::with_sampling_key`.
+pub enum SampleDecision {
+    ///Event is recorded regardless of sample ratio.
+    Keep,
+    ///Event is discarded.
+    Drop,
+    ///Event is recorded according to sample ratio, configured via `Builder::with_sample_ratio`.
+    UseDefaultRatio,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+///Policy to apply once limit of queued records is exceeded.
+pub enum OverflowPolicy {
+    ///New record is discarded.
+    DropNewest,
+    ///The oldest records within queue are discarded, until new record fits.
+    ///
+    ///Records that are already received by worker cannot be discarded.
+    DropOldest,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+///Kind of queue between layer and worker.
+pub enum QueueKind {
+    ///Linked queue without limit, that allocates as records are queued.
+    Unbounded,
+    ///Queue of preallocated slots for specified number of records, that doesn't allocate once it
+    ///is created.
+    ///
+    ///Once it is full, new records are discarded and reported as `DropReason::QueueFull`.
+    Ring(num::NonZeroUsize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+///Encoding of records written by worker.
+pub enum Codec {
+    ///Forward protocol message in msgpack format.
+    ///
+    ///This is default.
+    Msgpack,
+    #[cfg(feature = "json")]
+    ///Newline-delimited JSON, each record as object `{"tag":..,"time":..,"record":{..}}`.
+    ///
+    ///This is not fluentd protocol, so it is only useful to write into file or stdout for debugging.
+    ///Option section is not written.
+    ///
+    ///Requires `json` feature.
+    Ndjson,
 }
 
 ///`tracing`'s Layer
 pub struct Layer<F, C> {
     consumer: C,
     fmt: F,
+    opts: FmtOpts,
+    filter: LayerFilter,
+    eager_flush: Option<tracing_core::Level>,
+    rate_limit: Option<std::sync::Arc<rate_limit::RateLimiter>>,
+    dedup: Option<std::sync::Arc<dedup::Dedup>>,
+    on_drop: Option<worker::DropHandler>,
+    suppressed: core::sync::atomic::AtomicUsize,
+    timestamps: std::sync::Arc<clock::Timestamps>,
+    #[cfg(feature = "opentelemetry")]
+    otel: otel::OtelIds,
+}
+
+impl<F: Clone> Clone for Layer<F, worker::WorkerChannel> {
+    ///Creates layer, that shares worker, filter, rate limit and deduplication with this layer.
+    ///
+    ///Hence records of both layers are sent with the same tag.
+    fn clone(&self) -> Self {
+        Self {
+            consumer: self.consumer.clone(),
+            fmt: self.fmt.clone(),
+            opts: self.opts.clone(),
+            filter: self.filter.shared(),
+            eager_flush: self.eager_flush,
+            rate_limit: self.rate_limit.clone(),
+            dedup: self.dedup.clone(),
+            on_drop: self.on_drop.clone(),
+            suppressed: core::sync::atomic::AtomicUsize::new(0),
+            timestamps: self.timestamps.clone(),
+            #[cfg(feature = "opentelemetry")]
+            otel: otel::OtelIds::default(),
+        }
+    }
+}
+
+impl<F: FieldFormatter, W: worker::Consumer> Layer<F, W> {
+    #[inline]
+    ///Returns number of events that were ignored because worker is no longer running.
+    ///
+    ///Once worker stops, layer no longer creates records for events, as they cannot be delivered.
+    pub fn suppressed_events(&self) -> usize {
+        self.suppressed.load(core::sync::atomic::Ordering::Relaxed)
+    }
+
+    #[inline]
+    ///Returns number of callsites, which event metadata is cached by layer.
+    ///
+    ///Metadata is cached on first event of callsite, hence it is bounded by number of callsites.
+    pub fn cached_callsites(&self) -> usize {
+        self.opts.metadata_cache.len()
+    }
+
+    #[inline(always)]
+    ///Returns consumer of records.
+    pub fn consumer(&self) -> &W {
+        &self.consumer
+    }
+
+    #[inline]
+    ///Applies filter, configured via `Builder`, as per-layer filter.
+    ///
+    ///Plain layer has to check every event by itself, as disabling callsite would disable it for
+    ///all other layers.
+    ///Per-layer filter instead disables callsites only for this layer, hence events that would
+    ///never be recorded cost nearly nothing.
+    ///
+    ///Sampling is still performed by layer itself.
+    pub fn filtered<C: tracing_core::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(self) -> tracing_subscriber::filter::Filtered<Self, LayerFilter, C> {
+        let filter = self.filter.shared();
+        tracing_subscriber::Layer::with_filter(self, filter)
+    }
 }
 
 ///Builder to enable forwarding `tracing` events towards the `fluentd` server.
@@ -85,12 +628,18 @@ pub struct Layer<F, C> {
 ///## Type params
 ///
 ///- `F` - Attributes formatter, determines how to compose `fluent::Record`.
-///- `A` - function that returns `Fluentd` wrter. Default is to create tcp socket towards `127.0.0.1:24224` with timeout of 1s.
-pub struct Builder<F=NestedFmt, A=fn() -> std::io::Result<TcpStream>> {
+///- `A` - function that returns `Fluentd` wrter. Default is `Localhost`, creating tcp socket towards `127.0.0.1:24224` or `[::1]:24224`.
+#[derive(Clone)]
+pub struct Builder<F=NestedFmt, A=Localhost> {
     tag: &'static str,
     writer: A,
     fmt: F,
-    max_msg_record: usize,
+    opts: FmtOpts,
+    filter: LayerFilter,
+    eager_flush: Option<tracing_core::Level>,
+    rate_limit: rate_limit::Opts,
+    dedup_window: Option<core::time::Duration>,
+    worker: worker::Opts,
 }
 
 impl Builder {
@@ -104,21 +653,22 @@ impl Builder {
         const DEFAULT_MAX_MSG_RECORD: usize = 10;
         Self {
             tag,
-            writer: default,
+            writer: Localhost,
             fmt: NestedFmt,
-            max_msg_record: DEFAULT_MAX_MSG_RECORD,
+            opts: FmtOpts::new(),
+            filter: LayerFilter::new(),
+            eager_flush: None,
+            rate_limit: rate_limit::Opts::default(),
+            dedup_window: None,
+            worker: worker::Opts::new(DEFAULT_MAX_MSG_RECORD),
         }
     }
 
     #[inline(always)]
     ///Provides max message record to fetch up.
-    pub fn with_max_msg_record(self, max_msg_record: num::NonZeroUsize) -> Self {
-        Self {
-            tag: self.tag,
-            writer: self.writer,
-            fmt: self.fmt,
-            max_msg_record: max_msg_record.get()
-        }
+    pub fn with_max_msg_record(mut self, max_msg_record: num::NonZeroUsize) -> Self {
+        self.worker.max_msg_record = max_msg_record.get();
+        self
     }
 }
 
@@ -131,95 +681,1254 @@ impl<A: MakeWriter> Builder<NestedFmt, A> {
             tag: self.tag,
             writer: self.writer,
             fmt: FlattenFmt,
-            max_msg_record: self.max_msg_record,
+            opts: self.opts,
+            filter: self.filter,
+            eager_flush: self.eager_flush,
+            rate_limit: self.rate_limit,
+            dedup_window: self.dedup_window,
+            worker: self.worker,
         }
     }
-}
 
-impl<F: FieldFormatter, A: MakeWriter> Builder<F, A> {
     #[inline(always)]
-    ///Provides formatter.
-    pub fn with_formatter<NF: FieldFormatter>(self, fmt: NF) -> Builder<NF, A> {
+    ///Configures to insert event attributes at the root of record, while grouping attributes of
+    ///spans under `context` key, using `ContextFmt`.
+    ///
+    ///Use `with_formatter` to provide `ContextFmt` with custom configuration.
+    pub fn context(self) -> Builder<ContextFmt, A> {
+        self.with_formatter(ContextFmt::new())
+    }
+
+    #[inline]
+    ///Configures to produce records in shape of GELF, using `GelfFmt` with specified `host`.
+    pub fn gelf<H: Into<String>>(self, host: H) -> Builder<GelfFmt, A> {
         Builder {
             tag: self.tag,
             writer: self.writer,
-            fmt,
-            max_msg_record: self.max_msg_record,
+            fmt: GelfFmt {
+                host: host.into(),
+            },
+            opts: self.opts,
+            filter: self.filter,
+            eager_flush: self.eager_flush,
+            rate_limit: self.rate_limit,
+            dedup_window: self.dedup_window,
+            worker: self.worker,
         }
     }
 
     #[inline(always)]
-    ///Provides callback to get writer where to write records.
-    ///
-    ///Normally fluentd server expects connection to be closed immediately upon sending records.
-    ///hence created writer is dropped immediately upon writing being finished.
-    pub fn with_writer<MW: MakeWriter>(self, writer: MW) -> Builder<F, MW> {
+    ///Configures to produce records in shape of Logstash, using `LogstashFmt`.
+    pub fn logstash(self) -> Builder<LogstashFmt, A> {
         Builder {
             tag: self.tag,
-            writer,
-            fmt: self.fmt,
-            max_msg_record: self.max_msg_record,
+            writer: self.writer,
+            fmt: LogstashFmt,
+            opts: self.opts,
+            filter: self.filter,
+            eager_flush: self.eager_flush,
+            rate_limit: self.rate_limit,
+            dedup_window: self.dedup_window,
+            worker: self.worker,
         }
     }
 
     #[inline(always)]
-    ///Creates `tracing` layer.
-    ///
-    ///If you do not want to create multiple threads, consider using
-    ///`layer_guarded`/`layer_from_guard`.
-    ///
-    ///`Error` can happen during creation of worker thread.
-    pub fn layer(self) -> Result<Layer<F, worker::ThreadWorker>, std::io::Error> {
-        let consumer = worker::thread(self.tag, self.writer, self.max_msg_record)?;
-
-        Ok(Layer {
-            consumer,
-            fmt: self.fmt,
-        })
+    ///Configures to produce records in shape of Google Cloud Logging, using `GcpFmt`.
+    pub fn gcp(self) -> Builder<GcpFmt, A> {
+        Builder {
+            tag: self.tag,
+            writer: self.writer,
+            fmt: GcpFmt,
+            opts: self.opts,
+            filter: self.filter,
+            eager_flush: self.eager_flush,
+            rate_limit: self.rate_limit,
+            dedup_window: self.dedup_window,
+            worker: self.worker,
+        }
     }
+}
 
+impl<F: FieldFormatter, A: MakeWriter> Builder<F, writer::Handshake<A>> where A::Writer: std::io::Read {
     #[inline]
-    ///Creates `tracing` layer, returning guard that allows to stop `fluentd` worker on `Drop`.
-    ///
-    ///This may be necessary due to bad API that `tracing` provides to control lifetime of global
-    ///logger. As underlying implementations employs caching, it needs to perform flush once logger
-    ///is no longer necessary hence this API is provided.
-    ///
-    ///`Error` can happen during creation of worker thread.
-    pub fn layer_guarded(self) -> Result<(Layer<F, worker::WorkerChannel>, FlushingGuard), std::io::Error> {
-        let consumer = worker::thread(self.tag, self.writer, self.max_msg_record)?;
-        let guard = FlushingGuard(consumer);
-        let layer = Layer {
-            consumer: worker::WorkerChannel(guard.0.sender()),
-            fmt: self.fmt,
-        };
+    ///Provides username and password for handshake with server requiring user authentication.
+    pub fn with_user_auth<U: Into<String>, P: Into<String>>(mut self, username: U, password: P) -> Self {
+        self.writer = self.writer.credentials(username, password);
+        self
+    }
+}
 
-        Ok((layer, guard))
+impl<F: FieldFormatter, A: MakeWriter> Builder<F, A> {
+    #[inline(always)]
+    ///Returns tag of records.
+    pub fn tag(&self) -> &'static str {
+        self.tag
     }
 
     #[inline(always)]
-    ///Creates `tracing` layer, using guard returned by  `layer_guarded`.
+    ///Returns max number of records per message.
+    pub fn max_msg_record(&self) -> usize {
+        self.worker.max_msg_record
+    }
+
+    #[inline(always)]
+    ///Returns configured writer.
+    pub fn writer(&self) -> &A {
+        &self.writer
+    }
+
+    #[inline(always)]
+    ///Returns configured formatter.
+    pub fn formatter(&self) -> &F {
+        &self.fmt
+    }
+
+    #[inline(always)]
+    ///Returns options of formatter.
+    pub fn fmt_opts(&self) -> &FmtOpts {
+        &self.opts
+    }
+
+    #[inline(always)]
+    ///Returns filter of events.
+    pub fn filter(&self) -> &LayerFilter {
+        &self.filter
+    }
+
+    #[inline(always)]
+    ///Returns encoding of records.
+    pub fn codec(&self) -> Codec {
+        self.worker.codec
+    }
+
+    #[inline(always)]
+    ///Returns level, at and above which records are sent as soon as possible.
+    pub fn eager_flush_level(&self) -> Option<tracing_core::Level> {
+        self.eager_flush
+    }
+
+    #[inline(always)]
+    ///Returns limit of total size of undelivered records in bytes, with policy to apply once it is exceeded.
+    pub fn memory_budget(&self) -> Option<(usize, OverflowPolicy)> {
+        self.worker.memory_budget
+    }
+
+    #[inline(always)]
+    ///Returns whether worker checks cached writer before writing next message.
+    pub fn has_connection_probe(&self) -> bool {
+        self.worker.probe
+    }
+
+    #[inline(always)]
+    ///Returns whether error handler is specified.
+    pub fn has_error_handler(&self) -> bool {
+        self.worker.error_handler.is_some()
+    }
+
+    ///Configures builder for development, so that records are visible as soon as possible.
     ///
-    ///Specifically, it will use the same worker thread as first instance of `layer_guarded`,
+    ///- `max_msg_record` is `5`;
+    ///- Eager flush level is `TRACE`, i.e. every record is sent right away;
+    ///- Errors are reported to stderr via error handler.
+    ///
+    ///Every option can be overridden afterwards.
+    pub fn development(mut self) -> Self {
+        self.worker.max_msg_record = 5;
+        self.eager_flush = Some(tracing_core::Level::TRACE);
+        self.worker.error_handler = Some(std::sync::Arc::new(|error: &std::io::Error| {
+            eprintln!("tracing-fluentd: {}", error);
+        }));
+        self
+    }
+
+    ///Configures builder for production, so that records are batched and memory usage is bounded.
+    ///
+    ///- `max_msg_record` is `100`;
+    ///- Eager flush level is `ERROR`, so that errors are not lost if process terminates abruptly;
+    ///- Memory budget is 64MiB, discarding the oldest records once exceeded, i.e.
+    ///  `OverflowPolicy::DropOldest`;
+    ///- Connection probe is enabled, to detect connections closed by server.
+    ///
+    ///Every option can be overridden afterwards.
+    pub fn production(mut self) -> Self {
+        self.worker.max_msg_record = 100;
+        self.eager_flush = Some(tracing_core::Level::ERROR);
+        self.worker.memory_budget = Some((64 * 1024 * 1024, OverflowPolicy::DropOldest));
+        self.worker.probe = true;
+        self
+    }
+
+    #[inline(always)]
+    ///Overrides tag of records.
+    ///
+    ///Useful to create different layers out of the same `Builder` clone.
+    pub fn with_tag(mut self, tag: &'static str) -> Self {
+        self.tag = tag;
+        if let Some(level_tags) = self.opts.level_tags.as_mut() {
+            *level_tags = crate::tracing::LevelTags::new(tag, &self.opts.level_tag_suffixes);
+        }
+        self
+    }
+
+    #[inline(always)]
+    ///Provides formatter.
+    pub fn with_formatter<NF: FieldFormatter>(self, fmt: NF) -> Builder<NF, A> {
+        Builder {
+            tag: self.tag,
+            writer: self.writer,
+            fmt,
+            opts: self.opts,
+            filter: self.filter,
+            eager_flush: self.eager_flush,
+            rate_limit: self.rate_limit,
+            dedup_window: self.dedup_window,
+            worker: self.worker,
+        }
+    }
+
+    #[inline(always)]
+    ///Specifies max level of events to record.
+    ///
+    ///Default is to record all events.
+    pub fn with_max_level(mut self, max_level: tracing_subscriber::filter::LevelFilter) -> Self {
+        self.filter.set_max_level(max_level);
+        self
+    }
+
+    #[inline(always)]
+    ///Specifies targets of events to record.
+    ///
+    ///Applied in addition to `with_max_level`.
+    ///Default is to record events of all targets.
+    pub fn with_targets(mut self, targets: tracing_subscriber::filter::Targets) -> Self {
+        self.filter.set_targets(targets);
+        self
+    }
+
+    #[inline(always)]
+    ///Specifies ratio of enabled events to record, from `0.0` to `1.0`.
+    ///
+    ///Recorded events are spread evenly, e.g. with ratio `0.25` every fourth event is recorded.
+    ///Default is to record all events.
+    pub fn with_sample_ratio(mut self, ratio: f64) -> Self {
+        self.filter.set_sample_ratio(ratio);
+        self
+    }
+
+    #[inline]
+    ///Specifies to decide whether to record event by value of field `key`, via `decide`.
+    ///
+    ///Field is looked up within event first and then within its spans, starting from the closest
+    ///one.
+    ///If neither has it, event is sampled according to sample ratio, same as with
+    ///`SampleDecision::UseDefaultRatio`.
+    ///
+    ///```rust
+    ///use tracing_fluentd::SampleDecision;
+    ///
+    ///let builder = tracing_fluentd::Builder::new("rust").with_sample_ratio(0.1).with_sampling_key("tenant_id", |tenant| match tenant.as_str() {
+    ///    Some("canary") => SampleDecision::Keep,
+    ///    _ => SampleDecision::UseDefaultRatio,
+    ///});
+    ///```
+    pub fn with_sampling_key<D: Fn(&fluent::Value) -> SampleDecision + Send + Sync + 'static>(mut self, key: &'static str, decide: D) -> Self {
+        self.filter.set_sampling_key(key, std::sync::Arc::new(decide));
+        self
+    }
+
+    #[inline(always)]
+    ///Configures built-in formatters to emit level in lowercase (e.g. `info` instead of `INFO`).
+    ///
+    ///Default is uppercase.
+    pub fn with_lowercase_level(mut self) -> Self {
+        self.opts.lowercase_level = true;
+        self
+    }
+
+    #[inline(always)]
+    ///Configures built-in formatters to include `thread_name` and `thread_id` of the thread that
+    ///emitted event.
+    ///
+    ///These are inserted alongside the rest of event metadata: inside `metadata` object for
+    ///`NestedFmt` and at the root of record for `FlattenFmt`.
+    pub fn with_thread_info(mut self) -> Self {
+        self.opts.thread_info = true;
+        self
+    }
+
+    #[inline(always)]
+    ///Configures layer to insert record's timestamp under `key` within record itself.
+    ///
+    ///Timestamp is the same as the one sent as part of record's header.
+    ///If event already contains field `key`, it is left as it is.
+    pub fn with_record_timestamp(mut self, key: &'static str, style: TimestampStyle) -> Self {
+        self.opts.record_timestamp = Some((key, style));
+        self
+    }
+
+    #[inline(always)]
+    ///Configures layer to insert record's time under `key` within record itself, as number in
+    ///specified `unit`, for consumers that ignore time of entry (e.g. `fluent-bit`).
+    ///
+    ///Value is computed from the same timestamp as time of entry, hence they are always
+    ///consistent.
+    ///If event already contains field `key`, it is kept, which is reported once via error handler.
+    pub fn with_time_field(mut self, key: &'static str, unit: TimeUnit) -> Self {
+        self.opts.time_field = Some((key, unit));
+        self
+    }
+
+    #[inline(always)]
+    ///Configures built-in formatters to record errors as object with `message` and array of its
+    ///sources under `causes`.
+    ///
+    ///By default error is recorded as single string, joining error and its sources with `: `.
+    ///In both cases only up to 8 sources are recorded.
+    pub fn with_structured_errors(mut self) -> Self {
+        self.opts.structured_errors = true;
+        self
+    }
+
+    #[inline(always)]
+    ///Configures built-in formatters to additionally record alternate `Debug` output of error
+    ///under `<field>.debug` key.
+    pub fn with_error_debug(mut self) -> Self {
+        self.opts.error_debug = true;
+        self
+    }
+
+    #[inline(always)]
+    ///Configures built-in formatters to normalize events forwarded from `log` crate by `tracing-log`.
+    ///
+    ///Such events carry `log.target`, `log.module_path`, `log.file` and `log.line` fields, while
+    ///event's metadata refers to the bridge itself.
+    ///When enabled, these fields are used as `module`, `file` and `line` of the record and are
+    ///removed from the record.
+    ///Other events are unaffected.
+    pub fn with_log_bridge_normalization(mut self) -> Self {
+        self.opts.log_normalization = true;
+        self
+    }
+
+    #[inline(always)]
+    ///Specifies key of object with event metadata, inserted by `NestedFmt`.
+    ///
+    ///Default is `metadata`.
+    ///If event already contains field `key` or is within span named `key`, metadata is inserted
+    ///under `key` with numeric suffix instead, e.g. `metadata_1`, rather than overwriting it.
+    pub fn with_metadata_key(mut self, key: &'static str) -> Self {
+        self.opts.metadata_key = key;
+        self
+    }
+
+    #[inline(always)]
+    ///Configures `NestedFmt` to include span's metadata within each span's object under `meta` key.
+    ///
+    ///Metadata contains `target`, `file`, `line` and `level` of span.
+    pub fn with_span_metadata(mut self) -> Self {
+        self.opts.span_metadata = true;
+        self
+    }
+
+    #[inline(always)]
+    ///Configures formatters to include ids of spans and links, recorded via `Span::follows_from`.
+    ///
+    ///`NestedFmt` includes `id` of span and `follows_from` array of ids within each span's object,
+    ///while `FlattenFmt` includes `follows_from` ids of every span within scope at the root.
+    ///Multiple links of the same span are accumulated.
+    pub fn with_span_ids(mut self) -> Self {
+        self.opts.span_ids = true;
+        self
+    }
+
+    #[inline]
+    ///Specifies fields of spans to copy at the root of every record.
+    ///
+    ///Field is taken from the closest span within event's scope, that has it.
+    ///Event's own field with the same name takes precedence, while missing fields are not inserted.
+    ///
+    ///This is done regardless of formatter, allowing to always have correlation id (e.g.
+    ///`request_id`) at the same place.
+    pub fn with_propagated_fields(mut self, fields: &[&'static str]) -> Self {
+        self.opts.propagated_fields = fields.to_vec();
+        self
+    }
+
+    #[inline(always)]
+    ///Specifies limit of nested objects and arrays within record.
+    ///
+    ///Limit is enforced on serialization, replacing object or array, that exceeds it, with
+    ///`"[max depth exceeded]"` string, which prevents stack overflow on deeply nested values.
+    ///
+    ///Default is `fluent::DEFAULT_MAX_DEPTH`.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.opts.max_depth = max_depth;
+        self
+    }
+
+    #[cfg(feature = "json")]
+    #[inline]
+    ///Specifies fields, which string values are parsed as JSON.
+    ///
+    ///Values, that are valid JSON object or array, are inserted as structured objects, while
+    ///other values are kept as string.
+    ///Applies to fields of events and spans, including fields recorded via `Display`, e.g. `payload = %json`.
+    pub fn with_parse_json_fields(mut self, fields: &[&'static str]) -> Self {
+        self.opts.json_fields = fields.to_vec();
+        self
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    #[inline]
+    ///Specifies keys of OpenTelemetry trace and span ids, by default `trace_id` and `span_id`.
+    ///
+    ///Ids are recorded as lowercase hex strings, when event is within span that has OpenTelemetry
+    ///context, e.g. when `tracing-opentelemetry` layer is installed in the same subscriber.
+    ///
+    ///Requires `opentelemetry` feature.
+    pub fn with_opentelemetry_keys(mut self, trace_id: &'static str, span_id: &'static str) -> Self {
+        self.opts.otel_keys = (trace_id, span_id);
+        self
+    }
+
+    #[inline]
+    ///Adds syslog fields to every record: `severity`, derived from level, constant `facility` and `ident`.
+    ///
+    ///Default severity is `3` for `ERROR`, `4` for `WARN`, `6` for `INFO` and `7` for `DEBUG` and
+    ///`TRACE`, which can be changed via `with_syslog_severity`.
+    pub fn with_syslog_fields(mut self, facility: u8, ident: &'static str) -> Self {
+        self.opts.syslog = Some(crate::tracing::SyslogFields::new(facility, ident));
+        self
+    }
+
+    #[inline]
+    ///Specifies syslog `severity` of `level`.
+    ///
+    ///Has no effect unless `with_syslog_fields` is used, which can be called before or after.
+    pub fn with_syslog_severity(mut self, level: tracing_core::Level, severity: u8) -> Self {
+        self.opts.syslog_severity[crate::tracing::SyslogFields::idx(&level)] = severity;
+        self
+    }
+
+    #[inline]
+    ///Sends records with tag `<tag>.<suffix>`, where suffix is derived from level.
+    ///
+    ///Default suffix is `error` for `ERROR`, `warn` for `WARN` and `log` for other levels, which can
+    ///be changed via `with_level_tag_suffix_for`.
+    ///Tags are built once, hence records only reference them.
+    ///
+    ///Worker sends records of every tag as separate message.
+    pub fn with_level_tag_suffix(mut self) -> Self {
+        self.opts.level_tags = Some(crate::tracing::LevelTags::new(self.tag, &self.opts.level_tag_suffixes));
+        self
+    }
+
+    #[inline]
+    ///Specifies tag suffix of `level`.
+    ///
+    ///Has no effect unless `with_level_tag_suffix` is used, which can be called before or after.
+    pub fn with_level_tag_suffix_for(mut self, level: tracing_core::Level, suffix: &'static str) -> Self {
+        self.opts.level_tag_suffixes[crate::tracing::SyslogFields::idx(&level)] = suffix;
+        if let Some(level_tags) = self.opts.level_tags.as_mut() {
+            *level_tags = crate::tracing::LevelTags::new(self.tag, &self.opts.level_tag_suffixes);
+        }
+        self
+    }
+
+    #[inline(always)]
+    ///Enables field `key`, containing name of event, specified via `event!(name: "...")`.
+    ///
+    ///Names, generated by `tracing` macros, are recognized by `event ` prefix (e.g. `event src/main.rs:10`)
+    ///and are omitted, unless `generated` is `true`.
+    pub fn with_event_name(mut self, key: &'static str, generated: bool) -> Self {
+        self.opts.event_name = Some((key, generated));
+        self
+    }
+
+    #[inline(always)]
+    ///Enables `span_path` field, containing names of spans from root to current one, joined with `separator`.
+    ///
+    ///E.g. with `/` separator: `http_request/authorize/db_query`.
+    ///Field is omitted for events outside of any span.
+    pub fn with_span_path(mut self, separator: &'static str) -> Self {
+        self.opts.span_path = Some(separator);
+        self
+    }
+
+    #[inline(always)]
+    ///Configures attributes of span to be sent once per message, instead of within every record.
+    ///
+    ///Formatter no longer records attributes of spans, instead the first record of span within
+    ///message contains `span` object, with attributes of span and its parents, and `span_ref`,
+    ///which identifies span within process.
+    ///Following records of the same span within message contain only `span_ref`.
+    ///Record can be re-joined with attributes of its span only within single message, as every
+    ///message starts anew.
+    ///
+    ///Whenever attributes of any span are recorded, following records refer to newly identified
+    ///spans.
+    ///
+    ///If records are not sent within the same message, such as with `with_packed_forward` or
+    ///`Codec::Ndjson`, every record contains both `span` and `span_ref`.
+    ///Deduplication, configured via `with_dedup_window`, compares records without attributes of span.
+    ///
+    ///Default is disabled.
+    pub fn with_span_registry(mut self) -> Self {
+        self.opts.span_registry = true;
+        self
+    }
+
+    #[inline(always)]
+    ///Specifies prefix of span attributes, when used with `FlattenFmt`.
+    ///
+    ///By default span attributes share namespace with event fields, and attributes with the same
+    ///name as event field are omitted.
+    ///Prefix applies only to span attributes, while event fields and metadata are left as is.
+    pub fn with_span_field_prefix<P: Into<SpanFieldPrefix>>(mut self, prefix: P) -> Self {
+        self.opts.span_field_prefix = Some(prefix.into());
+        self
+    }
+
+    #[inline(always)]
+    ///Specifies to expand nested objects into dotted keys, when used with `FlattenFmt`.
+    ///
+    ///Object `{"http": {"request": {"method": "GET"}}}` becomes `{"http.request.method": "GET"}`,
+    ///expanding at most `max_depth` levels, while deeper objects are left as is.
+    ///Applies to event fields and span attributes, after they are merged into record.
+    ///
+    ///Same as with span attributes, field already present in record takes precedence over
+    ///expanded key with the same name.
+    pub fn with_flatten_nested_objects(mut self, max_depth: usize) -> Self {
+        let arrays = self.opts.flatten_nested.map_or(false, |(_, arrays)| arrays);
+        self.opts.flatten_nested = Some((max_depth, arrays));
+        self
+    }
+
+    #[inline(always)]
+    ///Specifies to expand arrays into keys with index, e.g. `items.0`, together with objects.
+    ///
+    ///Has effect only with `with_flatten_nested_objects`, otherwise arrays are left as is.
+    pub fn with_flatten_nested_arrays(mut self) -> Self {
+        let max_depth = self.opts.flatten_nested.map_or(0, |(max_depth, _)| max_depth);
+        self.opts.flatten_nested = Some((max_depth, true));
+        self
+    }
+
+    #[inline(always)]
+    ///Specifies name of field, which suppresses event, when set to `true`.
+    ///
+    ///Such events are never sent to fluentd, which is useful for events meant only for local
+    ///output, e.g. `tracing::info!(fluentd.skip = true, "progress")`.
+    ///
+    ///Field itself is never recorded, regardless of its value.
+    pub fn with_skip_field(mut self, name: &'static str) -> Self {
+        self.opts.skip_field = Some(name);
+        self
+    }
+
+    #[inline]
+    ///Specifies the only fields of events and spans that are recorded, including `message`.
+    ///
+    ///Other fields are dropped, unless `extra` is `true`, in which case they are recorded within
+    ///nested `extra` object.
+    ///Metadata is not affected, and is controlled by its own options.
+    ///
+    ///Overrides `with_field_denylist`.
+    pub fn with_field_allowlist(mut self, fields: &[&'static str], extra: bool) -> Self {
+        self.opts.field_filter = Some(crate::tracing::FieldFilter::Allow {
+            fields: fields.to_vec(),
+            extra,
+        });
+        self
+    }
+
+    #[inline]
+    ///Specifies fields of events and spans that are dropped.
+    ///
+    ///Metadata is not affected, and is controlled by its own options.
+    ///
+    ///Overrides `with_field_allowlist`.
+    pub fn with_field_denylist(mut self, fields: &[&'static str]) -> Self {
+        self.opts.field_filter = Some(crate::tracing::FieldFilter::Deny(fields.to_vec()));
+        self
+    }
+
+    #[inline]
+    ///Specifies to make timestamps of records monotonic within process.
+    ///
+    ///Instead of reading wall-clock time for every record, timestamp is computed as wall-clock
+    ///time at last anchor plus monotonic time elapsed since then.
+    ///Anchor is moved to current wall-clock time every minute or once wall-clock time is ahead
+    ///by more than a second, but never backwards, hence when system clock is stepped backwards
+    ///timestamps keep increasing until wall-clock time catches up.
+    pub fn with_monotonic_timestamps(mut self) -> Self {
+        self.opts.monotonic_timestamps = true;
+        self
+    }
+
+    #[inline(always)]
+    ///Specifies to insert sequence number of record under `key`, allowing to restore order of
+    ///records regardless of timestamp precision and order of delivery.
+    ///
+    ///Sequence number is taken from counter, shared by all layers within process, when event is
+    ///recorded, hence it keeps increasing across restarts of worker.
+    ///If event already contains field `key`, it is overwritten.
+    ///
+    ///Sequence of messages can be included within option section via `with_option_fields_fn`.
+    pub fn with_sequence_field(mut self, key: &'static str) -> Self {
+        self.opts.sequence_field = Some(key);
+        self
+    }
+
+    #[inline]
+    ///Specifies fields, which values are replaced with `"[REDACTED]"`.
+    ///
+    ///Pattern either matches field name exactly or, if it starts with `*`, matches suffix of
+    ///field name (e.g. `*_token` matches `access_token`).
+    ///
+    ///Applies to every field of record, including span's fields and fields of nested objects.
+    ///Redaction is performed within thread that emitted event, hence raw value never reaches
+    ///worker.
+    pub fn with_redacted_fields(mut self, patterns: &[&'static str]) -> Self {
+        self.opts.redacted_fields = patterns.to_vec();
+        self
+    }
+
+    #[inline]
+    ///Adds field with constant value to the root of every record.
+    ///
+    ///Record's own field with the same name takes precedence.
+    pub fn with_static_field<K: Into<std::borrow::Cow<'static, str>>, V: Into<fluent::Value>>(mut self, key: K, value: V) -> Self {
+        self.opts.static_fields.insert(key.into(), value.into());
+        self
+    }
+
+    ///Adds static fields with values of environment variables, as list of `(field, variable)`.
+    ///
+    ///Variables are read once, when this method is called.
+    ///Variables that are missing or not valid unicode are skipped.
+    pub fn with_env_fields(self, fields: &[(&'static str, &str)]) -> Self {
+        self.env_fields(fields, false)
+    }
+
+    ///Adds static fields with values of environment variables, as list of `(field, variable)`.
+    ///
+    ///Same as `with_env_fields`, except missing variables are recorded as `null`.
+    pub fn with_env_fields_or_null(self, fields: &[(&'static str, &str)]) -> Self {
+        self.env_fields(fields, true)
+    }
+
+    fn env_fields(mut self, fields: &[(&'static str, &str)], missing_as_null: bool) -> Self {
+        for (key, var) in fields {
+            match std::env::var(var) {
+                Ok(value) => {
+                    self.opts.static_fields.insert((*key).into(), value.into());
+                },
+                Err(_) if missing_as_null => {
+                    self.opts.static_fields.insert((*key).into(), fluent::Value::Null);
+                },
+                Err(_) => (),
+            }
+        }
+        self
+    }
+
+    ///Adds static fields with values of every environment variable, whose name starts with `prefix`.
+    ///
+    ///Field name is variable's name without `prefix`, e.g. with prefix `FLUENT_FIELD_` variable
+    ///`FLUENT_FIELD_team=payments` adds field `team` with value `payments`.
+    ///
+    ///Variables are read once, when this method is called.
+    pub fn with_env_prefix(mut self, prefix: &str) -> Self {
+        for (key, value) in env_prefixed(prefix) {
+            self.opts.static_fields.insert(key.into(), value.into());
+        }
+        self
+    }
+
+    #[inline]
+    ///Adds `kubernetes` object, composed of conventional environment variables, exported via downward API.
+    ///
+    ///Same as `with_kubernetes_fields_from(KubernetesVars::default())`.
+    pub fn with_kubernetes_fields(self) -> Self {
+        self.with_kubernetes_fields_from(KubernetesVars::default())
+    }
+
+    ///Adds `kubernetes` object, composed of environment variables `vars`, to the root of every record.
+    ///
+    ///Object has form of `{pod_name, namespace, node_name, labels: {...}}`, which is expected by
+    ///fluentd's kubernetes filter, and missing variables are omitted.
+    ///
+    ///Variables are read once, when this method is called.
+    pub fn with_kubernetes_fields_from(mut self, vars: KubernetesVars) -> Self {
+        let mut kubernetes = fluent::Map::new();
+        for (key, var) in [("pod_name", vars.pod_name), ("namespace", vars.namespace), ("node_name", vars.node_name)].iter() {
+            if let Ok(value) = std::env::var(var) {
+                kubernetes.insert((*key).into(), value.into());
+            }
+        }
+
+        let mut labels = fluent::Map::new();
+        for (key, value) in env_prefixed(vars.labels_prefix) {
+            labels.insert(key.into(), value.into());
+        }
+        if !labels.is_empty() {
+            kubernetes.insert("labels".into(), labels.into());
+        }
+
+        if !kubernetes.is_empty() {
+            self.opts.static_fields.insert("kubernetes".into(), kubernetes.into());
+        }
+        self
+    }
+
+    #[inline(always)]
+    ///Configures built-in formatters to always emit the same set of keys produced by formatter.
+    ///
+    ///Missing values, like `message`, `file` or `line`, are emitted as `null`.
+    ///In addition `NestedFmt` inserts spans as array under `spans` key, with each span being
+    ///object containing its `name`, `fields` and `meta`, if span metadata is enabled.
+    ///
+    ///Note that event's fields and, in case of `FlattenFmt`, span's fields are still inserted as
+    ///they are.
+    pub fn with_stable_schema(mut self) -> Self {
+        self.opts.stable_schema = true;
+        self
+    }
+
+    #[inline(always)]
+    ///Specifies to write records via `connection`, shared with other layers.
+    ///
+    ///Allows multiple layers, e.g. with different tags, to use a single connection to fluentd.
+    pub fn with_shared_connection(self, connection: &writer::Connection) -> Builder<F, writer::Connection> {
+        self.with_writer(connection.clone())
+    }
+
+    #[inline(always)]
+    ///Provides callback to get writer where to write records.
+    ///
+    ///Normally fluentd server expects connection to be closed immediately upon sending records.
+    ///hence created writer is dropped immediately upon writing being finished.
+    pub fn with_writer<MW: MakeWriter>(self, writer: MW) -> Builder<F, MW> {
+        Builder {
+            tag: self.tag,
+            writer,
+            fmt: self.fmt,
+            opts: self.opts,
+            filter: self.filter,
+            eager_flush: self.eager_flush,
+            rate_limit: self.rate_limit,
+            dedup_window: self.dedup_window,
+            worker: self.worker,
+        }
+    }
+
+    #[inline]
+    ///Provides callback to be invoked with number of records, whenever they are discarded.
+    ///
+    ///Callback is invoked either within worker thread or within thread that emitted event, hence
+    ///it should be cheap (e.g. increment counter).
+    ///By default discarded records are not reported.
+    pub fn on_drop<H: Fn(DropReason, usize) + Send + Sync + 'static>(mut self, handler: H) -> Self {
+        self.worker.on_drop = Some(std::sync::Arc::new(handler));
+        self
+    }
+
+    #[inline(always)]
+    ///Enables marker records with tag `<tag>.<suffix>`, sent by worker after records are discarded.
+    ///
+    ///Marker is sent per reason of drops, since previous marker, containing `reason`, `count` of
+    ///discarded records and time range of drops as `from` and `to`, in milliseconds since UNIX
+    ///epoch.
+    ///Markers are sent together with next message, so that gaps are visible within data stream.
+    ///
+    ///Ignored by layer, created via `layer_async`.
+    pub fn with_drop_markers(mut self, suffix: &'static str) -> Self {
+        self.worker.drop_markers = Some(suffix);
+        self
+    }
+
+    #[inline(always)]
+    ///Specifies interval to send stats of worker as record with tag `<tag>.stats`.
+    ///
+    ///Record contains `enqueued`, `delivered` and `dropped` number of records, `queue_depth` as
+    ///number of records not yet received by worker, `reconnects` number, `last_error` string (or
+    ///`null`) and `uptime` of worker in seconds.
+    ///
+    ///Records, received by worker so far, are sent together with stats, hence they are delayed
+    ///by no more than interval.
+    ///By default stats are not sent.
+    pub fn with_stats_interval(mut self, interval: core::time::Duration) -> Self {
+        self.worker.stats_interval = Some(interval);
+        self
+    }
+
+    #[cfg(feature = "metrics")]
+    #[inline]
+    ///Configures worker to report its counters via `metrics` facade, with names starting with `prefix`.
+    ///
+    ///Reported metrics, with `tracing_fluentd` prefix:
+    ///
+    ///- `tracing_fluentd_records_delivered_total` - Counter of delivered records.
+    ///- `tracing_fluentd_records_dropped_total` - Counter of dropped records with `reason` label.
+    ///- `tracing_fluentd_queue_depth` - Gauge of records, not yet received by worker.
+    ///- `tracing_fluentd_reconnects_total` - Counter of connections, created after the first one.
+    ///- `tracing_fluentd_delivery_errors_total` - Counter of errors with `kind` label.
+    ///
+    ///Counters are updated at the same points as stats, configured via `with_stats_interval`.
+    pub fn with_metrics(mut self, prefix: &str) -> Self {
+        self.worker.metrics_prefix = Some(prefix.to_owned());
+        self
+    }
+
+    #[inline]
+    ///Specifies clock used by worker for retry delays and stats interval, and by layer for
+    ///timestamps of records.
+    ///
+    ///Default is `SystemClock`, while `testing::TestClock` allows to control time in tests.
+    pub fn with_clock<C: Clock>(mut self, clock: C) -> Self {
+        self.worker.clock = std::sync::Arc::new(clock);
+        self
+    }
+
+    #[inline]
+    ///Provides callback to be invoked on failure to create writer or to write records.
+    ///
+    ///Callback is invoked within worker thread.
+    ///Additionally it is invoked once within thread of event, if system time is before UNIX epoch,
+    ///in which case epoch is used as timestamp of records.
+    ///By default errors are ignored.
+    pub fn with_error_handler<H: Fn(&std::io::Error) + Send + Sync + 'static>(mut self, handler: H) -> Self {
+        self.worker.error_handler = Some(std::sync::Arc::new(handler));
+        self
+    }
+
+    #[inline]
+    ///Provides callback to adjust builder of worker thread before it is spawned.
+    ///
+    ///Builder is named `tracing-fluentd-worker` by default, while callback can change its name,
+    ///stack size or replace it altogether.
+    ///Worker is still joined once layer or guard is dropped.
+    ///
+    ///Ignored by layer, created via `layer_async`.
+    pub fn with_thread_builder<T: Fn(std::thread::Builder) -> std::thread::Builder + Send + Sync + 'static>(mut self, thread_builder: T) -> Self {
+        self.worker.thread_builder = Some(std::sync::Arc::new(thread_builder));
+        self
+    }
+
+    #[inline]
+    ///Configures content of option section of every message.
+    ///
+    ///Default is `OptionSection::Size`.
+    pub fn with_option_section(mut self, section: fluent::OptionSection) -> Self {
+        self.worker.option_section = section;
+        self
+    }
+
+    #[inline]
+    ///Configures to include custom `fields` within option section of every message, alongside `size`.
+    ///
+    ///This is the same as `OptionSection::Custom`.
+    pub fn with_option_fields(self, fields: fluent::Map) -> Self {
+        self.with_option_section(fluent::OptionSection::Custom(fields))
+    }
+
+    #[inline]
+    ///Provides callback to update custom fields of option section before sending every message.
+    ///
+    ///Callback is invoked within worker thread.
+    ///Enables `OptionSection::Custom`, unless option section is omitted.
+    pub fn with_option_fields_fn<U: Fn(&mut fluent::Map) + Send + Sync + 'static>(mut self, update: U) -> Self {
+        if let fluent::OptionSection::Size = self.worker.option_section {
+            self.worker.option_section = fluent::OptionSection::Custom(fluent::Map::new());
+        }
+        self.worker.option_fields_fn = Some(std::sync::Arc::new(update));
+        self
+    }
+
+    #[inline(always)]
+    ///Limits rate of recorded events, using token bucket of `burst` size, refilled with
+    ///`records_per_second`.
+    ///
+    ///Events beyond limit are dropped before creating record.
+    ///Number of dropped events is reported via record with `rate_limit_dropped` field, sent at most
+    ///once per second, together with the next recorded event.
+    ///
+    ///Limit is shared by all threads. Default is unlimited.
+    pub fn with_rate_limit(mut self, records_per_second: num::NonZeroU32, burst: num::NonZeroU32) -> Self {
+        self.rate_limit.limit = Some((records_per_second.get().into(), burst.get().into()));
+        self
+    }
+
+    #[inline(always)]
+    ///Configures rate limit to always record `ERROR` events.
+    ///
+    ///Has no effect unless `with_rate_limit` is used, which can be called before or after.
+    pub fn with_rate_limit_exempt_errors(mut self) -> Self {
+        self.rate_limit.exempt_errors = true;
+        self
+    }
+
+    #[inline(always)]
+    ///Suppresses identical consecutive records of the same callsite within `window`.
+    ///
+    ///Records are compared by all their fields, including fields of spans and metadata.
+    ///Once `window` passes since the last sent record, the same record is sent again with field
+    ///`repeated`, containing number of suppressed records.
+    ///If different record is created by the callsite, number of suppressed records is reported via
+    ///separate record with `repeated` field instead.
+    ///
+    ///Default is disabled.
+    pub fn with_dedup_window(mut self, window: core::time::Duration) -> Self {
+        self.dedup_window = Some(window);
+        self
+    }
+
+    #[inline(always)]
+    ///Specifies level, at and above which records are sent as soon as possible.
+    ///
+    ///Worker sends such record together with records received before it, without waiting for
+    ///`max_msg_record`, so that important records are not lost if process terminates abruptly.
+    ///
+    ///Default is to always wait for `max_msg_record`.
+    pub fn with_eager_flush_level(mut self, level: tracing_core::Level) -> Self {
+        self.eager_flush = Some(level);
+        self
+    }
+
+    #[inline(always)]
+    ///Specifies limit of total size of records in bytes, that are not yet delivered.
+    ///
+    ///Size of record is estimated once it is queued, while its size is released once it is
+    ///delivered.
+    ///Once limit is exceeded, `policy` is applied, reporting discarded records as
+    ///`DropReason::QueueFull`.
+    ///Current usage is reported as `memory_usage` within stats, if enabled.
+    ///
+    ///By default there is no limit.
+    pub fn with_memory_budget(mut self, limit: num::NonZeroUsize, policy: OverflowPolicy) -> Self {
+        self.worker.memory_budget = Some((limit.get(), policy));
+        self
+    }
+
+    #[inline(always)]
+    ///Specifies kind of queue between layer and worker.
+    ///
+    ///Default is `QueueKind::Unbounded`.
+    pub fn with_queue(mut self, queue: QueueKind) -> Self {
+        self.worker.queue = queue;
+        self
+    }
+
+    #[inline(always)]
+    ///Configures each thread to buffer its records, sending them to worker all at once.
+    ///
+    ///Buffer is sent once it has `records` or its oldest record is older than `max_age`, which is
+    ///checked on the next event of the same thread.
+    ///Remaining records are sent once thread exits, on eager flush or once worker is stopped by the
+    ///same thread.
+    ///Hence records of long-living idle thread can be delayed until its next event.
+    ///
+    ///This reduces contention on worker's queue under high rate of events from many threads, at
+    ///the cost of latency of individual records.
+    ///
+    ///Ignored by layer, created via `layer_async`.
+    pub fn with_thread_local_buffer(mut self, records: num::NonZeroUsize, max_age: core::time::Duration) -> Self {
+        self.worker.local_buffer = Some((records.get(), max_age));
+        self
+    }
+
+    #[inline(always)]
+    ///Configures records to be encoded within thread that emitted event, instead of worker.
+    ///
+    ///Worker then only concatenates encoded records, sending them in PackedForward mode, which
+    ///avoids worker becoming bottleneck under high rate of events.
+    ///
+    ///Ignored if codec is not `Codec::Msgpack`.
+    pub fn with_packed_forward(mut self) -> Self {
+        self.worker.packed = true;
+        self
+    }
+
+    #[inline(always)]
+    ///Configures worker to return written records into pool of up to `size` records, from which
+    ///layer takes records for new events instead of allocating them.
+    ///
+    ///Records are cleared before reuse, but retain capacity of their fields.
+    ///If pool is empty, record is allocated as usual, while records that do not fit into full pool
+    ///are dropped.
+    ///
+    ///Ignored by layer, created via `layer_async` or `disabled_layer`.
+    pub fn with_record_pool(mut self, size: num::NonZeroUsize) -> Self {
+        self.worker.record_pool = Some(size.get());
+        self
+    }
+
+    #[inline(always)]
+    ///Specifies encoding of records written by worker.
+    ///
+    ///Default is `Codec::Msgpack`.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.worker.codec = codec;
+        self
+    }
+
+    #[inline(always)]
+    ///Configures worker to check whether cached writer is still usable before writing next message,
+    ///re-creating it otherwise.
+    ///
+    ///This allows to detect connection closed by server without losing message, at the cost of
+    ///extra syscalls per message.
+    ///Check is performed via `MakeWriter::probe`.
+    ///
+    ///Default is disabled.
+    pub fn with_connection_probe(mut self) -> Self {
+        self.worker.probe = true;
+        self
+    }
+
+    #[inline]
+    ///Configures to perform forward protocol handshake with `shared_key` on every new connection.
+    ///
+    ///Required by `in_forward` with `<security>` section.
+    ///Use `with_user_auth` if server requires user authentication.
+    pub fn with_shared_key<K: Into<String>>(self, shared_key: K) -> Builder<F, writer::Handshake<A>> where A::Writer: std::io::Read {
+        Builder {
+            tag: self.tag,
+            writer: writer::Handshake::new(self.writer, shared_key),
+            fmt: self.fmt,
+            opts: self.opts,
+            filter: self.filter,
+            eager_flush: self.eager_flush,
+            rate_limit: self.rate_limit,
+            dedup_window: self.dedup_window,
+            worker: self.worker,
+        }
+    }
+
+    #[inline]
+    ///Erases types of formatter and writer, allowing to store builder without naming them.
+    ///
+    ///`S` is type of subscriber to which layer is added, see `BoxedFmt` for details.
+    ///
+    ///Every call to formatter and writer is dynamically dispatched, which is negligible in
+    ///comparison with creation of records and IO.
+    pub fn boxed<S: tracing_core::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(self) -> BoxedBuilder<S> where F: Send + Sync, A::Writer: Send + 'static {
+        Builder {
+            tag: self.tag,
+            writer: writer::BoxMakeWriter::new(self.writer),
+            fmt: BoxedFmt::new(self.fmt),
+            opts: self.opts,
+            filter: self.filter,
+            eager_flush: self.eager_flush,
+            rate_limit: self.rate_limit,
+            dedup_window: self.dedup_window,
+            worker: self.worker,
+        }
+    }
+
+    #[inline(always)]
+    ///Provides callback to get writer, boxing it.
+    ///
+    ///Allows to select writer at runtime, while `Builder` type remains the same.
+    pub fn with_boxed_writer<MW: MakeWriter>(self, writer: MW) -> Builder<F, writer::BoxMakeWriter> where MW::Writer: Send + 'static {
+        self.with_writer(writer::BoxMakeWriter::new(writer))
+    }
+
+    #[inline(always)]
+    ///Creates `tracing` layer.
+    ///
+    ///If you do not want to create multiple threads, consider using
+    ///`layer_guarded`/`layer_from_guard`.
+    ///
+    ///`Error` can happen during creation of worker thread.
+    pub fn layer(self) -> Result<Layer<F, worker::ThreadWorker>, std::io::Error> {
+        self.spawn_layer(false)
+    }
+
+    #[inline]
+    ///Creates `tracing` layer, failing if writer cannot be created.
+    ///
+    ///Unlike `layer`, which creates writer only once there are records to send, it creates writer
+    ///before returning, which is then used to send first records.
+    ///Hence misconfigured address of fluentd is reported at startup.
+    ///
+    ///`Error` can happen during creation of worker thread or writer.
+    pub fn layer_checked(self) -> Result<Layer<F, worker::ThreadWorker>, std::io::Error> {
+        self.spawn_layer(true)
+    }
+
+    fn spawn_layer(self, checked: bool) -> Result<Layer<F, worker::ThreadWorker>, std::io::Error> {
+        let timestamps = std::sync::Arc::new(clock::Timestamps::new(&self.worker, self.opts.monotonic_timestamps));
+        let consumer = match checked {
+            true => worker::thread_checked(self.tag, self.writer, self.worker)?,
+            false => worker::thread(self.tag, self.writer, self.worker)?,
+        };
+        let on_drop = consumer.on_drop();
+
+        Ok(Layer {
+            consumer,
+            fmt: self.fmt,
+            opts: self.opts,
+            filter: self.filter,
+            eager_flush: self.eager_flush,
+            rate_limit: self.rate_limit.limiter().map(std::sync::Arc::new),
+            dedup: self.dedup_window.map(dedup::Dedup::new).map(std::sync::Arc::new),
+            on_drop,
+            suppressed: core::sync::atomic::AtomicUsize::new(0),
+            timestamps,
+            #[cfg(feature = "opentelemetry")]
+            otel: otel::OtelIds::default(),
+        })
+    }
+
+    ///Creates `tracing` layer, that formats records, but discards them via `NullConsumer`.
+    ///
+    ///Neither worker is spawned nor writer is created, so it can be used to disable sending of
+    ///records without changing type of layer's formatter, or to measure cost of formatting.
+    pub fn disabled_layer(self) -> Layer<F, NullConsumer> {
+        let timestamps = std::sync::Arc::new(clock::Timestamps::new(&self.worker, self.opts.monotonic_timestamps));
+        Layer {
+            consumer: NullConsumer::new(),
+            fmt: self.fmt,
+            opts: self.opts,
+            filter: self.filter,
+            eager_flush: self.eager_flush,
+            rate_limit: self.rate_limit.limiter().map(std::sync::Arc::new),
+            dedup: self.dedup_window.map(dedup::Dedup::new).map(std::sync::Arc::new),
+            on_drop: None,
+            suppressed: core::sync::atomic::AtomicUsize::new(0),
+            timestamps,
+            #[cfg(feature = "opentelemetry")]
+            otel: otel::OtelIds::default(),
+        }
+    }
+
+    #[inline]
+    ///Creates `tracing` layer, returning `Handle` that allows to change its filtering at runtime.
+    ///
+    ///`Error` can happen during creation of worker thread.
+    pub fn layer_with_handle(self) -> Result<(Layer<F, worker::ThreadWorker>, Handle), std::io::Error> {
+        let handle = self.filter.handle();
+        let layer = self.layer()?;
+        Ok((layer, handle))
+    }
+
+    #[inline]
+    ///Creates `tracing` layer, returning guard that allows to stop `fluentd` worker on `Drop`.
+    ///
+    ///This may be necessary due to bad API that `tracing` provides to control lifetime of global
+    ///logger. As underlying implementations employs caching, it needs to perform flush once logger
+    ///is no longer necessary hence this API is provided.
+    ///
+    ///`Error` can happen during creation of worker thread.
+    pub fn layer_guarded(self) -> Result<(Layer<F, worker::WorkerChannel>, FlushingGuard), std::io::Error> {
+        self.spawn_guarded(false)
+    }
+
+    #[inline]
+    ///Creates `tracing` layer with guard, same as `layer_guarded`, failing if writer cannot be
+    ///created, same as `layer_checked`.
+    ///
+    ///`Error` can happen during creation of worker thread or writer.
+    pub fn layer_guarded_checked(self) -> Result<(Layer<F, worker::WorkerChannel>, FlushingGuard), std::io::Error> {
+        self.spawn_guarded(true)
+    }
+
+    fn spawn_guarded(self, checked: bool) -> Result<(Layer<F, worker::WorkerChannel>, FlushingGuard), std::io::Error> {
+        let timestamps = std::sync::Arc::new(clock::Timestamps::new(&self.worker, self.opts.monotonic_timestamps));
+        let consumer = match checked {
+            true => worker::thread_checked(self.tag, self.writer, self.worker)?,
+            false => worker::thread(self.tag, self.writer, self.worker)?,
+        };
+        let on_drop = consumer.on_drop();
+        let panic_opts = panic_hook::PanicOpts {
+            fmt: self.opts.clone(),
+            timestamps: timestamps.clone(),
+        };
+        let guard = FlushingGuard(consumer, std::sync::Arc::new(panic_opts));
+        let layer = Layer {
+            consumer: guard.0.channel(),
+            fmt: self.fmt,
+            opts: self.opts,
+            filter: self.filter,
+            eager_flush: self.eager_flush,
+            rate_limit: self.rate_limit.limiter().map(std::sync::Arc::new),
+            dedup: self.dedup_window.map(dedup::Dedup::new).map(std::sync::Arc::new),
+            on_drop,
+            suppressed: core::sync::atomic::AtomicUsize::new(0),
+            timestamps,
+            #[cfg(feature = "opentelemetry")]
+            otel: otel::OtelIds::default(),
+        };
+
+        Ok((layer, guard))
+    }
+
+    #[inline(always)]
+    ///Creates `tracing` layer, using guard returned by  `layer_guarded`.
+    ///
+    ///Specifically, it will use the same worker thread as first instance of `layer_guarded`,
     ///without affecting lifetime of `guard`.
     ///Hence once `guard` is dropped, worker for all connected layers will stop sending logs.
     ///
     ///`Error` can happen during creation of worker thread.
     pub fn layer_from_guard(self, guard: &FlushingGuard) -> Layer<F, worker::WorkerChannel> {
+        let timestamps = std::sync::Arc::new(clock::Timestamps::new(&self.worker, self.opts.monotonic_timestamps));
         Layer {
-            consumer: worker::WorkerChannel(guard.0.sender()),
+            consumer: guard.0.channel(),
+            fmt: self.fmt,
+            opts: self.opts,
+            filter: self.filter,
+            eager_flush: self.eager_flush,
+            rate_limit: self.rate_limit.limiter().map(std::sync::Arc::new),
+            dedup: self.dedup_window.map(dedup::Dedup::new).map(std::sync::Arc::new),
+            on_drop: guard.0.on_drop(),
+            suppressed: core::sync::atomic::AtomicUsize::new(0),
+            timestamps,
+            #[cfg(feature = "opentelemetry")]
+            otel: otel::OtelIds::default(),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<F: FieldFormatter> Builder<F, Localhost> {
+    ///Creates `tracing` layer, with worker running as task of async `runtime`, sending records via `writer`.
+    ///
+    ///Worker is stopped once layer is dropped, sending remaining records in background.
+    ///
+    ///Since `writer` replaces writer of `Builder`, it is only available when `Builder::with_writer` is not used.
+    ///Memory budget, connection probe, stats, drop markers and clock are not supported by async worker.
+    pub fn layer_async<W: async_worker::AsyncMakeWriter, R: async_worker::Runtime>(self, writer: W, runtime: R) -> Layer<F, async_worker::AsyncWorker> {
+        let timestamps = std::sync::Arc::new(clock::Timestamps::new(&self.worker, self.opts.monotonic_timestamps));
+        let consumer = async_worker::spawn(self.tag, writer, runtime, self.worker);
+        let on_drop = consumer.on_drop();
+
+        Layer {
+            consumer,
             fmt: self.fmt,
+            opts: self.opts,
+            filter: self.filter,
+            eager_flush: self.eager_flush,
+            rate_limit: self.rate_limit.limiter().map(std::sync::Arc::new),
+            dedup: self.dedup_window.map(dedup::Dedup::new).map(std::sync::Arc::new),
+            on_drop,
+            suppressed: core::sync::atomic::AtomicUsize::new(0),
+            timestamps,
+            #[cfg(feature = "opentelemetry")]
+            otel: otel::OtelIds::default(),
         }
     }
 }
 
-#[repr(transparent)]
 ///Guard that flushes and terminates `fluentd` worker.
 ///
 ///Droping this guard should be done only when `Layer` is no longer needed.
 ///
 ///As part of destructor, it awaits to finish flushing `fluentd` records.
-pub struct FlushingGuard(worker::ThreadWorker);
+pub struct FlushingGuard(worker::ThreadWorker, std::sync::Arc<panic_hook::PanicOpts>);
 
 impl Drop for FlushingGuard {
     fn drop(&mut self) {
@@ -0,0 +1,105 @@
+use core::mem;
+use core::cell::RefCell;
+use core::time::Duration;
+use core::sync::atomic::Ordering;
+use std::time::Instant;
+
+use crate::worker::{Message, Queue};
+
+#[derive(Clone, Copy)]
+///Limits of per-thread buffer, after which its records are sent to worker.
+pub(crate) struct Limits {
+    pub(crate) records: usize,
+    pub(crate) max_age: Duration,
+}
+
+//Records of single thread, that are not yet sent to particular worker.
+struct Buffer {
+    sender: crossbeam_channel::Sender<Message>,
+    queue: Queue,
+    messages: Vec<Message>,
+    //Time when the oldest buffered record is added.
+    since: Instant,
+}
+
+impl Buffer {
+    fn flush(&mut self) {
+        if self.messages.is_empty() {
+            return;
+        }
+
+        let capacity = self.messages.capacity();
+        let messages = mem::replace(&mut self.messages, Vec::with_capacity(capacity));
+        self.queue.deliver(&self.sender, Message::Batch(messages));
+    }
+}
+
+//Buffers of all workers, used by thread.
+//
+//Remaining records are sent once thread exits.
+struct Buffers(Vec<Buffer>);
+
+impl Buffers {
+    #[inline]
+    fn get(&mut self, sender: &crossbeam_channel::Sender<Message>) -> Option<&mut Buffer> {
+        self.0.iter_mut().find(|buffer| buffer.sender.same_channel(sender))
+    }
+}
+
+impl Drop for Buffers {
+    fn drop(&mut self) {
+        for buffer in self.0.iter_mut() {
+            buffer.flush();
+        }
+    }
+}
+
+thread_local! {
+    static BUFFERS: RefCell<Buffers> = const { RefCell::new(Buffers(Vec::new())) };
+}
+
+///Adds `message` to current thread's buffer, sending buffer to worker once `limits` are reached.
+///
+///If thread is being destroyed, message is sent as it is.
+pub(crate) fn push(limits: Limits, sender: &crossbeam_channel::Sender<Message>, queue: &Queue, message: Message) {
+    let mut message = Some(message);
+    let _ = BUFFERS.try_with(|buffers| if let Ok(mut buffers) = buffers.try_borrow_mut() {
+        //Buffers of stopped workers are no longer needed.
+        buffers.0.retain(|buffer| !buffer.queue.closed.load(Ordering::Acquire));
+
+        let buffer = match buffers.get(sender) {
+            Some(buffer) => buffer,
+            None => {
+                buffers.0.push(Buffer {
+                    sender: sender.clone(),
+                    queue: queue.clone(),
+                    messages: Vec::with_capacity(limits.records),
+                    since: Instant::now(),
+                });
+                buffers.0.last_mut().unwrap()
+            }
+        };
+
+        let now = Instant::now();
+        if buffer.messages.is_empty() {
+            buffer.since = now;
+        }
+        buffer.messages.extend(message.take());
+        if buffer.messages.len() >= limits.records || now.duration_since(buffer.since) >= limits.max_age {
+            buffer.flush();
+        }
+    });
+
+    if let Some(message) = message {
+        queue.deliver(sender, message);
+    }
+}
+
+///Sends records of current thread's buffer, if any.
+pub(crate) fn flush(sender: &crossbeam_channel::Sender<Message>) {
+    let _ = BUFFERS.try_with(|buffers| if let Ok(mut buffers) = buffers.try_borrow_mut() {
+        if let Some(buffer) = buffers.get(sender) {
+            buffer.flush();
+        }
+    });
+}
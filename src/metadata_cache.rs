@@ -0,0 +1,100 @@
+//!Cache of event metadata per callsite.
+
+use tracing_core::callsite::Identifier;
+use tracing_core::Metadata;
+
+use crate::fluent;
+
+use core::hash::{BuildHasherDefault, Hasher};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+#[derive(Default)]
+///Hasher of callsite identifiers, which are hashed as pointer to callsite.
+///
+///Pointers are unique, hence it is enough to mix them, instead of using `SipHash`.
+struct CallsiteHasher(u64);
+
+impl CallsiteHasher {
+    #[inline(always)]
+    fn mix(&mut self, value: u64) {
+        self.0 = (self.0.rotate_left(5) ^ value).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+    }
+}
+
+impl Hasher for CallsiteHasher {
+    #[inline(always)]
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    #[inline]
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.mix(u64::from(*byte));
+        }
+    }
+
+    #[inline(always)]
+    fn write_usize(&mut self, value: usize) {
+        self.mix(value as u64);
+    }
+}
+
+///Prebuilt metadata of events, keyed by callsite.
+///
+///Metadata of event is the same for every event of callsite, hence it is built once, on first
+///event, and cloned afterwards.
+///Number of entries is bounded by number of callsites within process, as these are static.
+///
+///Cloned cache is empty, as its entries depend on `FmtOpts`, which might be changed after clone.
+pub(crate) struct MetadataCache {
+    maps: RwLock<HashMap<Identifier, Arc<fluent::Map>, BuildHasherDefault<CallsiteHasher>>>,
+}
+
+impl MetadataCache {
+    #[inline(always)]
+    pub(crate) fn new() -> Self {
+        Self {
+            maps: RwLock::new(HashMap::default()),
+        }
+    }
+
+    ///Returns metadata of callsite, building it via `init` if not cached yet.
+    pub(crate) fn get<I: FnOnce() -> fluent::Map>(&self, metadata: &'static Metadata<'static>, init: I) -> Arc<fluent::Map> {
+        let id = metadata.callsite();
+        if let Ok(maps) = self.maps.read() {
+            if let Some(map) = maps.get(&id) {
+                return map.clone();
+            }
+        }
+
+        let map = Arc::new(init());
+        if let Ok(mut maps) = self.maps.write() {
+            maps.entry(id).or_insert_with(|| map.clone());
+        }
+        map
+    }
+
+    #[inline]
+    ///Returns number of cached callsites.
+    pub(crate) fn len(&self) -> usize {
+        match self.maps.read() {
+            Ok(maps) => maps.len(),
+            Err(error) => error.into_inner().len(),
+        }
+    }
+}
+
+impl Clone for MetadataCache {
+    #[inline(always)]
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
+
+impl core::fmt::Debug for MetadataCache {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        fmt.debug_struct("MetadataCache").field("len", &self.len()).finish()
+    }
+}
@@ -0,0 +1,43 @@
+use std::sync::OnceLock;
+
+use opentelemetry::trace::TraceContextExt;
+use tracing_core::dispatcher::{Dispatch, WeakDispatch};
+use tracing_core::span::Id;
+
+use crate::fluent;
+
+///Looks up OpenTelemetry context of spans, stored by `tracing-opentelemetry` layer.
+#[derive(Default)]
+pub(crate) struct OtelIds {
+    //Weak to avoid cycle, as layer is owned by dispatcher itself.
+    dispatch: OnceLock<WeakDispatch>,
+}
+
+impl OtelIds {
+    #[inline]
+    pub(crate) fn register(&self, dispatch: &Dispatch) {
+        let _ = self.dispatch.set(dispatch.downgrade());
+    }
+
+    ///Inserts trace and span ids of span `id` as lowercase hex strings, if span has valid
+    ///OpenTelemetry context.
+    ///
+    ///Must not be called while holding extensions of the span.
+    pub(crate) fn insert(&self, record: &mut fluent::Map, id: &Id, (trace_key, span_key): (&'static str, &'static str)) {
+        let dispatch = match self.dispatch.get().and_then(WeakDispatch::upgrade) {
+            Some(dispatch) => dispatch,
+            None => return,
+        };
+        let context = match tracing_opentelemetry::get_otel_context(id, &dispatch) {
+            Some(context) => context,
+            None => return,
+        };
+
+        let span = context.span();
+        let span_context = span.span_context();
+        if span_context.is_valid() {
+            record.insert(trace_key.into(), span_context.trace_id().to_string().into());
+            record.insert(span_key.into(), span_context.span_id().to_string().into());
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use core::cell::Cell;
+use core::time::Duration;
+use std::panic;
+use std::sync::Arc;
+
+use crate::{FlushingGuard, FmtOpts};
+use crate::clock::Timestamps;
+use crate::fluent;
+use crate::tracing::insert_metadata_envelope;
+use crate::worker::Consumer;
+
+///Time to wait for panic record to be written, before continuing with panic.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(1);
+
+thread_local! {
+    static IS_IN_HOOK: Cell<bool> = const { Cell::new(false) };
+}
+
+///Options of guard's layer, so that panic record is created the same way as records of events.
+pub(crate) struct PanicOpts {
+    pub(crate) fmt: FmtOpts,
+    pub(crate) timestamps: Arc<Timestamps>,
+}
+
+fn panic_record(info: &panic::PanicHookInfo<'_>, opts: &PanicOpts) -> fluent::Record {
+    let payload = info.payload();
+    let message = match payload.downcast_ref::<&'static str>() {
+        Some(message) => (*message).to_owned(),
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "Box<dyn Any>".to_owned(),
+        },
+    };
+
+    let mut record = fluent::Record::with_time(opts.timestamps.now());
+    record.insert("message".into(), message.into());
+    let thread = std::thread::current();
+    record.insert("thread_name".into(), thread.name().unwrap_or("<unnamed>").to_owned().into());
+
+    let mut metadata = fluent::Map::new();
+    if let Some(location) = info.location() {
+        metadata.insert("file".into(), location.file().to_owned().into());
+        metadata.insert("line".into(), location.line().into());
+        metadata.insert("column".into(), location.column().into());
+    }
+    metadata.insert("module".into(), "panic".into());
+    metadata.insert("level".into(), opts.fmt.level(&tracing_core::Level::ERROR));
+    insert_metadata_envelope(&mut record, metadata, &opts.fmt);
+    record
+}
+
+///Installs panic hook, that sends panic as `ERROR` record via worker of `guard`.
+///
+///Record contains panic message, location within metadata and name of panicking thread.
+///Record is stamped by clock of guard's layer and follows its options for metadata key and
+///lowercase level.
+///Hook waits up to 1 second for worker to write it, so that it is not lost if process aborts or
+///terminates once panic is unwound.
+///Previously installed hook is invoked afterwards.
+///
+///Panics within worker thread and within hook itself are passed to the previous hook only.
+pub fn install_panic_hook(guard: &FlushingGuard) {
+    let channel = guard.0.channel();
+    let opts = guard.1.clone();
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let is_in_hook = IS_IN_HOOK.try_with(|is_in_hook| is_in_hook.replace(true)).unwrap_or(true);
+        let is_worker = crate::worker::is_worker_thread();
+        if !is_in_hook && !is_worker {
+            channel.record(panic_record(info, &opts));
+            channel.sync(SYNC_TIMEOUT);
+        }
+        if !is_in_hook {
+            let _ = IS_IN_HOOK.try_with(|is_in_hook| is_in_hook.set(false));
+        }
+
+        previous(info);
+    }));
+}
@@ -0,0 +1,251 @@
+//!Proxy support for tcp writer.
+//!
+//!Requires `proxy` feature.
+use crate::{MakeWriter, ProbeWrite};
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpStream, ToSocketAddrs};
+use core::time::Duration;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+//Limit on HTTP response header size, to avoid reading garbage forever.
+const MAX_HTTP_RESPONSE: usize = 8 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Socks5,
+    Http,
+}
+
+///`MakeWriter` that connects to `fluentd` through proxy.
+///
+///Proxy address is resolved on every attempt to connect, while target address is passed to the
+///proxy as it is, letting proxy to resolve it.
+///
+///Handshake failures are reported as `io::Error`, hence worker retries it as any other error.
+pub struct Proxy {
+    kind: Kind,
+    proxy: String,
+    target: String,
+    credentials: Option<(String, String)>,
+}
+
+impl Proxy {
+    ///Creates writer connecting to `target` via SOCKS5 `proxy`.
+    ///
+    ///Both addresses are expected in `host:port` format.
+    pub fn socks5<P: Into<String>, T: Into<String>>(proxy: P, target: T) -> Self {
+        Self {
+            kind: Kind::Socks5,
+            proxy: proxy.into(),
+            target: target.into(),
+            credentials: None,
+        }
+    }
+
+    ///Creates writer connecting to `target` via HTTP `proxy`, using `CONNECT` method.
+    ///
+    ///Both addresses are expected in `host:port` format.
+    pub fn http<P: Into<String>, T: Into<String>>(proxy: P, target: T) -> Self {
+        Self {
+            kind: Kind::Http,
+            proxy: proxy.into(),
+            target: target.into(),
+            credentials: None,
+        }
+    }
+
+    #[inline]
+    ///Sets username and password to authenticate with proxy.
+    ///
+    ///SOCKS5 uses username/password method, while HTTP uses basic authorization.
+    pub fn credentials<U: Into<String>, P: Into<String>>(mut self, username: U, password: P) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    fn connect_proxy(&self) -> io::Result<TcpStream> {
+        let addrs = match self.proxy.to_socket_addrs() {
+            Ok(addrs) => addrs,
+            Err(error) => return Err(io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve proxy address: {}", error))),
+        };
+
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(socket) => return Ok(socket),
+                Err(_) => continue,
+            }
+        }
+
+        Err(io::Error::new(io::ErrorKind::NotFound, "cannot connect to proxy"))
+    }
+
+    fn socks5_handshake(&self, socket: &mut TcpStream) -> io::Result<()> {
+        let (host, port) = split_host_port(&self.target)?;
+
+        match self.credentials {
+            Some(_) => socket.write_all(&[5, 2, 0, 2])?,
+            None => socket.write_all(&[5, 1, 0])?,
+        }
+
+        let mut reply = [0u8; 2];
+        socket.read_exact(&mut reply)?;
+        if reply[0] != 5 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid SOCKS5 version"));
+        }
+
+        match (reply[1], self.credentials.as_ref()) {
+            (0, _) => (),
+            (2, Some((username, password))) => {
+                if username.len() > u8::MAX as usize || password.len() > u8::MAX as usize {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "SOCKS5 credentials are too long"));
+                }
+
+                let mut request = Vec::with_capacity(3 + username.len() + password.len());
+                request.push(1);
+                request.push(username.len() as u8);
+                request.extend_from_slice(username.as_bytes());
+                request.push(password.len() as u8);
+                request.extend_from_slice(password.as_bytes());
+                socket.write_all(&request)?;
+
+                socket.read_exact(&mut reply)?;
+                if reply[1] != 0 {
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS5 authentication failed"));
+                }
+            },
+            _ => return Err(io::Error::new(io::ErrorKind::PermissionDenied, "no acceptable SOCKS5 authentication method")),
+        }
+
+        let mut request = vec![5, 1, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            },
+            Ok(IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            },
+            Err(_) => {
+                if host.len() > u8::MAX as usize {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "SOCKS5 target host is too long"));
+                }
+                request.push(3);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        socket.write_all(&request)?;
+
+        let mut reply = [0u8; 4];
+        socket.read_exact(&mut reply)?;
+        if reply[0] != 5 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid SOCKS5 version"));
+        } else if reply[1] != 0 {
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("SOCKS5 proxy failed to connect with code {}", reply[1])));
+        }
+
+        //Skip bound address and port
+        let len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut len = [0u8; 1];
+                socket.read_exact(&mut len)?;
+                len[0] as usize
+            },
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid SOCKS5 address type")),
+        };
+        let mut bound = [0u8; u8::MAX as usize + 2];
+        socket.read_exact(&mut bound[..len + 2])
+    }
+
+    fn http_handshake(&self, socket: &mut TcpStream) -> io::Result<()> {
+        split_host_port(&self.target)?;
+
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", self.target);
+        if let Some((username, password)) = self.credentials.as_ref() {
+            request.push_str("Proxy-Authorization: Basic ");
+            base64(format!("{}:{}", username, password).as_bytes(), &mut request);
+            request.push_str("\r\n");
+        }
+        request.push_str("\r\n");
+        socket.write_all(request.as_bytes())?;
+
+        //Read byte by byte to not consume anything after the response header.
+        let mut response = Vec::with_capacity(128);
+        let mut byte = [0u8; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_HTTP_RESPONSE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "HTTP proxy response is too long"));
+            }
+            socket.read_exact(&mut byte)?;
+            response.push(byte[0]);
+        }
+
+        let status = response.split(|byte| *byte == b' ').nth(1).and_then(|status| core::str::from_utf8(status).ok());
+        match status.and_then(|status| status.parse::<u16>().ok()) {
+            Some(200..=299) => Ok(()),
+            Some(407) => Err(io::Error::new(io::ErrorKind::PermissionDenied, "HTTP proxy requires authentication")),
+            Some(status) => Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("HTTP proxy failed to connect with status {}", status))),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP proxy response")),
+        }
+    }
+}
+
+impl MakeWriter for Proxy {
+    type Writer = TcpStream;
+
+    fn make(&self) -> io::Result<Self::Writer> {
+        let mut socket = self.connect_proxy()?;
+        socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        socket.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+        match self.kind {
+            Kind::Socks5 => self.socks5_handshake(&mut socket)?,
+            Kind::Http => self.http_handshake(&mut socket)?,
+        }
+
+        socket.set_read_timeout(None)?;
+        socket.set_write_timeout(None)?;
+        Ok(socket)
+    }
+
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> io::Result<()> {
+        ProbeWrite::probe(writer)
+    }
+}
+
+fn split_host_port(addr: &str) -> io::Result<(&str, u16)> {
+    match addr.rfind(':') {
+        Some(idx) => match addr[idx + 1..].parse() {
+            Ok(port) => {
+                let host = &addr[..idx];
+                let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+                Ok((host, port))
+            },
+            Err(_) => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid port in target address")),
+        },
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput, "target address must be in host:port format")),
+    }
+}
+
+fn base64(input: &[u8], out: &mut String) {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    for chunk in input.chunks(3) {
+        let bytes = [chunk[0], chunk.get(1).copied().unwrap_or(0), chunk.get(2).copied().unwrap_or(0)];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+
+        for idx in 0..4 {
+            match idx <= chunk.len() {
+                true => out.push(ALPHABET[(bits >> (18 - idx * 6)) as usize & 0x3f] as char),
+                false => out.push('='),
+            }
+        }
+    }
+}
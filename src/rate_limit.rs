@@ -0,0 +1,114 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use std::time::Instant;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Config {
+    pub(crate) records_per_second: u64,
+    pub(crate) burst: u64,
+    pub(crate) exempt_errors: bool,
+}
+
+///Options of rate limit, configured via `Builder::with_rate_limit`.
+///
+///Options are kept regardless of whether limit is set, so that they can be specified in any order.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Opts {
+    ///Records per second and burst.
+    pub(crate) limit: Option<(u64, u64)>,
+    pub(crate) exempt_errors: bool,
+}
+
+impl Opts {
+    ///Creates limiter, if limit is set.
+    pub(crate) fn limiter(&self) -> Option<RateLimiter> {
+        self.limit.map(|(records_per_second, burst)| RateLimiter::new(Config {
+            records_per_second,
+            burst,
+            exempt_errors: self.exempt_errors,
+        }))
+    }
+}
+
+///Token bucket, shared by all threads of process.
+pub(crate) struct RateLimiter {
+    config: Config,
+    start: Instant,
+    //Time of the last refill, in nanoseconds since `start`.
+    refilled_at: AtomicU64,
+    tokens: AtomicU64,
+    dropped: AtomicU64,
+    //Time of the last report, in nanoseconds since `start`.
+    reported_at: AtomicU64,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: Config) -> Self {
+        Self {
+            config,
+            start: Instant::now(),
+            refilled_at: AtomicU64::new(0),
+            tokens: AtomicU64::new(config.burst),
+            dropped: AtomicU64::new(0),
+            reported_at: AtomicU64::new(0),
+        }
+    }
+
+    #[inline(always)]
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+
+    fn refill(&self, now: u64) {
+        let refilled_at = self.refilled_at.load(Ordering::Acquire);
+        let elapsed = now.saturating_sub(refilled_at);
+        let new_tokens = (elapsed as u128 * self.config.records_per_second as u128 / NANOS_PER_SEC as u128) as u64;
+        if new_tokens == 0 {
+            return;
+        }
+
+        //Advance only by time of whole tokens, so that fractions are not lost.
+        let refilled_at_next = refilled_at + (new_tokens as u128 * NANOS_PER_SEC as u128 / self.config.records_per_second as u128) as u64;
+        if self.refilled_at.compare_exchange(refilled_at, refilled_at_next, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+            let burst = self.config.burst;
+            let _ = self.tokens.fetch_update(Ordering::AcqRel, Ordering::Acquire, |tokens| Some(tokens.saturating_add(new_tokens).min(burst)));
+        }
+    }
+
+    ///Returns whether event of `level` can be recorded, counting it as dropped otherwise.
+    pub(crate) fn acquire(&self, level: &tracing_core::Level) -> bool {
+        if self.config.exempt_errors && *level == tracing_core::Level::ERROR {
+            return true;
+        }
+
+        self.refill(self.now());
+        match self.tokens.fetch_update(Ordering::AcqRel, Ordering::Acquire, |tokens| tokens.checked_sub(1)) {
+            Ok(_) => true,
+            Err(_) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    ///Returns number of records dropped since the last report, once per report interval.
+    pub(crate) fn report(&self) -> Option<u64> {
+        let now = self.now();
+        let reported_at = self.reported_at.load(Ordering::Acquire);
+        if now.saturating_sub(reported_at) < REPORT_INTERVAL.as_nanos() as u64 {
+            return None;
+        }
+
+        if self.reported_at.compare_exchange(reported_at, now, Ordering::AcqRel, Ordering::Relaxed).is_err() {
+            return None;
+        }
+
+        match self.dropped.swap(0, Ordering::AcqRel) {
+            0 => None,
+            dropped => Some(dropped),
+        }
+    }
+}
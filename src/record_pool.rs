@@ -0,0 +1,45 @@
+//!Pool of records, returned by worker once they are written.
+
+use crate::fluent;
+
+use core::time;
+
+#[derive(Clone)]
+///Bounded pool of cleared records, which retain capacity of their maps.
+///
+///Worker puts records into pool once they are written, while layer takes them to format events,
+///falling back to allocation if pool is empty.
+pub(crate) struct RecordPool {
+    sender: crossbeam_channel::Sender<fluent::Record>,
+    recv: crossbeam_channel::Receiver<fluent::Record>,
+}
+
+impl RecordPool {
+    #[inline(always)]
+    pub(crate) fn new(size: usize) -> Self {
+        let (sender, recv) = crossbeam_channel::bounded(size);
+        Self {
+            sender,
+            recv,
+        }
+    }
+
+    #[inline]
+    ///Takes record from pool, or allocates new one, setting its `time`.
+    pub(crate) fn take(&self, time: time::Duration) -> fluent::Record {
+        match self.recv.try_recv() {
+            Ok(mut record) => {
+                record.set_time(time);
+                record
+            },
+            Err(_) => fluent::Record::with_time(time),
+        }
+    }
+
+    #[inline]
+    ///Clears `record` and puts it into pool, unless pool is full.
+    pub(crate) fn give(&self, mut record: fluent::Record) {
+        record.reset();
+        let _ = self.sender.try_send(record);
+    }
+}
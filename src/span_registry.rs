@@ -0,0 +1,138 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+use crate::fluent;
+use crate::tracing::{FmtOpts, Scope, redact};
+
+///Key of object with attributes of span.
+pub(crate) const SPAN_KEY: &str = "span";
+///Key of span's identifier, that is unique within process.
+pub(crate) const SPAN_REF_KEY: &str = "span_ref";
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+//Incremented whenever attributes of any span change, invalidating cached attributes of every span.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Debug)]
+///Attributes of event's span and its parents, attached to record by layer.
+pub(crate) struct SpanData {
+    id: u64,
+    fields: Arc<fluent::Map>,
+}
+
+impl SpanData {
+    #[inline(always)]
+    pub(crate) fn estimated_size(&self) -> usize {
+        self.fields.estimated_size() + 2 * 9
+    }
+
+    ///Returns attributes of `span`, merged with attributes of its parents.
+    ///
+    ///Attributes are redacted the same way as record's own fields, as they are attached to record
+    ///only by worker.
+    ///
+    ///Attributes are cached within span's extensions until attributes of any span change, so
+    ///that records of span share identifier.
+    pub(crate) fn get<'a, R: LookupSpan<'a>>(span: &SpanRef<'a, R>, opts: &FmtOpts) -> Self {
+        let generation = GENERATION.load(Ordering::Acquire);
+        if let Some(cached) = span.extensions().get::<Cached>() {
+            if cached.generation == generation {
+                return cached.data.clone();
+            }
+        }
+
+        let mut fields = fluent::Map::new();
+        for span in Scope::of(span) {
+            if let Some(record) = span.extensions().get::<fluent::Map>() {
+                for (key, value) in record.iter() {
+                    fields.entry(key.clone()).or_insert_with(|| value.clone());
+                }
+            }
+        }
+
+        if !opts.redacted_fields.is_empty() {
+            redact(&mut fields, &opts.redacted_fields);
+        }
+
+        let data = Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            fields: Arc::new(fields),
+        };
+        let mut extensions = span.extensions_mut();
+        extensions.remove::<Cached>();
+        extensions.insert(Cached {
+            generation,
+            data: data.clone(),
+        });
+        data
+    }
+}
+
+///Cached attributes of span, stored within span's extensions.
+struct Cached {
+    generation: u64,
+    data: SpanData,
+}
+
+#[inline(always)]
+///Invalidates cached attributes of spans, as attributes of some span changed.
+pub(crate) fn invalidate() {
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+fn insert(record: &mut fluent::Record, span: SpanData, full: bool) {
+    if full {
+        record.insert(SPAN_KEY.into(), fluent::Map::clone(&span.fields).into());
+    }
+    record.insert(SPAN_REF_KEY.into(), span.id.into());
+
+    //Keep metadata last for readability.
+    if let Some(idx) = record.metadata_key().and_then(|key| record.get_index_of(key)) {
+        let last = record.len() - 1;
+        record.move_index(idx, last);
+    }
+}
+
+#[inline]
+///Inserts attributes of span into record, if any.
+///
+///Used when record cannot reference span sent within the same message.
+pub(crate) fn inline(record: &mut fluent::Record) {
+    if let Some(span) = record.take_span() {
+        insert(record, span, true);
+    }
+}
+
+///Spans, whose attributes are already sent within message.
+pub(crate) struct Frame {
+    //Whether records are sent within single message, so that they can reference each other.
+    enabled: bool,
+    sent: HashSet<u64>,
+}
+
+impl Frame {
+    #[inline(always)]
+    pub(crate) fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            sent: HashSet::new(),
+        }
+    }
+
+    #[inline]
+    ///Inserts attributes of span into record, unless they are already sent within message.
+    pub(crate) fn resolve(&mut self, record: &mut fluent::Record) {
+        if let Some(span) = record.take_span() {
+            let full = !self.enabled || self.sent.insert(span.id);
+            insert(record, span, full);
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn clear(&mut self) {
+        self.sent.clear();
+    }
+}
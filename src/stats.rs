@@ -0,0 +1,142 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::fluent;
+use crate::worker::DropHandler;
+
+///Names of metrics, reported via `metrics` facade.
+#[cfg(feature = "metrics")]
+struct Metrics {
+    delivered: ::metrics::SharedString,
+    dropped: ::metrics::SharedString,
+    queue_depth: ::metrics::SharedString,
+    reconnects: ::metrics::SharedString,
+    delivery_errors: ::metrics::SharedString,
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    fn new(prefix: &str) -> Self {
+        //Names are shared, so that cloning them on every report doesn't allocate.
+        let name = |name: &str| -> ::metrics::SharedString {
+            ::metrics::SharedString::from(Arc::<str>::from(format!("{}_{}", prefix, name)))
+        };
+
+        Self {
+            delivered: name("records_delivered_total"),
+            dropped: name("records_dropped_total"),
+            queue_depth: name("queue_depth"),
+            reconnects: name("reconnects_total"),
+            delivery_errors: name("delivery_errors_total"),
+        }
+    }
+}
+
+///Counters of worker, reported via `Builder::with_stats_interval` and `Builder::with_metrics`.
+pub(crate) struct Stats {
+    started: Instant,
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+    connections: AtomicU64,
+    last_error: Mutex<Option<String>>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Metrics>,
+}
+
+impl Stats {
+    pub(crate) fn new(started: Instant) -> Self {
+        Self {
+            started,
+            delivered: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    ///Enables reporting of counters via `metrics` facade, with names starting with `prefix`.
+    pub(crate) fn with_metrics(mut self, prefix: &str) -> Self {
+        self.metrics = Some(Metrics::new(prefix));
+        self
+    }
+
+    #[inline(always)]
+    pub(crate) fn on_delivered(&self, count: usize) {
+        self.delivered.fetch_add(count as u64, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = self.metrics.as_ref() {
+            ::metrics::counter!(metrics.delivered.clone()).increment(count as u64);
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn on_connected(&self) {
+        let _connections = self.connections.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        if let (Some(metrics), true) = (self.metrics.as_ref(), _connections > 0) {
+            ::metrics::counter!(metrics.reconnects.clone()).increment(1);
+        }
+    }
+
+    #[inline(always)]
+    ///Reports number of records that are not yet received by worker.
+    pub(crate) fn on_queue_depth(&self, _depth: usize) {
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = self.metrics.as_ref() {
+            ::metrics::gauge!(metrics.queue_depth.clone()).set(_depth as f64);
+        }
+    }
+
+    pub(crate) fn on_error(&self, error: &std::io::Error) {
+        *self.last_error.lock().unwrap_or_else(|error| error.into_inner()) = Some(error.to_string());
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = self.metrics.as_ref() {
+            ::metrics::counter!(metrics.delivery_errors.clone(), "kind" => format!("{:?}", error.kind())).increment(1);
+        }
+    }
+
+    ///Wraps `on_drop` handler to count dropped records.
+    pub(crate) fn counting(self: &Arc<Self>, on_drop: Option<DropHandler>) -> DropHandler {
+        let stats = self.clone();
+        Arc::new(move |reason, count| {
+            stats.dropped.fetch_add(count as u64, Ordering::Relaxed);
+            #[cfg(feature = "metrics")]
+            if let Some(metrics) = stats.metrics.as_ref() {
+                ::metrics::counter!(metrics.dropped.clone(), "reason" => reason.as_str()).increment(count as u64);
+            }
+            if let Some(on_drop) = on_drop.as_ref() {
+                on_drop(reason, count);
+            }
+        })
+    }
+
+    ///Creates record with current values of counters.
+    ///
+    ///`pending` is number of records received by worker, but not yet delivered, while
+    ///`queue_depth` is number of records that are not yet received by worker.
+    ///`memory_usage` is size of records that are not yet delivered, if memory budget is set.
+    pub(crate) fn record(&self, now: Instant, pending: usize, queue_depth: usize, memory_usage: Option<usize>) -> fluent::Record {
+        let mut record = fluent::Record::now();
+        let delivered = self.delivered.load(Ordering::Relaxed);
+        let last_error = match self.last_error.lock().unwrap_or_else(|error| error.into_inner()).clone() {
+            Some(error) => error.into(),
+            None => fluent::Value::Null,
+        };
+
+        record.insert("enqueued".into(), (delivered + pending as u64 + queue_depth as u64).into());
+        record.insert("delivered".into(), delivered.into());
+        record.insert("dropped".into(), self.dropped.load(Ordering::Relaxed).into());
+        record.insert("queue_depth".into(), (queue_depth as u64).into());
+        record.insert("reconnects".into(), self.connections.load(Ordering::Relaxed).saturating_sub(1).into());
+        record.insert("last_error".into(), last_error);
+        record.insert("uptime".into(), now.saturating_duration_since(self.started).as_secs().into());
+        if let Some(memory_usage) = memory_usage {
+            record.insert("memory_usage".into(), (memory_usage as u64).into());
+        }
+        record
+    }
+}
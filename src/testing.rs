@@ -0,0 +1,575 @@
+//!Utilities to test logging.
+//!
+//!Requires `testing` feature.
+//!
+//!Recommended approach is to use `MemoryWriter` as writer, and inspect messages via `MemoryReader`:
+//!
+//!```rust
+//!use tracing_subscriber::layer::SubscriberExt;
+//!
+//!let (writer, reader) = tracing_fluentd::testing::MemoryWriter::new();
+//!let layer = tracing_fluentd::Builder::new("rust").with_writer(writer).layer().expect("Create layer");
+//!let guard = tracing::subscriber::set_default(tracing_subscriber::Registry::default().with(layer));
+//!tracing::info!("test");
+//!//Layer is dropped together with subscriber, flushing all records.
+//!drop(guard);
+//!
+//!let records = reader.records();
+//!assert_eq!(records.len(), 1);
+//!```
+//!
+//!To test logging end-to-end over network, use `MockFluentd`, which also allows to inject faults.
+//!
+//!To test timing of worker without waiting for real time, use `TestClock`.
+use crate::{Clock, MakeWriter};
+use crate::fluent::decode;
+
+use core::time::Duration;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Instant, SystemTime};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+#[inline(always)]
+fn lock(buffer: &Mutex<Vec<u8>>) -> MutexGuard<'_, Vec<u8>> {
+    buffer.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+#[derive(Clone)]
+///Writer that stores all data in memory.
+///
+///All writers created by it append to the same buffer.
+pub struct MemoryWriter {
+    buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MemoryWriter {
+    ///Creates new writer with reader of its buffer.
+    pub fn new() -> (Self, MemoryReader) {
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let reader = MemoryReader {
+            buffer: buffer.clone(),
+        };
+        (Self { buffer }, reader)
+    }
+}
+
+impl MakeWriter for MemoryWriter {
+    type Writer = Self;
+
+    #[inline(always)]
+    fn make(&self) -> io::Result<Self::Writer> {
+        Ok(self.clone())
+    }
+}
+
+impl Write for MemoryWriter {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        lock(&self.buffer).extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    #[inline(always)]
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+///Reader of `MemoryWriter` buffer.
+///
+///Note that records are written by worker thread, so in order to observe all records, layer must
+///be dropped first.
+pub struct MemoryReader {
+    buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MemoryReader {
+    #[inline]
+    ///Returns copy of raw bytes written so far.
+    pub fn bytes(&self) -> Vec<u8> {
+        lock(&self.buffer).clone()
+    }
+
+    #[inline]
+    ///Clears buffer.
+    pub fn clear(&self) {
+        lock(&self.buffer).clear()
+    }
+
+    ///Decodes all messages written so far.
+    ///
+    ///Each message is in format `[tag, [[time, record], ...], options]` or, in case of
+    ///PackedForward mode, `[tag, bin, options]`.
+    ///
+    ///Panics if buffer contains invalid data.
+    pub fn frames(&self) -> Vec<rmpv::Value> {
+        let buffer = lock(&self.buffer);
+        let mut cursor = io::Cursor::new(buffer.as_slice());
+        let mut frames = Vec::new();
+        while (cursor.position() as usize) < buffer.len() {
+            match rmpv::decode::read_value(&mut cursor) {
+                Ok(frame) => frames.push(frame),
+                Err(error) => panic!("Invalid fluentd message at offset {}: {}", cursor.position(), error),
+            }
+        }
+        frames
+    }
+
+    ///Returns all entries written so far, as pairs of time and record.
+    ///
+    ///Panics if buffer contains invalid data.
+    pub fn entries(&self) -> Vec<(rmpv::Value, rmpv::Value)> {
+        let mut result = Vec::new();
+        for frame in self.frames() {
+            match decode::split_frame(frame) {
+                Ok(frame) => result.extend(frame.entries),
+                Err(error) => panic!("{}", error),
+            }
+        }
+        result
+    }
+
+    #[inline]
+    ///Returns all records written so far.
+    ///
+    ///Panics if buffer contains invalid data.
+    pub fn records(&self) -> Vec<rmpv::Value> {
+        self.entries().into_iter().map(|(_, record)| record).collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+///Fault injected by `MockFluentd`.
+pub enum Fault {
+    ///Server works normally.
+    None,
+    ///Listener is closed, so that connections are refused.
+    ///
+    ///Listener is bound to the same address again once fault is cleared.
+    Refuse,
+    ///New connections are accepted and closed immediately.
+    Close,
+    ///Connections are kept open, but nothing is read from them until fault is cleared.
+    Stall,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+///Entry of received message.
+pub struct Entry {
+    ///Event time, decoded from either integer or `EventTime`.
+    pub time: core::time::Duration,
+    ///Record.
+    pub record: rmpv::Value,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+///Message received by `MockFluentd`.
+pub struct Frame {
+    ///Tag.
+    pub tag: String,
+    ///Entries of message.
+    ///
+    ///Message mode is decoded as single entry.
+    pub entries: Vec<Entry>,
+    ///Option section, if present.
+    pub options: Option<rmpv::Value>,
+}
+
+struct MockState {
+    frames: Mutex<Vec<Frame>>,
+    received: std::sync::Condvar,
+    fault: Mutex<Fault>,
+    ack: AtomicBool,
+    stop: AtomicBool,
+    generation: AtomicUsize,
+    connections: AtomicUsize,
+}
+
+impl MockState {
+    #[inline(always)]
+    fn fault(&self) -> Fault {
+        *self.fault.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+fn decode_frame(frame: rmpv::Value) -> Option<Frame> {
+    let frame = decode::split_frame(frame).ok()?;
+    let entries = frame.entries.into_iter().map(|(time, record)| Some(Entry {
+        time: decode::event_time(&time)?,
+        record,
+    })).collect::<Option<Vec<_>>>()?;
+
+    Some(Frame {
+        tag: frame.tag,
+        entries,
+        options: frame.options,
+    })
+}
+
+fn ack(frame: &Frame) -> Option<rmpv::Value> {
+    match frame.options.as_ref()? {
+        rmpv::Value::Map(options) => options.iter().find(|(key, _)| key.as_str() == Some("chunk")).map(|(_, chunk)| {
+            rmpv::Value::Map(vec![("ack".into(), chunk.clone())])
+        }),
+        _ => None,
+    }
+}
+
+fn serve(state: Arc<MockState>, mut socket: TcpStream) {
+    let generation = state.generation.load(Ordering::SeqCst);
+    let _ = socket.set_nonblocking(false);
+    let _ = socket.set_read_timeout(Some(POLL_INTERVAL));
+
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    while !state.stop.load(Ordering::SeqCst) && state.generation.load(Ordering::SeqCst) == generation {
+        if state.fault() == Fault::Stall {
+            std::thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        match socket.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(size) => buffer.extend_from_slice(&chunk[..size]),
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock || error.kind() == io::ErrorKind::TimedOut => continue,
+            Err(_) => break,
+        }
+
+        loop {
+            let mut cursor = io::Cursor::new(buffer.as_slice());
+            let frame = match rmpv::decode::read_value(&mut cursor) {
+                Ok(frame) => frame,
+                Err(rmpv::decode::Error::InvalidMarkerRead(error)) | Err(rmpv::decode::Error::InvalidDataRead(error)) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+                //Invalid data, there is no point to continue
+                Err(_) => return,
+            };
+            let position = cursor.position() as usize;
+            buffer.drain(..position);
+
+            let frame = match decode_frame(frame) {
+                Some(frame) => frame,
+                None => return,
+            };
+            let ack = match state.ack.load(Ordering::SeqCst) {
+                true => ack(&frame),
+                false => None,
+            };
+            state.frames.lock().unwrap_or_else(|error| error.into_inner()).push(frame);
+            state.received.notify_all();
+
+            if let Some(ack) = ack {
+                if rmpv::encode::write_value(&mut socket, &ack).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn listen(state: Arc<MockState>, addr: SocketAddr, listener: TcpListener) {
+    let mut listener = Some(listener);
+    while !state.stop.load(Ordering::SeqCst) {
+        let fault = state.fault();
+        let current = match (fault, listener.as_ref()) {
+            (Fault::Refuse, _) => {
+                listener = None;
+                std::thread::sleep(POLL_INTERVAL);
+                continue;
+            },
+            (_, Some(current)) => current,
+            (_, None) => {
+                listener = TcpListener::bind(addr).and_then(|listener| listener.set_nonblocking(true).map(|_| listener)).ok();
+                if listener.is_none() {
+                    std::thread::sleep(POLL_INTERVAL);
+                }
+                continue;
+            },
+        };
+
+        match current.accept() {
+            Ok((socket, _)) => {
+                state.connections.fetch_add(1, Ordering::SeqCst);
+                if fault == Fault::Close {
+                    drop(socket);
+                    continue;
+                }
+
+                let state = state.clone();
+                std::thread::spawn(move || serve(state, socket));
+            },
+            Err(_) => std::thread::sleep(POLL_INTERVAL),
+        }
+    }
+}
+
+///Mock of fluentd `in_forward` server.
+///
+///Listens on random local port and decodes every received message.
+///Supports Forward, PackedForward and Message modes, while handshake and compression are not
+///supported.
+///
+///```rust
+///use tracing_subscriber::layer::SubscriberExt;
+///use tracing_fluentd::testing::MockFluentd;
+///
+///let fluentd = MockFluentd::start().expect("Start fluentd");
+///let layer = tracing_fluentd::Builder::new("rust").with_writer(fluentd.addr()).layer().expect("Create layer");
+///let guard = tracing::subscriber::set_default(tracing_subscriber::Registry::default().with(layer));
+///tracing::info!("test");
+///drop(guard);
+///
+///let records = fluentd.wait_for_records(1, core::time::Duration::from_secs(5));
+///assert_eq!(records.len(), 1);
+///```
+///
+///Server is stopped on drop.
+pub struct MockFluentd {
+    addr: SocketAddr,
+    state: Arc<MockState>,
+    listener: Option<std::thread::JoinHandle<()>>,
+}
+
+impl MockFluentd {
+    ///Starts server on random port of `127.0.0.1`.
+    pub fn start() -> io::Result<Self> {
+        let listener = TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0))?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(MockState {
+            frames: Mutex::new(Vec::new()),
+            received: std::sync::Condvar::new(),
+            fault: Mutex::new(Fault::None),
+            ack: AtomicBool::new(false),
+            stop: AtomicBool::new(false),
+            generation: AtomicUsize::new(0),
+            connections: AtomicUsize::new(0),
+        });
+
+        let listener = {
+            let state = state.clone();
+            std::thread::Builder::new().name("mock-fluentd".to_owned()).spawn(move || listen(state, addr, listener))?
+        };
+
+        Ok(Self {
+            addr,
+            state,
+            listener: Some(listener),
+        })
+    }
+
+    #[inline(always)]
+    ///Returns address server listens on.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    #[inline]
+    ///Sets fault to inject.
+    pub fn set_fault(&self, fault: Fault) {
+        *self.state.fault.lock().unwrap_or_else(|error| error.into_inner()) = fault;
+    }
+
+    #[inline]
+    ///Sets whether to respond with ack to messages that specify `chunk` option.
+    ///
+    ///Default is disabled.
+    pub fn set_ack(&self, ack: bool) {
+        self.state.ack.store(ack, Ordering::SeqCst);
+    }
+
+    #[inline]
+    ///Closes all currently open connections.
+    pub fn disconnect(&self) {
+        self.state.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[inline]
+    ///Returns number of accepted connections so far.
+    pub fn connections(&self) -> usize {
+        self.state.connections.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    ///Returns all messages received so far.
+    pub fn frames(&self) -> Vec<Frame> {
+        self.state.frames.lock().unwrap_or_else(|error| error.into_inner()).clone()
+    }
+
+    #[inline]
+    ///Returns all entries received so far.
+    pub fn entries(&self) -> Vec<Entry> {
+        self.frames().into_iter().flat_map(|frame| frame.entries).collect()
+    }
+
+    #[inline]
+    ///Returns all records received so far.
+    pub fn records(&self) -> Vec<rmpv::Value> {
+        self.entries().into_iter().map(|entry| entry.record).collect()
+    }
+
+    #[inline]
+    ///Clears received messages.
+    pub fn clear(&self) {
+        self.state.frames.lock().unwrap_or_else(|error| error.into_inner()).clear()
+    }
+
+    ///Waits until at least `count` records are received, returning all records.
+    ///
+    ///Panics if `timeout` expires first.
+    pub fn wait_for_records(&self, count: usize, timeout: Duration) -> Vec<rmpv::Value> {
+        let deadline = std::time::Instant::now() + timeout;
+        let mut frames = self.state.frames.lock().unwrap_or_else(|error| error.into_inner());
+        loop {
+            let received = frames.iter().map(|frame| frame.entries.len()).sum::<usize>();
+            if received >= count {
+                return frames.iter().flat_map(|frame| frame.entries.iter().map(|entry| entry.record.clone())).collect();
+            }
+
+            let now = std::time::Instant::now();
+            if now >= deadline {
+                panic!("Expected {} records, but received only {} within {:?}", count, received, timeout);
+            }
+            frames = match self.state.received.wait_timeout(frames, deadline - now) {
+                Ok((frames, _)) => frames,
+                Err(error) => error.into_inner().0,
+            };
+        }
+    }
+
+    #[track_caller]
+    ///Asserts that exactly `count` records are received so far.
+    pub fn assert_record_count(&self, count: usize) {
+        let received = self.records().len();
+        assert_eq!(received, count, "Expected {} records, but received {}", count, received);
+    }
+}
+
+impl Drop for MockFluentd {
+    fn drop(&mut self) {
+        self.state.stop.store(true, Ordering::SeqCst);
+        if let Some(listener) = self.listener.take() {
+            let _ = listener.join();
+        }
+    }
+}
+
+struct ClockState {
+    elapsed: Duration,
+    sleeping: usize,
+    //Wall-clock time at `system_elapsed`
+    system: SystemTime,
+    system_elapsed: Duration,
+}
+
+#[derive(Clone)]
+///`Clock` that advances only via `TestClock::advance`.
+///
+///All clones share the same time.
+///Pass it to `Builder::with_clock` to control timing of worker, e.g. retry delays and stats interval.
+pub struct TestClock {
+    start: Instant,
+    state: Arc<(Mutex<ClockState>, Condvar)>,
+}
+
+impl TestClock {
+    ///Creates new clock, starting at current time.
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            state: Arc::new((Mutex::new(ClockState {
+                elapsed: Duration::from_secs(0),
+                sleeping: 0,
+                system: SystemTime::now(),
+                system_elapsed: Duration::from_secs(0),
+            }), Condvar::new())),
+        }
+    }
+
+    #[inline(always)]
+    fn lock(&self) -> MutexGuard<'_, ClockState> {
+        self.state.0.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    ///Advances time by `duration`, waking up threads sleeping until then.
+    pub fn advance(&self, duration: Duration) {
+        self.lock().elapsed += duration;
+        self.state.1.notify_all();
+    }
+
+    ///Sets wall-clock time, which then advances together with clock.
+    ///
+    ///Allows to simulate steps of system clock, e.g. backwards step by NTP, while `Clock::now`
+    ///stays monotonic.
+    pub fn set_system_time(&self, time: SystemTime) {
+        let mut state = self.lock();
+        state.system = time;
+        state.system_elapsed = state.elapsed;
+    }
+
+    ///Returns time elapsed since creation of clock.
+    pub fn elapsed(&self) -> Duration {
+        self.lock().elapsed
+    }
+
+    ///Waits for `count` threads to sleep on this clock, using real time `timeout`.
+    ///
+    ///Panics if `timeout` expires first.
+    pub fn wait_for_sleepers(&self, count: usize, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        while state.sleeping < count {
+            let now = Instant::now();
+            if now >= deadline {
+                panic!("Expected {} sleeping threads, but only {} within {:?}", count, state.sleeping, timeout);
+            }
+            state = match self.state.1.wait_timeout(state, deadline - now) {
+                Ok((state, _)) => state,
+                Err(error) => error.into_inner().0,
+            };
+        }
+    }
+}
+
+impl Default for TestClock {
+    #[inline(always)]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for TestClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    #[inline]
+    fn system_time(&self) -> SystemTime {
+        let state = self.lock();
+        state.system + (state.elapsed - state.system_elapsed)
+    }
+
+    fn sleep_until(&self, deadline: Instant) {
+        let mut state = self.lock();
+        state.sleeping += 1;
+        self.state.1.notify_all();
+        while self.start + state.elapsed < deadline {
+            state = self.state.1.wait(state).unwrap_or_else(|error| error.into_inner());
+        }
+        state.sleeping -= 1;
+    }
+
+    #[inline]
+    fn recv_timeout(&self, deadline: Instant) -> Duration {
+        //Time can be advanced at any moment, so it has to be checked often.
+        match self.now() >= deadline {
+            true => Duration::from_secs(0),
+            false => Duration::from_millis(1),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//!TLS support for writers.
+//!
+//!Requires `tls` feature.
+use crate::{MakeWriter, MakeContext};
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use core::convert::TryFrom;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+
+///TLS stream, created by `Tls`.
+///
+///Sends `close_notify` on drop, to let server know that connection is closed gracefully.
+pub struct TlsStream<W: Read + Write> {
+    inner: rustls::StreamOwned<rustls::ClientConnection, W>,
+}
+
+impl<W: Read + Write> Read for TlsStream<W> {
+    #[inline(always)]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<W: Read + Write> Write for TlsStream<W> {
+    #[inline(always)]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    #[inline(always)]
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Read + Write> Drop for TlsStream<W> {
+    fn drop(&mut self) {
+        self.inner.conn.send_close_notify();
+        while self.inner.conn.wants_write() {
+            if self.inner.conn.write_tls(&mut self.inner.sock).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[inline(always)]
+fn invalid_input<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+fn parse_certs(mut pem: &[u8]) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut certs = Vec::new();
+    for cert in rustls_pemfile::certs(&mut pem) {
+        match cert {
+            Ok(cert) => certs.push(cert),
+            Err(error) => return Err(invalid_input(format!("invalid PEM certificate: {}", error))),
+        }
+    }
+
+    match certs.is_empty() {
+        true => Err(invalid_input("no PEM certificate found")),
+        false => Ok(certs),
+    }
+}
+
+fn parse_key(mut pem: &[u8]) -> io::Result<PrivateKeyDer<'static>> {
+    match rustls_pemfile::private_key(&mut pem) {
+        Ok(Some(key)) => Ok(key),
+        Ok(None) => Err(invalid_input("no PEM private key found")),
+        Err(error) => Err(invalid_input(format!("invalid PEM private key: {}", error))),
+    }
+}
+
+///Builder of `Tls` writer.
+///
+///All certificates and keys are parsed as soon as they are provided, reporting error immediately
+///instead of on the first attempt to connect.
+pub struct TlsBuilder {
+    server_name: String,
+    roots: rustls::RootCertStore,
+    identity: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+}
+
+impl TlsBuilder {
+    ///Creates new builder expecting server to present certificate for `server_name`.
+    ///
+    ///There are no trusted root certificates by default, so at least one has to be added.
+    pub fn new<N: Into<String>>(server_name: N) -> Self {
+        Self {
+            server_name: server_name.into(),
+            roots: rustls::RootCertStore::empty(),
+            identity: None,
+        }
+    }
+
+    #[inline]
+    ///Overrides server name, expected in server's certificate.
+    pub fn server_name<N: Into<String>>(mut self, server_name: N) -> Self {
+        self.server_name = server_name.into();
+        self
+    }
+
+    ///Adds trusted root certificates from PEM bytes.
+    pub fn add_root_pem(mut self, pem: &[u8]) -> io::Result<Self> {
+        for cert in parse_certs(pem)? {
+            if let Err(error) = self.roots.add(cert) {
+                return Err(invalid_input(format!("invalid root certificate: {}", error)));
+            }
+        }
+        Ok(self)
+    }
+
+    #[inline]
+    ///Adds trusted root certificates from PEM file.
+    pub fn add_root_pem_file<P: AsRef<Path>>(self, path: P) -> io::Result<Self> {
+        let pem = std::fs::read(path)?;
+        self.add_root_pem(&pem)
+    }
+
+    ///Sets client identity from PEM certificate chain and private key, enabling mutual TLS.
+    pub fn client_identity_pem(mut self, cert_chain: &[u8], key: &[u8]) -> io::Result<Self> {
+        self.identity = Some((parse_certs(cert_chain)?, parse_key(key)?));
+        Ok(self)
+    }
+
+    #[inline]
+    ///Sets client identity from PEM certificate chain and private key files, enabling mutual TLS.
+    pub fn client_identity_pem_file<C: AsRef<Path>, K: AsRef<Path>>(self, cert_chain: C, key: K) -> io::Result<Self> {
+        let cert_chain = std::fs::read(cert_chain)?;
+        let key = std::fs::read(key)?;
+        self.client_identity_pem(&cert_chain, &key)
+    }
+
+    ///Creates `Tls` writer on top of `writer`.
+    pub fn build<MW: MakeWriter>(self, writer: MW) -> io::Result<Tls<MW>> where MW::Writer: Read {
+        let server_name = match ServerName::try_from(self.server_name) {
+            Ok(server_name) => server_name,
+            Err(error) => return Err(invalid_input(format!("invalid server name: {}", error))),
+        };
+
+        let config = match rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider())).with_safe_default_protocol_versions() {
+            Ok(config) => config.with_root_certificates(self.roots),
+            Err(error) => return Err(io::Error::other(error)),
+        };
+        let config = match self.identity {
+            Some((cert_chain, key)) => match config.with_client_auth_cert(cert_chain, key) {
+                Ok(config) => config,
+                Err(error) => return Err(invalid_input(format!("invalid client identity: {}", error))),
+            },
+            None => config.with_no_client_auth(),
+        };
+
+        Ok(Tls {
+            writer,
+            config: Arc::new(config),
+            server_name,
+        })
+    }
+}
+
+///`MakeWriter` that establishes TLS session on top of the `writer`.
+///
+///Handshake is performed on every new connection, before returning writer.
+///
+///Created via `TlsBuilder`.
+pub struct Tls<MW> {
+    writer: MW,
+    config: Arc<rustls::ClientConfig>,
+    server_name: ServerName<'static>,
+}
+
+impl<MW: MakeWriter> Tls<MW> where MW::Writer: Read {
+    fn connect(&self, mut conn: MW::Writer) -> io::Result<TlsStream<MW::Writer>> {
+        let mut session = match rustls::ClientConnection::new(self.config.clone(), self.server_name.clone()) {
+            Ok(session) => session,
+            Err(error) => return Err(io::Error::other(error)),
+        };
+
+        while session.is_handshaking() {
+            session.complete_io(&mut conn)?;
+        }
+
+        Ok(TlsStream {
+            inner: rustls::StreamOwned::new(session, conn),
+        })
+    }
+}
+
+impl<MW: MakeWriter> MakeWriter for Tls<MW> where MW::Writer: Read {
+    type Writer = TlsStream<MW::Writer>;
+
+    #[inline]
+    fn make(&self) -> io::Result<Self::Writer> {
+        self.writer.make().and_then(|conn| self.connect(conn))
+    }
+
+    #[inline]
+    fn make_with(&self, ctx: &MakeContext) -> io::Result<Self::Writer> {
+        self.writer.make_with(ctx).and_then(|conn| self.connect(conn))
+    }
+
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> io::Result<()> {
+        self.writer.probe(&mut writer.inner.sock)
+    }
+}
+
@@ -2,11 +2,16 @@ use tracing_subscriber::registry::{LookupSpan, SpanRef};
 use tracing_core::subscriber::Subscriber as Collect;
 use tracing_subscriber::layer::Context;
 use tracing_core::span::{Id, Attributes, Record};
-use tracing_core::{Event, Field};
+use tracing_core::{Event, Field, Metadata};
 
-use crate::{Layer, FlattenFmt, NestedFmt, fluent, worker};
+use crate::metadata_cache::MetadataCache;
+use crate::{Layer, ContextFmt, FlattenFmt, GcpFmt, GelfFmt, LogstashFmt, NestedFmt, SpanFieldPrefix, TimeUnit, TimestampStyle, dedup, fluent, span_registry, worker};
 
 use core::fmt;
+use std::sync::Arc;
+
+//Sequence number of the next record, shared by all layers.
+static SEQUENCE: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(0);
 
 macro_rules! get_span {
     ($ctx:ident[$id:ident]) => {
@@ -17,6 +22,210 @@ macro_rules! get_span {
     }
 }
 
+#[derive(Clone, Debug)]
+///Filter of field names, configured via `Builder::with_field_allowlist` or `Builder::with_field_denylist`.
+pub(crate) enum FieldFilter {
+    ///Only listed fields are recorded, while other fields are either dropped or recorded within
+    ///`extra` object.
+    Allow {
+        fields: Vec<&'static str>,
+        extra: bool,
+    },
+    ///Listed fields are dropped.
+    Deny(Vec<&'static str>),
+}
+
+#[derive(Clone, Debug)]
+///Syslog fields, configured via `Builder::with_syslog_fields`.
+pub(crate) struct SyslogFields {
+    pub(crate) facility: u8,
+    pub(crate) ident: &'static str,
+}
+
+impl SyslogFields {
+    pub(crate) fn new(facility: u8, ident: &'static str) -> Self {
+        Self {
+            facility,
+            ident,
+        }
+    }
+
+    ///Returns default severity of levels from `TRACE` to `ERROR`.
+    pub(crate) fn default_severity() -> [u8; 5] {
+        use tracing_core::Level;
+
+        let mut severity = [0; 5];
+        for level in [Level::TRACE, Level::DEBUG, Level::INFO, Level::WARN, Level::ERROR].iter() {
+            severity[Self::idx(level)] = syslog_severity(level) as u8;
+        }
+        severity
+    }
+
+    #[inline]
+    pub(crate) fn idx(level: &tracing_core::Level) -> usize {
+        match *level {
+            tracing_core::Level::TRACE => 0,
+            tracing_core::Level::DEBUG => 1,
+            tracing_core::Level::INFO => 2,
+            tracing_core::Level::WARN => 3,
+            tracing_core::Level::ERROR => 4,
+        }
+    }
+
+    fn insert(&self, record: &mut fluent::Map, severity: u8) {
+        record.insert("severity".into(), u64::from(severity).into());
+        record.insert("facility".into(), u64::from(self.facility).into());
+        record.insert("ident".into(), self.ident.into());
+    }
+}
+
+#[derive(Clone, Debug)]
+///Tags of records by level, configured via `Builder::with_level_tag_suffix`.
+pub(crate) struct LevelTags {
+    ///Tags of levels from `TRACE` to `ERROR`, built once, so that records only share them.
+    tags: [Arc<str>; 5],
+}
+
+impl LevelTags {
+    ///Default suffixes of levels from `TRACE` to `ERROR`.
+    pub(crate) const DEFAULT_SUFFIXES: [&'static str; 5] = ["log", "log", "log", "warn", "error"];
+
+    ///Builds tags as `<tag>.<suffix>`, sharing tag between levels with the same suffix.
+    pub(crate) fn new(tag: &'static str, suffixes: &[&'static str; 5]) -> Self {
+        let empty = Arc::<str>::from("");
+        let mut tags = [empty.clone(), empty.clone(), empty.clone(), empty.clone(), empty];
+        for idx in 0..suffixes.len() {
+            let suffix = suffixes[idx];
+            tags[idx] = match suffixes[..idx].iter().position(|built| *built == suffix) {
+                Some(built) => tags[built].clone(),
+                None => Arc::from(format!("{}.{}", tag, suffix)),
+            };
+        }
+
+        Self {
+            tags,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn get(&self, level: &tracing_core::Level) -> &Arc<str> {
+        &self.tags[SyslogFields::idx(level)]
+    }
+}
+
+#[derive(Clone, Debug)]
+///Options to tweak output of formatters.
+///
+///Configured via `Builder` and passed to `FieldFormatter` methods with options, e.g. `on_event_with_opts`.
+pub struct FmtOpts {
+    pub(crate) lowercase_level: bool,
+    pub(crate) thread_info: bool,
+    pub(crate) record_timestamp: Option<(&'static str, TimestampStyle)>,
+    pub(crate) time_field: Option<(&'static str, TimeUnit)>,
+    pub(crate) structured_errors: bool,
+    pub(crate) error_debug: bool,
+    pub(crate) log_normalization: bool,
+    pub(crate) span_metadata: bool,
+    pub(crate) span_ids: bool,
+    pub(crate) stable_schema: bool,
+    pub(crate) propagated_fields: Vec<&'static str>,
+    pub(crate) static_fields: fluent::Map,
+    pub(crate) redacted_fields: Vec<&'static str>,
+    pub(crate) max_depth: usize,
+    pub(crate) skip_field: Option<&'static str>,
+    pub(crate) span_field_prefix: Option<SpanFieldPrefix>,
+    pub(crate) flatten_nested: Option<(usize, bool)>,
+    pub(crate) span_path: Option<&'static str>,
+    pub(crate) span_registry: bool,
+    pub(crate) event_name: Option<(&'static str, bool)>,
+    pub(crate) syslog: Option<SyslogFields>,
+    pub(crate) syslog_severity: [u8; 5],
+    pub(crate) level_tags: Option<LevelTags>,
+    pub(crate) level_tag_suffixes: [&'static str; 5],
+    pub(crate) field_filter: Option<FieldFilter>,
+    pub(crate) monotonic_timestamps: bool,
+    pub(crate) sequence_field: Option<&'static str>,
+    pub(crate) metadata_key: &'static str,
+    pub(crate) metadata_cache: MetadataCache,
+    #[cfg(feature = "opentelemetry")]
+    pub(crate) otel_keys: (&'static str, &'static str),
+    #[cfg(feature = "json")]
+    pub(crate) json_fields: Vec<&'static str>,
+}
+
+impl FmtOpts {
+    #[inline(always)]
+    pub(crate) fn new() -> Self {
+        Self {
+            lowercase_level: false,
+            thread_info: false,
+            record_timestamp: None,
+            time_field: None,
+            structured_errors: false,
+            error_debug: false,
+            log_normalization: false,
+            span_metadata: false,
+            span_ids: false,
+            stable_schema: false,
+            propagated_fields: Vec::new(),
+            static_fields: fluent::Map::new(),
+            redacted_fields: Vec::new(),
+            max_depth: fluent::DEFAULT_MAX_DEPTH,
+            skip_field: None,
+            span_field_prefix: None,
+            flatten_nested: None,
+            span_path: None,
+            span_registry: false,
+            event_name: None,
+            syslog: None,
+            syslog_severity: SyslogFields::default_severity(),
+            level_tags: None,
+            level_tag_suffixes: LevelTags::DEFAULT_SUFFIXES,
+            field_filter: None,
+            monotonic_timestamps: false,
+            sequence_field: None,
+            metadata_key: "metadata",
+            metadata_cache: MetadataCache::new(),
+            #[cfg(feature = "opentelemetry")]
+            otel_keys: ("trace_id", "span_id"),
+            #[cfg(feature = "json")]
+            json_fields: Vec::new(),
+        }
+    }
+
+    #[inline(always)]
+    ///Creates visitor to record fields into `map` according to configuration.
+    pub fn visitor<'a>(&'a self, map: &'a mut fluent::Map) -> FieldVisitor<'a> {
+        FieldVisitor {
+            map,
+            opts: self,
+            shared: false,
+        }
+    }
+
+    #[inline(always)]
+    ///Creates visitor to record fields of span into `map` according to configuration.
+    ///
+    ///Unlike `visitor`, strings are recorded as `fluent::Value::Shared`, so that copying span's
+    ///fields into every event only increments reference counters.
+    pub fn span_visitor<'a>(&'a self, map: &'a mut fluent::Map) -> FieldVisitor<'a> {
+        FieldVisitor {
+            map,
+            opts: self,
+            shared: true,
+        }
+    }
+
+    #[inline]
+    ///Creates level value according to configuration.
+    pub fn level(&self, level: &tracing_core::Level) -> fluent::Value {
+        match self.lowercase_level {
+            true => fluent::Value::Str(fluent::tracing_level_to_lowercase_str(*level)),
+            false => fluent::Value::EventLevel(*level),
+        }
+    }
+}
+
 ///Describes how compose event fields.
 pub trait FieldFormatter: 'static {
     #[inline(always)]
@@ -53,64 +262,794 @@ pub trait FieldFormatter: 'static {
     ///Given `record` must be filled with data, after exiting this method, `record` is sent to the
     ///fluentd
     fn on_event<'a, R: LookupSpan<'a>>(&self, record: &mut fluent::Record, event: &Event<'_>, current_span: Option<SpanRef<'a, R>>);
+
+    #[inline(always)]
+    ///Handler for when `Layer::new_span` is invoked, given options of the layer.
+    ///
+    ///By default invokes `on_new_span`, ignoring options.
+    fn on_new_span_with_opts<C: Collect + for<'a> LookupSpan<'a>>(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, C>, _opts: &FmtOpts) {
+        self.on_new_span(attrs, id, ctx)
+    }
+
+    #[inline(always)]
+    ///Handler for when `Layer::on_record` is invoked, given options of the layer.
+    ///
+    ///By default invokes `on_record`, ignoring options.
+    fn on_record_with_opts<C: Collect + for<'a> LookupSpan<'a>>(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, C>, _opts: &FmtOpts) {
+        self.on_record(id, values, ctx)
+    }
+
+    #[inline(always)]
+    ///Handler for when `Layer::on_event` is invoked, given options of the layer.
+    ///
+    ///By default invokes `on_event`, ignoring options.
+    fn on_event_with_opts<'a, R: LookupSpan<'a>>(&self, record: &mut fluent::Record, event: &Event<'_>, current_span: Option<SpanRef<'a, R>>, _opts: &FmtOpts) {
+        self.on_event(record, event, current_span)
+    }
+
+    #[inline(always)]
+    ///Handler for when `Layer::on_event` is invoked, given context and options of the layer.
+    ///
+    ///By default invokes `on_event_with_opts` with span of the event.
+    fn on_event_with<C: Collect + for<'a> LookupSpan<'a>>(&self, record: &mut fluent::Record, event: &Event<'_>, ctx: &Context<'_, C>, opts: &FmtOpts) {
+        self.on_event_with_opts(record, event, ctx.event_span(event), opts)
+    }
+}
+
+//Handlers of built-in formatters, which respect options of the layer.
+//
+//Formatter itself implements `on_event_with_opts`, while `on_event` uses default options.
+macro_rules! with_opts_handlers {
+    () => {
+        #[inline(always)]
+        fn on_new_span_with_opts<C: Collect + for<'a> LookupSpan<'a>>(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, C>, opts: &FmtOpts) {
+            let span = get_span!(ctx[id]);
+
+            if span.extensions().get::<fluent::Map>().is_none() {
+                let mut record = fluent::Map::new();
+                attrs.record(&mut opts.span_visitor(&mut record));
+
+                span.extensions_mut().insert(record);
+            }
+        }
+
+        #[inline(always)]
+        fn on_record_with_opts<C: Collect + for<'a> LookupSpan<'a>>(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, C>, opts: &FmtOpts) {
+            let span = get_span!(ctx[id]);
+
+            let mut extensions = span.extensions_mut();
+            if let Some(record) = extensions.get_mut::<fluent::Map>() {
+                values.record(&mut opts.span_visitor(record));
+            }
+        }
+
+        #[inline(always)]
+        fn on_event<'a, R: LookupSpan<'a>>(&self, record: &mut fluent::Record, event: &Event<'_>, current_span: Option<SpanRef<'a, R>>) {
+            self.on_event_with_opts(record, event, current_span, &FmtOpts::new())
+        }
+    }
+}
+
+///Location of `log` record forwarded by `tracing-log`.
+struct LogMetadata {
+    target: Option<fluent::Value>,
+    file: Option<fluent::Value>,
+    line: Option<fluent::Value>,
+}
+
+impl LogMetadata {
+    ///Takes `log.*` fields out of the record, if event comes from `log` bridge and normalization is enabled.
+    fn take(event: &Event<'_>, record: &mut fluent::Map, opts: &FmtOpts) -> Option<Self> {
+        if !opts.log_normalization || event.metadata().fields().field("log.target").is_none() {
+            return None;
+        }
+
+        record.shift_remove("log.module_path");
+        Some(Self {
+            target: record.shift_remove("log.target"),
+            file: record.shift_remove("log.file"),
+            line: record.shift_remove("log.line"),
+        })
+    }
+}
+
+///Returns whether event name is generated by `tracing` macros, rather than specified explicitly.
+///
+///Generated names are `event <file>:<line>`, or `log event` for records of `tracing-log`.
+#[inline]
+fn is_generated_name(name: &str) -> bool {
+    name.starts_with("event ") || name == "log event"
+}
+
+fn insert_event_name(record: &mut fluent::Map, metadata: &'static Metadata<'static>, opts: &FmtOpts) {
+    if let Some((key, generated)) = opts.event_name {
+        let name = metadata.name();
+        if generated || !is_generated_name(name) {
+            record.insert(key.into(), name.into());
+        }
+    }
+}
+
+///Name and id of thread.
+type ThreadInfo = (Option<fluent::Value>, fluent::Value);
+
+fn thread_info() -> ThreadInfo {
+    let thread = std::thread::current();
+    (thread.name().map(|name| fluent::Value::Shared(name.into())), fluent::Value::Shared(format!("{:?}", thread.id()).into()))
+}
+
+thread_local! {
+    //Created once per thread, so that records only share it.
+    static THREAD_INFO: ThreadInfo = thread_info();
+}
+
+fn insert_metadata(record: &mut fluent::Map, metadata: &'static Metadata<'static>, log: Option<LogMetadata>, opts: &FmtOpts) {
+    match log {
+        Some(log) => {
+            if let Some(file) = log.file {
+                record.insert("file".into(), file);
+            }
+            if let Some(line) = log.line {
+                record.insert("line".into(), line);
+            }
+            record.insert("module".into(), log.target.unwrap_or_else(|| metadata.target().into()));
+            record.insert("level".into(), opts.level(metadata.level()));
+        },
+        None => {
+            let cached = opts.metadata_cache.get(metadata, || {
+                let mut cached = fluent::Map::new();
+                if let Some(name) = metadata.file() {
+                    cached.insert("file".into(), name.into());
+                }
+                if let Some(line) = metadata.line() {
+                    cached.insert("line".into(), line.into());
+                }
+                cached.insert("module".into(), metadata.target().into());
+                cached.insert("level".into(), opts.level(metadata.level()));
+                cached
+            });
+            record.reserve(cached.len() + if opts.thread_info { 2 } else { 0 });
+            for (key, value) in cached.iter() {
+                record.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    if opts.thread_info {
+        //Thread might be already destroying its locals.
+        let (name, id) = THREAD_INFO.try_with(Clone::clone).unwrap_or_else(|_| thread_info());
+        if let Some(name) = name {
+            record.insert("thread_name".into(), name);
+        }
+        record.insert("thread_id".into(), id);
+    }
+
+    if opts.stable_schema {
+        record.entry("file".into()).or_insert(fluent::Value::Null);
+        record.entry("line".into()).or_insert(fluent::Value::Null);
+        if opts.thread_info {
+            record.entry("thread_name".into()).or_insert(fluent::Value::Null);
+        }
+    }
+}
+
+///Copies `fields` from the closest span, that has them, at the root of `record`, unless event has
+///the same field.
+fn propagate_fields<'a, R: LookupSpan<'a>>(record: &mut fluent::Map, event: &Event<'_>, span: SpanRef<'a, R>, fields: &[&'static str]) {
+    let mut missing = fields.iter().filter(|field| event.metadata().fields().field(**field).is_none()).copied().collect::<Vec<_>>();
+
+    for span in Scope::new(span) {
+        if missing.is_empty() {
+            break;
+        }
+
+        let extensions = span.extensions();
+        let span_record = match extensions.get::<fluent::Map>() {
+            Some(span_record) => span_record,
+            None => continue,
+        };
+
+        missing.retain(|field| match span_record.get(field) {
+            Some(value) => {
+                record.insert((*field).into(), value.clone());
+                false
+            },
+            None => true,
+        });
+    }
+}
+
+///Visitor looking for `true` value of field, that suppresses event.
+struct SkipVisitor {
+    name: &'static str,
+    skip: bool,
+}
+
+impl tracing_core::field::Visit for SkipVisitor {
+    #[inline(always)]
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if field.name() == self.name {
+            self.skip = value;
+        }
+    }
+
+    #[inline(always)]
+    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {
+    }
+}
+
+///Returns whether `event` has field `name` set to `true`.
+fn is_skipped(event: &Event<'_>, name: &'static str) -> bool {
+    //Avoid visiting fields of events that cannot have it.
+    if event.metadata().fields().field(name).is_none() {
+        return false;
+    }
+
+    let mut visitor = SkipVisitor {
+        name,
+        skip: false,
+    };
+    event.record(&mut visitor);
+    visitor.skip
+}
+
+const REDACTED: &str = "[REDACTED]";
+
+#[inline]
+fn is_redacted(key: &str, patterns: &[&'static str]) -> bool {
+    patterns.iter().any(|pattern| match pattern.strip_prefix('*') {
+        Some(suffix) => key.ends_with(suffix),
+        None => key == *pattern,
+    })
+}
+
+///Replaces values of keys, matching `patterns`, within `map` and all nested objects.
+pub(crate) fn redact(map: &mut fluent::Map, patterns: &[&'static str]) {
+    for (key, value) in map.iter_mut() {
+        if is_redacted(key, patterns) {
+            *value = REDACTED.into();
+        } else {
+            redact_value(value, patterns);
+        }
+    }
+}
+
+fn redact_value(value: &mut fluent::Value, patterns: &[&'static str]) {
+    match value {
+        fluent::Value::Object(map) => redact(map, patterns),
+        fluent::Value::Array(values) => for value in values.iter_mut() {
+            redact_value(value, patterns);
+        },
+        _ => (),
+    }
+}
+
+///Inserts event metadata as object under `FmtOpts::metadata_key`.
+///
+///If record already contains such key, due to event's field or span's name, then envelope is
+///inserted under key with numeric suffix, e.g. `metadata_1`, in order to keep user's data.
+///Key of envelope is remembered within record, so that it is kept last.
+pub(crate) fn insert_metadata_envelope(record: &mut fluent::Record, metadata: fluent::Map, opts: &FmtOpts) {
+    let mut key = std::borrow::Cow::Borrowed(opts.metadata_key);
+    let mut suffix = 1usize;
+    while record.contains_key(&key) {
+        key = format!("{}_{}", opts.metadata_key, suffix).into();
+        suffix += 1;
+    }
+
+    record.insert(key.clone(), metadata.into());
+    record.set_metadata_key(key);
+}
+
+///Iterator over span's scope, from leaf to root.
+///
+///Skips spans that were already visited and stops after `MAX_DEPTH` spans, to guard against
+///pathological registry state.
+pub struct Scope<'a, R: LookupSpan<'a>> {
+    inner: tracing_subscriber::registry::Scope<'a, R>,
+    visited: Vec<Id>,
+    steps: usize,
+}
+
+impl<'a, R: LookupSpan<'a>> Scope<'a, R> {
+    ///Max number of spans to visit.
+    pub const MAX_DEPTH: usize = 100;
+
+    #[inline(always)]
+    ///Creates new iterator starting with `span`
+    pub fn new(span: SpanRef<'a, R>) -> Self {
+        Self::of(&span)
+    }
+
+    #[inline(always)]
+    pub(crate) fn of(span: &SpanRef<'a, R>) -> Self {
+        Self {
+            inner: span.scope(),
+            visited: Vec::new(),
+            steps: 0,
+        }
+    }
+}
+
+impl<'a, R: LookupSpan<'a>> Iterator for Scope<'a, R> {
+    type Item = SpanRef<'a, R>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.steps < Self::MAX_DEPTH {
+            self.steps += 1;
+            let span = self.inner.next()?;
+            let id = span.id();
+            if !self.visited.contains(&id) {
+                self.visited.push(id);
+                return Some(span);
+            }
+        }
+
+        None
+    }
+}
+
+///Cached metadata of span, stored within span's extensions.
+///
+///Extensions are shared by all layers, hence only location of span is cached, while level is
+///formatted according to options of each layer.
+struct SpanMetadata(fluent::Map);
+
+impl SpanMetadata {
+    fn get<'a, R: LookupSpan<'a>>(span: &SpanRef<'a, R>, opts: &FmtOpts) -> fluent::Map {
+        let metadata = span.metadata();
+        let cached = span.extensions().get::<SpanMetadata>().map(|meta| meta.0.clone());
+        let mut meta = match cached {
+            Some(meta) => meta,
+            None => {
+                let mut meta = fluent::Map::new();
+                meta.insert("target".into(), metadata.target().into());
+                if let Some(file) = metadata.file() {
+                    meta.insert("file".into(), file.into());
+                }
+                if let Some(line) = metadata.line() {
+                    meta.insert("line".into(), line.into());
+                }
+                span.extensions_mut().insert(SpanMetadata(meta.clone()));
+                meta
+            },
+        };
+
+        meta.insert("level".into(), opts.level(metadata.level()));
+        meta
+    }
+}
+
+///Ids of spans, which span follows from, stored within span's extensions.
+struct FollowsFrom(Vec<fluent::Value>);
+
+impl FollowsFrom {
+    fn add<'a, R: LookupSpan<'a>>(span: &SpanRef<'a, R>, follows: &Id) {
+        let id = fluent::Value::from(follows.into_u64());
+        let mut extensions = span.extensions_mut();
+        match extensions.get_mut::<FollowsFrom>() {
+            Some(follows_from) => follows_from.0.push(id),
+            None => extensions.insert(FollowsFrom(vec![id])),
+        }
+    }
+
+    ///Inserts `id` of span and ids, which it follows from, into span's `object`.
+    fn insert_ids<'a, R: LookupSpan<'a>>(object: &mut fluent::Map, span: &SpanRef<'a, R>) {
+        object.insert("id".into(), span.id().into_u64().into());
+        if let Some(follows_from) = span.extensions().get::<FollowsFrom>() {
+            object.insert("follows_from".into(), follows_from.0.clone().into());
+        }
+    }
+}
+
+///Cached paths of span names from root to span, stored within span's extensions.
+///
+///Extensions are shared by all layers, hence path is cached per separator.
+struct SpanPath(Vec<(&'static str, fluent::Value)>);
+
+impl SpanPath {
+    fn get<'a, R: LookupSpan<'a>>(span: &SpanRef<'a, R>, separator: &'static str) -> fluent::Value {
+        if let Some(paths) = span.extensions().get::<SpanPath>() {
+            if let Some((_, path)) = paths.0.iter().find(|(cached, _)| *cached == separator) {
+                return path.clone();
+            }
+        }
+
+        let mut names = Scope::of(span).map(|span| span.name()).collect::<Vec<_>>();
+        names.reverse();
+        let path = fluent::Value::from(names.join(separator));
+
+        let mut extensions = span.extensions_mut();
+        match extensions.get_mut::<SpanPath>() {
+            Some(paths) => paths.0.push((separator, path.clone())),
+            None => extensions.insert(SpanPath(vec![(separator, path.clone())])),
+        }
+        path
+    }
+}
+
+///Cached prefixed keys of span attributes, stored within span's extensions.
+///
+///Keys follow order of span's `fluent::Map`, which only grows.
+///Extensions are shared by all layers, hence keys are rebuilt if they are created with different prefix.
+struct PrefixedKeys {
+    prefix: SpanFieldPrefix,
+    keys: Vec<std::borrow::Cow<'static, str>>,
+}
+
+impl PrefixedKeys {
+    fn update<'a, R: LookupSpan<'a>>(event_record: &mut fluent::Record, span: &SpanRef<'a, R>, prefix: SpanFieldPrefix) {
+        let mut extensions = span.extensions_mut();
+        let mut keys = match extensions.remove::<PrefixedKeys>() {
+            Some(keys) if keys.prefix == prefix => keys.keys,
+            _ => Vec::new(),
+        };
+
+        if let Some(record) = extensions.get_mut::<fluent::Map>() {
+            for key in record.keys().skip(keys.len()) {
+                keys.push(prefix.key(span.name(), key).into());
+            }
+            for (key, value) in keys.iter().zip(record.values()) {
+                event_record.entry(key.clone()).or_insert_with(|| value.clone());
+            }
+        }
+
+        extensions.insert(PrefixedKeys {
+            prefix,
+            keys,
+        });
+    }
 }
 
 impl FieldFormatter for NestedFmt {
+    with_opts_handlers!();
+
     #[inline(always)]
-    fn on_event<'a, R: LookupSpan<'a>>(&self, event_record: &mut fluent::Record, event: &Event<'_>, current_span: Option<SpanRef<'a, R>>) {
+    fn on_event_with_opts<'a, R: LookupSpan<'a>>(&self, event_record: &mut fluent::Record, event: &Event<'_>, current_span: Option<SpanRef<'a, R>>, opts: &FmtOpts) {
         use core::ops::DerefMut;
 
-        event.record(event_record.deref_mut());
+        event.record(&mut opts.visitor(event_record.deref_mut()));
+        let log = LogMetadata::take(event, event_record, opts);
+        if opts.stable_schema {
+            event_record.entry("message".into()).or_insert(fluent::Value::Null);
 
-        if let Some(span) = current_span {
-            for span in span.scope() {
-                let extensions = span.extensions();
-                if let Some(record) = extensions.get::<fluent::Map>() {
-                    event_record.insert(span.name().into(), record.clone().into());
+            let mut spans = Vec::new();
+            if let Some(span) = current_span {
+                for span in Scope::new(span) {
+                    let record = match span.extensions().get::<fluent::Map>() {
+                        Some(record) => record.clone(),
+                        None => continue,
+                    };
+
+                    let mut object = fluent::Map::new();
+                    object.insert("name".into(), span.name().into());
+                    object.insert("fields".into(), record.into());
+                    if opts.span_metadata {
+                        object.insert("meta".into(), SpanMetadata::get(&span, opts).into());
+                    }
+                    if opts.span_ids {
+                        FollowsFrom::insert_ids(&mut object, &span);
+                    }
+                    spans.push(object.into());
+                }
+            }
+            event_record.insert("spans".into(), spans.into());
+        } else if let Some(span) = current_span {
+            for span in Scope::new(span) {
+                let mut record = match span.extensions().get::<fluent::Map>() {
+                    Some(record) => record.clone(),
+                    None => continue,
+                };
+
+                if opts.span_metadata {
+                    record.insert("meta".into(), SpanMetadata::get(&span, opts).into());
                 }
+                if opts.span_ids {
+                    FollowsFrom::insert_ids(&mut record, &span);
+                }
+                event_record.insert(span.name().into(), record.into());
             }
         }
 
+        insert_event_name(event_record, event.metadata(), opts);
+
         let mut metadata = fluent::Map::new();
+        insert_metadata(&mut metadata, event.metadata(), log, opts);
+        insert_metadata_envelope(event_record, metadata, opts);
+    }
+}
+
+impl FieldFormatter for FlattenFmt {
+    with_opts_handlers!();
+
+    #[inline(always)]
+    fn on_event_with_opts<'a, R: LookupSpan<'a>>(&self, event_record: &mut fluent::Record, event: &Event<'_>, current_span: Option<SpanRef<'a, R>>, opts: &FmtOpts) {
+        use core::ops::DerefMut;
 
-        if let Some(name) = event.metadata().file() {
-            metadata.insert("file".into(), name.into());
+        event.record(&mut opts.visitor(event_record.deref_mut()));
+        let log = LogMetadata::take(event, event_record, opts);
+        if opts.stable_schema {
+            event_record.entry("message".into()).or_insert(fluent::Value::Null);
+        }
+
+        if let Some(span) = current_span {
+            flatten_spans(event_record, span, opts);
         }
-        if let Some(line) = event.metadata().line() {
-            metadata.insert("line".into(), line.into());
+
+        if let Some((max_depth, arrays)) = opts.flatten_nested {
+            flatten_nested(event_record, max_depth, arrays);
         }
-        metadata.insert("module".into(), event.metadata().target().into());
-        metadata.insert("level".into(), event.metadata().level().to_owned().into());
 
-        event_record.insert("metadata".into(), metadata.into());
+        insert_event_name(event_record, event.metadata(), opts);
+        insert_metadata(event_record, event.metadata(), log, opts);
     }
 }
 
-impl FieldFormatter for FlattenFmt {
-    #[inline(always)]
-    fn on_event<'a, R: LookupSpan<'a>>(&self, event_record: &mut fluent::Record, event: &Event<'_>, current_span: Option<SpanRef<'a, R>>) {
+impl FieldFormatter for ContextFmt {
+    with_opts_handlers!();
+
+    fn on_event_with_opts<'a, R: LookupSpan<'a>>(&self, event_record: &mut fluent::Record, event: &Event<'_>, current_span: Option<SpanRef<'a, R>>, opts: &FmtOpts) {
         use core::ops::DerefMut;
 
-        event.record(event_record.deref_mut());
+        event.record(&mut opts.visitor(event_record.deref_mut()));
+        let log = LogMetadata::take(event, event_record, opts);
+        if opts.stable_schema {
+            event_record.entry("message".into()).or_insert(fluent::Value::Null);
+        }
 
+        let mut context = fluent::Map::new();
         if let Some(span) = current_span {
-            for span in span.scope() {
-                let extensions = span.extensions();
-                if let Some(record) = extensions.get::<fluent::Map>() {
-                    event_record.update(record);
+            for span in Scope::new(span) {
+                if let Some(record) = span.extensions().get::<fluent::Map>() {
+                    for (key, value) in record.iter() {
+                        context.entry(key.clone()).or_insert_with(|| value.clone());
+                    }
                 }
             }
         }
+        if !context.is_empty() || opts.stable_schema {
+            event_record.insert(self.key.into(), context.into());
+        }
 
-        if let Some(name) = event.metadata().file() {
-            event_record.insert("file".into(), name.into());
+        insert_event_name(event_record, event.metadata(), opts);
+
+        if self.nested_metadata {
+            let mut metadata = fluent::Map::new();
+            insert_metadata(&mut metadata, event.metadata(), log, opts);
+            insert_metadata_envelope(event_record, metadata, opts);
+        } else {
+            insert_metadata(event_record, event.metadata(), log, opts);
+        }
+    }
+}
+
+///Maps level to severity of Google Cloud Logging.
+#[inline]
+fn gcp_severity(level: &tracing_core::Level) -> &'static str {
+    match *level {
+        tracing_core::Level::TRACE | tracing_core::Level::DEBUG => "DEBUG",
+        tracing_core::Level::INFO => "INFO",
+        tracing_core::Level::WARN => "WARNING",
+        tracing_core::Level::ERROR => "ERROR",
+    }
+}
+
+impl FieldFormatter for GcpFmt {
+    with_opts_handlers!();
+
+    fn on_event_with_opts<'a, R: LookupSpan<'a>>(&self, event_record: &mut fluent::Record, event: &Event<'_>, current_span: Option<SpanRef<'a, R>>, opts: &FmtOpts) {
+        use core::ops::DerefMut;
+
+        event.record(&mut opts.visitor(event_record.deref_mut()));
+        let log = LogMetadata::take(event, event_record, opts);
+        if opts.stable_schema {
+            event_record.entry("message".into()).or_insert(fluent::Value::Null);
+        }
+
+        if let Some(span) = current_span {
+            flatten_spans(event_record, span, opts);
+        }
+
+        insert_event_name(event_record, event.metadata(), opts);
+
+        let metadata = event.metadata();
+        let (file, line, function) = match log {
+            Some(log) => (log.file, log.line, log.target.unwrap_or_else(|| metadata.target().into())),
+            None => (metadata.file().map(Into::into), metadata.line().map(Into::into), metadata.target().into()),
+        };
+        let mut location = fluent::Map::new();
+        if let Some(file) = file {
+            location.insert("file".into(), file);
+        }
+        if let Some(line) = line {
+            location.insert("line".into(), line);
+        }
+        location.insert("function".into(), function);
+
+        event_record.insert("severity".into(), gcp_severity(metadata.level()).into());
+        event_record.insert("logging.googleapis.com/sourceLocation".into(), location.into());
+    }
+}
+
+impl FieldFormatter for LogstashFmt {
+    with_opts_handlers!();
+
+    fn on_event_with_opts<'a, R: LookupSpan<'a>>(&self, event_record: &mut fluent::Record, event: &Event<'_>, current_span: Option<SpanRef<'a, R>>, opts: &FmtOpts) {
+        use core::ops::DerefMut;
+
+        event.record(&mut opts.visitor(event_record.deref_mut()));
+        let log = LogMetadata::take(event, event_record, opts);
+        if opts.stable_schema {
+            event_record.entry("message".into()).or_insert(fluent::Value::Null);
+        }
+
+        if let Some(span) = current_span {
+            flatten_spans(event_record, span, opts);
         }
-        if let Some(line) = event.metadata().line() {
-            event_record.insert("line".into(), line.into());
+
+        insert_event_name(event_record, event.metadata(), opts);
+
+        let time = event_record.time();
+        event_record.insert("@timestamp".into(), fluent::rfc3339(time).into());
+        event_record.insert("@version".into(), "1".into());
+
+        let mut metadata = fluent::Map::new();
+        insert_metadata(&mut metadata, event.metadata(), log, opts);
+        event_record.insert("@metadata".into(), metadata.into());
+    }
+}
+
+///Maps level to syslog severity.
+#[inline]
+fn syslog_severity(level: &tracing_core::Level) -> u64 {
+    match *level {
+        tracing_core::Level::TRACE | tracing_core::Level::DEBUG => 7,
+        tracing_core::Level::INFO => 6,
+        tracing_core::Level::WARN => 4,
+        tracing_core::Level::ERROR => 3,
+    }
+}
+
+///Creates name of GELF additional field, replacing characters other than alphanumeric, `_`, `.`
+///and `-` with `_`.
+///
+///Field `id` is reserved, hence it is renamed to `_id_`.
+fn gelf_field(name: &str) -> String {
+    if name == "id" {
+        return "_id_".to_owned();
+    }
+
+    let mut field = String::with_capacity(name.len() + 1);
+    field.push('_');
+    for ch in name.chars() {
+        match ch.is_ascii_alphanumeric() || ch == '_' || ch == '.' || ch == '-' {
+            true => field.push(ch),
+            false => field.push('_'),
         }
-        event_record.insert("module".into(), event.metadata().target().into());
-        event_record.insert("level".into(), event.metadata().level().to_owned().into());
     }
+    field
+}
+
+impl FieldFormatter for GelfFmt {
+    with_opts_handlers!();
+
+    fn on_event_with_opts<'a, R: LookupSpan<'a>>(&self, event_record: &mut fluent::Record, event: &Event<'_>, current_span: Option<SpanRef<'a, R>>, opts: &FmtOpts) {
+        use core::ops::DerefMut;
+
+        event.record(&mut opts.visitor(event_record.deref_mut()));
+        let log = LogMetadata::take(event, event_record, opts);
+        if let Some(span) = current_span {
+            flatten_spans(event_record, span, opts);
+        }
+        insert_event_name(event_record, event.metadata(), opts);
+
+        let metadata = event.metadata();
+        let message = match event_record.shift_remove("message") {
+            Some(fluent::Value::Str(message)) => message.to_owned(),
+            Some(fluent::Value::String(message)) => message,
+            Some(fluent::Value::Shared(message)) => message.to_string(),
+            _ => metadata.name().to_owned(),
+        };
+        let fields = event_record.drain().collect::<Vec<_>>();
+
+        event_record.insert("version".into(), "1.1".into());
+        event_record.insert("host".into(), self.host.clone().into());
+        match message.find('\n') {
+            Some(idx) => {
+                event_record.insert("short_message".into(), message[..idx].to_owned().into());
+                event_record.insert("full_message".into(), message.into());
+            },
+            None => {
+                event_record.insert("short_message".into(), message.into());
+            },
+        }
+        let time = event_record.time();
+        event_record.insert("timestamp".into(), time.as_secs_f64().into());
+        event_record.insert("level".into(), syslog_severity(metadata.level()).into());
+
+        for (key, value) in fields {
+            event_record.insert(gelf_field(&key).into(), value);
+        }
+
+        let mut location = fluent::Map::new();
+        insert_metadata(&mut location, metadata, log, opts);
+        //Level is already mapped to severity.
+        location.shift_remove("level");
+        for (key, value) in location.drain() {
+            event_record.insert(gelf_field(&key).into(), value);
+        }
+    }
+}
+
+///Inserts attributes of `span` and its parents at the root of `record`.
+fn flatten_spans<'a, R: LookupSpan<'a>>(record: &mut fluent::Record, span: SpanRef<'a, R>, opts: &FmtOpts) {
+    let mut follows_from = Vec::new();
+    for span in Scope::new(span) {
+        if opts.span_ids {
+            if let Some(ids) = span.extensions().get::<FollowsFrom>() {
+                follows_from.extend(ids.0.iter().cloned());
+            }
+        }
+        match opts.span_field_prefix {
+            Some(prefix) => PrefixedKeys::update(record, &span, prefix),
+            None => {
+                let extensions = span.extensions();
+                if let Some(span_record) = extensions.get::<fluent::Map>() {
+                    record.update(span_record);
+                }
+            },
+        }
+    }
+
+    if !follows_from.is_empty() {
+        record.entry("follows_from".into()).or_insert_with(|| follows_from.into());
+    }
+}
+
+#[inline(always)]
+fn is_expandable(value: &fluent::Value, arrays: bool) -> bool {
+    match value {
+        fluent::Value::Object(map) => !map.is_empty(),
+        fluent::Value::Array(values) => arrays && !values.is_empty(),
+        _ => false,
+    }
+}
+
+fn expand_nested(record: &mut fluent::Map, key: String, value: fluent::Value, depth: usize, arrays: bool) {
+    match value {
+        value if depth == 0 || !is_expandable(&value, arrays) => {
+            record.entry(key.into()).or_insert(value);
+        },
+        fluent::Value::Object(map) => for (child, value) in map {
+            expand_nested(record, format!("{}.{}", key, child), value, depth - 1, arrays);
+        },
+        fluent::Value::Array(values) => for (idx, value) in values.into_iter().enumerate() {
+            expand_nested(record, format!("{}.{}", key, idx), value, depth - 1, arrays);
+        },
+        value => {
+            record.entry(key.into()).or_insert(value);
+        },
+    }
+}
+
+///Expands nested objects of root into dotted keys, up to `max_depth` levels.
+///
+///As with span attributes, fields already present in record take precedence over expanded keys,
+///and among expanded keys the first one wins.
+fn flatten_nested(record: &mut fluent::Map, max_depth: usize, arrays: bool) {
+    if max_depth == 0 || !record.values().any(|value| is_expandable(value, arrays)) {
+        return;
+    }
+
+    let mut flat = fluent::Map::with_capacity(record.len());
+    for (key, value) in record.drain() {
+        if is_expandable(&value, arrays) {
+            expand_nested(&mut flat, key.into_owned(), value, max_depth, arrays);
+        } else {
+            flat.insert(key, value);
+        }
+    }
+    *record = flat;
 }
 
 impl tracing_core::field::Visit for fluent::Map {
@@ -130,6 +1069,17 @@ impl tracing_core::field::Visit for fluent::Map {
         self.insert(field.name().into(), value.into());
     }
 
+    #[inline(always)]
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field.name().into(), value.into());
+    }
+
+    #[cfg(all(tracing_unstable, feature = "valuable"))]
+    #[inline(always)]
+    fn record_value(&mut self, field: &Field, value: valuable::Value<'_>) {
+        self.insert(field.name().into(), value.into());
+    }
+
     #[inline(always)]
     fn record_bool(&mut self, field: &Field, value: bool) {
         self.insert(field.name().into(), value.into());
@@ -142,20 +1092,291 @@ impl tracing_core::field::Visit for fluent::Map {
 
     #[inline(always)]
     fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
-        let value = format!("{}", value);
-        self.insert(field.name().into(), value.into());
+        use fmt::Write;
+
+        let mut chain = format!("{}", value);
+        for source in ErrorSources::new(value) {
+            let _ = write!(chain, ": {}", source);
+        }
+        self.insert(field.name().into(), chain.into());
+    }
+}
+
+///Iterator over `Error::source` chain, excluding error itself.
+///
+///Limited to `MAX_DEPTH` entries to guard against pathological cycles.
+struct ErrorSources<'a> {
+    next: Option<&'a (dyn std::error::Error + 'static)>,
+    depth: usize,
+}
+
+impl<'a> ErrorSources<'a> {
+    const MAX_DEPTH: usize = 8;
+
+    #[inline(always)]
+    fn new(error: &'a (dyn std::error::Error + 'static)) -> Self {
+        Self {
+            next: error.source(),
+            depth: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for ErrorSources<'a> {
+    type Item = &'a (dyn std::error::Error + 'static);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.depth >= Self::MAX_DEPTH {
+            return None;
+        }
+
+        let result = self.next.take()?;
+        self.depth += 1;
+        self.next = result.source();
+        Some(result)
+    }
+}
+
+///Visitor that records fields into `fluent::Map` according to `FmtOpts`.
+///
+///Created via `FmtOpts::visitor`.
+pub struct FieldVisitor<'a> {
+    map: &'a mut fluent::Map,
+    opts: &'a FmtOpts,
+    shared: bool,
+}
+
+impl FieldVisitor<'_> {
+    ///Returns map, where `field` should be recorded, if it should be recorded at all.
+    fn target(&mut self, field: &Field) -> Option<&mut fluent::Map> {
+        let name = field.name();
+        if self.opts.skip_field == Some(name) {
+            return None;
+        }
+
+        match self.opts.field_filter.as_ref() {
+            Some(FieldFilter::Deny(fields)) if fields.contains(&name) => None,
+            Some(FieldFilter::Allow { fields, extra }) if !fields.contains(&name) => match extra {
+                true => match self.map.entry("extra".into()).or_insert_with(|| fluent::Map::new().into()) {
+                    fluent::Value::Object(extra) => Some(extra),
+                    //Allowed field `extra` is not object.
+                    _ => None,
+                },
+                false => None,
+            },
+            _ => Some(self.map),
+        }
+    }
+}
+
+#[cfg(feature = "json")]
+#[inline(always)]
+fn is_json(opts: &FmtOpts, field: &Field) -> bool {
+    opts.json_fields.contains(&field.name())
+}
+
+#[cfg(feature = "json")]
+///Records `value` as structured object, if it is valid JSON object or array, otherwise as string.
+fn record_json(map: &mut fluent::Map, field: &Field, value: String) {
+    let value = match serde_json::from_str::<serde_json::Value>(&value) {
+        Ok(json @ serde_json::Value::Object(_)) | Ok(json @ serde_json::Value::Array(_)) => json.into(),
+        _ => value.into(),
+    };
+    map.insert(field.name().into(), value);
+}
+
+impl tracing_core::field::Visit for FieldVisitor<'_> {
+    #[inline(always)]
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let _opts = self.opts;
+        if let Some(map) = self.target(field) {
+            #[cfg(feature = "json")]
+            if is_json(_opts, field) {
+                return record_json(map, field, format!("{:?}", value));
+            }
+            map.record_debug(field, value)
+        }
+    }
+
+    #[inline(always)]
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if let Some(map) = self.target(field) {
+            map.record_i64(field, value)
+        }
+    }
+
+    #[inline(always)]
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if let Some(map) = self.target(field) {
+            map.record_u64(field, value)
+        }
+    }
+
+    #[inline(always)]
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if let Some(map) = self.target(field) {
+            map.record_f64(field, value)
+        }
+    }
+
+    #[cfg(all(tracing_unstable, feature = "valuable"))]
+    #[inline(always)]
+    fn record_value(&mut self, field: &Field, value: valuable::Value<'_>) {
+        if let Some(map) = self.target(field) {
+            map.record_value(field, value)
+        }
+    }
+
+    #[inline(always)]
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        if let Some(map) = self.target(field) {
+            map.record_bool(field, value)
+        }
+    }
+
+    #[inline(always)]
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let _opts = self.opts;
+        let shared = self.shared;
+        if let Some(map) = self.target(field) {
+            #[cfg(feature = "json")]
+            if is_json(_opts, field) {
+                return record_json(map, field, value.to_owned());
+            }
+            match shared {
+                true => {
+                    map.insert(field.name().into(), fluent::Value::Shared(value.into()));
+                },
+                false => map.record_str(field, value),
+            }
+        }
+    }
+
+    #[inline]
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        let opts = self.opts;
+        let map = match self.target(field) {
+            Some(map) => map,
+            None => return,
+        };
+
+        if opts.error_debug {
+            map.insert(format!("{}.debug", field.name()).into(), format!("{:#?}", value).into());
+        }
+
+        if opts.structured_errors {
+            let mut error = fluent::Map::new();
+            error.insert("message".into(), format!("{}", value).into());
+            let causes = ErrorSources::new(value).map(|source| format!("{}", source).into()).collect::<Vec<fluent::Value>>();
+            error.insert("causes".into(), causes.into());
+            map.insert(field.name().into(), error.into());
+        } else {
+            map.record_error(field, value)
+        }
+    }
+}
+
+impl<F, W: worker::Consumer> Layer<F, W> {
+    ///Creates record, reporting `count` of events that were not sent.
+    ///
+    ///Record is stamped and completed the same way as records of events.
+    fn summary_record(&self, message: String, key: &'static str, count: u64, target: &'static str, level: &tracing_core::Level) -> fluent::Record {
+        let mut record = self.consumer.create_record(self.timestamps.now());
+        if let Some(level_tags) = self.opts.level_tags.as_ref() {
+            record.set_tag(level_tags.get(level).clone());
+        }
+        record.insert("message".into(), message.into());
+        record.insert(key.into(), count.into());
+
+        let mut metadata = fluent::Map::new();
+        metadata.insert("target".into(), target.into());
+        metadata.insert("level".into(), self.opts.level(level));
+        insert_metadata_envelope(&mut record, metadata, &self.opts);
+
+        self.insert_extra_fields(&mut record, level);
+        self.complete_record(&mut record);
+        record
+    }
+
+    ///Inserts fields, configured via `Builder`, and redacts record.
+    fn insert_extra_fields(&self, record: &mut fluent::Record, level: &tracing_core::Level) {
+        if let Some(syslog) = self.opts.syslog.as_ref() {
+            syslog.insert(record, self.opts.syslog_severity[SyslogFields::idx(level)]);
+        }
+
+        for (key, value) in self.opts.static_fields.iter() {
+            record.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+
+        if !self.opts.redacted_fields.is_empty() {
+            redact(record, &self.opts.redacted_fields);
+        }
+    }
+
+    ///Inserts time and sequence fields, keeping message first and metadata last.
+    fn complete_record(&self, record: &mut fluent::Record) {
+        if let Some((key, style)) = self.opts.record_timestamp {
+            let time = record.time();
+            record.entry(key.into()).or_insert_with(|| style.format(time));
+        }
+
+        if let Some((key, unit)) = self.opts.time_field {
+            match record.contains_key(key) {
+                true => self.timestamps.report_conflict(key),
+                false => {
+                    let time = record.time();
+                    record.insert(key.into(), unit.format(time));
+                },
+            }
+        }
+
+        //Assigned after deduplication, as otherwise every record would differ.
+        if let Some(key) = self.opts.sequence_field {
+            record.insert(key.into(), SEQUENCE.fetch_add(1, core::sync::atomic::Ordering::Relaxed).into());
+        }
+
+        //Keep message first and metadata last for readability.
+        if let Some(idx) = record.get_index_of("message") {
+            record.move_index(idx, 0);
+        }
+        if record.metadata_key().is_none() {
+            record.set_metadata_key(self.opts.metadata_key.into());
+        }
+        if let Some(idx) = record.metadata_key().and_then(|key| record.get_index_of(key)) {
+            let last = record.len() - 1;
+            record.move_index(idx, last);
+        }
     }
 }
 
 impl<F: FieldFormatter, W: worker::Consumer, C: Collect + for<'a> LookupSpan<'a>> tracing_subscriber::layer::Layer<C> for Layer<F, W> {
+    #[cfg(feature = "opentelemetry")]
+    #[inline(always)]
+    fn on_register_dispatch(&self, dispatch: &tracing_core::Dispatch) {
+        self.otel.register(dispatch);
+    }
+
     #[inline(always)]
     fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, C>) {
-        self.fmt.on_new_span(attrs, id, ctx);
+        self.fmt.on_new_span_with_opts(attrs, id, ctx, &self.opts);
     }
 
     #[inline(always)]
     fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, C>) {
-        self.fmt.on_record(id, values, ctx);
+        self.fmt.on_record_with_opts(id, values, ctx, &self.opts);
+        if self.opts.span_registry {
+            span_registry::invalidate();
+        }
+    }
+
+    #[inline]
+    fn on_follows_from(&self, id: &Id, follows: &Id, ctx: Context<'_, C>) {
+        if self.opts.span_ids {
+            if let Some(span) = ctx.span(id) {
+                FollowsFrom::add(&span, follows);
+            }
+        }
     }
 
     #[inline(always)]
@@ -172,10 +1393,103 @@ impl<F: FieldFormatter, W: worker::Consumer, C: Collect + for<'a> LookupSpan<'a>
 
     #[inline]
     fn on_event(&self, event: &Event<'_>, ctx: Context<'_, C>) {
-        let mut record = fluent::Record::now();
+        if !self.filter.would_enable(event.metadata()) {
+            return;
+        }
+        if let Some(skip_field) = self.opts.skip_field {
+            if is_skipped(event, skip_field) {
+                return;
+            }
+        }
+        if !self.filter.sample_event(event, ctx.event_span(event)) {
+            return;
+        }
+        if self.consumer.is_closed() {
+            self.suppressed.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+            if let Some(on_drop) = self.on_drop.as_ref() {
+                on_drop(crate::DropReason::DeliveryGivenUp, 1);
+            }
+            return;
+        }
+
+        if let Some(rate_limit) = self.rate_limit.as_ref() {
+            if let Some(dropped) = rate_limit.report() {
+                let message = format!("rate limit dropped {} records", dropped);
+                self.consumer.record(self.summary_record(message, "rate_limit_dropped", dropped, env!("CARGO_PKG_NAME"), &tracing_core::Level::WARN));
+            }
+
+            if !rate_limit.acquire(event.metadata().level()) {
+                if let Some(on_drop) = self.on_drop.as_ref() {
+                    on_drop(crate::DropReason::RateLimited, 1);
+                }
+                return;
+            }
+        }
+
+        let mut record = self.consumer.create_record(self.timestamps.now());
+        //Typical record fits into inline map, hence it is filled without reallocation.
+        record.reserve(fluent::INLINE_LEN);
+        record.set_max_depth(self.opts.max_depth);
+        //Assigned before formatting, so that formatter can override it.
+        if let Some(level_tags) = self.opts.level_tags.as_ref() {
+            record.set_tag(level_tags.get(event.metadata().level()).clone());
+        }
+
+        //`event_span` respects explicit parent of event, returning `None` for root events and
+        //current span only for contextual events.
+        match self.opts.span_registry {
+            //Attributes of span are inserted by worker, once per message.
+            true => {
+                self.fmt.on_event_with_opts(&mut record, event, None::<SpanRef<'_, C>>, &self.opts);
+                if let Some(span) = ctx.event_span(event) {
+                    record.set_span(span_registry::SpanData::get(&span, &self.opts));
+                }
+            },
+            false => self.fmt.on_event_with(&mut record, event, &ctx, &self.opts),
+        }
+
+        if !self.opts.propagated_fields.is_empty() {
+            if let Some(span) = ctx.event_span(event) {
+                propagate_fields(&mut record, event, span, &self.opts.propagated_fields);
+            }
+        }
+
+        if let Some(separator) = self.opts.span_path {
+            if let Some(span) = ctx.event_span(event) {
+                record.insert("span_path".into(), SpanPath::get(&span, separator));
+            }
+        }
+
+        #[cfg(feature = "opentelemetry")]
+        if let Some(span) = ctx.event_span(event) {
+            self.otel.insert(&mut record, &span.id(), self.opts.otel_keys);
+        }
 
-        self.fmt.on_event(&mut record, event, ctx.event_span(event));
+        self.insert_extra_fields(&mut record, event.metadata().level());
+
+        if let Some(dedup) = self.dedup.as_ref() {
+            match dedup.check(event.metadata().callsite(), &record) {
+                dedup::Outcome::Suppressed => return,
+                dedup::Outcome::Repeated(0) | dedup::Outcome::Changed(0) => (),
+                dedup::Outcome::Repeated(repeated) => {
+                    record.insert("repeated".into(), repeated.into());
+                },
+                dedup::Outcome::Changed(repeated) => {
+                    let metadata = event.metadata();
+                    let message = format!("previous record repeated {} times", repeated);
+                    self.consumer.record(self.summary_record(message, "repeated", repeated, metadata.target(), metadata.level()));
+                },
+            }
+        }
+
+        self.complete_record(&mut record);
 
         self.consumer.record(record);
+
+        if let Some(level) = self.eager_flush {
+            if *event.metadata().level() <= level {
+                self.consumer.flush();
+            }
+        }
     }
 }
@@ -1,9 +1,27 @@
 use core::{mem, time};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::borrow::Cow;
+use std::sync::Arc;
 
-use crate::{fluent, MakeWriter};
+use crate::{fluent, Clock, Codec, MakeWriter, MakeContext, OverflowPolicy, QueueKind};
+use crate::budget::Budget;
+use crate::stats::Stats;
+use crate::clock;
+use crate::local_buffer;
+use crate::record_pool::RecordPool;
+use crate::drop_marker::DropMarkers;
+use crate::span_registry;
 
 pub enum Message {
     Record(fluent::Record),
+    //Record, encoded by `Consumer`, if enabled via `Builder::with_packed_forward`, with its tag
+    Encoded(Option<Arc<str>>, Vec<u8>),
+    //Requests to write already received records without waiting for `max_msg_record`
+    Flush,
+    //Same as `Flush`, but sender is dropped once records are written, allowing to await it.
+    Sync(crossbeam_channel::Sender<()>),
+    //Records, buffered by producer thread, if enabled via `Builder::with_thread_local_buffer`
+    Batch(Vec<Message>),
     Terminate,
 }
 
@@ -14,33 +32,330 @@ impl Into<Message> for fluent::Record {
     }
 }
 
+impl Message {
+    #[inline]
+    ///Returns approximate size of record in bytes.
+    pub(crate) fn estimated_size(&self) -> usize {
+        match self {
+            Message::Record(record) => record.estimated_size(),
+            Message::Encoded(_, entry) => entry.len(),
+            Message::Batch(messages) => messages.iter().map(Message::estimated_size).sum(),
+            Message::Flush | Message::Sync(_) | Message::Terminate => 0,
+        }
+    }
+
+    #[inline]
+    ///Returns number of records within message.
+    pub(crate) fn records(&self) -> usize {
+        match self {
+            Message::Record(_) | Message::Encoded(..) => 1,
+            Message::Batch(messages) => messages.len(),
+            Message::Flush | Message::Sync(_) | Message::Terminate => 0,
+        }
+    }
+}
+
+#[inline]
+fn encode_record(record: &mut fluent::Record) -> Option<Message> {
+    //Encoded records are concatenated as they are, hence span cannot be referenced.
+    span_registry::inline(record);
+    //Encoding into `Vec` can fail only due to record itself, e.g. time out of range, hence it is dropped.
+    record.encode().ok().map(|entry| Message::Encoded(record.shared_tag(), entry))
+}
+
+#[inline]
+pub(crate) fn record_message(mut record: fluent::Record, packed: bool) -> Option<Message> {
+    match packed {
+        true => encode_record(&mut record),
+        false => Some(record.into()),
+    }
+}
+
+pub type ErrorHandler = std::sync::Arc<dyn Fn(&std::io::Error) + Send + Sync>;
+pub type OptionFieldsFn = std::sync::Arc<dyn Fn(&mut fluent::Map) + Send + Sync>;
+pub type DropHandler = std::sync::Arc<dyn Fn(crate::DropReason, usize) + Send + Sync>;
+pub type ThreadBuilderFn = std::sync::Arc<dyn Fn(std::thread::Builder) -> std::thread::Builder + Send + Sync>;
+
+#[derive(Clone)]
+///Worker options.
+pub struct Opts {
+    pub max_msg_record: usize,
+    pub error_handler: Option<ErrorHandler>,
+    pub probe: bool,
+    pub option_section: fluent::OptionSection,
+    pub option_fields_fn: Option<OptionFieldsFn>,
+    pub codec: Codec,
+    pub on_drop: Option<DropHandler>,
+    pub stats_interval: Option<time::Duration>,
+    pub clock: Arc<dyn Clock>,
+    pub packed: bool,
+    pub memory_budget: Option<(usize, OverflowPolicy)>,
+    pub local_buffer: Option<(usize, time::Duration)>,
+    pub queue: QueueKind,
+    pub thread_builder: Option<ThreadBuilderFn>,
+    pub record_pool: Option<usize>,
+    pub drop_markers: Option<&'static str>,
+    #[cfg(feature = "metrics")]
+    pub metrics_prefix: Option<String>,
+}
+
+impl Opts {
+    #[inline(always)]
+    pub fn new(max_msg_record: usize) -> Self {
+        Self {
+            max_msg_record,
+            error_handler: None,
+            probe: false,
+            option_section: fluent::OptionSection::Size,
+            option_fields_fn: None,
+            codec: Codec::Msgpack,
+            on_drop: None,
+            stats_interval: None,
+            clock: Arc::new(clock::SystemClock),
+            packed: false,
+            memory_budget: None,
+            local_buffer: None,
+            queue: QueueKind::Unbounded,
+            thread_builder: None,
+            record_pool: None,
+            drop_markers: None,
+            #[cfg(feature = "metrics")]
+            metrics_prefix: None,
+        }
+    }
+}
+
+///Receiver of records, created by `Layer`.
 pub trait Consumer: 'static {
+    ///Consumes record.
     fn record(&self, record: fluent::Record);
+    ///Requests to send records as soon as possible.
+    fn flush(&self);
+    ///Returns whether consumer no longer accepts records.
+    fn is_closed(&self) -> bool;
+
+    #[inline(always)]
+    ///Creates record with `time`, which is to be filled by layer and passed to `record`.
+    ///
+    ///Default implementation allocates new record.
+    fn create_record(&self, time: time::Duration) -> fluent::Record {
+        fluent::Record::with_time(time)
+    }
+}
+
+#[derive(Debug, Default)]
+///Consumer that discards records, counting them.
+///
+///Used by layer created via `Builder::disabled_layer`, which neither spawns worker nor creates writer.
+pub struct NullConsumer {
+    records: AtomicUsize,
 }
 
-#[repr(transparent)]
-pub struct WorkerChannel(pub(crate) crossbeam_channel::Sender<Message>);
+impl NullConsumer {
+    #[inline(always)]
+    ///Creates new consumer.
+    pub const fn new() -> Self {
+        Self {
+            records: AtomicUsize::new(0),
+        }
+    }
+
+    #[inline(always)]
+    ///Returns number of discarded records.
+    pub fn records(&self) -> usize {
+        self.records.load(Ordering::Relaxed)
+    }
+}
+
+impl Consumer for NullConsumer {
+    #[inline(always)]
+    fn record(&self, _: fluent::Record) {
+        self.records.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    fn flush(&self) {
+    }
+
+    #[inline(always)]
+    fn is_closed(&self) -> bool {
+        false
+    }
+}
+
+//Set once worker thread exits, even due to panic.
+pub(crate) struct CloseOnDrop(pub(crate) Arc<AtomicBool>);
+
+impl Drop for CloseOnDrop {
+    #[inline(always)]
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+//State of queue, shared by all consumers of worker.
+#[derive(Clone)]
+pub(crate) struct Queue {
+    pub(crate) closed: Arc<AtomicBool>,
+    //Whether channel has fixed capacity, in which case records are discarded once it is full.
+    bounded: bool,
+    packed: bool,
+    budget: Option<Arc<Budget>>,
+    on_drop: Option<DropHandler>,
+    local_buffer: Option<local_buffer::Limits>,
+    pool: Option<RecordPool>,
+}
+
+impl Queue {
+    #[inline]
+    fn create_record(&self, time: time::Duration) -> fluent::Record {
+        match self.pool.as_ref() {
+            Some(pool) => pool.take(time),
+            None => fluent::Record::with_time(time),
+        }
+    }
+
+    #[inline]
+    fn send(&self, sender: &crossbeam_channel::Sender<Message>, record: fluent::Record) {
+        let message = match (self.packed, self.pool.as_ref()) {
+            //Encoded record is no longer needed, hence it is returned to pool right away.
+            (true, Some(pool)) => {
+                let mut record = record;
+                let message = encode_record(&mut record);
+                pool.give(record);
+                message
+            },
+            (packed, _) => record_message(record, packed),
+        };
+        let message = match message {
+            Some(message) => message,
+            None => return if let Some(on_drop) = self.on_drop.as_ref() {
+                on_drop(crate::DropReason::EncodeFailed, 1);
+            },
+        };
+
+        if let Some(budget) = self.budget.as_ref() {
+            if !budget.reserve(message.estimated_size(), sender, self.on_drop.as_ref()) {
+                return;
+            }
+        }
+
+        match self.local_buffer {
+            Some(limits) => local_buffer::push(limits, sender, self, message),
+            None => self.deliver(sender, message),
+        }
+    }
+
+    #[inline]
+    ///Sends records to the worker, discarding them if channel is bounded and full.
+    pub(crate) fn deliver(&self, sender: &crossbeam_channel::Sender<Message>, message: Message) {
+        let message = match self.bounded {
+            true => match sender.try_send(message) {
+                Ok(()) => return,
+                Err(crossbeam_channel::TrySendError::Full(message)) => {
+                    if let Some(budget) = self.budget.as_ref() {
+                        budget.release(message.estimated_size());
+                    }
+                    if let Some(on_drop) = self.on_drop.as_ref() {
+                        on_drop(crate::DropReason::QueueFull, message.records());
+                    }
+                    return;
+                },
+                Err(crossbeam_channel::TrySendError::Disconnected(message)) => message,
+            },
+            false => message,
+        };
+
+        if sender.send(message).is_err() {
+            self.closed.store(true, Ordering::Release);
+        }
+    }
+
+    #[inline(always)]
+    fn flush(&self, sender: &crossbeam_channel::Sender<Message>) {
+        if self.local_buffer.is_some() {
+            local_buffer::flush(sender);
+        }
+        //If bounded channel is full, worker is to write records anyway.
+        let _ = sender.try_send(Message::Flush);
+    }
+}
+
+#[derive(Clone)]
+///Channel to the worker thread, shared by layers created via `Builder::layer_guarded` and
+///`Builder::layer_from_guard`.
+///
+///Clones send records to the same worker.
+pub struct WorkerChannel {
+    sender: crossbeam_channel::Sender<Message>,
+    queue: Queue,
+}
+
+impl WorkerChannel {
+    ///Requests to write received records, waiting up to `timeout` for worker to finish writing.
+    pub(crate) fn sync(&self, timeout: time::Duration) {
+        if self.queue.local_buffer.is_some() {
+            local_buffer::flush(&self.sender);
+        }
+        let (sync, synced) = crossbeam_channel::bounded::<()>(0);
+        if self.sender.send(Message::Sync(sync)).is_ok() {
+            //Worker drops sender once it is done.
+            let _ = synced.recv_timeout(timeout);
+        }
+    }
+}
 
 impl Consumer for WorkerChannel {
     #[inline(always)]
     fn record(&self, record: fluent::Record) {
-        let _ = self.0.send(record.into());
+        self.queue.send(&self.sender, record)
+    }
+
+    #[inline(always)]
+    fn flush(&self) {
+        self.queue.flush(&self.sender)
+    }
+
+    #[inline(always)]
+    fn is_closed(&self) -> bool {
+        self.queue.closed.load(Ordering::Acquire)
+    }
+
+    #[inline(always)]
+    fn create_record(&self, time: time::Duration) -> fluent::Record {
+        self.queue.create_record(time)
     }
 }
 
+///Worker thread, owned by layer created via `Builder::layer`.
+///
+///Worker is stopped on drop, awaiting until remaining records are sent.
 pub struct ThreadWorker {
     sender: mem::ManuallyDrop<crossbeam_channel::Sender<Message>>,
     worker: mem::ManuallyDrop<std::thread::JoinHandle<()>>,
+    queue: Queue,
 }
 
 impl ThreadWorker {
     #[inline(always)]
-    pub fn sender(&self) -> crossbeam_channel::Sender<Message> {
-        mem::ManuallyDrop::into_inner(self.sender.clone())
+    pub(crate) fn channel(&self) -> WorkerChannel {
+        WorkerChannel {
+            sender: mem::ManuallyDrop::into_inner(self.sender.clone()),
+            queue: self.queue.clone(),
+        }
     }
 
     #[inline(always)]
-    pub fn stop(&self) {
+    ///Returns handler of dropped records, that layers should use.
+    pub(crate) fn on_drop(&self) -> Option<DropHandler> {
+        self.queue.on_drop.clone()
+    }
+
+    #[inline(always)]
+    pub(crate) fn stop(&self) {
+        if self.queue.local_buffer.is_some() {
+            local_buffer::flush(&self.sender);
+        }
         let _result = self.sender.send(Message::Terminate);
         debug_assert!(_result.is_ok());
     }
@@ -49,12 +364,32 @@ impl ThreadWorker {
 impl Consumer for ThreadWorker {
     #[inline(always)]
     fn record(&self, record: fluent::Record) {
-        let _ = self.sender.send(record.into());
+        self.queue.send(&self.sender, record)
+    }
+
+    #[inline(always)]
+    fn flush(&self) {
+        self.queue.flush(&self.sender)
+    }
+
+    #[inline(always)]
+    fn is_closed(&self) -> bool {
+        self.queue.closed.load(Ordering::Acquire)
+    }
+
+    #[inline(always)]
+    fn create_record(&self, time: time::Duration) -> fluent::Record {
+        self.queue.create_record(time)
     }
 }
 
 impl Drop for ThreadWorker {
     fn drop(&mut self) {
+        //Threads keep channel until they exit, so worker must be asked to stop explicitly.
+        if self.queue.local_buffer.is_some() {
+            local_buffer::flush(&self.sender);
+            let _ = self.sender.send(Message::Terminate);
+        }
         let worker = unsafe {
             mem::ManuallyDrop::drop(&mut self.sender);
             mem::ManuallyDrop::take(&mut self.worker)
@@ -65,98 +400,734 @@ impl Drop for ThreadWorker {
     }
 }
 
-pub fn thread<MW: MakeWriter>(tag: &'static str, writer: MW, max_msg_record: usize) -> std::io::Result<ThreadWorker> {
+///Creates writers, keeping track of `MakeContext`.
+struct Connector<MW> {
+    writer: MW,
+    ctx: MakeContext,
+    error_handler: Option<ErrorHandler>,
+    stats: Option<Arc<Stats>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<MW: MakeWriter> Connector<MW> {
+    #[inline(always)]
+    fn new(writer: MW, error_handler: Option<ErrorHandler>, stats: Option<Arc<Stats>>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            writer,
+            ctx: MakeContext::new(),
+            error_handler,
+            stats,
+            clock,
+        }
+    }
+
+    #[inline(always)]
+    fn report(&self, error: &std::io::Error) {
+        if let Some(handler) = self.error_handler.as_ref() {
+            handler(error);
+        }
+        if let Some(stats) = self.stats.as_ref() {
+            stats.on_error(error);
+        }
+    }
+
+    fn make(&mut self) -> std::io::Result<MW::Writer> {
+        match self.writer.make_with(&self.ctx) {
+            Ok(writer) => {
+                self.ctx = MakeContext::new();
+                if let Some(stats) = self.stats.as_ref() {
+                    stats.on_connected();
+                }
+                Ok(writer)
+            },
+            Err(error) => {
+                self.report(&error);
+                self.ctx.attempt += 1;
+                self.ctx.previous_failed = true;
+                self.ctx.last_error = Some(error.kind());
+                Err(error)
+            }
+        }
+    }
+
+    //Attempts to create writer, retrying once after delay.
+    fn make_with_retry(&mut self) -> std::io::Result<MW::Writer> {
+        match self.make() {
+            Ok(writer) => Ok(writer),
+            Err(_) => {
+                self.clock.sleep(time::Duration::from_secs(1));
+                self.make()
+            }
+        }
+    }
+
+    //Takes cached writer, if it is still usable.
+    fn take_cached(&mut self, cached: &mut Option<MW::Writer>, probe: bool) -> Option<MW::Writer> {
+        let mut writer = cached.take()?;
+        if self.writer.is_outdated(&writer) {
+            tracing::event!(tracing::Level::DEBUG, "Fluent writer is outdated");
+            return None;
+        }
+        if probe {
+            if let Err(error) = self.writer.probe(&mut writer) {
+                self.on_write_error(&error);
+                tracing::event!(tracing::Level::DEBUG, "Fluent writer is no longer usable {}", error);
+                return None;
+            }
+        }
+        Some(writer)
+    }
+
+    #[inline]
+    fn on_write_error(&mut self, error: &std::io::Error) {
+        self.report(error);
+        self.ctx.previous_failed = true;
+        self.ctx.last_error = Some(error.kind());
+    }
+}
+
+#[inline(always)]
+fn encode_error(error: rmp_serde::encode::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, error)
+}
+
+#[inline]
+///Returns whether `error` is failure to encode message, rather than to write it.
+pub(crate) fn is_encode_error(error: &std::io::Error) -> bool {
+    match error.get_ref() {
+        #[cfg(feature = "json")]
+        Some(inner) if inner.is::<serde_json::Error>() => true,
+        Some(inner) => inner.is::<rmp_serde::encode::Error>(),
+        None => false,
+    }
+}
+
+#[inline]
+///Encodes single record of message with `tag`, appending it to the `buffer`.
+fn encode_entry(record: &fluent::Record, tag: &str, buffer: &mut Vec<u8>, codec: Codec) -> std::io::Result<()> {
+    #[cfg(feature = "json")]
+    if let Codec::Ndjson = codec {
+        return record.write_ndjson(buffer, tag);
+    }
+    #[cfg(not(feature = "json"))]
+    let (Codec::Msgpack, _) = (codec, tag);
+
+    rmp_serde::encode::write(buffer, record).map_err(encode_error)
+}
+
+pub(crate) trait Encode {
+    ///Encodes message, appending it to the `buffer`.
+    fn encode(&self, buffer: &mut Vec<u8>, codec: Codec) -> std::io::Result<()>;
+}
+
+impl Encode for fluent::Message {
+    fn encode(&self, buffer: &mut Vec<u8>, codec: Codec) -> std::io::Result<()> {
+        #[cfg(feature = "json")]
+        if let Codec::Ndjson = codec {
+            return self.write_ndjson(buffer);
+        }
+        #[cfg(not(feature = "json"))]
+        let Codec::Msgpack = codec;
+
+        rmp_serde::encode::write(buffer, self).map_err(encode_error)
+    }
+}
+
+///Records of the same tag, received by worker, either as they are or already encoded.
+struct Group {
+    records: fluent::Message,
+    packed: fluent::PackedMessage,
+    spans: span_registry::Frame,
+}
+
+impl Group {
+    fn new(tag: Cow<'static, str>, option_section: &fluent::OptionSection, codec: Codec) -> Self {
+        let mut records = fluent::Message::with_tag(tag.clone());
+        let mut packed = fluent::PackedMessage::with_tag(tag);
+        records.set_option_section(option_section.clone());
+        packed.set_option_section(option_section.clone());
+        Self {
+            records,
+            packed,
+            //Records can reference span only within msgpack message, while ndjson lines are
+            //independent.
+            spans: span_registry::Frame::new(matches!(codec, Codec::Msgpack)),
+        }
+    }
+
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.records.len() + self.packed.len()
+    }
+}
+
+///Records received by worker, grouped by tag.
+///
+///Records of every tag are written as separate message.
+pub(crate) struct Batch {
+    //The first group is of worker's tag, while the rest is created for records with own tag.
+    groups: Vec<Group>,
+    option_section: fluent::OptionSection,
+    codec: Codec,
+    //Approximate size of records, if tracked.
+    size: Option<usize>,
+    //Pool to return written records into, if enabled via `Builder::with_record_pool`.
+    pool: Option<RecordPool>,
+}
+
+impl Batch {
+    pub(crate) fn new(tag: &'static str, option_section: fluent::OptionSection, codec: Codec, track_size: bool) -> Self {
+        Self {
+            groups: vec![Group::new(Cow::Borrowed(tag), &option_section, codec)],
+            option_section,
+            codec,
+            size: match track_size {
+                true => Some(0),
+                false => None,
+            },
+            pool: None,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn with_pool(mut self, pool: Option<RecordPool>) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    #[inline]
+    ///Returns group of records with `tag`, creating it if necessary.
+    fn group(&mut self, tag: Option<&str>) -> &mut Group {
+        let idx = match tag {
+            None => 0,
+            Some(tag) => match self.groups.iter().position(|group| group.records.tag() == tag) {
+                Some(idx) => idx,
+                None => {
+                    self.groups.push(Group::new(Cow::Owned(tag.to_owned()), &self.option_section, self.codec));
+                    self.groups.len() - 1
+                }
+            },
+        };
+        &mut self.groups[idx]
+    }
+
+    #[inline(always)]
+    pub(crate) fn add(&mut self, message: Message) {
+        let message = match message {
+            Message::Batch(messages) => return messages.into_iter().for_each(|message| self.add(message)),
+            message => message,
+        };
+        if let Some(size) = self.size.as_mut() {
+            *size += message.estimated_size();
+        }
+        match message {
+            Message::Record(mut record) => {
+                let group = self.group(record.tag());
+                group.spans.resolve(&mut record);
+                group.records.add(record)
+            },
+            Message::Encoded(tag, entry) => self.group(tag.as_deref()).packed.add_encoded(&entry),
+            Message::Flush | Message::Sync(_) | Message::Batch(_) | Message::Terminate => (),
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn len(&self) -> usize {
+        self.groups.iter().map(Group::len).sum()
+    }
+
+    #[inline(always)]
+    pub(crate) fn clear(&mut self) {
+        for group in self.groups.iter_mut() {
+            match self.pool.as_ref() {
+                Some(pool) => group.records.drain().for_each(|record| pool.give(record)),
+                None => group.records.clear(),
+            }
+            group.packed.clear();
+            group.spans.clear();
+        }
+        if let Some(size) = self.size.as_mut() {
+            *size = 0;
+        }
+    }
+
+    pub(crate) fn set_option_fields(&mut self, option_fields_fn: &OptionFieldsFn) {
+        for group in self.groups.iter_mut() {
+            if let Some(fields) = group.records.option_fields_mut() {
+                option_fields_fn(fields);
+            }
+            if let Some(fields) = group.packed.option_fields_mut() {
+                option_fields_fn(fields);
+            }
+        }
+    }
+
+    ///Drops records, that cannot be encoded, reporting each of them via `report`.
+    ///
+    ///Records are encoded one by one, so that only offending ones are dropped, while if none of them
+    ///fails on its own, every record is dropped, as message would never be sent.
+    ///
+    ///Returns number of dropped records.
+    pub(crate) fn drop_unencodable<R: FnMut(&std::io::Error)>(&mut self, buffer: &mut Vec<u8>, codec: Codec, mut report: R) -> usize {
+        let len = self.len();
+        for group in self.groups.iter_mut() {
+            let tag = group.records.tag().to_owned();
+            group.records.retain(|idx, record| {
+                buffer.clear();
+                match encode_entry(record, &tag, buffer, codec) {
+                    Ok(()) => true,
+                    Err(error) => {
+                        report(&std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Dropped record #{} of '{}' as it cannot be encoded: {}", idx, tag, error)));
+                        false
+                    }
+                }
+            });
+        }
+
+        if self.len() == len {
+            for group in self.groups.iter_mut().filter(|group| group.len() > 0) {
+                report(&std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Dropped {} records of '{}' as message cannot be encoded", group.len(), group.records.tag())));
+                group.records.clear();
+                group.packed.clear();
+            }
+        }
+        len - self.len()
+    }
+}
+
+impl Encode for Batch {
+    fn encode(&self, buffer: &mut Vec<u8>, codec: Codec) -> std::io::Result<()> {
+        for group in self.groups.iter() {
+            if group.records.len() > 0 {
+                group.records.encode(buffer, codec)?;
+            }
+            //Records are encoded beforehand only if codec is msgpack.
+            if group.packed.len() > 0 {
+                rmp_serde::encode::write(buffer, &group.packed).map_err(encode_error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[inline]
+///Returns whether `error` indicates that connection is broken, rather than failure of message.
+fn is_connection_error(error: &std::io::Error) -> bool {
+    matches!(error.kind(),
+             std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted
+             | std::io::ErrorKind::NotConnected | std::io::ErrorKind::UnexpectedEof)
+}
+
+///Time to wait for writer, that is not ready to accept data, to make progress.
+const WRITE_STALL_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+///Delay before retrying write into writer, that is not ready to accept data.
+const WRITE_RETRY_DELAY: time::Duration = time::Duration::from_millis(1);
+
+///Tracks for how long writer doesn't make progress.
+struct Stall<'a> {
+    clock: &'a dyn Clock,
+    deadline: Option<std::time::Instant>,
+}
+
+impl<'a> Stall<'a> {
+    #[inline(always)]
+    fn new(clock: &'a dyn Clock) -> Self {
+        Self {
+            clock,
+            deadline: None,
+        }
+    }
+
+    #[inline(always)]
+    fn progress(&mut self) {
+        self.deadline = None;
+    }
+
+    ///Handles `error` of writer, returning it back unless operation should be retried.
+    fn on_error(&mut self, error: std::io::Error) -> std::io::Result<()> {
+        match error.kind() {
+            std::io::ErrorKind::Interrupted => Ok(()),
+            std::io::ErrorKind::WouldBlock => {
+                let now = self.clock.now();
+                let deadline = *self.deadline.get_or_insert(now + WRITE_STALL_TIMEOUT);
+                if now >= deadline {
+                    return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Writer made no progress within timeout"));
+                }
+                self.clock.sleep(WRITE_RETRY_DELAY);
+                Ok(())
+            },
+            _ => Err(error),
+        }
+    }
+}
+
+///Writes whole `buffer`, flushing writer afterwards.
+///
+///Unlike `write_all`, `WouldBlock` is retried until writer makes no progress for
+///`WRITE_STALL_TIMEOUT`, hence nonblocking writers can be used.
+fn write_all<W: std::io::Write>(clock: &dyn Clock, writer: &mut W, mut buffer: &[u8]) -> std::io::Result<()> {
+    let mut stall = Stall::new(clock);
+    while !buffer.is_empty() {
+        match writer.write(buffer) {
+            Ok(0) => return Err(std::io::Error::new(std::io::ErrorKind::WriteZero, "failed to write whole message")),
+            Ok(written) => {
+                buffer = &buffer[written..];
+                stall.progress();
+            },
+            Err(error) => stall.on_error(error)?,
+        }
+    }
+
+    stall.progress();
+    loop {
+        match writer.flush() {
+            Ok(()) => break Ok(()),
+            Err(error) => stall.on_error(error)?,
+        }
+    }
+}
+
+///Writes message, flushing writer afterwards.
+///
+///Message is encoded into `buffer` first, replacing its content, so that it is sent as whole and
+///encoding error cannot leave partially written message within writer.
+///On error message may be partially written, hence writer must not be used anymore.
+fn write<W: std::io::Write, M: Encode>(clock: &dyn Clock, writer: &mut W, buffer: &mut Vec<u8>, msg: &M, codec: Codec) -> std::io::Result<()> {
+    buffer.clear();
+    msg.encode(buffer, codec)?;
+    write_all(clock, writer, buffer)
+}
+
+///Default name of worker thread.
+pub(crate) const THREAD_NAME: &str = "tracing-fluentd-worker";
+
+thread_local! {
+    static IS_WORKER: core::cell::Cell<bool> = const { core::cell::Cell::new(false) };
+}
+
+#[inline]
+///Returns whether current thread is worker thread, regardless of its name.
+pub(crate) fn is_worker_thread() -> bool {
+    IS_WORKER.try_with(|is_worker| is_worker.get()).unwrap_or(false)
+}
+
+#[inline(always)]
+pub fn thread<MW: MakeWriter>(tag: &'static str, writer: MW, opts: Opts) -> std::io::Result<ThreadWorker> {
+    spawn(tag, writer, opts, false)
+}
+
+#[inline(always)]
+///Creates worker, that creates writer before returning, failing if writer cannot be created.
+///
+///Created writer is used to send first message.
+pub fn thread_checked<MW: MakeWriter>(tag: &'static str, writer: MW, opts: Opts) -> std::io::Result<ThreadWorker> {
+    spawn(tag, writer, opts, true)
+}
+
+fn spawn<MW: MakeWriter>(tag: &'static str, writer: MW, mut opts: Opts, checked: bool) -> std::io::Result<ThreadWorker> {
     //const MAX_WAIT: time::Duration = time::Duration::from_secs(60);
 
-    let (sender, recv) = crossbeam_channel::unbounded();
-    let worker = std::thread::Builder::new().name("tracing-fluentd-worker".to_owned());
+    let (sender, recv) = match opts.queue {
+        QueueKind::Unbounded => crossbeam_channel::unbounded(),
+        QueueKind::Ring(capacity) => crossbeam_channel::bounded(capacity.get()),
+    };
+    let budget = opts.memory_budget.map(|(limit, policy)| Arc::new(Budget::new(limit, policy, &recv)));
+    let worker = std::thread::Builder::new().name(THREAD_NAME.to_owned());
+    let worker = match opts.thread_builder.as_ref() {
+        Some(thread_builder) => thread_builder(worker),
+        None => worker,
+    };
+
+    let closed = Arc::new(AtomicBool::new(false));
+    let close_on_drop = CloseOnDrop(closed.clone());
 
+    let clock = opts.clock.clone();
+    let stats = opts.stats_interval.map(|_| Stats::new(clock.now()));
+    #[cfg(feature = "metrics")]
+    let stats = match opts.metrics_prefix.as_ref() {
+        Some(prefix) => Some(stats.unwrap_or_else(|| Stats::new(clock.now())).with_metrics(prefix)),
+        None => stats,
+    };
+    let stats = stats.map(Arc::new);
+    if let Some(stats) = stats.as_ref() {
+        opts.on_drop = Some(stats.counting(opts.on_drop.take()));
+    }
+    let markers = opts.drop_markers.map(|suffix| Arc::new(DropMarkers::new(tag, suffix, clock.clone())));
+    if let Some(markers) = markers.as_ref() {
+        opts.on_drop = Some(markers.counting(opts.on_drop.take()));
+    }
+    let on_drop = opts.on_drop.clone();
+    //Ndjson is written from records as they are.
+    let packed = opts.packed && matches!(opts.codec, Codec::Msgpack);
+    let bounded = matches!(opts.queue, QueueKind::Ring(_));
+    let local_buffer = opts.local_buffer.map(|(records, max_age)| local_buffer::Limits { records, max_age });
+    let pool = opts.record_pool.map(RecordPool::new);
+    let worker_pool = pool.clone();
+    let mut stats_deadline = opts.stats_interval.map(|interval| clock.now() + interval);
+
+    //Writer is created within worker, as it is not necessary `Send`.
+    let (ready_sender, ready) = crossbeam_channel::bounded(1);
+    let worker_budget = budget.clone();
     let worker = worker.spawn(move || {
-        let mut msg = fluent::Message::new(tag);
+        IS_WORKER.with(|is_worker| is_worker.set(true));
+        let _close_on_drop = close_on_drop;
+        let budget = worker_budget;
+        let mut msg = Batch::new(tag, opts.option_section, opts.codec, budget.is_some()).with_pool(worker_pool);
+        let mut connector = Connector::new(writer, opts.error_handler, stats.clone(), clock.clone());
         let mut ongoing_writer = None;
+        //Kept across messages to avoid re-allocating it every time.
+        let mut buffer = Vec::new();
+        //Stats are sent as separate message, since message has single tag.
+        let mut stats_msg = opts.stats_interval.map(|_| fluent::Message::with_tag(Cow::Owned(format!("{}.stats", tag))));
+        //Markers are sent as separate message too, so that they are not accounted by memory budget.
+        let mut marker_msg = markers.as_ref().map(|markers| fluent::Message::with_tag(Cow::Owned(markers.tag().to_owned())));
+
+        if checked {
+            match connector.make() {
+                Ok(writer) => {
+                    ongoing_writer = Some(writer);
+                    let _ = ready_sender.send(Ok(()));
+                },
+                Err(error) => {
+                    let _ = ready_sender.send(Err(error));
+                    return;
+                },
+            }
+        }
+        drop(ready_sender);
+        //Senders of `Message::Sync`, dropped once records are written.
+        let mut synced = Vec::new();
 
         'main_loop: loop {
-            //Fetch up to max_msg_record
-            while msg.len() < max_msg_record {
-                match recv.recv() {
-                    Ok(Message::Record(record)) => msg.add(record),
-                    Ok(Message::Terminate) | Err(crossbeam_channel::RecvError) => break 'main_loop
+            //Fetch up to max_msg_record, unless it is time to send stats, which also sends
+            //already received records.
+            while msg.len() < opts.max_msg_record {
+                let message = match stats_deadline {
+                    Some(deadline) => match clock::recv_deadline(&*clock, &recv, deadline) {
+                        Ok(message) => message,
+                        Err(crossbeam_channel::RecvTimeoutError::Timeout) => break,
+                        Err(crossbeam_channel::RecvTimeoutError::Disconnected) => break 'main_loop,
+                    },
+                    None => match recv.recv() {
+                        Ok(message) => message,
+                        Err(crossbeam_channel::RecvError) => break 'main_loop,
+                    },
+                };
+
+                match message {
+                    Message::Flush => if msg.len() > 0 {
+                        break
+                    },
+                    Message::Sync(sync) => {
+                        synced.push(sync);
+                        break
+                    },
+                    Message::Terminate => break 'main_loop,
+                    message => msg.add(message),
                 }
             }
 
             //Get every extra record we can get at the current moment.
             loop {
                 match recv.try_recv() {
-                    Ok(Message::Record(record)) => msg.add(record),
-                    Err(crossbeam_channel::TryRecvError::Empty) => break,
-                    Ok(Message::Terminate) | Err(crossbeam_channel::TryRecvError::Disconnected) => break 'main_loop
+                    Ok(Message::Flush) | Err(crossbeam_channel::TryRecvError::Empty) => break,
+                    Ok(Message::Sync(sync)) => {
+                        synced.push(sync);
+                        break
+                    },
+                    Ok(Message::Terminate) | Err(crossbeam_channel::TryRecvError::Disconnected) => break 'main_loop,
+                    Ok(message) => msg.add(message),
                 }
             }
 
-            let mut writer = match ongoing_writer.take() {
+            if let Some(stats) = stats.as_ref() {
+                stats.on_queue_depth(recv.len());
+            }
+
+            if let (Some(interval), Some(stats), Some(deadline), Some(stats_msg)) = (opts.stats_interval, stats.as_ref(), stats_deadline.as_mut(), stats_msg.as_mut()) {
+                let now = clock.now();
+                if now >= *deadline {
+                    *deadline = now + interval;
+                    //Only the latest stats are of interest, so unsent ones are replaced.
+                    stats_msg.clear();
+                    stats_msg.add(stats.record(now, msg.len(), recv.len(), budget.as_ref().map(|budget| budget.used())));
+                }
+            }
+
+            if let (Some(markers), Some(marker_msg)) = (markers.as_ref(), marker_msg.as_mut()) {
+                markers.take().into_iter().for_each(|record| marker_msg.add(record));
+            }
+
+            if msg.len() == 0 && stats_msg.as_ref().map_or(true, |stats_msg| stats_msg.len() == 0) && marker_msg.as_ref().map_or(true, |marker_msg| marker_msg.len() == 0) {
+                synced.clear();
+                continue 'main_loop;
+            }
+
+            let mut writer = match connector.take_cached(&mut ongoing_writer, opts.probe) {
                 Some(writer) => writer,
-                None => match writer.make() {
+                None => match connector.make_with_retry() {
                     Ok(writer) => writer,
-                    Err(_) => {
-                        std::thread::sleep(time::Duration::from_secs(1));
-                        match writer.make() {
-                            Ok(writer) => writer,
-                            Err(error) => {
-                                tracing::event!(tracing::Level::DEBUG, "Failed to create fluent writer {}", error);
-                                continue 'main_loop;
-                            }
-                        }
+                    Err(error) => {
+                        tracing::event!(tracing::Level::DEBUG, "Failed to create fluent writer {}", error);
+                        synced.clear();
+                        continue 'main_loop;
                     }
                 }
             };
 
-            match rmp_serde::encode::write(&mut writer, &msg) {
-                Ok(()) => {
+            if let Some(option_fields_fn) = opts.option_fields_fn.as_ref() {
+                msg.set_option_fields(option_fields_fn);
+            }
+
+            let mut is_reconnected = false;
+            loop {
+                let mut result = match msg.len() {
+                    0 => Ok(()),
+                    _ => write(&*clock, &mut writer, &mut buffer, &msg, opts.codec),
+                };
+                if result.is_ok() {
+                    if let Some(stats) = stats.as_ref() {
+                        stats.on_delivered(msg.len());
+                    }
+                    if let (Some(budget), Some(size)) = (budget.as_ref(), msg.size) {
+                        budget.release(size);
+                    }
                     msg.clear();
-                    ongoing_writer = Some(writer);
-                },
-                //In case of error we'll just retry at later date.
-                //Ideally we should be able to recover.
-                //But report error?
-                Err(error) => {
-                    tracing::event!(tracing::Level::INFO, "Failed to send records to fluent server {}", error);
-                },
+                    if let Some(stats_msg) = stats_msg.as_mut().filter(|stats_msg| stats_msg.len() > 0) {
+                        result = write(&*clock, &mut writer, &mut buffer, stats_msg, opts.codec);
+                        if result.is_ok() {
+                            stats_msg.clear();
+                        }
+                    }
+                    if let Some(marker_msg) = marker_msg.as_mut().filter(|marker_msg| result.is_ok() && marker_msg.len() > 0) {
+                        result = write(&*clock, &mut writer, &mut buffer, marker_msg, opts.codec);
+                        if result.is_ok() {
+                            marker_msg.clear();
+                        }
+                    }
+                }
+
+                match result {
+                    Ok(()) => {
+                        ongoing_writer = Some(writer);
+                    },
+                    //Message is encoded before anything is written, hence writer is still usable,
+                    //while records, that cannot be encoded, are dropped and the rest is re-sent.
+                    Err(error) if msg.len() > 0 && is_encode_error(&error) => {
+                        tracing::event!(tracing::Level::INFO, "Failed to encode records {}", error);
+                        let dropped = msg.drop_unencodable(&mut buffer, opts.codec, |error| connector.report(error));
+                        if let Some(on_drop) = opts.on_drop.as_ref() {
+                            on_drop(crate::DropReason::EncodeFailed, dropped);
+                        }
+                        if msg.len() > 0 {
+                            continue;
+                        }
+
+                        if let (Some(budget), Some(size)) = (budget.as_ref(), msg.size) {
+                            budget.release(size);
+                        }
+                        msg.clear();
+                        ongoing_writer = Some(writer);
+                    },
+                    //In case of error we'll just retry at later date.
+                    //Writer is dropped, as it may contain partially written message.
+                    Err(error) => {
+                        connector.on_write_error(&error);
+                        tracing::event!(tracing::Level::INFO, "Failed to send records to fluent server {}", error);
+
+                        //Connection is broken, e.g. server restarted, hence new one is likely to
+                        //succeed right away.
+                        if !is_reconnected && is_connection_error(&error) {
+                            is_reconnected = true;
+                            match connector.make() {
+                                Ok(new_writer) => {
+                                    writer = new_writer;
+                                    continue;
+                                },
+                                Err(error) => {
+                                    tracing::event!(tracing::Level::DEBUG, "Failed to re-create fluent writer {}", error);
+                                },
+                            }
+                        }
+                    },
+                }
+                break;
             }
+            synced.clear();
+        }
+
+        //Remaining markers are sent together with last records, as tag of record is respected.
+        if let (Some(markers), Some(marker_msg)) = (markers.as_ref(), marker_msg.as_mut()) {
+            marker_msg.drain().chain(markers.take()).for_each(|record| msg.add(record.into()));
         }
 
         if msg.len() > 0 {
+            if let Some(option_fields_fn) = opts.option_fields_fn.as_ref() {
+                msg.set_option_fields(option_fields_fn);
+            }
+
             //Try to flush last records, but don't wait too much
+            let mut is_sent = false;
             for _ in 0..3 {
-                let mut writer = match ongoing_writer.take() {
+                let mut writer = match connector.take_cached(&mut ongoing_writer, opts.probe) {
                     Some(writer) => writer,
-                    None => match writer.make() {
+                    None => match connector.make_with_retry() {
                         Ok(writer) => writer,
-                        Err(_) => {
-                            std::thread::sleep(time::Duration::from_secs(1));
-                            match writer.make() {
-                                Ok(writer) => writer,
-                                Err(error) => {
-                                    tracing::event!(tracing::Level::DEBUG, "Failed to create fluent writer {}", error);
-                                    continue
-                                }
-                            }
+                        Err(error) => {
+                            tracing::event!(tracing::Level::DEBUG, "Failed to create fluent writer {}", error);
+                            continue
                         }
                     }
                 };
 
-                if let Err(error) = rmp_serde::encode::write(&mut writer, &msg) {
+                if let Err(error) = write(&*clock, &mut writer, &mut buffer, &msg, opts.codec) {
+                    if is_encode_error(&error) {
+                        let dropped = msg.drop_unencodable(&mut buffer, opts.codec, |error| connector.report(error));
+                        if let Some(on_drop) = opts.on_drop.as_ref() {
+                            on_drop(crate::DropReason::EncodeFailed, dropped);
+                        }
+                        ongoing_writer = Some(writer);
+                        if msg.len() == 0 {
+                            is_sent = true;
+                            break;
+                        }
+                        continue;
+                    }
+
+                    connector.on_write_error(&error);
                     tracing::event!(tracing::Level::INFO, "Failed to send last records to fluent server {}", error);
-                    std::thread::sleep(time::Duration::from_secs(1));
+                    clock.sleep(time::Duration::from_secs(1));
                 } else {
+                    is_sent = true;
                     break;
                 }
             }
+
+            if let (false, Some(on_drop)) = (is_sent, opts.on_drop.as_ref()) {
+                on_drop(crate::DropReason::ShutdownTimeout, msg.len());
+            }
         }
     })?;
 
+    if checked {
+        if let Ok(Err(error)) = ready.recv() {
+            let _ = worker.join();
+            return Err(error);
+        }
+    }
+
     Ok(ThreadWorker {
         sender: mem::ManuallyDrop::new(sender),
         worker: mem::ManuallyDrop::new(worker),
+        queue: Queue {
+            closed,
+            bounded,
+            packed,
+            budget,
+            on_drop,
+            local_buffer,
+            pool,
+        },
     })
 
 }
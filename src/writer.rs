@@ -0,0 +1,1175 @@
+//!`MakeWriter` combinators.
+use crate::{MakeWriter, MakeContext, ProbeWrite};
+
+use std::{fs, time};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub use crate::default_writers::TcpConfig;
+pub use crate::handshake::Handshake;
+#[cfg(feature = "heartbeat")]
+pub use crate::heartbeat::{heartbeat, Heartbeat};
+
+///Creates writer that duplicates every write into both `primary` and `secondary` writers.
+///
+///By default failure of either writer is considered failure.
+///Use `Tee::best_effort` to ignore errors of `secondary` writer.
+pub fn tee<A: MakeWriter, B: MakeWriter>(primary: A, secondary: B) -> Tee<A, B> {
+    Tee {
+        primary,
+        secondary,
+        best_effort: false,
+    }
+}
+
+///`MakeWriter` that duplicates records into two writers.
+///
+///Created via `tee`.
+pub struct Tee<A, B> {
+    primary: A,
+    secondary: B,
+    best_effort: bool,
+}
+
+impl<A: MakeWriter, B: MakeWriter> Tee<A, B> {
+    #[inline(always)]
+    ///Configures to ignore errors of `secondary` writer.
+    ///
+    ///Once `secondary` writer fails, it is no longer used until writer is re-created.
+    pub fn best_effort(mut self) -> Self {
+        self.best_effort = true;
+        self
+    }
+}
+
+impl<A: MakeWriter, B: MakeWriter> MakeWriter for Tee<A, B> {
+    type Writer = TeeWriter<A::Writer, B::Writer>;
+
+    #[inline]
+    fn make(&self) -> io::Result<Self::Writer> {
+        let primary = self.primary.make()?;
+        let secondary = match self.secondary.make() {
+            Ok(secondary) => Some(secondary),
+            Err(_) if self.best_effort => None,
+            Err(error) => return Err(error),
+        };
+
+        Ok(TeeWriter {
+            primary,
+            secondary,
+            best_effort: self.best_effort,
+        })
+    }
+
+    fn probe(&self, writer: &mut Self::Writer) -> io::Result<()> {
+        self.primary.probe(&mut writer.primary)?;
+        if let Some(secondary) = writer.secondary.as_mut() {
+            let result = self.secondary.probe(secondary);
+            writer.on_secondary_result(result)?;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn is_outdated(&self, writer: &Self::Writer) -> bool {
+        self.primary.is_outdated(&writer.primary) || writer.secondary.as_ref().map_or(false, |secondary| self.secondary.is_outdated(secondary))
+    }
+}
+
+///Writer created by `Tee`.
+pub struct TeeWriter<A, B> {
+    primary: A,
+    secondary: Option<B>,
+    best_effort: bool,
+}
+
+impl<A: Write, B: Write> TeeWriter<A, B> {
+    #[inline]
+    fn on_secondary_result(&mut self, result: io::Result<()>) -> io::Result<()> {
+        match result {
+            Ok(()) => Ok(()),
+            Err(_) if self.best_effort => {
+                self.secondary = None;
+                Ok(())
+            },
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl<A: Write, B: Write> Write for TeeWriter<A, B> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.primary.write(buf)?;
+        if let Some(secondary) = self.secondary.as_mut() {
+            let result = secondary.write_all(&buf[..size]);
+            self.on_secondary_result(result)?;
+        }
+        Ok(size)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.primary.flush()?;
+        if let Some(secondary) = self.secondary.as_mut() {
+            let result = secondary.flush();
+            self.on_secondary_result(result)?;
+        }
+        Ok(())
+    }
+}
+
+///Creates writer that uses `secondary` writer whenever `primary` writer cannot be created.
+///
+///Once `primary` fails, `secondary` is used for at least `Fallback::probe_interval` (default
+///is 30 seconds) after which `primary` is probed again, even if `secondary` keeps working.
+///Probing happens when creating new writer or when flushing `secondary` writer, so that switch
+///happens only after complete message is written.
+pub fn fallback<A: MakeWriter + Sync, B: MakeWriter>(primary: A, secondary: B) -> Fallback<A, B> {
+    Fallback {
+        primary: Arc::new(primary),
+        secondary,
+        probe_interval: time::Duration::from_secs(30),
+        primary_failed_at: Arc::new(Mutex::new(None)),
+    }
+}
+
+///`MakeWriter` that falls back to secondary writer when primary is not available.
+///
+///Created via `fallback`.
+pub struct Fallback<A, B> {
+    primary: Arc<A>,
+    secondary: B,
+    probe_interval: time::Duration,
+    primary_failed_at: Arc<Mutex<Option<time::Instant>>>,
+}
+
+impl<A: MakeWriter + Sync, B: MakeWriter> Fallback<A, B> {
+    #[inline(always)]
+    ///Configures interval after which `primary` is probed again.
+    pub fn probe_interval(mut self, interval: time::Duration) -> Self {
+        self.probe_interval = interval;
+        self
+    }
+}
+
+///Returns whether primary writer should be probed.
+fn should_probe(primary_failed_at: &Mutex<Option<time::Instant>>, interval: time::Duration) -> bool {
+    match *primary_failed_at.lock().unwrap_or_else(|error| error.into_inner()) {
+        Some(failed_at) => failed_at.elapsed() >= interval,
+        None => true,
+    }
+}
+
+fn set_primary_failed_at(primary_failed_at: &Mutex<Option<time::Instant>>, value: Option<time::Instant>) {
+    *primary_failed_at.lock().unwrap_or_else(|error| error.into_inner()) = value;
+}
+
+impl<A: MakeWriter + Sync, B: MakeWriter> MakeWriter for Fallback<A, B> {
+    type Writer = FallbackWriter<A, B::Writer>;
+
+    #[inline(always)]
+    fn make(&self) -> io::Result<Self::Writer> {
+        self.make_with(&MakeContext::new())
+    }
+
+    fn make_with(&self, ctx: &MakeContext) -> io::Result<Self::Writer> {
+        let mut state = None;
+
+        if should_probe(&self.primary_failed_at, self.probe_interval) {
+            match self.primary.make_with(ctx) {
+                Ok(writer) => {
+                    set_primary_failed_at(&self.primary_failed_at, None);
+                    state = Some(FallbackState::Primary(writer));
+                },
+                Err(_) => set_primary_failed_at(&self.primary_failed_at, Some(time::Instant::now())),
+            }
+        }
+
+        let state = match state {
+            Some(state) => state,
+            None => FallbackState::Secondary(self.secondary.make_with(ctx)?),
+        };
+
+        Ok(FallbackWriter {
+            state,
+            primary: self.primary.clone(),
+            probe_interval: self.probe_interval,
+            primary_failed_at: self.primary_failed_at.clone(),
+        })
+    }
+
+    #[inline]
+    fn probe(&self, writer: &mut Self::Writer) -> io::Result<()> {
+        match &mut writer.state {
+            FallbackState::Primary(writer) => self.primary.probe(writer),
+            FallbackState::Secondary(writer) => self.secondary.probe(writer),
+        }
+    }
+
+    #[inline]
+    fn is_outdated(&self, writer: &Self::Writer) -> bool {
+        match &writer.state {
+            FallbackState::Primary(writer) => self.primary.is_outdated(writer),
+            FallbackState::Secondary(writer) => self.secondary.is_outdated(writer),
+        }
+    }
+}
+
+enum FallbackState<A, B> {
+    Primary(A),
+    Secondary(B),
+}
+
+///Writer created by `Fallback`.
+pub struct FallbackWriter<A: MakeWriter, B> {
+    state: FallbackState<A::Writer, B>,
+    primary: Arc<A>,
+    probe_interval: time::Duration,
+    primary_failed_at: Arc<Mutex<Option<time::Instant>>>,
+}
+
+impl<A: MakeWriter, B> FallbackWriter<A, B> {
+    #[inline(always)]
+    ///Returns whether primary writer is used.
+    pub fn is_primary(&self) -> bool {
+        match self.state {
+            FallbackState::Primary(_) => true,
+            FallbackState::Secondary(_) => false,
+        }
+    }
+}
+
+impl<A: MakeWriter, B: Write> Write for FallbackWriter<A, B> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.state {
+            FallbackState::Primary(writer) => writer.write(buf),
+            FallbackState::Secondary(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.state {
+            FallbackState::Primary(writer) => writer.flush(),
+            FallbackState::Secondary(writer) => {
+                writer.flush()?;
+
+                //Switch only after everything is written to secondary
+                if should_probe(&self.primary_failed_at, self.probe_interval) {
+                    match self.primary.make() {
+                        Ok(primary) => {
+                            set_primary_failed_at(&self.primary_failed_at, None);
+                            self.state = FallbackState::Primary(primary);
+                        },
+                        Err(_) => set_primary_failed_at(&self.primary_failed_at, Some(time::Instant::now())),
+                    }
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+///Creates writer that connects to the first available of `endpoints`, preferring the last
+///successfully used one.
+///
+///Unlike `Vec<SocketAddr>`, which always tries addresses in the same order, it keeps using the
+///same endpoint (sticky), so that flapping endpoint doesn't cause switching on every reconnect,
+///and endpoint that is down doesn't delay every connection by its timeout.
+///
+///- Endpoint is demoted, i.e. tried last, after `Failover::max_failures` (default is 3)
+///  consecutive failures of its writers;
+///- Once other endpoint is used, the first endpoint, which is considered primary, is probed again
+///  every `Failover::probe_interval` (default is 30 seconds).
+///  Switch happens only between messages.
+///
+///This is recommended over plain list of addresses for high availability setups:
+///
+///```rust
+///let addrs: Vec<std::net::SocketAddr> = vec!["127.0.0.1:24224".parse().unwrap(), "127.0.0.1:24225".parse().unwrap()];
+///let layer = tracing_fluentd::Builder::new("rust").with_writer(tracing_fluentd::writer::failover(addrs))
+///                                                  .layer()
+///                                                  .expect("Create layer");
+///```
+pub fn failover<MW: MakeWriter>(endpoints: Vec<MW>) -> Failover<MW> {
+    Failover {
+        endpoints,
+        probe_interval: time::Duration::from_secs(30),
+        max_failures: 3,
+        state: Mutex::new(FailoverState {
+            current: 0,
+            failures: 0,
+            primary_failed_at: None,
+        }),
+    }
+}
+
+struct FailoverState {
+    current: usize,
+    failures: usize,
+    primary_failed_at: Option<time::Instant>,
+}
+
+impl FailoverState {
+    #[inline(always)]
+    fn should_probe_primary(&self, interval: time::Duration) -> bool {
+        match self.primary_failed_at {
+            Some(failed_at) => self.current != 0 && failed_at.elapsed() >= interval,
+            None => self.current != 0,
+        }
+    }
+}
+
+///`MakeWriter` that fails over between multiple endpoints, preferring the last successfully used
+///one.
+///
+///Created via `failover`.
+pub struct Failover<MW> {
+    endpoints: Vec<MW>,
+    probe_interval: time::Duration,
+    max_failures: usize,
+    state: Mutex<FailoverState>,
+}
+
+impl<MW: MakeWriter> Failover<MW> {
+    #[inline(always)]
+    ///Configures interval after which primary endpoint is probed again.
+    pub fn probe_interval(mut self, interval: time::Duration) -> Self {
+        self.probe_interval = interval;
+        self
+    }
+
+    #[inline(always)]
+    ///Sets number of consecutive failures, after which endpoint is tried last.
+    pub fn max_failures(mut self, max_failures: core::num::NonZeroUsize) -> Self {
+        self.max_failures = max_failures.get();
+        self
+    }
+
+    #[inline]
+    fn lock(&self) -> std::sync::MutexGuard<'_, FailoverState> {
+        self.state.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    ///Returns order in which endpoints are tried.
+    ///
+    ///Primary endpoint, that failed within probe interval, and demoted endpoint are tried last.
+    fn order(&self, state: &FailoverState) -> Vec<usize> {
+        let mut order = Vec::with_capacity(self.endpoints.len());
+        let is_primary_due = state.current == 0 || state.should_probe_primary(self.probe_interval);
+        if is_primary_due {
+            order.push(0);
+        }
+        let is_demoted = state.failures >= self.max_failures;
+        if !is_demoted && state.current != 0 {
+            order.push(state.current);
+        }
+        for idx in 1..self.endpoints.len() {
+            if idx != state.current && !order.contains(&idx) {
+                order.push(idx);
+            }
+        }
+        if !is_primary_due {
+            order.push(0);
+        }
+        if is_demoted {
+            order.retain(|idx| *idx != state.current);
+            order.push(state.current);
+        }
+        order
+    }
+}
+
+impl<MW: MakeWriter + Sync> MakeWriter for Failover<MW> {
+    type Writer = FailoverWriter<MW::Writer>;
+
+    #[inline(always)]
+    fn make(&self) -> io::Result<Self::Writer> {
+        self.make_with(&MakeContext::new())
+    }
+
+    fn make_with(&self, ctx: &MakeContext) -> io::Result<Self::Writer> {
+        if self.endpoints.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no fluentd address provided"));
+        }
+
+        let mut state = self.lock();
+        //Failed attempts to create writer are already counted.
+        match (ctx.previous_failed, ctx.attempt) {
+            (true, 0) => state.failures += 1,
+            (true, _) => (),
+            (false, _) => state.failures = 0,
+        }
+
+        for idx in self.order(&state) {
+            match self.endpoints[idx].make_with(ctx) {
+                Ok(writer) => {
+                    if idx != state.current {
+                        state.current = idx;
+                        state.failures = 0;
+                    }
+                    match idx {
+                        0 => state.primary_failed_at = None,
+                        //Primary is demoted, rather than failed to connect.
+                        _ => if state.primary_failed_at.is_none() {
+                            state.primary_failed_at = Some(time::Instant::now());
+                        },
+                    }
+                    return Ok(FailoverWriter {
+                        writer,
+                        endpoint: idx,
+                    });
+                },
+                Err(_) => {
+                    if idx == 0 {
+                        state.primary_failed_at = Some(time::Instant::now());
+                    }
+                    if idx == state.current {
+                        state.failures += 1;
+                    }
+                },
+            }
+        }
+
+        Err(io::Error::new(io::ErrorKind::NotFound, "cannot connect to fluentd"))
+    }
+
+    #[inline]
+    fn probe(&self, writer: &mut Self::Writer) -> io::Result<()> {
+        self.endpoints[writer.endpoint].probe(&mut writer.writer)
+    }
+
+    #[inline]
+    fn is_outdated(&self, writer: &Self::Writer) -> bool {
+        if self.endpoints[writer.endpoint].is_outdated(&writer.writer) {
+            return true;
+        }
+        writer.endpoint != 0 && self.lock().should_probe_primary(self.probe_interval)
+    }
+}
+
+///Writer created by `Failover`.
+pub struct FailoverWriter<W> {
+    writer: W,
+    endpoint: usize,
+}
+
+impl<W> FailoverWriter<W> {
+    #[inline(always)]
+    ///Returns index of endpoint, that created writer.
+    pub fn endpoint(&self) -> usize {
+        self.endpoint
+    }
+}
+
+impl<W: Write> Write for FailoverWriter<W> {
+    #[inline(always)]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    #[inline(always)]
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+struct RotatingState {
+    dir: PathBuf,
+    name: String,
+    max_size: u64,
+    max_files: usize,
+    file: Option<fs::File>,
+    size: u64,
+}
+
+impl RotatingState {
+    #[inline(always)]
+    fn path(&self, idx: usize) -> PathBuf {
+        match idx {
+            0 => self.dir.join(&self.name),
+            idx => self.dir.join(format!("{}.{}", self.name, idx)),
+        }
+    }
+
+    fn file(&mut self) -> io::Result<&mut fs::File> {
+        match self.file {
+            Some(ref mut file) => Ok(file),
+            None => {
+                let file = fs::OpenOptions::new().append(true).create(true).open(self.path(0))?;
+                self.size = file.metadata()?.len();
+                Ok(self.file.get_or_insert(file))
+            }
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        self.size = 0;
+
+        if self.max_files <= 1 {
+            return fs::remove_file(self.path(0));
+        }
+
+        let oldest = self.path(self.max_files - 1);
+        if oldest.exists() {
+            fs::remove_file(oldest)?;
+        }
+
+        for idx in (0..self.max_files - 1).rev() {
+            let from = self.path(idx);
+            if from.exists() {
+                fs::rename(from, self.path(idx + 1))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+///`MakeWriter` that appends records to the file, rotating it once it exceeds size limit.
+///
+///Active file is `<dir>/<name>`, while rotated files are named `<name>.1` (most recent) to
+///`<name>.<max_files - 1>` (oldest).
+///Files beyond `max_files` are removed.
+///
+///Rotation happens only when writer is flushed, which worker does after writing each message,
+///hence every file contains only complete messages.
+///As a consequence file may exceed limit by size of the last message.
+///
+///All writers created by it share the same state.
+pub struct RotatingFile {
+    state: Arc<Mutex<RotatingState>>,
+}
+
+impl RotatingFile {
+    ///Creates new instance writing into `<dir>/<name>`.
+    ///
+    ///Defaults to max file size of 10MiB and 5 files.
+    pub fn new<D: Into<PathBuf>, N: Into<String>>(dir: D, name: N) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(RotatingState {
+                dir: dir.into(),
+                name: name.into(),
+                max_size: 10 * 1024 * 1024,
+                max_files: 5,
+                file: None,
+                size: 0,
+            }))
+        }
+    }
+
+    #[inline]
+    ///Sets max size of file in bytes, after which it is rotated.
+    pub fn max_size(self, max_size: u64) -> Self {
+        self.lock().max_size = max_size;
+        self
+    }
+
+    #[inline]
+    ///Sets max number of files, including the active one.
+    pub fn max_files(self, max_files: core::num::NonZeroUsize) -> Self {
+        self.lock().max_files = max_files.get();
+        self
+    }
+
+    #[inline(always)]
+    fn lock(&self) -> std::sync::MutexGuard<'_, RotatingState> {
+        self.state.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+impl MakeWriter for RotatingFile {
+    type Writer = RotatingFileWriter;
+
+    #[inline]
+    fn make(&self) -> io::Result<Self::Writer> {
+        self.lock().file()?;
+        Ok(RotatingFileWriter {
+            state: self.state.clone(),
+        })
+    }
+}
+
+///Writer created by `RotatingFile`
+pub struct RotatingFileWriter {
+    state: Arc<Mutex<RotatingState>>,
+}
+
+impl Write for RotatingFileWriter {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap_or_else(|error| error.into_inner());
+        let size = state.file()?.write(buf)?;
+        state.size += size as u64;
+        Ok(size)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|error| error.into_inner());
+        state.file()?.flush()?;
+        if state.size >= state.max_size {
+            state.rotate()?;
+        }
+        Ok(())
+    }
+}
+
+type LookupFn = dyn Fn(&str) -> io::Result<Vec<SocketAddr>> + Send + Sync;
+
+///Creates writer that connects to `addr`, caching result of its resolution.
+///
+///By default resolved addresses are cached for 60s and re-resolved after 3 consecutive failures
+///to connect.
+pub fn resolve<A: Into<String>>(addr: A) -> Resolver {
+    Resolver {
+        addr: addr.into(),
+        ttl: time::Duration::from_secs(60),
+        max_failures: 3,
+        lookup: Box::new(|addr| addr.to_socket_addrs().map(Iterator::collect)),
+        state: Mutex::new(ResolverState {
+            addrs: Vec::new(),
+            resolved_at: None,
+            next: 0,
+            failures: 0,
+        }),
+    }
+}
+
+struct ResolverState {
+    addrs: Vec<SocketAddr>,
+    resolved_at: Option<time::Instant>,
+    next: usize,
+    failures: usize,
+}
+
+///`MakeWriter` that connects to the host, caching its resolved addresses.
+///
+///Created via `resolve`.
+///
+///Addresses are tried in order, starting from the last successfully connected one.
+///Cache is refreshed once `ttl` expires or after `max_failures` consecutive failures to connect
+///to any of the addresses.
+pub struct Resolver {
+    addr: String,
+    ttl: time::Duration,
+    max_failures: usize,
+    lookup: Box<LookupFn>,
+    state: Mutex<ResolverState>,
+}
+
+impl Resolver {
+    #[inline(always)]
+    ///Sets duration for which resolved addresses are cached.
+    pub fn ttl(mut self, ttl: time::Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    #[inline(always)]
+    ///Sets number of consecutive failures to connect, after which address is re-resolved.
+    pub fn max_failures(mut self, max_failures: core::num::NonZeroUsize) -> Self {
+        self.max_failures = max_failures.get();
+        self
+    }
+
+    #[inline]
+    ///Replaces function used to resolve address.
+    ///
+    ///Default is to use `ToSocketAddrs`.
+    pub fn with_lookup<F: Fn(&str) -> io::Result<Vec<SocketAddr>> + Send + Sync + 'static>(mut self, lookup: F) -> Self {
+        self.lookup = Box::new(lookup);
+        self
+    }
+}
+
+impl MakeWriter for Resolver {
+    type Writer = TcpStream;
+
+    fn make(&self) -> io::Result<Self::Writer> {
+        let mut state = self.state.lock().unwrap_or_else(|error| error.into_inner());
+
+        let is_expired = match state.resolved_at {
+            Some(resolved_at) => resolved_at.elapsed() >= self.ttl || state.failures >= self.max_failures,
+            None => true,
+        };
+
+        if is_expired {
+            let addrs = match (self.lookup)(&self.addr) {
+                Ok(addrs) => addrs,
+                Err(error) => return Err(io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve fluentd address: {}", error))),
+            };
+            state.addrs = addrs;
+            state.resolved_at = Some(time::Instant::now());
+            state.next = 0;
+            state.failures = 0;
+        }
+
+        let len = state.addrs.len();
+        for _ in 0..len {
+            let addr = state.addrs[state.next];
+            match TcpStream::connect_timeout(&addr, time::Duration::from_secs(1)) {
+                Ok(socket) => {
+                    state.failures = 0;
+                    return Ok(socket);
+                },
+                Err(_) => state.next = (state.next + 1) % len,
+            }
+        }
+
+        state.failures += 1;
+        Err(io::Error::new(io::ErrorKind::NotFound, "cannot connect to fluentd"))
+    }
+
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> io::Result<()> {
+        ProbeWrite::probe(writer)
+    }
+}
+
+///Type-erased writer, produced by `BoxMakeWriter`.
+pub type BoxWriter = Box<dyn Write + Send>;
+
+///Object-safe version of `MakeWriter`, producing `BoxWriter`.
+///
+///Implemented for every `MakeWriter` which writer is `Send`.
+pub trait DynMakeWriter: 'static + Send {
+    ///Creates new boxed writer.
+    fn make_boxed(&self) -> io::Result<BoxWriter>;
+    ///Creates new boxed writer, with context of the attempt.
+    fn make_boxed_with(&self, ctx: &MakeContext) -> io::Result<BoxWriter>;
+}
+
+impl<M: MakeWriter> DynMakeWriter for M where M::Writer: Send + 'static {
+    #[inline(always)]
+    fn make_boxed(&self) -> io::Result<BoxWriter> {
+        self.make().map(|writer| Box::new(writer) as BoxWriter)
+    }
+
+    #[inline(always)]
+    fn make_boxed_with(&self, ctx: &MakeContext) -> io::Result<BoxWriter> {
+        self.make_with(ctx).map(|writer| Box::new(writer) as BoxWriter)
+    }
+}
+
+///Type-erased `MakeWriter`.
+///
+///Allows to select writer at runtime, while keeping the same type of `Builder`.
+///
+///Note that type-erased writer cannot be probed, hence `MakeWriter::probe` does nothing.
+pub struct BoxMakeWriter {
+    inner: Box<dyn DynMakeWriter>,
+}
+
+impl BoxMakeWriter {
+    #[inline]
+    ///Wraps `writer` into box.
+    pub fn new<M: MakeWriter>(writer: M) -> Self where M::Writer: Send + 'static {
+        Self {
+            inner: Box::new(writer),
+        }
+    }
+}
+
+impl MakeWriter for BoxMakeWriter {
+    type Writer = BoxWriter;
+
+    #[inline(always)]
+    fn make(&self) -> io::Result<Self::Writer> {
+        self.inner.make_boxed()
+    }
+
+    #[inline(always)]
+    fn make_with(&self, ctx: &MakeContext) -> io::Result<Self::Writer> {
+        self.inner.make_boxed_with(ctx)
+    }
+}
+
+///Creates writer that buffers writes, passing them to the `writer` only once buffer exceeds
+///threshold or on flush.
+///
+///Since worker flushes writer after every message, this coalesces multiple small writes, performed
+///during serialization of message, into a single write.
+///
+///Default threshold is 8KiB.
+pub fn buffered<MW: MakeWriter>(writer: MW) -> Buffered<MW> {
+    Buffered {
+        writer,
+        threshold: 8 * 1024,
+    }
+}
+
+///`MakeWriter` that buffers writes of underlying writer.
+///
+///Created via `buffered`.
+pub struct Buffered<MW> {
+    writer: MW,
+    threshold: usize,
+}
+
+impl<MW: MakeWriter> Buffered<MW> {
+    #[inline(always)]
+    ///Sets size of buffer in bytes, after which it is written into underlying writer.
+    pub fn threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+}
+
+impl<MW: MakeWriter> MakeWriter for Buffered<MW> {
+    type Writer = BufferedWriter<MW::Writer>;
+
+    #[inline(always)]
+    fn make(&self) -> io::Result<Self::Writer> {
+        self.writer.make().map(|writer| BufferedWriter::new(writer, self.threshold))
+    }
+
+    #[inline(always)]
+    fn make_with(&self, ctx: &MakeContext) -> io::Result<Self::Writer> {
+        self.writer.make_with(ctx).map(|writer| BufferedWriter::new(writer, self.threshold))
+    }
+
+    #[inline(always)]
+    fn probe(&self, writer: &mut Self::Writer) -> io::Result<()> {
+        self.writer.probe(&mut writer.inner)
+    }
+
+    #[inline(always)]
+    fn is_outdated(&self, writer: &Self::Writer) -> bool {
+        self.writer.is_outdated(&writer.inner)
+    }
+}
+
+///Writer created by `Buffered`.
+///
+///Buffer is discarded on failure to write it, as worker re-sends whole message with new writer.
+///Hence any buffered data is not written on drop, unless flushed.
+pub struct BufferedWriter<W> {
+    inner: W,
+    buffer: Vec<u8>,
+    threshold: usize,
+}
+
+impl<W: Write> BufferedWriter<W> {
+    #[inline(always)]
+    fn new(inner: W, threshold: usize) -> Self {
+        Self {
+            inner,
+            buffer: Vec::with_capacity(threshold),
+            threshold,
+        }
+    }
+
+    fn write_buffer(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let result = self.inner.write_all(&self.buffer);
+        self.buffer.clear();
+        result
+    }
+}
+
+impl<W: Write> Write for BufferedWriter<W> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= self.threshold {
+            self.write_buffer()?;
+        }
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_buffer()?;
+        self.inner.flush()
+    }
+}
+
+///Creates writer, which target can be replaced at runtime via `WriterHandle`.
+///
+///Handle is obtained via `Switchable::handle`, e.g. `builder.writer().handle()`.
+pub fn switchable<MW: MakeWriter>(writer: MW) -> Switchable where MW::Writer: Send + 'static {
+    Switchable {
+        state: Arc::new(SwitchState {
+            target: Mutex::new(BoxMakeWriter::new(writer)),
+            generation: AtomicU64::new(0),
+        }),
+    }
+}
+
+struct SwitchState {
+    target: Mutex<BoxMakeWriter>,
+    //Incremented on every change of target.
+    generation: AtomicU64,
+}
+
+///`MakeWriter` which target can be replaced at runtime.
+///
+///Created via `switchable`.
+///
+///Once target is replaced, worker drops writer created by previous target before writing next
+///message, while message that is being written is finished with previous writer.
+pub struct Switchable {
+    state: Arc<SwitchState>,
+}
+
+impl Switchable {
+    #[inline(always)]
+    ///Returns handle to replace target of this writer.
+    pub fn handle(&self) -> WriterHandle {
+        WriterHandle {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl MakeWriter for Switchable {
+    type Writer = SwitchableWriter;
+
+    #[inline(always)]
+    fn make(&self) -> io::Result<Self::Writer> {
+        self.make_with(&MakeContext::new())
+    }
+
+    fn make_with(&self, ctx: &MakeContext) -> io::Result<Self::Writer> {
+        let target = self.state.target.lock().unwrap_or_else(|error| error.into_inner());
+        //Target cannot change while lock is held.
+        let generation = self.state.generation.load(Ordering::Acquire);
+        Ok(SwitchableWriter {
+            inner: target.make_with(ctx)?,
+            generation,
+        })
+    }
+
+    #[inline(always)]
+    fn is_outdated(&self, writer: &Self::Writer) -> bool {
+        writer.generation != self.state.generation.load(Ordering::Acquire)
+    }
+}
+
+///Writer created by `Switchable`.
+pub struct SwitchableWriter {
+    inner: BoxWriter,
+    generation: u64,
+}
+
+impl Write for SwitchableWriter {
+    #[inline(always)]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    #[inline(always)]
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)
+    }
+
+    #[inline(always)]
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[derive(Clone)]
+///Handle to replace target of `Switchable` writer.
+pub struct WriterHandle {
+    state: Arc<SwitchState>,
+}
+
+impl WriterHandle {
+    ///Replaces target, used to create writers starting with the next message.
+    pub fn set_target<MW: MakeWriter>(&self, writer: MW) where MW::Writer: Send + 'static {
+        let mut target = self.state.target.lock().unwrap_or_else(|error| error.into_inner());
+        *target = BoxMakeWriter::new(writer);
+        self.state.generation.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+struct ConnectionState {
+    writer: BoxMakeWriter,
+    cached: Option<BoxWriter>,
+    //Incremented on every new connection.
+    generation: u64,
+}
+
+#[derive(Clone)]
+///Connection, that can be shared by multiple workers via `Builder::with_shared_connection`.
+///
+///Connection is created on demand via underlying writer and cached until any worker fails to write
+///into it, in which case it is re-created for all workers.
+///Every worker buffers its message and writes it as a whole while holding lock of connection,
+///hence messages are never interleaved.
+pub struct Connection {
+    state: Arc<Mutex<ConnectionState>>,
+}
+
+impl Connection {
+    ///Creates new connection, created via `writer` on demand.
+    pub fn new<MW: MakeWriter>(writer: MW) -> Self where MW::Writer: Send + 'static {
+        Self {
+            state: Arc::new(Mutex::new(ConnectionState {
+                writer: BoxMakeWriter::new(writer),
+                cached: None,
+                generation: 0,
+            })),
+        }
+    }
+
+    #[inline(always)]
+    fn lock(&self) -> std::sync::MutexGuard<'_, ConnectionState> {
+        self.state.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+impl MakeWriter for Connection {
+    type Writer = ConnectionWriter;
+
+    #[inline(always)]
+    fn make(&self) -> io::Result<Self::Writer> {
+        self.make_with(&MakeContext::new())
+    }
+
+    fn make_with(&self, ctx: &MakeContext) -> io::Result<Self::Writer> {
+        let mut state = self.lock();
+        if state.cached.is_none() {
+            state.cached = Some(state.writer.make_with(ctx)?);
+            state.generation = state.generation.wrapping_add(1);
+        }
+
+        Ok(ConnectionWriter {
+            connection: self.clone(),
+            generation: state.generation,
+            buffer: Vec::new(),
+        })
+    }
+
+    #[inline]
+    fn is_outdated(&self, writer: &Self::Writer) -> bool {
+        let state = self.lock();
+        state.cached.is_none() || state.generation != writer.generation
+    }
+}
+
+///Writer created by `Connection`.
+///
+///Writes are buffered until flush, which writes whole buffer into shared connection.
+pub struct ConnectionWriter {
+    connection: Connection,
+    generation: u64,
+    buffer: Vec<u8>,
+}
+
+impl Write for ConnectionWriter {
+    #[inline(always)]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut state = self.connection.lock();
+        let generation = state.generation;
+        let result = match state.cached.as_mut() {
+            Some(writer) if generation == self.generation => writer.write_all(&self.buffer).and_then(|_| writer.flush()),
+            _ => Err(io::Error::new(io::ErrorKind::NotConnected, "Shared connection is re-created")),
+        };
+        self.buffer.clear();
+
+        if result.is_err() && generation == self.generation {
+            //Connection may contain partially written message, so it must not be used by anyone.
+            state.cached = None;
+        }
+        result
+    }
+}
+
+///`MakeWriter` over existing writer, shared via mutex, e.g. connection established beforehand.
+///
+///Created writers forward writes into the shared writer, so it is not closed when worker drops
+///them.
+///Every write is performed as whole while holding lock, and worker writes each message via
+///single write, hence messages of multiple workers are never interleaved.
+///
+///If mutex is poisoned, it is reported as error of writer.
+pub struct Shared<W> {
+    writer: Arc<Mutex<W>>,
+}
+
+impl<W> Shared<W> {
+    #[inline(always)]
+    ///Creates new instance, taking ownership of `writer`.
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(writer)),
+        }
+    }
+
+    #[inline(always)]
+    ///Returns shared writer.
+    pub fn get(&self) -> &Arc<Mutex<W>> {
+        &self.writer
+    }
+}
+
+impl<W> Clone for Shared<W> {
+    #[inline(always)]
+    fn clone(&self) -> Self {
+        Self {
+            writer: self.writer.clone(),
+        }
+    }
+}
+
+impl<W> From<Arc<Mutex<W>>> for Shared<W> {
+    #[inline(always)]
+    fn from(writer: Arc<Mutex<W>>) -> Self {
+        Self {
+            writer,
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> MakeWriter for Shared<W> {
+    type Writer = SharedWriter<W>;
+
+    #[inline(always)]
+    fn make(&self) -> io::Result<Self::Writer> {
+        Ok(SharedWriter {
+            writer: self.writer.clone(),
+        })
+    }
+}
+
+///Writer created by `Shared`.
+pub struct SharedWriter<W> {
+    writer: Arc<Mutex<W>>,
+}
+
+impl<W> SharedWriter<W> {
+    #[inline(always)]
+    fn lock(&self) -> io::Result<std::sync::MutexGuard<'_, W>> {
+        self.writer.lock().map_err(|_| io::Error::other("Shared writer is poisoned"))
+    }
+}
+
+impl<W: Write> Write for SharedWriter<W> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock()?.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock()?.flush()
+    }
+}
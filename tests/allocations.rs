@@ -0,0 +1,66 @@
+//!Counts allocations of thread, that emits events.
+use tracing_subscriber::Registry;
+use tracing_subscriber::layer::SubscriberExt;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use core::cell::Cell;
+
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
+
+const EVENTS: usize = 100;
+
+fn count<F: tracing_fluentd::FieldFormatter + Send + Sync>(builder: tracing_fluentd::Builder<F>) -> usize {
+    let dispatch = tracing::Dispatch::new(Registry::default().with(builder.with_thread_info().disabled_layer()));
+    tracing::dispatcher::with_default(&dispatch, || {
+        //Warm up caches of callsite.
+        let emit = || tracing::info!(idx = 1, "message");
+        emit();
+
+        let before = allocations();
+        for _ in 0..EVENTS {
+            emit();
+        }
+        allocations() - before
+    })
+}
+
+#[test]
+fn should_allocate_per_event_only_record_and_message() {
+    //Metadata and thread info are shared, hence flatten record allocates only its map and message.
+    let flatten = count(tracing_fluentd::Builder::new("rust").flatten());
+    assert_eq!(flatten, 2 * EVENTS);
+
+    //Nested record additionally allocates object of metadata.
+    let nested = count(tracing_fluentd::Builder::new("rust"));
+    assert_eq!(nested, 3 * EVENTS);
+}
@@ -0,0 +1,126 @@
+#![cfg(feature = "async-std")]
+
+use tracing_subscriber::Registry;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_fluentd::async_worker::{AsyncStd, tcp};
+use tracing_fluentd::testing::{MemoryWriter, MockFluentd, Fault};
+
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+fn idx(record: &rmpv::Value) -> Option<u64> {
+    record.as_map().expect("map").iter().find(|(key, _)| key.as_str() == Some("idx")).and_then(|(_, value)| value.as_u64())
+}
+
+#[test]
+fn should_send_records_via_async_worker() {
+    let fluentd = MockFluentd::start().expect("start fluentd");
+    let layer = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(2).unwrap())
+                                                     .layer_async(tcp(fluentd.addr()), AsyncStd);
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    for idx in 0..5 {
+        tracing::info!(idx, "async");
+    }
+    drop(guard);
+
+    let records = fluentd.wait_for_records(5, Duration::from_secs(5));
+    fluentd.assert_record_count(5);
+    for (expected, record) in records.iter().enumerate() {
+        assert_eq!(idx(record), Some(expected as u64));
+    }
+    assert_eq!(fluentd.connections(), 1);
+}
+
+#[test]
+fn should_retry_connection_of_async_worker() {
+    let fluentd = MockFluentd::start().expect("start fluentd");
+    fluentd.set_fault(Fault::Refuse);
+    std::thread::sleep(Duration::from_millis(50));
+
+    let layer = tracing_fluentd::Builder::new("rust").with_eager_flush_level(tracing::Level::INFO)
+                                                     .layer_async(tcp(fluentd.addr()), AsyncStd);
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!(idx = 0, "refused");
+    std::thread::sleep(Duration::from_millis(100));
+    fluentd.set_fault(Fault::None);
+
+    let records = fluentd.wait_for_records(1, Duration::from_secs(5));
+    assert_eq!(idx(&records[0]), Some(0));
+    drop(guard);
+}
+
+struct CountingWrite {
+    writer: MemoryWriter,
+    writes: Arc<AtomicUsize>,
+    fail: bool,
+}
+
+impl futures_io::AsyncWrite for CountingWrite {
+    fn poll_write(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        match self.fail {
+            true => Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken"))),
+            false => Poll::Ready(self.writer.write(buf)),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[test]
+fn should_retry_whole_message_of_async_worker_after_failure() {
+    let (memory, reader) = MemoryWriter::new();
+    let writes = Arc::new(AtomicUsize::new(0));
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    let writer = {
+        let writes = writes.clone();
+        let connections = connections.clone();
+        move || {
+            let writer = CountingWrite {
+                writer: memory.clone(),
+                writes: writes.clone(),
+                //Only first connection is broken
+                fail: connections.fetch_add(1, Ordering::SeqCst) == 0,
+            };
+            async move {
+                Ok(writer)
+            }
+        }
+    };
+    let layer = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                     .layer_async(writer, AsyncStd);
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    for idx in 0..5 {
+        tracing::info!(idx, "retry");
+    }
+    drop(guard);
+
+    for _ in 0..250 {
+        if reader.records().len() == 5 {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    //Failed connection is dropped, and message is re-sent in full over new one.
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+    let frames = reader.frames();
+    assert_eq!(writes.load(Ordering::SeqCst), frames.len() + 1);
+
+    let records = reader.records();
+    assert_eq!(records.len(), 5);
+    for (expected, record) in records.iter().enumerate() {
+        assert_eq!(idx(record), Some(expected as u64));
+    }
+}
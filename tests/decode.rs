@@ -0,0 +1,186 @@
+use tracing_fluentd::fluent::{self, decode};
+use tracing_fluentd::testing::MemoryWriter;
+
+use tracing_subscriber::Registry;
+use tracing_subscriber::layer::SubscriberExt;
+
+fn record(time: core::time::Duration, idx: u64) -> fluent::Record {
+    let mut record = fluent::Record::with_time(time);
+    record.insert("message".into(), "test".into());
+    record.insert("idx".into(), idx.into());
+    record.insert("offset".into(), (-1i64).into());
+    record.insert("ratio".into(), 0.5.into());
+    record.insert("flag".into(), true.into());
+    record.insert("none".into(), fluent::Value::Null);
+    let mut nested = fluent::Map::new();
+    nested.insert("list".into(), vec![fluent::Value::from(1u64), "two".into()].into());
+    record.insert("nested".into(), nested.into());
+    record
+}
+
+fn assert_record(record: &fluent::Record, time: core::time::Duration, idx: u64) {
+    assert_eq!(record.time(), time);
+    assert_eq!(record["message"].as_str(), Some("test"));
+    assert!(matches!(record["idx"], fluent::Value::Uint(value) if value == idx));
+    assert!(matches!(record["offset"], fluent::Value::Int(-1)));
+    assert!(matches!(record["ratio"], fluent::Value::Float(value) if value == 0.5));
+    assert!(matches!(record["flag"], fluent::Value::Bool(true)));
+    assert!(matches!(record["none"], fluent::Value::Null));
+    match &record["nested"] {
+        fluent::Value::Object(nested) => match &nested["list"] {
+            fluent::Value::Array(list) => {
+                assert!(matches!(list[0], fluent::Value::Uint(1)));
+                assert_eq!(list[1].as_str(), Some("two"));
+            },
+            list => panic!("Unexpected list {:?}", list),
+        },
+        nested => panic!("Unexpected nested {:?}", nested),
+    }
+}
+
+#[test]
+fn should_round_trip_forward_mode() {
+    let time = core::time::Duration::from_secs(1_700_000_000);
+    let sections = [
+        fluent::OptionSection::Size,
+        fluent::OptionSection::Omit,
+        fluent::OptionSection::Custom({
+            let mut fields = fluent::Map::new();
+            fields.insert("chunk".into(), "abc".into());
+            fields
+        }),
+    ];
+
+    for section in sections {
+        let mut message = fluent::Message::new("rust");
+        message.set_option_section(section.clone());
+        message.add(record(time, 0));
+        message.add(record(time, 1));
+        let bytes = rmp_serde::to_vec(&message).expect("encode");
+
+        let frame = decode::read_frame(&mut bytes.as_slice()).expect("decode").expect("frame");
+        assert_eq!(frame.mode, decode::Mode::Forward);
+        assert_eq!(frame.message.tag(), "rust");
+        assert_eq!(frame.message.len(), 2);
+        for (idx, record) in frame.message.records().iter().enumerate() {
+            assert_record(record, time, idx as u64);
+        }
+        match (section, frame.message.option_section()) {
+            (fluent::OptionSection::Size, fluent::OptionSection::Size) | (fluent::OptionSection::Omit, fluent::OptionSection::Omit) => (),
+            (fluent::OptionSection::Custom(_), fluent::OptionSection::Custom(fields)) => {
+                assert_eq!(fields.len(), 1);
+                assert_eq!(fields["chunk"].as_str(), Some("abc"));
+            },
+            (expected, section) => panic!("Expected {:?}, got {:?}", expected, section),
+        }
+
+        //Decoded message is encoded the same way.
+        assert_eq!(rmp_serde::to_vec(&frame.message).expect("encode"), bytes);
+    }
+}
+
+#[test]
+fn should_round_trip_packed_forward_mode() {
+    let time = core::time::Duration::from_secs(1_700_000_000);
+    let mut message = fluent::PackedMessage::new("rust");
+    for idx in 0..3 {
+        message.add(&record(time, idx)).expect("encode record");
+    }
+    let bytes = rmp_serde::to_vec(&message).expect("encode");
+
+    let (frames, error) = decode::read_frames(bytes.as_slice());
+    assert!(error.is_none(), "{:?}", error);
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].mode, decode::Mode::PackedForward);
+    assert_eq!(frames[0].message.tag(), "rust");
+    assert_eq!(frames[0].message.len(), 3);
+    for (idx, record) in frames[0].message.records().iter().enumerate() {
+        assert_record(record, time, idx as u64);
+    }
+    assert!(matches!(frames[0].message.option_section(), fluent::OptionSection::Size));
+}
+
+#[test]
+fn should_decode_message_mode_with_event_time() {
+    let time = core::time::Duration::new(1_700_000_000, 123_456_789);
+    let mut event_time = Vec::new();
+    event_time.extend_from_slice(&(time.as_secs() as u32).to_be_bytes());
+    event_time.extend_from_slice(&time.subsec_nanos().to_be_bytes());
+    let record = rmpv::Value::Map(vec![("message".into(), "test".into())]);
+    let options = rmpv::Value::Map(vec![("size".into(), 1.into()), ("chunk".into(), "abc".into())]);
+    let frame = rmpv::Value::Array(vec!["rust".into(), rmpv::Value::Ext(0, event_time), record, options]);
+    let mut bytes = Vec::new();
+    rmpv::encode::write_value(&mut bytes, &frame).expect("encode");
+
+    let frame = decode::read_frame(&mut bytes.as_slice()).expect("decode").expect("frame");
+    assert_eq!(frame.mode, decode::Mode::Message);
+    assert_eq!(frame.message.len(), 1);
+    assert_eq!(frame.message.records()[0].time(), time);
+    assert_eq!(frame.message.records()[0]["message"].as_str(), Some("test"));
+    match frame.message.option_section() {
+        fluent::OptionSection::Custom(fields) => assert_eq!(fields["chunk"].as_str(), Some("abc")),
+        section => panic!("Unexpected section {:?}", section),
+    }
+}
+
+#[test]
+fn should_return_decoded_frames_before_trailing_garbage() {
+    let time = core::time::Duration::from_secs(1_700_000_000);
+    let mut bytes = Vec::new();
+    for idx in 0..2 {
+        let mut message = fluent::Message::new("rust");
+        message.add(record(time, idx));
+        bytes.extend(rmp_serde::to_vec(&message).expect("encode"));
+    }
+
+    //Truncated message
+    let mut truncated = bytes.clone();
+    truncated.extend_from_slice(&bytes[..bytes.len() / 2 - 3]);
+    let (frames, error) = decode::read_frames(truncated.as_slice());
+    assert_eq!(frames.len(), 2);
+    assert!(matches!(error, Some(decode::Error::Read(_))), "{:?}", error);
+
+    //Valid msgpack, but not message
+    let mut invalid = bytes.clone();
+    rmpv::encode::write_value(&mut invalid, &rmpv::Value::from(1)).expect("encode");
+    let (frames, error) = decode::read_frames(invalid.as_slice());
+    assert_eq!(frames.len(), 2);
+    assert!(matches!(error, Some(decode::Error::Invalid(_))), "{:?}", error);
+
+    let (frames, error) = decode::read_frames(&[][..]);
+    assert!(frames.is_empty());
+    assert!(error.is_none());
+}
+
+#[test]
+fn should_decode_messages_written_by_layer() {
+    for packed in [false, true] {
+        let (test_writer, reader) = MemoryWriter::new();
+        let builder = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(2).unwrap())
+                                                           .with_writer(test_writer);
+        let builder = match packed {
+            true => builder.with_packed_forward(),
+            false => builder,
+        };
+        let layer = builder.layer().expect("Create layer");
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            for idx in 0..4u64 {
+                tracing::info!(idx, "decoded");
+            }
+        });
+
+        let (frames, error) = decode::read_frames(reader.bytes().as_slice());
+        assert!(error.is_none(), "{:?}", error);
+        let expected_mode = match packed {
+            true => decode::Mode::PackedForward,
+            false => decode::Mode::Forward,
+        };
+        assert!(frames.iter().all(|frame| frame.mode == expected_mode));
+        let records = frames.iter().flat_map(|frame| frame.message.records()).collect::<Vec<_>>();
+        assert_eq!(records.len(), 4);
+        for (idx, record) in records.iter().enumerate() {
+            assert_eq!(record["message"].as_str(), Some("decoded"));
+            assert!(matches!(record["idx"], fluent::Value::Uint(value) if value == idx as u64));
+        }
+    }
+}
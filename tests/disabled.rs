@@ -0,0 +1,41 @@
+use tracing_subscriber::Registry;
+use tracing_subscriber::layer::SubscriberExt;
+
+#[cfg(target_os = "linux")]
+fn threads() -> usize {
+    std::fs::read_dir("/proc/self/task").expect("read tasks").count()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn threads() -> usize {
+    0
+}
+
+#[test]
+fn should_count_records_of_disabled_layer_without_worker() {
+    let writer = || -> std::io::Result<std::io::Sink> {
+        panic!("Writer must not be created");
+    };
+
+    let threads_before = threads();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(writer)
+                                                     .flatten()
+                                                     .with_max_level(tracing_subscriber::filter::LevelFilter::INFO)
+                                                     .disabled_layer();
+    assert_eq!(threads(), threads_before);
+
+    let dispatch = tracing::Dispatch::new(Registry::default().with(layer));
+    tracing::dispatcher::with_default(&dispatch, || {
+        tracing::info_span!("request", id = 1).in_scope(|| {
+            for idx in 0..10 {
+                tracing::info!(idx, "counted");
+            }
+            tracing::debug!("filtered");
+        });
+    });
+    assert_eq!(threads(), threads_before);
+
+    let layer = dispatch.downcast_ref::<tracing_fluentd::Layer<tracing_fluentd::FlattenFmt, tracing_fluentd::NullConsumer>>().expect("layer");
+    assert_eq!(layer.consumer().records(), 10);
+    assert_eq!(layer.suppressed_events(), 0);
+}
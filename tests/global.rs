@@ -0,0 +1,29 @@
+use tracing_fluentd::testing::MemoryWriter;
+
+#[test]
+fn should_flush_pending_records_on_shutdown() {
+    let (test_writer, reader) = MemoryWriter::new();
+    tracing_fluentd::init(tracing_fluentd::Builder::new("rust").with_writer(test_writer)).expect("init");
+
+    tracing::info!(idx = 1, "first");
+    tracing::info!(idx = 2, "second");
+    //Batch is not full yet.
+    assert!(reader.records().is_empty());
+
+    tracing_fluentd::shutdown(core::time::Duration::from_secs(5));
+    let records = reader.records();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["message"].as_str(), Some("first"));
+    assert_eq!(records[1]["message"].as_str(), Some("second"));
+
+    //Subsequent calls do nothing.
+    tracing::info!("after shutdown");
+    tracing_fluentd::shutdown(core::time::Duration::from_secs(5));
+    assert_eq!(reader.records().len(), 2);
+
+    let (test_writer, _) = MemoryWriter::new();
+    match tracing_fluentd::init(tracing_fluentd::Builder::new("rust").with_writer(test_writer)) {
+        Err(tracing_fluentd::InitError::AlreadySet(_)) => (),
+        result => panic!("Unexpected result: {:?}", result),
+    }
+}
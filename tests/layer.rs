@@ -1,7 +1,7 @@
 use tracing_subscriber::Registry;
 use tracing_subscriber::layer::SubscriberExt;
 
-use std::fs;
+use tracing_fluentd::testing::{MemoryWriter, MemoryReader, TestClock};
 
 #[tracing::instrument]
 fn test_func(arg: u8) {
@@ -12,23 +12,28 @@ fn test_func(arg: u8) {
     })
 }
 
-#[track_caller]
-fn create_test_writer() -> (String, impl tracing_fluentd::MakeWriter<Writer=fs::File>) {
-    let location = core::panic::Location::caller();
-    let file_name = format!("fluent-records-{}.fluentd", location.line());
-    let name = file_name.clone();
-    (name, move || {
-        fs::OpenOptions::new().read(true)
-                              .write(true)
-                              .append(true)
-                              .create(true)
-                              .open(file_name.as_str())
-    })
+fn header_time(time: &rmpv::Value) -> core::time::Duration {
+    match tracing_fluentd::fluent::decode::event_time(time) {
+        Some(time) => time,
+        None => panic!("Unexpected time {}", time),
+    }
+}
+
+fn read_entries(reader: &MemoryReader) -> Vec<(core::time::Duration, rmpv::Value)> {
+    reader.entries().into_iter().map(|(time, record)| (header_time(&time), record)).collect()
+}
+
+fn read_records(reader: &MemoryReader) -> Vec<rmpv::Value> {
+    reader.records()
+}
+
+fn get<'a>(record: &'a rmpv::Value, key: &str) -> Option<&'a rmpv::Value> {
+    record.as_map().expect("Record map").iter().find(|(name, _)| name.as_str() == Some(key)).map(|(_, value)| value)
 }
 
 #[test]
 fn should_flatten_events_data() {
-    let (log_name, test_writer) = create_test_writer();
+    let (test_writer, reader) = MemoryWriter::new();
 
     let layer = tracing_fluentd::Builder::new("rust")
         .with_max_msg_record(core::num::NonZeroUsize::new(10).unwrap())
@@ -46,19 +51,14 @@ fn should_flatten_events_data() {
 
     drop(guard);
 
-    let mut file = fs::File::open(log_name.as_str()).expect("To open logs");
-    while let Ok(Some(output)) = rmp_serde::from_read::<_, Option<rmpv::Value>>(&mut file) {
-        let output = format!("{}", output);
+    for output in reader.frames() {
         println!("output={}", output);
     }
-
-    drop(file);
-    let _ = fs::remove_file(log_name);
 }
 
 #[test]
 fn should_nest_events_data() {
-    let (log_name, test_writer) = create_test_writer();
+    let (test_writer, reader) = MemoryWriter::new();
 
     let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).layer().expect("Create layer");
     let sub = Registry::default().with(layer);
@@ -71,12 +71,2867 @@ fn should_nest_events_data() {
 
     drop(guard);
 
-    let mut file = fs::File::open(log_name.as_str()).expect("To open logs");
-    while let Ok(Some(output)) = rmp_serde::from_read::<_, Option<rmpv::Value>>(&mut file) {
-        let output = format!("{}", output);
+    for output in reader.frames() {
         println!("output={}", output);
     }
+}
+
+#[test]
+fn should_use_uppercase_level_by_default() {
+    let (test_writer, reader) = MemoryWriter::new();
+
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).flatten().layer().expect("Create layer");
+    let sub = Registry::default().with(layer);
+
+    let guard = tracing::subscriber::set_default(sub);
+    tracing::info!("info");
+    tracing::warn!("warn");
+    drop(guard);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 2);
+    assert_eq!(get(&records[0], "level").and_then(|level| level.as_str()), Some("INFO"));
+    assert_eq!(get(&records[1], "level").and_then(|level| level.as_str()), Some("WARN"));
+}
+
+#[test]
+fn should_use_lowercase_level() {
+    let (test_writer, reader) = MemoryWriter::new();
+
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).with_lowercase_level().layer().expect("Create layer");
+    let sub = Registry::default().with(layer);
+
+    let guard = tracing::subscriber::set_default(sub);
+    tracing::info!("info");
+    tracing::warn!("warn");
+    drop(guard);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 2);
+    let metadata = get(&records[0], "metadata").expect("metadata");
+    assert_eq!(get(metadata, "level").and_then(|level| level.as_str()), Some("info"));
+    let metadata = get(&records[1], "metadata").expect("metadata");
+    assert_eq!(get(metadata, "level").and_then(|level| level.as_str()), Some("warn"));
+}
+
+#[test]
+fn should_include_thread_info_when_enabled() {
+    let (test_writer, reader) = MemoryWriter::new();
+
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).with_thread_info().layer().expect("Create layer");
+    let sub = Registry::default().with(layer);
+
+    let guard = tracing::subscriber::set_default(sub);
+    tracing::info!("nested");
+    drop(guard);
+
+    let (test_writer, flat_reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).with_thread_info().flatten().layer().expect("Create layer");
+    let sub = Registry::default().with(layer);
+
+    let guard = tracing::subscriber::set_default(sub);
+    tracing::info!("flatten");
+    drop(guard);
+
+    let thread = std::thread::current();
+    let expected_id = format!("{:?}", thread.id());
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 1);
+    let metadata = get(&records[0], "metadata").expect("metadata");
+    assert_eq!(get(metadata, "thread_name").and_then(|name| name.as_str()), thread.name());
+    assert_eq!(get(metadata, "thread_id").and_then(|id| id.as_str()), Some(expected_id.as_str()));
+    assert!(get(&records[0], "thread_id").is_none());
+
+    let records = read_records(&flat_reader);
+    assert_eq!(records.len(), 1);
+    assert_eq!(get(&records[0], "thread_name").and_then(|name| name.as_str()), thread.name());
+    assert_eq!(get(&records[0], "thread_id").and_then(|id| id.as_str()), Some(expected_id.as_str()));
+}
+
+#[test]
+fn should_not_include_thread_info_by_default() {
+    let (test_writer, reader) = MemoryWriter::new();
+
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).layer().expect("Create layer");
+    let sub = Registry::default().with(layer);
+
+    let guard = tracing::subscriber::set_default(sub);
+    tracing::info!("nested");
+    drop(guard);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 1);
+    let metadata = get(&records[0], "metadata").expect("metadata");
+    assert!(get(metadata, "thread_name").is_none());
+    assert!(get(metadata, "thread_id").is_none());
+}
+
+fn parse_rfc3339(timestamp: &str) -> core::time::Duration {
+    let num = |range: core::ops::Range<usize>| timestamp[range].parse::<u64>().expect("number");
+    assert_eq!(timestamp.len(), 30);
+    assert!(timestamp.ends_with('Z'));
+
+    let (year, month, day) = (num(0..4), num(5..7), num(8..10));
+    //http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs = days * 86400 + num(11..13) * 3600 + num(14..16) * 60 + num(17..19);
+    core::time::Duration::new(secs, num(20..29) as u32)
+}
+
+#[test]
+fn should_insert_record_timestamp() {
+    use tracing_fluentd::TimestampStyle;
+
+    let (test_writer, rfc_reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).with_record_timestamp("timestamp", TimestampStyle::Rfc3339).layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("rfc3339");
+    drop(guard);
+
+    let (test_writer, millis_reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).with_record_timestamp("ts", TimestampStyle::EpochMillis).layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("millis");
+    drop(guard);
+
+    let (test_writer, nanos_reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).flatten().with_record_timestamp("ts", TimestampStyle::EpochNanos).layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!(ts = "user", "nanos user field");
+    tracing::info!("nanos");
+    drop(guard);
+
+    let entries = read_entries(&rfc_reader);
+    assert_eq!(entries.len(), 1);
+    let (header, record) = &entries[0];
+    let timestamp = get(record, "timestamp").and_then(|time| time.as_str()).expect("timestamp");
+    let timestamp = parse_rfc3339(timestamp);
+    assert_eq!(timestamp.as_secs(), header.as_secs());
+    #[cfg(feature = "event_time")]
+    assert_eq!(timestamp, *header);
+
+    let entries = read_entries(&millis_reader);
+    assert_eq!(entries.len(), 1);
+    let (header, record) = &entries[0];
+    let millis = get(record, "ts").and_then(|time| time.as_u64()).expect("ts");
+    assert_eq!(millis / 1000, header.as_secs());
+
+    let entries = read_entries(&nanos_reader);
+    assert_eq!(entries.len(), 2);
+    assert_eq!(get(&entries[0].1, "ts").and_then(|time| time.as_str()), Some("user"));
+    let (header, record) = &entries[1];
+    let nanos = get(record, "ts").and_then(|time| time.as_u64()).expect("ts");
+    assert_eq!(nanos / 1_000_000_000, header.as_secs());
+    #[cfg(feature = "event_time")]
+    assert_eq!(nanos, header.as_nanos() as u64);
+}
+
+#[test]
+fn should_insert_time_field_consistent_with_header() {
+    use tracing_fluentd::TimeUnit;
+
+    let (test_writer, secs_reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).with_time_field("time", TimeUnit::Seconds).layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("seconds");
+    drop(guard);
+
+    let errors = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let (test_writer, millis_reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .flatten()
+                                                     .with_time_field("ts", TimeUnit::Millis)
+                                                     .with_error_handler({
+                                                         let errors = errors.clone();
+                                                         move |error| errors.lock().unwrap().push(error.to_string())
+                                                     })
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!(ts = "user", "user field");
+    tracing::info!(ts = "user", "user field again");
+    tracing::info!("millis");
+    drop(guard);
+
+    let entries = read_entries(&secs_reader);
+    assert_eq!(entries.len(), 1);
+    let (header, record) = &entries[0];
+    assert_eq!(get(record, "time").and_then(|time| time.as_u64()), Some(header.as_secs()));
+
+    let entries = read_entries(&millis_reader);
+    assert_eq!(entries.len(), 3);
+    assert_eq!(get(&entries[0].1, "ts").and_then(|time| time.as_str()), Some("user"));
+    assert_eq!(get(&entries[1].1, "ts").and_then(|time| time.as_str()), Some("user"));
+    let (header, record) = &entries[2];
+    let millis = get(record, "ts").and_then(|time| time.as_u64()).expect("ts");
+    assert_eq!(millis / 1000, header.as_secs());
+    #[cfg(feature = "event_time")]
+    assert_eq!(millis, header.as_millis() as u64);
+
+    //Conflict is reported only once.
+    let errors = errors.lock().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].contains("'ts'"), "{}", errors[0]);
+}
+
+#[derive(Debug)]
+struct ChainError {
+    message: &'static str,
+    source: Option<Box<ChainError>>,
+}
+
+impl core::fmt::Display for ChainError {
+    fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::fmt::Result {
+        fmt.write_str(self.message)
+    }
+}
+
+impl std::error::Error for ChainError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.as_deref().map(|source| source as _)
+    }
+}
+
+fn chain_error() -> ChainError {
+    ChainError {
+        message: "top",
+        source: Some(Box::new(ChainError {
+            message: "middle",
+            source: Some(Box::new(ChainError {
+                message: "bottom",
+                source: None,
+            }))
+        }))
+    }
+}
+
+#[test]
+fn should_record_error_chain() {
+    let (test_writer, reader) = MemoryWriter::new();
+
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).flatten().layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    let error = chain_error();
+    tracing::error!(error = &error as &dyn std::error::Error, "failed");
+    drop(guard);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 1);
+    assert_eq!(get(&records[0], "error").and_then(|error| error.as_str()), Some("top: middle: bottom"));
+    assert!(get(&records[0], "error.debug").is_none());
+}
+
+#[test]
+fn should_record_structured_error_chain() {
+    let (test_writer, reader) = MemoryWriter::new();
+
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .flatten()
+                                                     .with_structured_errors()
+                                                     .with_error_debug()
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    let error = chain_error();
+    tracing::error!(error = &error as &dyn std::error::Error, "failed");
+    drop(guard);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 1);
+    let recorded = get(&records[0], "error").expect("error");
+    assert_eq!(get(recorded, "message").and_then(|message| message.as_str()), Some("top"));
+    let causes = get(recorded, "causes").and_then(|causes| causes.as_array()).expect("causes");
+    let causes = causes.iter().map(|cause| cause.as_str().expect("cause string")).collect::<Vec<_>>();
+    assert_eq!(causes, ["middle", "bottom"]);
+    assert_eq!(get(&records[0], "error.debug").and_then(|error| error.as_str()), Some(format!("{:#?}", error).as_str()));
+}
+
+#[cfg(all(tracing_unstable, feature = "valuable"))]
+#[test]
+fn should_record_valuable_as_object() {
+    use valuable::{Valuable, Value, Visit, NamedField, NamedValues, Structable, StructDef, Fields};
+
+    struct Address {
+        city: &'static str,
+    }
+
+    static ADDRESS_FIELDS: &[NamedField<'static>] = &[NamedField::new("city")];
+
+    impl Valuable for Address {
+        fn as_value(&self) -> Value<'_> {
+            Value::Structable(self)
+        }
+
+        fn visit(&self, visit: &mut dyn Visit) {
+            visit.visit_named_fields(&NamedValues::new(ADDRESS_FIELDS, &[self.city.as_value()]));
+        }
+    }
+
+    impl Structable for Address {
+        fn definition(&self) -> StructDef<'_> {
+            StructDef::new_static("Address", Fields::Named(ADDRESS_FIELDS))
+        }
+    }
+
+    struct User {
+        name: String,
+        age: u32,
+        address: Address,
+        tags: Vec<&'static str>,
+    }
+
+    static USER_FIELDS: &[NamedField<'static>] = &[NamedField::new("name"), NamedField::new("age"), NamedField::new("address"), NamedField::new("tags")];
+
+    impl Valuable for User {
+        fn as_value(&self) -> Value<'_> {
+            Value::Structable(self)
+        }
+
+        fn visit(&self, visit: &mut dyn Visit) {
+            visit.visit_named_fields(&NamedValues::new(USER_FIELDS, &[self.name.as_value(), self.age.as_value(), self.address.as_value(), self.tags.as_value()]));
+        }
+    }
+
+    impl Structable for User {
+        fn definition(&self) -> StructDef<'_> {
+            StructDef::new_static("User", Fields::Named(USER_FIELDS))
+        }
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).flatten().layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    let user = User {
+        name: "lolka".to_owned(),
+        age: 25,
+        address: Address {
+            city: "Tokyo",
+        },
+        tags: vec!["admin", "ops"],
+    };
+    tracing::info!(user = tracing::field::valuable(&user), "valuable");
+    drop(guard);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 1);
+    let user = get(&records[0], "user").expect("user");
+    assert!(user.is_map());
+    assert_eq!(get(user, "name").and_then(|name| name.as_str()), Some("lolka"));
+    assert_eq!(get(user, "age").and_then(|age| age.as_u64()), Some(25));
+    let address = get(user, "address").expect("address");
+    assert_eq!(get(address, "city").and_then(|city| city.as_str()), Some("Tokyo"));
+    let tags = get(user, "tags").and_then(|tags| tags.as_array()).expect("tags");
+    assert_eq!(tags.iter().map(|tag| tag.as_str().expect("tag")).collect::<Vec<_>>(), ["admin", "ops"]);
+}
+
+#[test]
+fn should_normalize_log_bridge_events() {
+    let _ = tracing_log::LogTracer::init();
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).flatten().with_log_bridge_normalization().layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    log::info!(target: "my_target", "from log");
+    tracing::info!("from tracing");
+    drop(guard);
+
+    let (test_writer, raw_reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).flatten().layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    log::info!(target: "my_target", "from log");
+    drop(guard);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 2);
+    let record = &records[0];
+    assert_eq!(get(record, "message").and_then(|message| message.as_str()), Some("from log"));
+    assert_eq!(get(record, "module").and_then(|module| module.as_str()), Some("my_target"));
+    assert_eq!(get(record, "file").and_then(|file| file.as_str()), Some(file!()));
+    assert!(get(record, "line").and_then(|line| line.as_u64()).is_some());
+    for (key, _) in record.as_map().expect("map") {
+        assert!(!key.as_str().expect("key").starts_with("log."), "Unexpected key {}", key);
+    }
+
+    let record = &records[1];
+    assert_eq!(get(record, "message").and_then(|message| message.as_str()), Some("from tracing"));
+    assert_eq!(get(record, "module").and_then(|module| module.as_str()), Some(module_path!()));
+
+    let records = read_records(&raw_reader);
+    assert_eq!(records.len(), 1);
+    assert_eq!(get(&records[0], "module").and_then(|module| module.as_str()), Some("log"));
+    assert_eq!(get(&records[0], "log.target").and_then(|target| target.as_str()), Some("my_target"));
+}
+
+#[test]
+fn should_respect_event_parent() {
+    for flatten in [false, true] {
+        let (test_writer, reader) = MemoryWriter::new();
+        let builder = tracing_fluentd::Builder::new("rust").with_writer(test_writer);
+        let guard = match flatten {
+            true => tracing::subscriber::set_default(Registry::default().with(builder.flatten().layer().expect("Create layer"))),
+            false => tracing::subscriber::set_default(Registry::default().with(builder.layer().expect("Create layer"))),
+        };
+
+        let other = tracing::info_span!("other", other_arg = 2);
+        tracing::info_span!("current", current_arg = 1).in_scope(|| {
+            tracing::info!("contextual");
+            tracing::info!(parent: other.id(), "explicit");
+            tracing::info!(parent: None, "root");
+        });
+        drop(other);
+        drop(guard);
+
+        let records = read_records(&reader);
+        assert_eq!(records.len(), 3);
+
+        let span_value = |record: &rmpv::Value, span: &str, key: &str| match flatten {
+            true => get(record, key).and_then(|value| value.as_u64()),
+            false => get(record, span).and_then(|span| get(span, key)).and_then(|value| value.as_u64()),
+        };
+
+        assert_eq!(get(&records[0], "message").and_then(|message| message.as_str()), Some("contextual"));
+        assert_eq!(span_value(&records[0], "current", "current_arg"), Some(1));
+        assert_eq!(span_value(&records[0], "other", "other_arg"), None);
+
+        assert_eq!(get(&records[1], "message").and_then(|message| message.as_str()), Some("explicit"));
+        assert_eq!(span_value(&records[1], "current", "current_arg"), None);
+        assert_eq!(span_value(&records[1], "other", "other_arg"), Some(2));
+
+        assert_eq!(get(&records[2], "message").and_then(|message| message.as_str()), Some("root"));
+        assert_eq!(span_value(&records[2], "current", "current_arg"), None);
+        assert_eq!(span_value(&records[2], "other", "other_arg"), None);
+    }
+}
+
+mod first {
+    pub fn request() {
+        tracing::info_span!("request", id = 1).in_scope(|| {
+            tracing::info!("first");
+            tracing::info!("first again");
+        });
+    }
+}
+
+mod second {
+    pub fn request() {
+        tracing::warn_span!("request", id = 2).in_scope(|| tracing::info!("second"));
+    }
+}
+
+#[test]
+fn should_include_span_metadata() {
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).with_span_metadata().with_lowercase_level().layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    first::request();
+    second::request();
+    drop(guard);
+
+    let (test_writer, plain_reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    first::request();
+    drop(guard);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 3);
+    let expected = [("layer::first", 1, "info"), ("layer::first", 1, "info"), ("layer::second", 2, "warn")];
+    for (record, (target, id, level)) in records.iter().zip(expected.iter()) {
+        let span = get(record, "request").expect("request span");
+        assert_eq!(get(span, "id").and_then(|id| id.as_u64()), Some(*id));
+        let meta = get(span, "meta").expect("meta");
+        assert_eq!(get(meta, "target").and_then(|target| target.as_str()), Some(*target));
+        assert_eq!(get(meta, "file").and_then(|file| file.as_str()), Some(file!()));
+        assert!(get(meta, "line").and_then(|line| line.as_u64()).is_some());
+        assert_eq!(get(meta, "level").and_then(|level| level.as_str()), Some(*level));
+    }
+
+    let records = read_records(&plain_reader);
+    assert_eq!(records.len(), 2);
+    let span = get(&records[0], "request").expect("request span");
+    assert!(get(span, "meta").is_none());
+
+    //Layers with different level case share span's extensions.
+    let (lower_writer, lower) = MemoryWriter::new();
+    let (upper_writer, upper) = MemoryWriter::new();
+    let lower_layer = tracing_fluentd::Builder::new("rust").with_writer(lower_writer).with_span_metadata().with_lowercase_level().layer().expect("Create layer");
+    let upper_layer = tracing_fluentd::Builder::new("rust").with_writer(upper_writer).with_span_metadata().layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(lower_layer).with(upper_layer));
+    second::request();
+    drop(guard);
+
+    for (reader, level) in [(&lower, "warn"), (&upper, "WARN")].iter() {
+        let records = read_records(reader);
+        assert_eq!(records.len(), 1);
+        let meta = get(get(&records[0], "request").expect("request span"), "meta").expect("meta");
+        assert_eq!(get(meta, "target").and_then(|target| target.as_str()), Some("layer::second"));
+        assert_eq!(get(meta, "level").and_then(|level| level.as_str()), Some(*level));
+    }
+}
+
+fn deep_span(depth: u64) {
+    if depth == 50 {
+        tracing::info!("deep");
+    } else {
+        tracing::info_span!("depth", depth).in_scope(|| deep_span(depth + 1));
+    }
+}
+
+#[test]
+fn should_handle_deep_span_chain() {
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).flatten().layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    deep_span(0);
+    drop(guard);
+
+    let (test_writer, nested_reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    deep_span(0);
+    drop(guard);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 1);
+    //Leaf span's attributes take precedence in flatten mode
+    assert_eq!(get(&records[0], "depth").and_then(|depth| depth.as_u64()), Some(49));
+
+    let records = read_records(&nested_reader);
+    assert_eq!(records.len(), 1);
+    let record = records[0].as_map().expect("map");
+    assert_eq!(record.iter().filter(|(key, _)| key.as_str() == Some("depth")).count(), 1);
+    assert!(get(&records[0], "depth").and_then(|span| get(span, "depth")).is_some());
+}
+
+fn keys(record: &rmpv::Value) -> Vec<String> {
+    let mut keys = record.as_map().expect("map").iter().map(|(key, _)| key.as_str().expect("key").to_owned()).collect::<Vec<_>>();
+    keys.sort();
+    keys
+}
+
+#[test]
+fn should_emit_stable_schema() {
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).with_thread_info().with_stable_schema().layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!(arg = 1);
+    tracing::info_span!("span", span_arg = 2).in_scope(|| {
+        tracing::info!(arg = 1, "in span");
+    });
+    drop(guard);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 2);
+    assert_eq!(keys(&records[0]), keys(&records[1]));
+    assert_eq!(keys(&records[0]), ["arg", "message", "metadata", "spans"]);
+    assert_eq!(keys(get(&records[0], "metadata").expect("metadata")), keys(get(&records[1], "metadata").expect("metadata")));
+
+    assert!(get(&records[0], "message").expect("message").is_nil());
+    assert_eq!(get(&records[0], "spans").and_then(|spans| spans.as_array()).map(|spans| spans.len()), Some(0));
+
+    let spans = get(&records[1], "spans").and_then(|spans| spans.as_array()).expect("spans");
+    assert_eq!(spans.len(), 1);
+    assert_eq!(get(&spans[0], "name").and_then(|name| name.as_str()), Some("span"));
+    let fields = get(&spans[0], "fields").expect("fields");
+    assert_eq!(get(fields, "span_arg").and_then(|arg| arg.as_u64()), Some(2));
+}
+
+#[test]
+fn should_serialize_message_first() {
+    use tracing_fluentd::TimestampStyle;
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).with_record_timestamp("ts", TimestampStyle::EpochMillis).layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info_span!("span", span_arg = 2).in_scope(|| {
+        tracing::info!(first = 1, second = 2, "message");
+    });
+    drop(guard);
+
+    let (test_writer, flat_reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).flatten().layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!(first = 1, second = 2, "message");
+    drop(guard);
+
+    let order = |record: &rmpv::Value| record.as_map().expect("map").iter().map(|(key, _)| key.as_str().expect("key").to_owned()).collect::<Vec<_>>();
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 1);
+    let order = order(&records[0]);
+    let mut sorted_order = order.clone();
+    sorted_order.sort();
+    assert_eq!(sorted_order, ["first", "message", "metadata", "second", "span", "ts"]);
+    assert_eq!(order.first().map(String::as_str), Some("message"));
+    assert_eq!(order.last().map(String::as_str), Some("metadata"));
+
+    let records = read_records(&flat_reader);
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].as_map().expect("map")[0].0.as_str(), Some("message"));
+}
+
+#[test]
+fn should_configure_option_section() {
+    use tracing_fluentd::fluent::{Map, OptionSection};
+
+    let frames = |section: Option<OptionSection>| {
+        let (test_writer, reader) = MemoryWriter::new();
+        let builder = tracing_fluentd::Builder::new("rust").with_writer(test_writer);
+        let builder = match section {
+            Some(section) => builder.with_option_section(section),
+            None => builder,
+        };
+        let layer = builder.layer().expect("Create layer");
+        let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+        tracing::info!("first");
+        tracing::info!("second");
+        drop(guard);
+        reader.frames()
+    };
+
+    for frame in frames(None).iter().chain(frames(Some(OptionSection::Size)).iter()) {
+        let frame = frame.as_array().expect("frame");
+        assert_eq!(frame.len(), 3);
+        let options = frame[2].as_map().expect("options");
+        assert_eq!(options.len(), 1);
+        assert_eq!(get(&frame[2], "size").and_then(rmpv::Value::as_u64), Some(frame[1].as_array().expect("entries").len() as u64));
+    }
+
+    for frame in frames(Some(OptionSection::Omit)) {
+        let frame = frame.as_array().expect("frame");
+        assert_eq!(frame.len(), 2);
+        assert_eq!(frame[0].as_str(), Some("rust"));
+    }
+
+    let mut custom = Map::new();
+    custom.insert("source_instance".into(), "instance-1".into());
+    custom.insert("size".into(), 100u64.into());
+    for frame in frames(Some(OptionSection::Custom(custom))) {
+        let frame = frame.as_array().expect("frame");
+        assert_eq!(frame.len(), 3);
+        let options = frame[2].as_map().expect("options");
+        assert_eq!(options.len(), 2);
+        assert_eq!(get(&frame[2], "size").and_then(rmpv::Value::as_u64), Some(frame[1].as_array().expect("entries").len() as u64));
+        assert_eq!(get(&frame[2], "source_instance").and_then(rmpv::Value::as_str), Some("instance-1"));
+    }
+}
+
+#[test]
+fn should_include_custom_option_fields() {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tracing_fluentd::fluent::Map;
+
+    let mut fields = Map::new();
+    fields.insert("source_instance".into(), "instance-1".into());
+    fields.insert("deploy_id".into(), "deploy-1".into());
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                     .with_writer(test_writer)
+                                                     .with_option_fields(fields)
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("first");
+    drop(guard);
+
+    let frames = reader.frames();
+    assert!(!frames.is_empty());
+    for frame in frames.iter() {
+        assert_eq!(get(&frame[2], "size").and_then(rmpv::Value::as_u64), Some(frame[1].as_array().expect("entries").len() as u64));
+        assert_eq!(get(&frame[2], "source_instance").and_then(rmpv::Value::as_str), Some("instance-1"));
+        assert_eq!(get(&frame[2], "deploy_id").and_then(rmpv::Value::as_str), Some("deploy-1"));
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let batch = AtomicU64::new(0);
+    let layer = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                     .with_writer(test_writer)
+                                                     .with_option_fields_fn(move |fields| {
+                                                         fields.insert("batch".into(), batch.fetch_add(1, Ordering::SeqCst).into());
+                                                     })
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    for idx in 0..5 {
+        tracing::info!(idx, "batch");
+    }
+    drop(guard);
+
+    let frames = reader.frames();
+    assert!(!frames.is_empty());
+    for (idx, frame) in frames.iter().enumerate() {
+        assert_eq!(frame[2].as_map().expect("options").len(), 2);
+        assert_eq!(get(&frame[2], "batch").and_then(rmpv::Value::as_u64), Some(idx as u64));
+    }
+}
+
+#[test]
+fn should_write_ndjson_records() {
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                     .with_writer(test_writer)
+                                                     .with_codec(tracing_fluentd::Codec::Ndjson)
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    for idx in 0..3 {
+        tracing::info!(idx, "json");
+    }
+    drop(guard);
+
+    let bytes = reader.bytes();
+    let text = core::str::from_utf8(&bytes).expect("utf-8 output");
+    assert!(text.ends_with('\n'));
+
+    let lines = text.lines().map(|line| serde_json::from_str::<serde_json::Value>(line).expect("valid json line")).collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3);
+    for (idx, line) in lines.iter().enumerate() {
+        assert_eq!(line["tag"], "rust");
+        assert!(line["time"].as_u64().expect("time") > 0);
+        assert_eq!(line["record"]["message"], "json");
+        assert_eq!(line["record"]["idx"], idx as u64);
+        assert_eq!(line["record"]["metadata"]["level"], "INFO");
+    }
+}
+
+#[test]
+fn should_filter_events_without_affecting_other_layers() {
+    use tracing_subscriber::filter::{LevelFilter, Targets};
+
+    fn log_events() {
+        tracing::info_span!("request", id = 5).in_scope(|| {
+            tracing::info!("filtered info");
+            tracing::warn!("recorded warn");
+            tracing::warn!(target: "noisy", "filtered target");
+        });
+    }
+
+    fn check(fluent: &MemoryReader, fmt: &MemoryReader) {
+        let records = read_records(fluent);
+        assert_eq!(records.len(), 1);
+        assert_eq!(get(&records[0], "message").and_then(rmpv::Value::as_str), Some("recorded warn"));
+        //Spans are not filtered
+        let span = get(&records[0], "request").expect("span is recorded");
+        assert_eq!(get(span, "id").and_then(rmpv::Value::as_u64), Some(5));
+
+        let fmt = String::from_utf8(fmt.bytes()).expect("utf-8");
+        assert!(fmt.contains("filtered info"));
+        assert!(fmt.contains("recorded warn"));
+        assert!(fmt.contains("filtered target"));
+    }
+
+    let builder = |writer: MemoryWriter| {
+        tracing_fluentd::Builder::new("rust").with_writer(writer)
+                                             .with_max_level(LevelFilter::WARN)
+                                             .with_targets(Targets::new().with_default(LevelFilter::TRACE).with_target("noisy", LevelFilter::OFF))
+    };
+
+    //Plain layer
+    let (test_writer, reader) = MemoryWriter::new();
+    let (fmt_writer, fmt_reader) = MemoryWriter::new();
+    let layer = builder(test_writer).layer().expect("Create layer");
+    let fmt = tracing_subscriber::fmt::layer().with_writer(move || fmt_writer.clone());
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer).with(fmt));
+    log_events();
+    drop(guard);
+    check(&reader, &fmt_reader);
+
+    //Per-layer filter, in both orders
+    let (test_writer, reader) = MemoryWriter::new();
+    let (fmt_writer, fmt_reader) = MemoryWriter::new();
+    let layer = builder(test_writer).layer().expect("Create layer").filtered();
+    let fmt = tracing_subscriber::fmt::layer().with_writer(move || fmt_writer.clone());
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer).with(fmt));
+    log_events();
+    drop(guard);
+    check(&reader, &fmt_reader);
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let (fmt_writer, fmt_reader) = MemoryWriter::new();
+    let layer = builder(test_writer).layer().expect("Create layer").filtered();
+    let fmt = tracing_subscriber::fmt::layer().with_writer(move || fmt_writer.clone());
+    let guard = tracing::subscriber::set_default(Registry::default().with(fmt).with(layer));
+    log_events();
+    drop(guard);
+    check(&reader, &fmt_reader);
+}
+
+#[test]
+fn should_not_create_records_once_worker_is_stopped() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingFmt(Arc<AtomicUsize>);
+
+    impl tracing_fluentd::FieldFormatter for CountingFmt {
+        fn on_event<'a, R: tracing_subscriber::registry::LookupSpan<'a>>(&self, record: &mut tracing_fluentd::fluent::Record, event: &tracing::Event<'_>, current_span: Option<tracing_subscriber::registry::SpanRef<'a, R>>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            tracing_fluentd::NestedFmt.on_event(record, event, current_span);
+        }
+    }
+
+    let count = Arc::new(AtomicUsize::new(0));
+    let (test_writer, reader) = MemoryWriter::new();
+    let (layer, guard) = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                              .with_formatter(CountingFmt(count.clone()))
+                                                              .layer_guarded()
+                                                              .expect("Create layer");
+    let dispatch = tracing::Dispatch::new(Registry::default().with(layer));
+    tracing::dispatcher::with_default(&dispatch, || {
+        tracing::info!("before stop");
+        drop(guard);
+
+        for idx in 0..1000 {
+            tracing::info!(idx, "after stop");
+        }
+    });
+
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+    assert_eq!(read_records(&reader).len(), 1);
+    let layer = dispatch.downcast_ref::<tracing_fluentd::Layer<CountingFmt, tracing_fluentd::WorkerChannel>>().expect("layer");
+    assert_eq!(layer.suppressed_events(), 1000);
+}
+
+#[test]
+fn should_clone_builder_into_independent_layers() {
+    let (test_writer, reader) = MemoryWriter::new();
+    let base = tracing_fluentd::Builder::new("first").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                     .with_writer(test_writer)
+                                                     .with_lowercase_level();
+    let second = base.clone().with_tag("second");
+    assert_eq!(base.tag(), "first");
+    assert_eq!(second.tag(), "second");
+    assert_eq!(second.max_msg_record(), 1);
+
+    let (first_layer, first_guard) = base.layer_guarded().expect("Create layer");
+    let (second_layer, second_guard) = second.layer_guarded().expect("Create layer");
 
-    drop(file);
-    let _ = fs::remove_file(log_name);
+    tracing::subscriber::with_default(Registry::default().with(first_layer), || tracing::info!("first"));
+    //Stopping first worker doesn't affect second one
+    drop(first_guard);
+    tracing::subscriber::with_default(Registry::default().with(second_layer), || tracing::info!("second"));
+    drop(second_guard);
+
+    let frames = reader.frames();
+    assert_eq!(frames.len(), 2);
+    for (frame, tag) in frames.iter().zip(["first", "second"].iter()) {
+        assert_eq!(frame[0].as_str(), Some(*tag));
+        let record = &frame[1][0][1];
+        assert_eq!(get(record, "message").and_then(rmpv::Value::as_str), Some(*tag));
+        assert_eq!(get(get(record, "metadata").expect("metadata"), "level").and_then(rmpv::Value::as_str), Some("info"));
+    }
+}
+
+#[test]
+fn should_send_records_of_cloned_layers_to_same_worker() {
+    let (test_writer, reader) = MemoryWriter::new();
+    let (layer, guard) = tracing_fluentd::Builder::new("shared").with_writer(test_writer)
+                                                                .layer_guarded()
+                                                                .expect("Create layer");
+    let threads = vec![layer.clone(), layer].into_iter().enumerate().map(|(thread, layer)| std::thread::spawn(move || {
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            for idx in 0..10 {
+                tracing::info!(thread, idx, "cloned");
+            }
+        });
+    })).collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().expect("Join thread");
+    }
+    drop(guard);
+
+    let frames = reader.frames();
+    assert!(frames.iter().all(|frame| frame[0].as_str() == Some("shared")));
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 20);
+    for thread in 0..2u64 {
+        let count = records.iter().filter(|record| get(record, "thread").and_then(rmpv::Value::as_u64) == Some(thread)).count();
+        assert_eq!(count, 10);
+    }
+}
+
+#[test]
+fn should_format_records_same_way_through_boxed_builder() {
+    fn run<F: tracing_fluentd::FieldFormatter + Send + Sync, A: tracing_fluentd::MakeWriter>(builder: tracing_fluentd::Builder<F, A>, reader: MemoryReader) -> Vec<rmpv::Value> {
+        let (layer, guard) = builder.layer_guarded().expect("Create layer");
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            let span = tracing::info_span!("request", user = "admin", attempt = 1);
+            let _entered = span.enter();
+            span.record("attempt", 2);
+            tracing::info!(idx = 1, "inside");
+            tracing::info!(parent: None, idx = 2, "root");
+            tracing::debug!(idx = 3, "filtered");
+        });
+        drop(guard);
+        read_records(&reader)
+    }
+
+    //Formatter is chosen at runtime, while builder type remains the same.
+    fn boxed(writer: MemoryWriter, flat: bool) -> tracing_fluentd::BoxedBuilder {
+        match flat {
+            true => tracing_fluentd::Builder::new("rust").with_writer(writer).flatten().boxed(),
+            false => tracing_fluentd::Builder::new("rust").with_writer(writer).boxed(),
+        }
+    }
+
+    let level = tracing_subscriber::filter::LevelFilter::INFO;
+    for flat in [false, true].iter().copied() {
+        let (test_writer, reader) = MemoryWriter::new();
+        let builder = tracing_fluentd::Builder::new("rust").with_writer(test_writer).with_max_level(level);
+        let generic = match flat {
+            true => run(builder.flatten(), reader),
+            false => run(builder, reader),
+        };
+
+        let (test_writer, reader) = MemoryWriter::new();
+        let boxed = run(boxed(test_writer, flat).with_max_level(level), reader);
+
+        assert_eq!(generic.len(), 2);
+        assert!(get(&generic[0], "user").is_some() || get(&generic[0], "request").is_some());
+        assert_eq!(generic, boxed);
+    }
+}
+
+fn wait_records(reader: &MemoryReader, expected: usize) -> Vec<rmpv::Value> {
+    let mut records = Vec::new();
+    for _ in 0..500 {
+        records = read_records(reader);
+        if records.len() >= expected {
+            break;
+        }
+        std::thread::sleep(core::time::Duration::from_millis(10));
+    }
+    records
+}
+
+#[test]
+fn should_send_thread_local_buffer_on_thread_exit() {
+    let (test_writer, reader) = MemoryWriter::new();
+    let (layer, guard) = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                              .with_writer(test_writer)
+                                                              .with_thread_local_buffer(core::num::NonZeroUsize::new(1000).unwrap(), core::time::Duration::from_secs(3600))
+                                                              .layer_guarded()
+                                                              .expect("Create layer");
+    let dispatch = tracing::Dispatch::new(Registry::default().with(layer));
+    let threads = (0..4u64).map(|thread| {
+        let dispatch = dispatch.clone();
+        std::thread::spawn(move || tracing::dispatcher::with_default(&dispatch, || {
+            for idx in 0..25 {
+                tracing::info!(thread, idx, "buffered");
+            }
+        }))
+    }).collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().expect("Join thread");
+    }
+
+    //Records are sent by exited threads, without stopping worker.
+    let records = wait_records(&reader, 100);
+    assert_eq!(records.len(), 100);
+    for thread in 0..4u64 {
+        let idx = records.iter().filter(|record| get(record, "thread").and_then(rmpv::Value::as_u64) == Some(thread))
+                                .map(|record| get(record, "idx").and_then(rmpv::Value::as_u64).expect("idx"))
+                                .collect::<Vec<_>>();
+        assert_eq!(idx, (0..25).collect::<Vec<_>>());
+    }
+    drop(guard);
+}
+
+#[test]
+fn should_send_thread_local_buffer_once_full() {
+    let (test_writer, reader) = MemoryWriter::new();
+    let (layer, guard) = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                              .with_writer(test_writer)
+                                                              .with_thread_local_buffer(core::num::NonZeroUsize::new(3).unwrap(), core::time::Duration::from_secs(3600))
+                                                              .layer_guarded()
+                                                              .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        for idx in 0..4 {
+            tracing::info!(idx, "buffered");
+        }
+        assert_eq!(wait_records(&reader, 3).len(), 3);
+        std::thread::sleep(core::time::Duration::from_millis(50));
+        //The last record is still buffered.
+        assert_eq!(read_records(&reader).len(), 3);
+        //Stopping worker sends buffer of current thread.
+        drop(guard);
+    });
+    assert_eq!(read_records(&reader).len(), 4);
+}
+
+#[test]
+fn should_flush_eagerly_on_error() {
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_eager_flush_level(tracing::Level::ERROR)
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("before");
+    tracing::error!("failure");
+
+    let mut records = Vec::new();
+    for _ in 0..500 {
+        records = read_records(&reader);
+        if !records.is_empty() {
+            break;
+        }
+        std::thread::sleep(core::time::Duration::from_millis(10));
+    }
+    //Both records are sent without waiting for max_msg_record or guard drop
+    assert_eq!(records.len(), 2);
+    assert_eq!(get(&records[0], "message").and_then(rmpv::Value::as_str), Some("before"));
+    assert_eq!(get(&records[1], "message").and_then(rmpv::Value::as_str), Some("failure"));
+
+    tracing::info!("after");
+    std::thread::sleep(core::time::Duration::from_millis(50));
+    assert_eq!(read_records(&reader).len(), 2);
+    drop(guard);
+
+    let frames = reader.frames();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(get(&frames[1][1][0][1], "message").and_then(rmpv::Value::as_str), Some("after"));
+}
+
+#[test]
+fn should_rate_limit_records() {
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_rate_limit(core::num::NonZeroU32::new(5).unwrap(), core::num::NonZeroU32::new(5).unwrap())
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    for idx in 0..20 {
+        tracing::info!(idx, "burst");
+    }
+    std::thread::sleep(core::time::Duration::from_millis(1100));
+    tracing::info!("after burst");
+    drop(guard);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 7);
+    for (idx, record) in records.iter().take(5).enumerate() {
+        assert_eq!(get(record, "idx").and_then(rmpv::Value::as_u64), Some(idx as u64));
+    }
+    assert_eq!(get(&records[5], "rate_limit_dropped").and_then(rmpv::Value::as_u64), Some(15));
+    assert_eq!(get(&records[5], "message").and_then(rmpv::Value::as_str), Some("rate limit dropped 15 records"));
+    assert_eq!(get(&records[6], "message").and_then(rmpv::Value::as_str), Some("after burst"));
+
+    //Errors can be exempt
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_rate_limit(core::num::NonZeroU32::new(1).unwrap(), core::num::NonZeroU32::new(1).unwrap())
+                                                     .with_rate_limit_exempt_errors()
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("allowed");
+    tracing::info!("dropped");
+    for _ in 0..3 {
+        tracing::error!("exempt");
+    }
+    drop(guard);
+
+    let records = read_records(&reader);
+    let messages = records.iter().map(|record| get(record, "message").and_then(rmpv::Value::as_str).expect("message")).collect::<Vec<_>>();
+    assert_eq!(messages, ["allowed", "exempt", "exempt", "exempt"]);
+
+    //Exemption is kept regardless of order
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_rate_limit_exempt_errors()
+                                                     .with_rate_limit(core::num::NonZeroU32::new(1).unwrap(), core::num::NonZeroU32::new(1).unwrap())
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("allowed");
+    tracing::info!("dropped");
+    tracing::error!("exempt");
+    drop(guard);
+
+    let records = read_records(&reader);
+    let messages = records.iter().map(|record| get(record, "message").and_then(rmpv::Value::as_str).expect("message")).collect::<Vec<_>>();
+    assert_eq!(messages, ["allowed", "exempt"]);
+}
+
+#[test]
+fn should_suppress_identical_consecutive_records() {
+    fn healthcheck(status: u64) {
+        tracing::info!(status, "healthcheck");
+    }
+
+    fn ping() {
+        tracing::info!("ping");
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_dedup_window(core::time::Duration::from_millis(200))
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    //Interleaved callsites are tracked separately
+    for _ in 0..5 {
+        healthcheck(200);
+        ping();
+    }
+    std::thread::sleep(core::time::Duration::from_millis(250));
+    healthcheck(200);
+    //Different record reports suppressed records first
+    healthcheck(200);
+    healthcheck(500);
+    //Span fields are part of record
+    for id in 0..2 {
+        tracing::info_span!("request", id).in_scope(ping);
+    }
+    drop(guard);
+
+    let records = read_records(&reader);
+    let summary = records.iter().map(|record| (
+        get(record, "message").and_then(rmpv::Value::as_str).expect("message"),
+        get(record, "status").and_then(rmpv::Value::as_u64),
+        get(record, "repeated").and_then(rmpv::Value::as_u64),
+    )).collect::<Vec<_>>();
+    assert_eq!(summary, [
+        ("healthcheck", Some(200), None),
+        ("ping", None, None),
+        ("healthcheck", Some(200), Some(4)),
+        ("previous record repeated 1 times", None, Some(1)),
+        ("healthcheck", Some(500), None),
+        ("previous record repeated 4 times", None, Some(4)),
+        ("ping", None, None),
+        ("ping", None, None),
+    ]);
+}
+
+#[test]
+fn should_complete_summary_records_as_records_of_events() {
+    let clock = TestClock::new();
+    clock.set_system_time(std::time::UNIX_EPOCH + core::time::Duration::from_secs(1_000_000));
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_clock(clock)
+                                                     .with_dedup_window(core::time::Duration::from_secs(60))
+                                                     .with_static_field("service", "api")
+                                                     .with_sequence_field("seq")
+                                                     .with_time_field("ts", tracing_fluentd::TimeUnit::Seconds)
+                                                     .with_level_tag_suffix()
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    for elapsed in [5, 5, 5, 6].iter() {
+        tracing::warn!(elapsed, "slow");
+    }
+    drop(guard);
+
+    let frames = reader.frames();
+    assert!(frames.iter().all(|frame| frame[0].as_str() == Some("rust.warn")));
+    let entries = read_entries(&reader);
+    assert_eq!(entries.len(), 3);
+    let (time, summary) = &entries[1];
+    assert_eq!(*time, core::time::Duration::from_secs(1_000_000));
+    assert_eq!(get(summary, "repeated").and_then(rmpv::Value::as_u64), Some(2));
+    assert_eq!(get(summary, "service").and_then(rmpv::Value::as_str), Some("api"));
+    assert_eq!(get(summary, "ts").and_then(rmpv::Value::as_u64), Some(1_000_000));
+    let keys = summary.as_map().expect("record").iter().map(|(key, _)| key.as_str().expect("key")).collect::<Vec<_>>();
+    assert_eq!(keys.first(), Some(&"message"));
+    assert_eq!(keys.last(), Some(&"metadata"));
+
+    let sequence = entries.iter().map(|(_, record)| get(record, "seq").and_then(rmpv::Value::as_u64).expect("seq")).collect::<Vec<_>>();
+    assert!(sequence[0] < sequence[1] && sequence[1] < sequence[2]);
+}
+
+#[test]
+fn should_report_dropped_records() {
+    use std::sync::{Arc, Mutex};
+    use tracing_fluentd::DropReason;
+
+    let dropped = Arc::new(Mutex::new(Vec::new()));
+    let (test_writer, reader) = MemoryWriter::new();
+    let (layer, guard) = {
+        let dropped = dropped.clone();
+        tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                             .with_rate_limit(core::num::NonZeroU32::new(1).unwrap(), core::num::NonZeroU32::new(1).unwrap())
+                                             .on_drop(move |reason, count| dropped.lock().expect("lock").push((reason, count)))
+                                             .layer_guarded()
+                                             .expect("Create layer")
+    };
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        for _ in 0..3 {
+            tracing::info!("limited");
+        }
+        drop(guard);
+        //Worker is stopped, hence event cannot be delivered
+        tracing::error!("after stop");
+    });
+
+    assert_eq!(read_records(&reader).len(), 1);
+    let dropped = dropped.lock().expect("lock");
+    assert_eq!(*dropped, [(DropReason::RateLimited, 1), (DropReason::RateLimited, 1), (DropReason::DeliveryGivenUp, 1)]);
+}
+
+#[cfg(feature = "event_time")]
+#[test]
+fn should_drop_only_records_that_cannot_be_encoded() {
+    use std::sync::{Arc, Mutex};
+    use tracing_fluentd::DropReason;
+
+    let errors = Arc::new(Mutex::new(Vec::new()));
+    let dropped = Arc::new(Mutex::new(Vec::new()));
+    let clock = TestClock::new();
+    let (test_writer, reader) = MemoryWriter::new();
+    let (layer, guard) = {
+        let errors = errors.clone();
+        let dropped = dropped.clone();
+        tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                             .with_clock(clock.clone())
+                                             .with_error_handler(move |error| errors.lock().expect("lock").push(error.to_string()))
+                                             .on_drop(move |reason, count| dropped.lock().expect("lock").push((reason, count)))
+                                             .layer_guarded()
+                                             .expect("Create layer")
+    };
+    let now = std::time::SystemTime::now();
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info!(idx = 0, "good");
+        //EventTime cannot represent time after year 2106
+        clock.set_system_time(std::time::UNIX_EPOCH + core::time::Duration::from_secs(u64::from(u32::MAX) + 1));
+        tracing::info!(idx = 1, "bad");
+        clock.set_system_time(now);
+        tracing::info!(idx = 2, "good");
+        drop(guard);
+    });
+
+    let records = read_records(&reader);
+    let idx = records.iter().map(|record| get(record, "idx").and_then(rmpv::Value::as_u64)).collect::<Vec<_>>();
+    assert_eq!(idx, [Some(0), Some(2)]);
+
+    let errors = errors.lock().expect("lock");
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].starts_with("Dropped record #"), "{}", errors[0]);
+    assert!(errors[0].ends_with("of 'rust' as it cannot be encoded: time exceeds range of EventTime"), "{}", errors[0]);
+    assert_eq!(*dropped.lock().expect("lock"), [(DropReason::EncodeFailed, 1)]);
+}
+
+#[test]
+fn should_send_records_with_tag_suffixed_by_level() {
+    for packed in [false, true].iter() {
+        let (test_writer, reader) = MemoryWriter::new();
+        let builder = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                           .with_level_tag_suffix()
+                                                           .with_tag("app");
+        let builder = match packed {
+            true => builder.with_packed_forward(),
+            false => builder,
+        };
+        let layer = builder.layer().expect("Create layer");
+        let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+        tracing::error!("failed");
+        tracing::info!("started");
+        tracing::warn!("slow");
+        tracing::debug!("details");
+        drop(guard);
+
+        let mut tags = reader.frames().iter().map(|frame| frame[0].as_str().expect("tag").to_owned()).collect::<Vec<_>>();
+        tags.sort();
+        tags.dedup();
+        assert_eq!(tags, ["app.error", "app.log", "app.warn"]);
+        assert_eq!(reader.records().len(), 4);
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_level_tag_suffix()
+                                                     .with_level_tag_suffix_for(tracing::Level::DEBUG, "debug")
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("started");
+    tracing::debug!("details");
+    drop(guard);
+
+    let frames = reader.frames();
+    let mut tags = frames.iter().map(|frame| frame[0].as_str().expect("tag")).collect::<Vec<_>>();
+    tags.sort();
+    assert_eq!(tags, ["rust.debug", "rust.log"]);
+
+    //Suffix is kept regardless of order
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_level_tag_suffix_for(tracing::Level::DEBUG, "debug")
+                                                     .with_level_tag_suffix()
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("started");
+    tracing::debug!("details");
+    drop(guard);
+
+    let frames = reader.frames();
+    let mut tags = frames.iter().map(|frame| frame[0].as_str().expect("tag")).collect::<Vec<_>>();
+    tags.sort();
+    assert_eq!(tags, ["rust.debug", "rust.log"]);
+}
+
+#[test]
+fn should_record_follows_from_links_of_span() {
+    fn log_linked() -> (u64, u64, u64) {
+        let leader = tracing::info_span!("leader");
+        let other = tracing::info_span!("other");
+        let follower = tracing::info_span!("follower", task = 1);
+        follower.follows_from(&leader);
+        follower.follows_from(&other);
+        follower.in_scope(|| tracing::info!("fan out"));
+
+        let id = |span: &tracing::Span| span.id().expect("span id").into_u64();
+        (id(&leader), id(&other), id(&follower))
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_span_ids()
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    let (leader, other, follower) = log_linked();
+    drop(guard);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 1);
+    let span = get(&records[0], "follower").expect("span");
+    assert_eq!(get(span, "task").and_then(rmpv::Value::as_u64), Some(1));
+    assert_eq!(get(span, "id").and_then(rmpv::Value::as_u64), Some(follower));
+    let follows_from = get(span, "follows_from").and_then(rmpv::Value::as_array).expect("follows_from");
+    assert_eq!(follows_from.iter().map(|id| id.as_u64()).collect::<Vec<_>>(), [Some(leader), Some(other)]);
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_span_ids()
+                                                     .flatten()
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    let (leader, other, _) = log_linked();
+    drop(guard);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 1);
+    assert_eq!(get(&records[0], "task").and_then(rmpv::Value::as_u64), Some(1));
+    let follows_from = get(&records[0], "follows_from").and_then(rmpv::Value::as_array).expect("follows_from");
+    assert_eq!(follows_from.iter().map(|id| id.as_u64()).collect::<Vec<_>>(), [Some(leader), Some(other)]);
+
+    //Links are not recorded unless span ids are enabled.
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .flatten()
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    log_linked();
+    drop(guard);
+
+    let records = read_records(&reader);
+    assert!(get(&records[0], "follows_from").is_none());
+}
+
+#[test]
+fn should_propagate_span_fields_to_record_root() {
+    fn log_nested() {
+        tracing::info_span!("request", request_id = "outer", tenant = "acme").in_scope(|| {
+            tracing::info!("root depth");
+            tracing::info_span!("handler", request_id = "inner").in_scope(|| {
+                tracing::info!("leaf depth");
+                tracing::info!(request_id = "event", "event field");
+            });
+        });
+        tracing::info!("no span");
+    }
+
+    fn check(reader: &MemoryReader) {
+        let records = read_records(reader);
+        let fields = records.iter().map(|record| (
+            get(record, "message").and_then(rmpv::Value::as_str).expect("message"),
+            get(record, "request_id").and_then(rmpv::Value::as_str),
+            get(record, "tenant").and_then(rmpv::Value::as_str),
+        )).collect::<Vec<_>>();
+        assert_eq!(fields, [
+            ("root depth", Some("outer"), Some("acme")),
+            ("leaf depth", Some("inner"), Some("acme")),
+            ("event field", Some("event"), Some("acme")),
+            ("no span", None, None),
+        ]);
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_propagated_fields(&["request_id", "tenant"])
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), log_nested);
+    check(&reader);
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .flatten()
+                                                     .with_propagated_fields(&["request_id", "tenant"])
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), log_nested);
+    check(&reader);
+}
+
+#[test]
+fn should_add_fields_from_environment() {
+    std::env::set_var("TRACING_FLUENTD_TEST_POD", "pod-1");
+    std::env::set_var("TRACING_FLUENTD_TEST_FIELD_team", "payments");
+    std::env::set_var("TRACING_FLUENTD_TEST_FIELD_message", "overridden");
+    std::env::remove_var("TRACING_FLUENTD_TEST_NODE");
+
+    fn check(reader: &MemoryReader, node: Option<rmpv::Value>) {
+        let records = read_records(reader);
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(get(record, "message").and_then(rmpv::Value::as_str), Some("env"));
+        assert_eq!(get(record, "pod").and_then(rmpv::Value::as_str), Some("pod-1"));
+        assert_eq!(get(record, "team").and_then(rmpv::Value::as_str), Some("payments"));
+        assert_eq!(get(record, "region").and_then(rmpv::Value::as_str), Some("eu"));
+        assert_eq!(get(record, "node").cloned(), node);
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_static_field("region", "eu")
+                                                     .with_env_fields(&[("pod", "TRACING_FLUENTD_TEST_POD"), ("node", "TRACING_FLUENTD_TEST_NODE")])
+                                                     .with_env_prefix("TRACING_FLUENTD_TEST_FIELD_")
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || tracing::info!("env"));
+    check(&reader, None);
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_static_field("region", "eu")
+                                                     .with_env_fields_or_null(&[("pod", "TRACING_FLUENTD_TEST_POD"), ("node", "TRACING_FLUENTD_TEST_NODE")])
+                                                     .with_env_prefix("TRACING_FLUENTD_TEST_FIELD_")
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || tracing::info!("env"));
+    check(&reader, Some(rmpv::Value::Nil));
+}
+
+#[test]
+fn should_change_filtering_at_runtime() {
+    use tracing_subscriber::filter::{LevelFilter, Targets};
+
+    fn log(handle: &tracing_fluentd::Handle) {
+        for _ in 0..4 {
+            tracing::debug!("before");
+            tracing::info!("before");
+        }
+
+        handle.set_max_level(LevelFilter::DEBUG);
+        for _ in 0..4 {
+            tracing::debug!("debug");
+        }
+
+        handle.set_targets(Some(Targets::new().with_target("other", LevelFilter::TRACE)));
+        tracing::info!("wrong target");
+        tracing::info!(target: "other", "other target");
+        handle.set_targets(None);
+
+        handle.set_sample_ratio(0.5);
+        for _ in 0..4 {
+            tracing::info!("sampled");
+        }
+    }
+
+    fn check(reader: &MemoryReader) {
+        let records = read_records(reader);
+        let messages = records.iter().map(|record| get(record, "message").and_then(rmpv::Value::as_str).expect("message")).collect::<Vec<_>>();
+        assert_eq!(messages, [
+            "before", "before", "before", "before",
+            "debug", "debug", "debug", "debug",
+            "other target",
+            "sampled", "sampled",
+        ]);
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let (layer, handle) = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                               .with_max_level(LevelFilter::INFO)
+                                                               .layer_with_handle()
+                                                               .expect("Create layer");
+    assert_eq!(handle.max_level(), LevelFilter::INFO);
+    tracing::subscriber::with_default(Registry::default().with(layer), || log(&handle));
+    check(&reader);
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let (layer, handle) = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                               .with_max_level(LevelFilter::INFO)
+                                                               .layer_with_handle()
+                                                               .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer.filtered()), || log(&handle));
+    check(&reader);
+}
+
+#[test]
+fn should_encode_same_entries_in_packed_forward_mode() {
+    fn log() {
+        tracing::info_span!("span", id = 1).in_scope(|| {
+            tracing::info!(number = 2, flag = true, "first");
+            tracing::warn!(text = "value", "second");
+        });
+        tracing::error!("third");
+    }
+
+    //Both layers stamp records by the same clock, so that entries are identical.
+    let clock = TestClock::new();
+    let (forward_writer, forward) = MemoryWriter::new();
+    let (packed_writer, packed) = MemoryWriter::new();
+    let forward_layer = tracing_fluentd::Builder::new("rust").with_writer(forward_writer).with_clock(clock.clone()).layer().expect("Create layer");
+    let packed_layer = tracing_fluentd::Builder::new("rust").with_writer(packed_writer).with_clock(clock).with_packed_forward().layer().expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(forward_layer).with(packed_layer), log);
+
+    let forward_frames = forward.frames();
+    let packed_frames = packed.frames();
+    assert_eq!(forward_frames.len(), 1);
+    assert_eq!(packed_frames.len(), 1);
+    assert_eq!(packed_frames[0][0], forward_frames[0][0]);
+    assert_eq!(packed_frames[0][2], forward_frames[0][2]);
+    let packed_entries = packed_frames[0][1].as_slice().expect("PackedForward entries").to_owned();
+    assert_eq!(packed.records(), forward.records());
+    assert_eq!(packed.entries(), forward.entries());
+
+    //Forward mode contains the same entries after header of array.
+    let mut expected = packed_entries;
+    rmpv::encode::write_value(&mut expected, &packed_frames[0][2]).expect("encode options");
+    assert!(forward.bytes().ends_with(&expected));
+}
+
+#[test]
+fn should_redact_sensitive_fields() {
+    fn log() {
+        tracing::info_span!("login", user = "admin", password = "span secret").in_scope(|| {
+            tracing::info!(password = "event secret", access_token = "token", token_kind = "bearer", "login");
+        });
+    }
+
+    fn client() -> tracing_fluentd::fluent::Map {
+        let mut credentials = tracing_fluentd::fluent::Map::new();
+        credentials.insert("authorization".into(), "Basic secret".into());
+        credentials.insert("scheme".into(), "basic".into());
+        let mut client = tracing_fluentd::fluent::Map::new();
+        client.insert("name".into(), "curl".into());
+        client.insert("credentials".into(), vec![tracing_fluentd::fluent::Value::from(credentials)].into());
+        client
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_static_field("client", client())
+                                                     .with_redacted_fields(&["password", "authorization", "*_token"])
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), log);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(record["message"].as_str(), Some("login"));
+    assert_eq!(record["password"].as_str(), Some("[REDACTED]"));
+    assert_eq!(record["access_token"].as_str(), Some("[REDACTED]"));
+    assert_eq!(record["token_kind"].as_str(), Some("bearer"));
+    assert_eq!(record["login"]["user"].as_str(), Some("admin"));
+    assert_eq!(record["login"]["password"].as_str(), Some("[REDACTED]"));
+    assert_eq!(record["client"]["name"].as_str(), Some("curl"));
+    assert_eq!(record["client"]["credentials"][0]["authorization"].as_str(), Some("[REDACTED]"));
+    assert_eq!(record["client"]["credentials"][0]["scheme"].as_str(), Some("basic"));
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .flatten()
+                                                     .with_redacted_fields(&["password"])
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info_span!("login", span_password = "span secret", password = "span secret").in_scope(|| tracing::info!("login"));
+    });
+
+    let records = read_records(&reader);
+    assert_eq!(records[0]["password"].as_str(), Some("[REDACTED]"));
+    assert_eq!(records[0]["span_password"].as_str(), Some("span secret"));
+
+    //Attributes of span are attached by worker, yet still redacted.
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_span_registry()
+                                                     .with_redacted_fields(&["password"])
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), log);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["password"].as_str(), Some("[REDACTED]"));
+    assert_eq!(records[0]["span"]["user"].as_str(), Some("admin"));
+    assert_eq!(records[0]["span"]["password"].as_str(), Some("[REDACTED]"));
+}
+
+#[test]
+fn should_limit_depth_of_nested_objects() {
+    use tracing_fluentd::fluent::{self, Map, Value};
+
+    fn nested(depth: usize) -> Map {
+        let mut map = Map::new();
+        map.insert("level".into(), (depth as u64).into());
+        for level in (1..depth).rev() {
+            let mut parent = Map::new();
+            parent.insert("level".into(), (level as u64).into());
+            parent.insert("child".into(), Value::from(map));
+            map = parent;
+        }
+        map
+    }
+
+    fn depth(record: &rmpv::Value) -> usize {
+        let mut depth = 0;
+        let mut value = &record["nested"];
+        while value.is_map() {
+            depth += 1;
+            assert_eq!(value["level"].as_u64(), Some(depth as u64));
+            value = &value["child"];
+        }
+        assert_eq!(value.as_str(), Some("[max depth exceeded]"));
+        depth
+    }
+
+    let mut record = fluent::Record::now();
+    record.insert("nested".into(), nested(1000).into());
+    let encoded = rmp_serde::to_vec(&record).expect("Serialize record");
+    let decoded = rmpv::decode::read_value(&mut &encoded[..]).expect("Decode record");
+    assert_eq!(depth(&decoded[1]), fluent::DEFAULT_MAX_DEPTH);
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_static_field("nested", nested(10))
+                                                     .with_max_depth(3)
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || tracing::info!("deep"));
+    let records = read_records(&reader);
+    assert_eq!(depth(&records[0]), 3);
+}
+
+#[test]
+fn should_skip_events_with_skip_field() {
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_skip_field("fluentd.skip")
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info!(fluentd.skip = true, "progress");
+        tracing::info!(fluentd.skip = true, progress = 50, "progress");
+    });
+    assert!(reader.frames().is_empty());
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_skip_field("fluentd.skip")
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info_span!("job", fluentd.skip = true, id = 1).in_scope(|| {
+            tracing::info!(fluentd.skip = true, "progress");
+            tracing::info!(fluentd.skip = false, "kept");
+            tracing::info!("done");
+        });
+    });
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["message"].as_str(), Some("kept"));
+    assert_eq!(records[1]["message"].as_str(), Some("done"));
+    for record in records.iter() {
+        assert!(get(record, "fluentd.skip").is_none());
+        assert!(get(&record["job"], "fluentd.skip").is_none());
+        assert_eq!(record["job"]["id"].as_u64(), Some(1));
+    }
+}
+
+#[test]
+fn should_prefix_span_fields_in_flatten_fmt() {
+    fn log() {
+        tracing::info_span!("request", id = 1, user = "span user").in_scope(|| {
+            tracing::info_span!("query", id = 2).in_scope(|| {
+                tracing::info!(id = 3, user = "event user", "done");
+                tracing::info!("again");
+            });
+        });
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .flatten()
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), log);
+    let records = read_records(&reader);
+    assert_eq!(records[0]["id"].as_u64(), Some(3));
+    assert_eq!(records[0]["user"].as_str(), Some("event user"));
+    assert_eq!(records[1]["id"].as_u64(), Some(2));
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .flatten()
+                                                     .with_span_field_prefix("ctx_")
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), log);
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["id"].as_u64(), Some(3));
+    assert_eq!(records[0]["user"].as_str(), Some("event user"));
+    assert_eq!(records[0]["ctx_id"].as_u64(), Some(2));
+    assert_eq!(records[0]["ctx_user"].as_str(), Some("span user"));
+    assert!(get(&records[0], "module").is_some());
+    assert!(get(&records[1], "id").is_none());
+    assert_eq!(records[1]["ctx_id"].as_u64(), Some(2));
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .flatten()
+                                                     .with_span_field_prefix(tracing_fluentd::SpanFieldPrefix::SpanName)
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), log);
+    let records = read_records(&reader);
+    for record in records.iter() {
+        assert_eq!(record["span.query.id"].as_u64(), Some(2));
+        assert_eq!(record["span.request.id"].as_u64(), Some(1));
+        assert_eq!(record["span.request.user"].as_str(), Some("span user"));
+    }
+    assert_eq!(records[0]["id"].as_u64(), Some(3));
+
+    //Layers with different prefixes share span's extensions.
+    let (static_writer, static_reader) = MemoryWriter::new();
+    let (name_writer, name_reader) = MemoryWriter::new();
+    let static_layer = tracing_fluentd::Builder::new("rust").with_writer(static_writer)
+                                                            .flatten()
+                                                            .with_span_field_prefix("ctx_")
+                                                            .layer()
+                                                            .expect("Create layer");
+    let name_layer = tracing_fluentd::Builder::new("rust").with_writer(name_writer)
+                                                          .flatten()
+                                                          .with_span_field_prefix(tracing_fluentd::SpanFieldPrefix::SpanName)
+                                                          .layer()
+                                                          .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(static_layer).with(name_layer), log);
+    let records = read_records(&static_reader);
+    assert_eq!(records.len(), 2);
+    for record in records.iter() {
+        assert_eq!(record["ctx_id"].as_u64(), Some(2));
+        assert!(get(record, "span.query.id").is_none());
+    }
+    let records = read_records(&name_reader);
+    assert_eq!(records.len(), 2);
+    for record in records.iter() {
+        assert_eq!(record["span.query.id"].as_u64(), Some(2));
+        assert!(get(record, "ctx_id").is_none());
+    }
+}
+
+#[test]
+fn should_record_span_path() {
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_span_path("/")
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info!("root");
+        tracing::info_span!("http_request").in_scope(|| {
+            tracing::info_span!("authorize").in_scope(|| {
+                tracing::info_span!("db_query").in_scope(|| {
+                    tracing::info!("first");
+                    tracing::info!("second");
+                });
+                tracing::info!("authorized");
+            });
+        });
+    });
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 4);
+    assert!(get(&records[0], "span_path").is_none());
+    assert_eq!(records[1]["span_path"].as_str(), Some("http_request/authorize/db_query"));
+    assert_eq!(records[2]["span_path"].as_str(), Some("http_request/authorize/db_query"));
+    assert_eq!(records[3]["span_path"].as_str(), Some("http_request/authorize"));
+
+    //Layers with different separators share span's extensions.
+    let (slash_writer, slash) = MemoryWriter::new();
+    let (dot_writer, dot) = MemoryWriter::new();
+    let slash_layer = tracing_fluentd::Builder::new("rust").with_writer(slash_writer).with_span_path("/").layer().expect("Create layer");
+    let dot_layer = tracing_fluentd::Builder::new("rust").with_writer(dot_writer).with_span_path(".").layer().expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(slash_layer).with(dot_layer), || {
+        tracing::info_span!("http_request").in_scope(|| {
+            tracing::info_span!("authorize").in_scope(|| {
+                tracing::info!("first");
+                tracing::info!("second");
+            });
+        });
+    });
+
+    for (reader, expected) in [(&slash, "http_request/authorize"), (&dot, "http_request.authorize")].iter() {
+        let records = read_records(reader);
+        assert_eq!(records.len(), 2);
+        for record in records.iter() {
+            assert_eq!(record["span_path"].as_str(), Some(*expected));
+        }
+    }
+}
+
+#[test]
+fn should_record_event_name() {
+    fn log() {
+        tracing::info!(name: "user_login", "login");
+        tracing::info!("unnamed");
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_event_name("event", false)
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), log);
+    let records = read_records(&reader);
+    assert_eq!(records[0]["event"].as_str(), Some("user_login"));
+    assert!(get(&records[1], "event").is_none());
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .flatten()
+                                                     .with_event_name("event_name", true)
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), log);
+    let records = read_records(&reader);
+    assert_eq!(records[0]["event_name"].as_str(), Some("user_login"));
+    assert!(records[1]["event_name"].as_str().expect("event name").starts_with("event tests"));
+}
+
+#[test]
+fn should_add_kubernetes_fields() {
+    std::env::set_var("POD_NAME", "api-7f9c");
+    std::env::set_var("POD_NAMESPACE", "payments");
+    std::env::set_var("NODE_NAME", "node-1");
+    std::env::set_var("POD_LABEL_app", "api");
+    std::env::set_var("POD_LABEL_version", "v2");
+    std::env::set_var("TRACING_FLUENTD_TEST_K8S_POD", "worker-1");
+    std::env::remove_var("TRACING_FLUENTD_TEST_K8S_NAMESPACE");
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_kubernetes_fields()
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || tracing::info!("k8s"));
+    let records = read_records(&reader);
+    let kubernetes = &records[0]["kubernetes"];
+    assert_eq!(kubernetes["pod_name"].as_str(), Some("api-7f9c"));
+    assert_eq!(kubernetes["namespace"].as_str(), Some("payments"));
+    assert_eq!(kubernetes["node_name"].as_str(), Some("node-1"));
+    assert_eq!(kubernetes["labels"]["app"].as_str(), Some("api"));
+    assert_eq!(kubernetes["labels"]["version"].as_str(), Some("v2"));
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let vars = tracing_fluentd::KubernetesVars {
+        pod_name: "TRACING_FLUENTD_TEST_K8S_POD",
+        namespace: "TRACING_FLUENTD_TEST_K8S_NAMESPACE",
+        labels_prefix: "TRACING_FLUENTD_TEST_K8S_LABEL_",
+        ..Default::default()
+    };
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_kubernetes_fields_from(vars)
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || tracing::info!("k8s"));
+    let records = read_records(&reader);
+    let kubernetes = &records[0]["kubernetes"];
+    assert_eq!(kubernetes["pod_name"].as_str(), Some("worker-1"));
+    assert_eq!(kubernetes["node_name"].as_str(), Some("node-1"));
+    assert!(get(kubernetes, "namespace").is_none());
+    assert!(get(kubernetes, "labels").is_none());
+}
+
+#[test]
+fn should_format_records_for_gcp() {
+    use rmpv::Value;
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").gcp()
+                                                     .with_writer(test_writer)
+                                                     .layer()
+                                                     .expect("Create layer");
+    let line = line!();
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info_span!("request", id = 1).in_scope(|| {
+            tracing::trace!(step = 1, "trace");
+            tracing::debug!(step = 2, "debug");
+            tracing::info!(step = 3, "info");
+            tracing::warn!(step = 4, "warn");
+            tracing::error!(step = 5, "error");
+        });
+    });
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 5);
+    let expected = [("trace", "DEBUG"), ("debug", "DEBUG"), ("info", "INFO"), ("warn", "WARNING"), ("error", "ERROR")];
+    for (idx, (record, (message, severity))) in records.iter().zip(expected.iter()).enumerate() {
+        let location = Value::Map(vec![
+            ("file".into(), file!().into()),
+            ("line".into(), (line + 3 + idx as u32).into()),
+            ("function".into(), module_path!().into()),
+        ]);
+        let expected = Value::Map(vec![
+            ("message".into(), (*message).into()),
+            ("step".into(), (idx as u64 + 1).into()),
+            ("id".into(), 1u64.into()),
+            ("severity".into(), (*severity).into()),
+            ("logging.googleapis.com/sourceLocation".into(), location),
+        ]);
+        assert_eq!(*record, expected);
+    }
+}
+
+#[test]
+fn should_format_records_for_logstash() {
+    use rmpv::Value;
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").logstash()
+                                                     .with_writer(test_writer)
+                                                     .layer()
+                                                     .expect("Create layer");
+    let line = line!();
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info_span!("request", id = 1).in_scope(|| {
+            tracing::warn!(attempt = 2, "retry");
+        });
+    });
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 1);
+    let mut record = records[0].as_map().expect("record").clone();
+    let idx = record.iter().position(|(key, _)| key.as_str() == Some("@timestamp")).expect("@timestamp");
+    let (_, timestamp) = record.remove(idx);
+    let timestamp = timestamp.as_str().expect("timestamp string").to_owned();
+    assert_eq!(timestamp.len(), "2021-01-01T00:00:00.000000000Z".len());
+    assert_eq!(&timestamp[4..5], "-");
+    assert_eq!(&timestamp[10..11], "T");
+    assert!(timestamp.ends_with('Z'));
+
+    let metadata = Value::Map(vec![
+        ("file".into(), file!().into()),
+        ("line".into(), (line + 3).into()),
+        ("module".into(), module_path!().into()),
+        ("level".into(), "WARN".into()),
+    ]);
+    let expected = vec![
+        ("message".into(), "retry".into()),
+        ("attempt".into(), 2u64.into()),
+        ("id".into(), 1u64.into()),
+        ("@version".into(), "1".into()),
+        ("@metadata".into(), metadata),
+    ];
+    assert_eq!(record, expected);
+}
+
+#[test]
+fn should_format_records_for_gelf() {
+    use rmpv::Value;
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").gelf("app-1")
+                                                     .with_writer(test_writer)
+                                                     .layer()
+                                                     .expect("Create layer");
+    let line = line!();
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info_span!("request", id = 7, http.method = "GET").in_scope(|| {
+            tracing::error!(count = 2, ratio = 0.5, cached = false, user = "admin", "request failed\nat handler");
+            tracing::debug!("done");
+        });
+    });
+
+    let entries = read_entries(&reader);
+    assert_eq!(entries.len(), 2);
+    let mut expected_level = [3u64, 7].iter();
+    for (idx, (time, record)) in entries.iter().enumerate() {
+        let timestamp = record["timestamp"].as_f64().expect("timestamp");
+        assert_eq!(timestamp.trunc() as u64, time.as_secs());
+
+        let mut fields = vec![
+            ("version".into(), "1.1".into()),
+            ("host".into(), "app-1".into()),
+        ];
+        if idx == 0 {
+            fields.push(("short_message".into(), "request failed".into()));
+            fields.push(("full_message".into(), "request failed\nat handler".into()));
+        } else {
+            fields.push(("short_message".into(), "done".into()));
+        }
+        fields.push(("timestamp".into(), timestamp.into()));
+        fields.push(("level".into(), (*expected_level.next().unwrap()).into()));
+        if idx == 0 {
+            fields.push(("_count".into(), 2u64.into()));
+            fields.push(("_ratio".into(), 0.5.into()));
+            fields.push(("_cached".into(), false.into()));
+            fields.push(("_user".into(), "admin".into()));
+        }
+        fields.push(("_id_".into(), 7u64.into()));
+        fields.push(("_http.method".into(), "GET".into()));
+        fields.push(("_file".into(), file!().into()));
+        fields.push(("_line".into(), (line + 3 + idx as u32).into()));
+        fields.push(("_module".into(), module_path!().into()));
+        assert_eq!(*record, Value::Map(fields));
+    }
+}
+
+#[test]
+fn should_add_syslog_fields() {
+    fn log() {
+        tracing::trace!("trace");
+        tracing::debug!("debug");
+        tracing::info!("info");
+        tracing::warn!("warn");
+        tracing::error!("error");
+    }
+
+    fn check(reader: &MemoryReader, severity: [u64; 5]) {
+        let records = read_records(reader);
+        assert_eq!(records.len(), 5);
+        for (record, severity) in records.iter().zip(severity.iter()) {
+            assert_eq!(record["severity"].as_u64(), Some(*severity));
+            assert_eq!(record["facility"].as_u64(), Some(16));
+            assert_eq!(record["ident"].as_str(), Some("app"));
+        }
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_syslog_fields(16, "app")
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), log);
+    check(&reader, [7, 7, 6, 4, 3]);
+    assert!(get(&read_records(&reader)[0], "metadata").is_some());
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").flatten()
+                                                     .with_writer(test_writer)
+                                                     .with_syslog_fields(16, "app")
+                                                     .with_syslog_severity(tracing::Level::INFO, 5)
+                                                     .with_syslog_severity(tracing::Level::ERROR, 2)
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), log);
+    check(&reader, [7, 7, 5, 4, 2]);
+
+    //Severity is kept regardless of order
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").flatten()
+                                                     .with_writer(test_writer)
+                                                     .with_syslog_severity(tracing::Level::INFO, 5)
+                                                     .with_syslog_fields(16, "app")
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), log);
+    check(&reader, [7, 7, 5, 4, 3]);
+}
+
+#[test]
+fn should_parse_json_fields() {
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_parse_json_fields(&["payload", "response", "items"])
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        let payload = serde_json::json!({"user": {"id": 1, "name": "admin"}, "scores": [1.5, -2]}).to_string();
+        tracing::info_span!("request", items = "[1, 2]").in_scope(|| {
+            tracing::info!(payload = %payload, response = "{not json", other = "{\"a\":1}", "json");
+            tracing::info!(payload = "42", response = "\"string\"", "scalars");
+        });
+    });
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 2);
+    let record = &records[0];
+    assert_eq!(record["payload"]["user"]["id"].as_u64(), Some(1));
+    assert_eq!(record["payload"]["user"]["name"].as_str(), Some("admin"));
+    assert_eq!(record["payload"]["scores"][0].as_f64(), Some(1.5));
+    assert_eq!(record["payload"]["scores"][1].as_i64(), Some(-2));
+    assert_eq!(record["response"].as_str(), Some("{not json"));
+    assert_eq!(record["other"].as_str(), Some("{\"a\":1}"));
+    assert_eq!(record["request"]["items"][1].as_u64(), Some(2));
+
+    //Only objects and arrays are parsed.
+    let record = &records[1];
+    assert_eq!(record["payload"].as_str(), Some("42"));
+    assert_eq!(record["response"].as_str(), Some("\"string\""));
+}
+
+#[test]
+fn should_flatten_nested_objects_into_dotted_keys() {
+    fn log() {
+        let payload = serde_json::json!({
+            "http": {"request": {"method": "GET", "headers": {"host": "localhost"}}, "status": 200},
+            "items": [1, 2],
+        }).to_string();
+        tracing::info_span!("request", user = "{\"id\":1}").in_scope(|| {
+            tracing::info!(payload = %payload, payload.http.status = "literal", "nested");
+        });
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .flatten()
+                                                     .with_parse_json_fields(&["payload", "user"])
+                                                     .with_flatten_nested_objects(2)
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), log);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(record["message"].as_str(), Some("nested"));
+    assert!(get(record, "payload").is_none());
+    assert_eq!(record["user.id"].as_u64(), Some(1));
+    //Objects deeper than two levels are left as is.
+    assert_eq!(record["payload.http.request"]["method"].as_str(), Some("GET"));
+    assert_eq!(record["payload.http.request"]["headers"]["host"].as_str(), Some("localhost"));
+    //Field of event takes precedence over expanded key.
+    assert_eq!(record["payload.http.status"].as_str(), Some("literal"));
+    assert_eq!(record["payload.items"][1].as_u64(), Some(2));
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .flatten()
+                                                     .with_parse_json_fields(&["payload", "user"])
+                                                     .with_flatten_nested_objects(4)
+                                                     .with_flatten_nested_arrays()
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), log);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(record["payload.http.request.method"].as_str(), Some("GET"));
+    assert_eq!(record["payload.http.request.headers.host"].as_str(), Some("localhost"));
+    assert_eq!(record["payload.http.status"].as_str(), Some("literal"));
+    assert_eq!(record["payload.items.0"].as_u64(), Some(1));
+    assert_eq!(record["payload.items.1"].as_u64(), Some(2));
+    assert!(get(record, "payload.items").is_none());
+}
+
+#[test]
+fn should_filter_fields_by_allowlist_and_denylist() {
+    fn log() {
+        tracing::info_span!("job", id = 1, secret = "span secret").in_scope(|| {
+            tracing::info!(user = "user", secret = "event secret", attempt = 2, "done");
+        });
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_field_denylist(&["secret"])
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), log);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(record["message"].as_str(), Some("done"));
+    assert_eq!(record["user"].as_str(), Some("user"));
+    assert_eq!(record["attempt"].as_u64(), Some(2));
+    assert!(get(record, "secret").is_none());
+    assert_eq!(record["job"]["id"].as_u64(), Some(1));
+    assert!(get(&record["job"], "secret").is_none());
+    assert!(get(record, "metadata").is_some());
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_field_allowlist(&["message", "id", "user"], false)
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), log);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(record["message"].as_str(), Some("done"));
+    assert_eq!(record["user"].as_str(), Some("user"));
+    assert!(get(record, "secret").is_none());
+    assert!(get(record, "attempt").is_none());
+    assert!(get(record, "extra").is_none());
+    assert_eq!(record["job"]["id"].as_u64(), Some(1));
+    assert!(get(&record["job"], "secret").is_none());
+    assert!(get(&record["job"], "extra").is_none());
+    assert!(get(record, "metadata").is_some());
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_field_allowlist(&["message", "id", "user"], true)
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), log);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(record["message"].as_str(), Some("done"));
+    assert_eq!(record["user"].as_str(), Some("user"));
+    assert!(get(record, "secret").is_none());
+    assert_eq!(record["extra"]["secret"].as_str(), Some("event secret"));
+    assert_eq!(record["extra"]["attempt"].as_u64(), Some(2));
+    assert_eq!(record["job"]["id"].as_u64(), Some(1));
+    assert_eq!(record["job"]["extra"]["secret"].as_str(), Some("span secret"));
+    assert!(get(record, "metadata").is_some());
+}
+
+#[test]
+fn should_keep_monotonic_timestamps_across_backwards_clock_step() {
+    use core::time::Duration;
+    use std::time::SystemTime;
+
+    let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    fn log(clock: &TestClock, start: SystemTime) {
+        tracing::info!("start");
+        clock.advance(Duration::from_secs(2));
+        clock.set_system_time(start - Duration::from_secs(3600));
+        tracing::info!("stepped back");
+        clock.advance(Duration::from_secs(2));
+        tracing::info!("after step");
+        clock.advance(Duration::from_secs(60));
+        tracing::info!("after minute");
+        clock.set_system_time(start + Duration::from_secs(1000));
+        tracing::info!("stepped forward");
+    }
+
+    let clock = TestClock::new();
+    clock.set_system_time(start);
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_clock(clock.clone())
+                                                     .with_monotonic_timestamps()
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || log(&clock, start));
+
+    let times = read_entries(&reader).into_iter().map(|(time, _)| time.as_secs()).collect::<Vec<_>>();
+    assert_eq!(times, [1_000_000, 1_000_002, 1_000_004, 1_000_064, 1_001_000]);
+
+    let clock = TestClock::new();
+    clock.set_system_time(start);
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_clock(clock.clone())
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || log(&clock, start));
+
+    let times = read_entries(&reader).into_iter().map(|(time, _)| time.as_secs()).collect::<Vec<_>>();
+    assert_eq!(times, [1_000_000, 996_400, 996_402, 996_462, 1_001_000]);
+}
+
+#[test]
+fn should_clamp_timestamps_before_unix_epoch() {
+    use core::time::Duration;
+    use std::sync::{Arc, Mutex};
+    use std::time::SystemTime;
+
+    for monotonic in [false, true] {
+        let clock = TestClock::new();
+        clock.set_system_time(SystemTime::UNIX_EPOCH - Duration::from_secs(10));
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let (test_writer, reader) = MemoryWriter::new();
+        let mut builder = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                               .with_clock(clock.clone())
+                                                               .with_error_handler({
+                                                                   let errors = errors.clone();
+                                                                   move |error| errors.lock().unwrap().push(error.to_string())
+                                                               });
+        if monotonic {
+            builder = builder.with_monotonic_timestamps();
+        }
+        let layer = builder.layer().expect("Create layer");
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::info!("first");
+            clock.advance(Duration::from_secs(2));
+            tracing::info!("second");
+            clock.advance(Duration::from_secs(10));
+            tracing::info!("after epoch");
+        });
+
+        let entries = read_entries(&reader);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].0, Duration::from_secs(0));
+        assert_eq!(entries[0].1["message"].as_str(), Some("first"));
+        assert_eq!(entries[1].0.as_secs(), if monotonic { 2 } else { 0 });
+        assert_eq!(entries[2].0.as_secs(), if monotonic { 12 } else { 2 });
+
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].contains("before UNIX epoch"));
+    }
+}
+
+#[test]
+fn should_serialize_shared_strings_same_as_owned() {
+    use tracing_fluentd::fluent;
+
+    let mut shared = fluent::Record::with_time(core::time::Duration::from_secs(1));
+    shared.insert("text".into(), fluent::Value::Shared("span text".into()));
+    let mut owned = fluent::Record::with_time(core::time::Duration::from_secs(1));
+    owned.insert("text".into(), "span text".to_owned().into());
+    assert_eq!(shared.encode().expect("encode"), owned.encode().expect("encode"));
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).layer().expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info_span!("job", text = "span text", display = %"span text").in_scope(|| {
+            tracing::info!("first");
+            tracing::info!("second");
+        });
+    });
+
+    let (test_writer, flatten_reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).flatten().layer().expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info_span!("job", text = "span text", display = %"span text").in_scope(|| {
+            tracing::info!("first");
+        });
+    });
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 2);
+    for record in records.iter() {
+        assert_eq!(record["job"]["text"].as_str(), Some("span text"));
+        assert_eq!(record["job"]["text"], record["job"]["display"]);
+    }
+    let records = read_records(&flatten_reader);
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["text"].as_str(), Some("span text"));
+}
+
+#[test]
+fn should_configure_builder_presets() {
+    use tracing_fluentd::OverflowPolicy;
+
+    let default = tracing_fluentd::Builder::new("rust");
+    assert_eq!(default.max_msg_record(), 10);
+    assert_eq!(default.eager_flush_level(), None);
+    assert_eq!(default.memory_budget(), None);
+    assert!(!default.has_connection_probe());
+    assert!(!default.has_error_handler());
+
+    let development = tracing_fluentd::Builder::new("rust").development();
+    assert_eq!(development.max_msg_record(), 5);
+    assert_eq!(development.eager_flush_level(), Some(tracing::Level::TRACE));
+    assert_eq!(development.memory_budget(), None);
+    assert!(!development.has_connection_probe());
+    assert!(development.has_error_handler());
+
+    let production = tracing_fluentd::Builder::new("rust").production();
+    assert_eq!(production.max_msg_record(), 100);
+    assert_eq!(production.eager_flush_level(), Some(tracing::Level::ERROR));
+    assert_eq!(production.memory_budget(), Some((64 * 1024 * 1024, OverflowPolicy::DropOldest)));
+    assert!(production.has_connection_probe());
+    assert!(!production.has_error_handler());
+
+    //Presets remain overridable.
+    let production = tracing_fluentd::Builder::new("rust").production()
+                                                          .with_max_msg_record(core::num::NonZeroUsize::new(1000).unwrap())
+                                                          .with_memory_budget(core::num::NonZeroUsize::new(1024).unwrap(), OverflowPolicy::DropNewest);
+    assert_eq!(production.max_msg_record(), 1000);
+    assert_eq!(production.memory_budget(), Some((1024, OverflowPolicy::DropNewest)));
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let (layer, _guard) = tracing_fluentd::Builder::new("rust").development()
+                                                               .with_writer(test_writer)
+                                                               .layer_guarded()
+                                                               .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || tracing::info!("development"));
+    //Sent without waiting for more records or shutdown.
+    for _ in 0..250 {
+        if !reader.bytes().is_empty() {
+            break;
+        }
+        std::thread::sleep(core::time::Duration::from_millis(20));
+    }
+    assert_eq!(read_records(&reader).len(), 1);
+}
+
+#[test]
+fn should_send_span_attributes_once_per_message() {
+    fn run(registry: bool, writer: MemoryWriter) {
+        let builder = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(4).unwrap())
+                                                         .with_writer(writer)
+                                                         .flatten();
+        let layer = match registry {
+            true => builder.with_span_registry().layer(),
+            false => builder.layer(),
+        };
+        let layer = layer.expect("Create layer");
+
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            let request = tracing::info_span!("request", user = "admin", attempt = 1);
+            let entered = request.enter();
+            tracing::info!(idx = 0, "first");
+            tracing::info!(idx = 1, user = "guest", "overrides span attribute");
+            request.record("attempt", 2);
+            tracing::info!(idx = 2, "after record");
+            tracing::info_span!("route", path = "/login").in_scope(|| {
+                for idx in 3..8 {
+                    tracing::info!(idx, "nested");
+                }
+            });
+            tracing::info!(parent: None, idx = 8, "root");
+            tracing::info!(idx = 9, "last");
+            drop(entered);
+            tracing::info!(idx = 10, "outside");
+        });
+    }
+
+    //Compares records regardless of order of fields.
+    fn sorted(record: rmpv::Value) -> Vec<(String, rmpv::Value)> {
+        let mut fields = record.as_map().expect("Record map").iter().map(|(key, value)| (key.as_str().expect("key").to_owned(), value.clone())).collect::<Vec<_>>();
+        fields.sort_by(|left, right| left.0.cmp(&right.0));
+        fields
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+    run(false, test_writer);
+    let expected = read_records(&reader).into_iter().map(sorted).collect::<Vec<_>>();
+    assert_eq!(expected.len(), 11);
+
+    let (test_writer, reader) = MemoryWriter::new();
+    run(true, test_writer);
+
+    let mut records = Vec::new();
+    let mut spans_sent = 0;
+    for frame in reader.frames() {
+        let entries = frame.as_array().expect("Frame")[1].as_array().expect("Entries").clone();
+        //Attributes of span are known only within the same message.
+        let mut spans = std::collections::HashMap::new();
+        for entry in entries {
+            let mut record = entry.as_array().expect("Entry")[1].clone();
+            let fields = match &mut record {
+                rmpv::Value::Map(fields) => fields,
+                record => panic!("Invalid record {}", record),
+            };
+
+            let span = fields.iter().position(|(key, _)| key.as_str() == Some("span")).map(|idx| fields.remove(idx).1);
+            let span_ref = fields.iter().position(|(key, _)| key.as_str() == Some("span_ref")).map(|idx| fields.remove(idx).1);
+            let span_ref = match span_ref {
+                Some(span_ref) => span_ref.as_u64().expect("span_ref"),
+                None => {
+                    assert!(span.is_none());
+                    records.push(sorted(record));
+                    continue;
+                },
+            };
+            if let Some(span) = span {
+                spans_sent += 1;
+                assert!(spans.insert(span_ref, span).is_none(), "span is sent twice within message");
+            }
+
+            let span = spans.get(&span_ref).expect("span_ref without span within message");
+            for (key, value) in span.as_map().expect("Span map") {
+                if !fields.iter().any(|(name, _)| name == key) {
+                    fields.push((key.clone(), value.clone()));
+                }
+            }
+            records.push(sorted(record));
+        }
+    }
+
+    assert_eq!(records, expected);
+    assert!(spans_sent < 9, "span attributes are sent {} times", spans_sent);
+}
+
+#[test]
+fn should_spawn_worker_via_thread_builder() {
+    use std::sync::{Arc, Mutex};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    let invoked = Arc::new(AtomicUsize::new(0));
+    let worker_name = Arc::new(Mutex::new(None));
+    let (test_writer, reader) = MemoryWriter::new();
+
+    let layer = {
+        let invoked = invoked.clone();
+        let worker_name = worker_name.clone();
+        tracing_fluentd::Builder::new("rust").with_thread_builder(move |builder| {
+                                                 invoked.fetch_add(1, Ordering::SeqCst);
+                                                 builder.name("custom-worker".to_owned()).stack_size(256 * 1024)
+                                             })
+                                             .with_writer(move || {
+                                                 *worker_name.lock().unwrap() = std::thread::current().name().map(str::to_owned);
+                                                 tracing_fluentd::MakeWriter::make(&test_writer)
+                                             })
+                                             .layer()
+                                             .expect("Create layer")
+    };
+    assert_eq!(invoked.load(Ordering::SeqCst), 1);
+
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info!("spawned");
+    });
+
+    //Worker is joined once layer is dropped.
+    assert_eq!(read_records(&reader).len(), 1);
+    assert_eq!(worker_name.lock().unwrap().as_deref(), Some("custom-worker"));
+}
+
+#[test]
+fn should_include_increasing_sequence_field() {
+    let (test_writer, reader) = MemoryWriter::new();
+    let (layer, guard) = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                              .with_writer(test_writer)
+                                                              .with_sequence_field("seq")
+                                                              .layer_guarded()
+                                                              .expect("Create layer");
+    let dispatch = tracing::Dispatch::new(Registry::default().with(layer));
+    let threads = (0..4u64).map(|thread| {
+        let dispatch = dispatch.clone();
+        std::thread::spawn(move || tracing::dispatcher::with_default(&dispatch, || {
+            for idx in 0..25 {
+                tracing::info!(thread, idx, seq = "overwritten", "sequenced");
+            }
+        }))
+    }).collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().expect("Join thread");
+    }
+    drop(dispatch);
+    drop(guard);
+
+    let records = reader.records();
+    assert_eq!(records.len(), 100);
+    let mut all = Vec::new();
+    for thread in 0..4u64 {
+        let seq = records.iter().filter(|record| get(record, "thread").and_then(rmpv::Value::as_u64) == Some(thread))
+                                .map(|record| get(record, "seq").and_then(rmpv::Value::as_u64).expect("seq"))
+                                .collect::<Vec<_>>();
+        assert_eq!(seq.len(), 25);
+        assert!(seq.windows(2).all(|pair| pair[0] < pair[1]), "Not increasing: {:?}", seq);
+        all.extend(seq);
+    }
+    all.sort_unstable();
+    all.dedup();
+    assert_eq!(all.len(), 100);
+}
+
+#[test]
+fn should_sample_by_field_value() {
+    use tracing_fluentd::SampleDecision;
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let (layer, guard) = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                              .with_writer(test_writer)
+                                                              .with_sample_ratio(0.5)
+                                                              .with_sampling_key("tenant_id", |tenant| match tenant.as_str() {
+                                                                  Some("canary") => SampleDecision::Keep,
+                                                                  Some("banned") => SampleDecision::Drop,
+                                                                  _ => SampleDecision::UseDefaultRatio,
+                                                              })
+                                                              .layer_guarded()
+                                                              .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info_span!("request", tenant_id = "canary").in_scope(|| {
+            tracing::info_span!("handler").in_scope(|| {
+                for idx in 0..10 {
+                    tracing::info!(idx, "canary");
+                }
+            });
+            //Field of event takes precedence over span.
+            tracing::info!(tenant_id = "banned", "overridden");
+        });
+        tracing::info_span!("request", tenant_id = "regular").in_scope(|| {
+            for idx in 0..10 {
+                tracing::info!(idx, "regular");
+            }
+        });
+        tracing::info_span!("request", tenant_id = "banned").in_scope(|| {
+            tracing::info!("banned");
+        });
+        for idx in 0..4 {
+            tracing::info!(idx, "unkeyed");
+        }
+    });
+    drop(guard);
+
+    let records = reader.records();
+    let count = |message: &str| records.iter().filter(|record| get(record, "message").and_then(rmpv::Value::as_str) == Some(message)).count();
+    assert_eq!(count("canary"), 10);
+    assert_eq!(count("overridden"), 0);
+    assert_eq!(count("regular"), 5);
+    assert_eq!(count("banned"), 0);
+    assert_eq!(count("unkeyed"), 2);
+}
+
+#[test]
+fn should_format_same_event_with_nested_flatten_and_context_fmt() {
+    use rmpv::Value;
+
+    fn format<F: tracing_fluentd::FieldFormatter + Send + Sync + 'static>(builder: tracing_fluentd::Builder<F, MemoryWriter>, reader: MemoryReader) -> (Value, Vec<(Value, Value)>) {
+        let layer = builder.layer().expect("Create layer");
+        let line = line!();
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            tracing::info_span!("request", id = 1, user = "outer").in_scope(|| {
+                tracing::info_span!("handler", user = "inner").in_scope(|| {
+                    tracing::info!(attempt = 2, "done");
+                })
+            })
+        });
+
+        let records = read_records(&reader);
+        assert_eq!(records.len(), 1);
+        let metadata = vec![
+            ("file".into(), file!().into()),
+            ("line".into(), (line + 4).into()),
+            ("module".into(), module_path!().into()),
+            ("level".into(), "INFO".into()),
+        ];
+        (records[0].clone(), metadata)
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let (record, metadata) = format(tracing_fluentd::Builder::new("rust").with_writer(test_writer), reader);
+    let expected = Value::Map(vec![
+        ("message".into(), "done".into()),
+        ("attempt".into(), 2u64.into()),
+        ("handler".into(), Value::Map(vec![("user".into(), "inner".into())])),
+        ("request".into(), Value::Map(vec![("id".into(), 1u64.into()), ("user".into(), "outer".into())])),
+        ("metadata".into(), Value::Map(metadata)),
+    ]);
+    assert_eq!(record, expected);
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let (record, metadata) = format(tracing_fluentd::Builder::new("rust").with_writer(test_writer).flatten(), reader);
+    let mut expected = vec![
+        ("message".into(), "done".into()),
+        ("attempt".into(), 2u64.into()),
+        ("user".into(), "inner".into()),
+        ("id".into(), 1u64.into()),
+    ];
+    expected.extend(metadata);
+    assert_eq!(record, Value::Map(expected));
+
+    let context = Value::Map(vec![("user".into(), "inner".into()), ("id".into(), 1u64.into())]);
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let (record, metadata) = format(tracing_fluentd::Builder::new("rust").with_writer(test_writer).context(), reader);
+    let mut expected = vec![
+        ("message".into(), "done".into()),
+        ("attempt".into(), 2u64.into()),
+        ("context".into(), context.clone()),
+    ];
+    expected.extend(metadata);
+    assert_eq!(record, Value::Map(expected));
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let fmt = tracing_fluentd::ContextFmt::new().with_key("spans").with_nested_metadata();
+    let (record, metadata) = format(tracing_fluentd::Builder::new("rust").with_writer(test_writer).with_formatter(fmt), reader);
+    let expected = Value::Map(vec![
+        ("message".into(), "done".into()),
+        ("attempt".into(), 2u64.into()),
+        ("spans".into(), context),
+        ("metadata".into(), Value::Map(metadata)),
+    ]);
+    assert_eq!(record, expected);
+}
+
+#[test]
+fn should_rename_metadata_envelope_on_collision() {
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer).layer().expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info!(metadata = "user", "event field");
+        tracing::info_span!("metadata", id = 1).in_scope(|| {
+            tracing::info!("span name");
+        });
+        tracing::info!("no collision");
+    });
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 3);
+
+    assert_eq!(get(&records[0], "metadata").and_then(|value| value.as_str()), Some("user"));
+    let envelope = get(&records[0], "metadata_1").expect("renamed envelope");
+    assert_eq!(get(envelope, "level").and_then(|value| value.as_str()), Some("INFO"));
+
+    let span = get(&records[1], "metadata").expect("span object");
+    assert_eq!(get(span, "id").and_then(|value| value.as_u64()), Some(1));
+    let envelope = get(&records[1], "metadata_1").expect("renamed envelope");
+    assert_eq!(get(envelope, "level").and_then(|value| value.as_str()), Some("INFO"));
+
+    let envelope = get(&records[2], "metadata").expect("envelope");
+    assert_eq!(get(envelope, "level").and_then(|value| value.as_str()), Some("INFO"));
+    assert!(get(&records[2], "metadata_1").is_none());
+}
+
+#[test]
+fn should_use_custom_metadata_key() {
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_metadata_key("meta")
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info!(metadata = "user", "not reserved");
+        tracing::info!(meta = "user", meta_1 = "user", "reserved");
+    });
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 2);
+
+    assert_eq!(get(&records[0], "metadata").and_then(|value| value.as_str()), Some("user"));
+    let envelope = get(&records[0], "meta").expect("envelope");
+    assert_eq!(get(envelope, "level").and_then(|value| value.as_str()), Some("INFO"));
+    let keys = records[0].as_map().expect("record").iter().map(|(key, _)| key.as_str().expect("key")).collect::<Vec<_>>();
+    assert_eq!(keys.last(), Some(&"meta"));
+
+    assert_eq!(get(&records[1], "meta").and_then(|value| value.as_str()), Some("user"));
+    assert_eq!(get(&records[1], "meta_1").and_then(|value| value.as_str()), Some("user"));
+    let envelope = get(&records[1], "meta_2").expect("renamed envelope");
+    assert_eq!(get(envelope, "level").and_then(|value| value.as_str()), Some("INFO"));
+    let keys = records[1].as_map().expect("record").iter().map(|(key, _)| key.as_str().expect("key")).collect::<Vec<_>>();
+    assert_eq!(keys.last(), Some(&"meta_2"));
+
+    //Span attributes, inserted by worker, are placed before envelope.
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .with_metadata_key("meta")
+                                                     .with_span_registry()
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info_span!("request", id = 1).in_scope(|| {
+            tracing::info!("plain");
+            tracing::info!(meta = "user", "reserved");
+        });
+    });
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["span"]["id"].as_u64(), Some(1));
+    for (record, envelope) in records.iter().zip(["meta", "meta_1"].iter()) {
+        assert!(get(record, "span_ref").is_some());
+        assert!(get(record, "metadata").is_none());
+        assert_eq!(get(&record[*envelope], "level").and_then(|value| value.as_str()), Some("INFO"));
+        let keys = record.as_map().expect("record").iter().map(|(key, _)| key.as_str().expect("key")).collect::<Vec<_>>();
+        assert_eq!(keys.last(), Some(envelope));
+    }
+}
+
+#[test]
+fn should_cache_event_metadata_per_callsite() {
+    use rmpv::Value;
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let builder = tracing_fluentd::Builder::new("rust").with_writer(test_writer);
+    //Cloned builder must not share metadata, built with different options.
+    let lowercase = builder.clone().with_lowercase_level().disabled_layer();
+    let layer = builder.layer().expect("Create layer");
+
+    let dispatch = tracing::Dispatch::new(Registry::default().with(layer));
+    let lowercase = tracing::Dispatch::new(Registry::default().with(lowercase));
+    let line = line!();
+    for idx in 0..100u64 {
+        tracing::dispatcher::with_default(&lowercase, || tracing::info!(idx, "first"));
+        tracing::dispatcher::with_default(&dispatch, || {
+            tracing::info!(idx, "first");
+            tracing::warn!(idx, "second");
+        });
+    }
+
+    let layer = dispatch.downcast_ref::<tracing_fluentd::Layer<tracing_fluentd::NestedFmt, tracing_fluentd::ThreadWorker>>().expect("layer");
+    assert_eq!(layer.cached_callsites(), 2);
+    let lowercase_layer = lowercase.downcast_ref::<tracing_fluentd::Layer<tracing_fluentd::NestedFmt, tracing_fluentd::NullConsumer>>().expect("layer");
+    assert_eq!(lowercase_layer.cached_callsites(), 1);
+    drop(dispatch);
+
+    let records = read_records(&reader);
+    assert_eq!(records.len(), 200);
+    for (idx, pair) in records.chunks(2).enumerate() {
+        for (offset, (record, (message, level))) in pair.iter().zip([("first", "INFO"), ("second", "WARN")]).enumerate() {
+            let metadata = Value::Map(vec![
+                ("file".into(), file!().into()),
+                ("line".into(), (line + 4 + offset as u32).into()),
+                ("module".into(), module_path!().into()),
+                ("level".into(), level.into()),
+            ]);
+            let expected = Value::Map(vec![
+                ("message".into(), message.into()),
+                ("idx".into(), (idx as u64).into()),
+                ("metadata".into(), metadata),
+            ]);
+            assert_eq!(*record, expected);
+        }
+    }
+}
+
+#[test]
+fn should_not_leak_fields_between_pooled_records() {
+    for packed in [false, true] {
+        let (test_writer, reader) = MemoryWriter::new();
+        let builder = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                           .flatten()
+                                                           .with_record_pool(core::num::NonZeroUsize::new(4).unwrap())
+                                                           .with_eager_flush_level(tracing::Level::TRACE);
+        //Packed records are returned to pool right after encoding, hence every event reuses record.
+        let builder = match packed {
+            true => builder.with_packed_forward(),
+            false => builder,
+        };
+        let layer = builder.layer().expect("Create layer");
+
+        tracing::subscriber::with_default(Registry::default().with(layer), || {
+            for idx in 0..20u64 {
+                match idx % 2 {
+                    0 => tracing::info!(idx, even = true, "even"),
+                    _ => tracing::info_span!("odd", span_field = 1).in_scope(|| tracing::warn!(idx, odd = "yes", "odd")),
+                }
+                //Let worker write record, returning it into pool.
+                std::thread::sleep(core::time::Duration::from_millis(1));
+            }
+        });
+
+        let records = read_records(&reader);
+        assert_eq!(records.len(), 20);
+        for (idx, record) in records.iter().enumerate() {
+            let keys = record.as_map().expect("record").iter().map(|(key, _)| key.as_str().expect("key")).collect::<Vec<_>>();
+            let expected = match idx % 2 {
+                0 => ["message", "idx", "even", "file", "line", "module", "level"].as_slice(),
+                _ => ["message", "idx", "odd", "span_field", "file", "line", "module", "level"].as_slice(),
+            };
+            assert_eq!(keys, expected, "packed={}", packed);
+            assert_eq!(get(record, "idx").and_then(|idx| idx.as_u64()), Some(idx as u64));
+        }
+    }
 }
@@ -0,0 +1,238 @@
+use tracing_fluentd::fluent::{self, Map, Value, INLINE_LEN};
+
+fn key(idx: usize) -> fluent::Key {
+    format!("key{}", idx).into()
+}
+
+fn filled(len: usize) -> Map {
+    (0..len).map(|idx| (key(idx), Value::from(idx as u64))).collect()
+}
+
+fn keys(map: &Map) -> Vec<&str> {
+    map.keys().map(|key| key.as_ref()).collect()
+}
+
+fn expected_keys(range: impl Iterator<Item = usize>) -> Vec<String> {
+    range.map(|idx| format!("key{}", idx)).collect()
+}
+
+fn value(map: &Map, key: &str) -> Option<u64> {
+    match map.get(key) {
+        Some(Value::Uint(value)) => Some(*value),
+        Some(_) => panic!("unexpected value of {}", key),
+        None => None,
+    }
+}
+
+#[test]
+fn should_spill_once_inline_capacity_is_exceeded() {
+    let mut map = filled(INLINE_LEN);
+    assert!(map.is_inline());
+    assert_eq!(map.len(), INLINE_LEN);
+
+    //Replacing existing key does not grow map.
+    assert!(matches!(map.insert(key(0), 100u64.into()), Some(Value::Uint(0))));
+    assert!(map.is_inline());
+
+    assert!(map.insert(key(INLINE_LEN), (INLINE_LEN as u64).into()).is_none());
+    assert!(!map.is_inline());
+    assert_eq!(map.len(), INLINE_LEN + 1);
+    assert_eq!(keys(&map), expected_keys(0..=INLINE_LEN));
+    assert_eq!(value(&map, "key0"), Some(100));
+    for idx in 1..=INLINE_LEN {
+        assert_eq!(value(&map, &format!("key{}", idx)), Some(idx as u64));
+        assert_eq!(map.get_index_of(&format!("key{}", idx)), Some(idx));
+    }
+
+    //Spilled map is kept spilled, retaining its capacity.
+    map.clear();
+    assert!(map.is_empty());
+    assert!(!map.is_inline());
+    assert!(map.capacity() > INLINE_LEN);
+}
+
+#[test]
+fn should_choose_representation_by_capacity() {
+    assert!(Map::with_capacity(INLINE_LEN).is_inline());
+    assert!(!Map::with_capacity(INLINE_LEN + 1).is_inline());
+
+    let mut map = filled(4);
+    map.reserve(INLINE_LEN - 4);
+    assert!(map.is_inline());
+    map.reserve(INLINE_LEN - 3);
+    assert!(!map.is_inline());
+    assert_eq!(keys(&map), expected_keys(0..4));
+}
+
+fn uint(value: Option<Value>) -> Option<u64> {
+    match value {
+        Some(Value::Uint(value)) => Some(value),
+        Some(_) => panic!("unexpected value"),
+        None => None,
+    }
+}
+
+#[test]
+fn should_modify_map_same_way_in_both_representations() {
+    for len in [INLINE_LEN, 4 * INLINE_LEN] {
+        let mut map = filled(len);
+        assert_eq!(map.is_inline(), len == INLINE_LEN);
+
+        assert_eq!(uint(map.shift_remove("key1")), Some(1));
+        assert_eq!(uint(map.shift_remove("key1")), None);
+        let mut expected = expected_keys((0..len).filter(|idx| *idx != 1));
+        assert_eq!(keys(&map), expected);
+
+        assert_eq!(uint(map.swap_remove("key0")), Some(0));
+        let last = expected.pop().expect("last");
+        expected[0] = last;
+        assert_eq!(keys(&map), expected);
+
+        let last = map.len() - 1;
+        map.move_index(last, 0);
+        let moved = expected.pop().expect("last");
+        expected.insert(0, moved);
+        assert_eq!(keys(&map), expected);
+
+        map.retain(|_, value| !matches!(value, Value::Uint(value) if *value % 2 == 0));
+        expected.retain(|key| key[3..].parse::<usize>().expect("idx") % 2 == 1);
+        assert_eq!(keys(&map), expected);
+
+        for (_, value) in map.iter_mut() {
+            if let Value::Uint(value) = value {
+                *value *= 10;
+            }
+        }
+        assert_eq!(value(&map, "key3"), Some(30));
+        if let Some(value) = map.get_mut("key3") {
+            *value = "three".into();
+        }
+        assert_eq!(map["key3"].as_str(), Some("three"));
+        assert_eq!(map.first().map(|(key, _)| key.as_ref()), Some(expected[0].as_str()));
+        assert_eq!(map.get_index(1).map(|(key, _)| key.as_ref()), Some(expected[1].as_str()));
+        assert!(map.get_index(map.len()).is_none());
+
+        map.entry("key3".into()).or_insert(Value::Null);
+        map.entry("extra".into()).or_insert_with(|| 7u64.into());
+        map.entry("key5".into()).and_modify(|value| *value = 5u64.into()).or_insert(Value::Null);
+        assert_eq!(map["key3"].as_str(), Some("three"));
+        assert_eq!(value(&map, "extra"), Some(7));
+        assert_eq!(value(&map, "key5"), Some(5));
+        expected.push("extra".to_owned());
+        assert_eq!(keys(&map), expected);
+        assert_eq!(map.values().count(), expected.len());
+        assert_eq!(map.iter().rev().map(|(key, _)| key.as_ref()).next(), Some("extra"));
+
+        let drained = map.drain().map(|(key, _)| key.into_owned()).collect::<Vec<_>>();
+        assert_eq!(drained, expected);
+        assert!(map.is_empty());
+        assert!(map.get("extra").is_none());
+    }
+}
+
+#[test]
+fn should_encode_same_regardless_of_representation() {
+    let mut inline = filled(INLINE_LEN);
+    let mut spilled = filled(INLINE_LEN + 1);
+    spilled.shift_remove(&format!("key{}", INLINE_LEN));
+    assert!(inline.is_inline());
+    assert!(!spilled.is_inline());
+
+    let nested = filled(2 * INLINE_LEN);
+    inline.shift_remove("key0");
+    inline.insert("nested".into(), nested.clone().into());
+    spilled.shift_remove("key0");
+    spilled.insert("nested".into(), nested.into());
+    assert_eq!(keys(&inline), keys(&spilled));
+
+    let inline = rmp_serde::to_vec(&inline).expect("encode inline");
+    let spilled = rmp_serde::to_vec(&spilled).expect("encode spilled");
+    assert_eq!(inline, spilled);
+
+    let value = rmpv::decode::read_value(&mut inline.as_slice()).expect("decode");
+    let value = value.as_map().expect("map");
+    assert_eq!(value.len(), INLINE_LEN);
+    assert_eq!(value[0].0.as_str(), Some("key1"));
+    assert_eq!(value.last().and_then(|(_, nested)| nested.as_map()).map(Vec::len), Some(2 * INLINE_LEN));
+}
+
+#[test]
+fn should_collect_and_iterate_owned_entries() {
+    let map = filled(3 * INLINE_LEN);
+    let owned = map.clone().into_iter().map(|(key, _)| key.into_owned()).collect::<Vec<_>>();
+    assert_eq!(owned, expected_keys(0..3 * INLINE_LEN));
+    assert_eq!((&map).into_iter().len(), 3 * INLINE_LEN);
+
+    let mut extended = Map::new();
+    extended.extend(map.into_iter().take(INLINE_LEN));
+    assert!(extended.is_inline());
+    assert_eq!(keys(&extended), expected_keys(0..INLINE_LEN));
+    assert_eq!(format!("{:?}", filled(1)), "{\"key0\": 0}");
+}
+
+#[test]
+fn should_build_nested_map_via_macro() {
+    use tracing_fluentd::fluent_map;
+
+    let port = 8080u64;
+    let map = fluent_map! {
+        "service" => "api",
+        "port" => port,
+        "offset" => -1,
+        "ratio" => 0.5,
+        "enabled" => true,
+        "owner" => String::from("team"),
+        "hosts" => vec![Value::from("a"), Value::from("b")],
+        "labels" => fluent_map! {
+            "zone" => "eu-1",
+            "tier" => fluent_map! {
+                "level" => 2u64,
+            },
+        },
+        format!("dynamic_{}", 1) => Value::Null,
+    };
+
+    assert_eq!(keys(&map), ["service", "port", "offset", "ratio", "enabled", "owner", "hosts", "labels", "dynamic_1"]);
+    assert_eq!(map["service"].as_str(), Some("api"));
+    assert_eq!(value(&map, "port"), Some(8080));
+    assert!(matches!(map["offset"], Value::Int(-1)));
+    assert!(matches!(map["ratio"], Value::Float(ratio) if ratio == 0.5));
+    assert!(matches!(map["enabled"], Value::Bool(true)));
+    assert_eq!(map["owner"].as_str(), Some("team"));
+    assert!(matches!(&map["hosts"], Value::Array(hosts) if hosts.len() == 2));
+    assert!(matches!(map["dynamic_1"], Value::Null));
+
+    let labels = match &map["labels"] {
+        Value::Object(labels) => labels,
+        value => panic!("unexpected labels {:?}", value),
+    };
+    assert_eq!(labels["zone"].as_str(), Some("eu-1"));
+    match &labels["tier"] {
+        Value::Object(tier) => assert_eq!(value(tier, "level"), Some(2)),
+        value => panic!("unexpected tier {:?}", value),
+    }
+
+    //Later value of duplicate key replaces earlier one, keeping its position.
+    let map = fluent_map! { "key" => 1u64, "other" => 2u64, "key" => 3u64 };
+    assert_eq!(keys(&map), ["key", "other"]);
+    assert_eq!(value(&map, "key"), Some(3));
+    assert!(fluent_map! {}.is_empty());
+}
+
+#[test]
+fn should_build_object_via_closure() {
+    let object = Value::object(|map| {
+        map.insert("zone".into(), "eu-1".into());
+        map.insert("tier".into(), Value::object(|tier| {
+            tier.insert("level".into(), 2u64.into());
+        }));
+    });
+
+    let map = match object {
+        Value::Object(map) => map,
+        object => panic!("unexpected value {:?}", object),
+    };
+    assert_eq!(keys(&map), ["zone", "tier"]);
+    assert!(matches!(&map["tier"], Value::Object(tier) if value(tier, "level") == Some(2)));
+    assert!(matches!(Value::object(|_| ()), Value::Object(map) if map.is_empty()));
+}
@@ -0,0 +1,111 @@
+#![cfg(feature = "metrics")]
+
+use tracing_subscriber::Registry;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_fluentd::testing::MemoryWriter;
+use metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshotter};
+
+use core::time::Duration;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+fn counter(snapshotter: &Snapshotter, name: &str, labels: &[(&str, &str)]) -> u64 {
+    snapshotter.snapshot().into_vec().into_iter().find_map(|(key, _, _, value)| {
+        let key = key.key();
+        let is_match = key.name() == name && key.labels().map(|label| (label.key(), label.value())).eq(labels.iter().copied());
+        match (is_match, value) {
+            (true, DebugValue::Counter(value)) => Some(value),
+            _ => None,
+        }
+    }).unwrap_or(0)
+}
+
+fn wait_for(condition: impl Fn() -> bool) {
+    for _ in 0..250 {
+        if condition() {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    panic!("Condition is not met in time");
+}
+
+struct FlakyWrite {
+    writer: MemoryWriter,
+    down: Arc<AtomicBool>,
+}
+
+impl Write for FlakyWrite {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.down.load(Ordering::SeqCst) {
+            true => Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken")),
+            false => self.writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn should_report_metrics_during_outage_and_recovery() {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    recorder.install().expect("Install recorder");
+
+    let (memory, reader) = MemoryWriter::new();
+    let down = Arc::new(AtomicBool::new(false));
+    let writer = {
+        let down = down.clone();
+        move || match down.load(Ordering::SeqCst) {
+            true => Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused")),
+            false => Ok(FlakyWrite {
+                writer: memory.clone(),
+                down: down.clone(),
+            }),
+        }
+    };
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(writer)
+                                                     .with_eager_flush_level(tracing::Level::INFO)
+                                                     .with_metrics("tracing_fluentd")
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+
+    tracing::info!(idx = 0, "up");
+    wait_for(|| counter(&snapshotter, "tracing_fluentd_records_delivered_total", &[]) == 1);
+
+    //Cached connection breaks
+    down.store(true, Ordering::SeqCst);
+    tracing::info!(idx = 1, "down");
+    wait_for(|| counter(&snapshotter, "tracing_fluentd_delivery_errors_total", &[("kind", "BrokenPipe")]) == 1);
+
+    //Connection cannot be created, with single retry
+    tracing::info!(idx = 2, "down");
+    wait_for(|| counter(&snapshotter, "tracing_fluentd_delivery_errors_total", &[("kind", "ConnectionRefused")]) == 2);
+    assert_eq!(counter(&snapshotter, "tracing_fluentd_records_delivered_total", &[]), 1);
+    assert_eq!(counter(&snapshotter, "tracing_fluentd_reconnects_total", &[]), 0);
+
+    down.store(false, Ordering::SeqCst);
+    tracing::info!(idx = 3, "recovered");
+    wait_for(|| counter(&snapshotter, "tracing_fluentd_records_delivered_total", &[]) == 4);
+    assert_eq!(counter(&snapshotter, "tracing_fluentd_reconnects_total", &[]), 1);
+    assert_eq!(reader.records().len(), 4);
+    drop(guard);
+
+    //Records beyond memory budget are dropped
+    let (memory, _reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(memory)
+                                                     .with_memory_budget(core::num::NonZeroUsize::new(1).unwrap(), tracing_fluentd::OverflowPolicy::DropNewest)
+                                                     .with_metrics("other")
+                                                     .layer()
+                                                     .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info!("dropped");
+        tracing::info!("dropped");
+    });
+    assert_eq!(counter(&snapshotter, "other_records_dropped_total", &[("reason", "queue_full")]), 2);
+    assert_eq!(counter(&snapshotter, "tracing_fluentd_records_dropped_total", &[("reason", "queue_full")]), 0);
+}
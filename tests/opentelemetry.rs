@@ -0,0 +1,75 @@
+#![cfg(feature = "opentelemetry")]
+
+use tracing_subscriber::Registry;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_fluentd::testing::MemoryWriter;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use opentelemetry::trace::{TraceContextExt, TracerProvider};
+
+fn get<'a>(record: &'a rmpv::Value, key: &str) -> Option<&'a rmpv::Value> {
+    record.as_map().expect("Record map").iter().find(|(name, _)| name.as_str() == Some(key)).map(|(_, value)| value)
+}
+
+#[test]
+fn should_record_opentelemetry_ids() {
+    let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder().build();
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .flatten()
+                                                     .with_opentelemetry_keys("trace.id", "span.id")
+                                                     .layer()
+                                                     .expect("Create layer");
+    let otel = tracing_opentelemetry::layer().with_tracer(provider.tracer("test"));
+
+    let mut ids = Vec::new();
+    tracing::subscriber::with_default(Registry::default().with(otel).with(layer), || {
+        tracing::info!("outside");
+        tracing::info_span!("request").in_scope(|| {
+            tracing::info!("request");
+            tracing::info_span!("query").in_scope(|| {
+                tracing::info!("query");
+                let context = tracing::Span::current().context();
+                let span = context.span();
+                ids.push((span.span_context().trace_id().to_string(), span.span_context().span_id().to_string()));
+            });
+            let context = tracing::Span::current().context();
+            let span = context.span();
+            ids.insert(0, (span.span_context().trace_id().to_string(), span.span_context().span_id().to_string()));
+        });
+    });
+
+    let records = reader.records();
+    assert_eq!(records.len(), 3);
+    assert!(get(&records[0], "trace.id").is_none());
+    assert!(get(&records[0], "span.id").is_none());
+
+    assert_eq!(ids[0].0, ids[1].0);
+    assert_ne!(ids[0].1, ids[1].1);
+    for (record, (trace_id, span_id)) in records[1..].iter().zip(ids.iter()) {
+        assert_eq!(record["trace.id"].as_str(), Some(trace_id.as_str()));
+        assert_eq!(record["span.id"].as_str(), Some(span_id.as_str()));
+        assert_eq!(trace_id.len(), 32);
+        assert_eq!(span_id.len(), 16);
+        assert!(trace_id.chars().chain(span_id.chars()).all(|ch| matches!(ch, '0'..='9' | 'a'..='f')));
+    }
+}
+
+#[test]
+fn should_not_record_opentelemetry_ids_without_opentelemetry_layer() {
+    let (test_writer, reader) = MemoryWriter::new();
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                     .flatten()
+                                                     .layer()
+                                                     .expect("Create layer");
+
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info_span!("request").in_scope(|| {
+            tracing::info!("request");
+        });
+    });
+
+    let records = reader.records();
+    assert_eq!(records.len(), 1);
+    assert!(get(&records[0], "trace_id").is_none());
+    assert!(get(&records[0], "span_id").is_none());
+}
@@ -0,0 +1,100 @@
+use tracing_fluentd::testing::{MemoryWriter, TestClock};
+
+//Panic hook is global, hence tests must not panic concurrently.
+static HOOK_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+fn lock_hook() -> std::sync::MutexGuard<'static, ()> {
+    HOOK_LOCK.lock().unwrap_or_else(|error| error.into_inner())
+}
+
+fn header_time(time: &rmpv::Value) -> core::time::Duration {
+    match time {
+        rmpv::Value::Integer(secs) => core::time::Duration::from_secs(secs.as_u64().expect("u64 time")),
+        rmpv::Value::Ext(0, bytes) => {
+            let secs = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            let nanos = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+            core::time::Duration::new(secs.into(), nanos)
+        },
+        other => panic!("Unexpected time {}", other),
+    }
+}
+
+#[test]
+fn should_send_panic_record_before_unwinding() {
+    let _lock = lock_hook();
+    let (test_writer, reader) = MemoryWriter::new();
+    let (_layer, guard) = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                               .layer_guarded()
+                                                               .expect("Create layer");
+    tracing_fluentd::install_panic_hook(&guard);
+
+    let line = line!() + 1;
+    let result = std::panic::catch_unwind(|| panic!("boom {}", 1));
+    assert!(result.is_err());
+
+    //Record is written before panic continues, without waiting for guard to be dropped.
+    let records = reader.records();
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(record["message"].as_str(), Some("boom 1"));
+    assert_eq!(record["thread_name"].as_str(), std::thread::current().name());
+    assert_eq!(record["metadata"]["level"].as_str(), Some("ERROR"));
+    assert_eq!(record["metadata"]["module"].as_str(), Some("panic"));
+    assert_eq!(record["metadata"]["file"].as_str(), Some(file!()));
+    assert_eq!(record["metadata"]["line"].as_u64(), Some(line.into()));
+
+    let result = std::panic::catch_unwind(|| std::panic::panic_any(5u8));
+    assert!(result.is_err());
+    drop(guard);
+
+    let records = reader.records();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[1]["message"].as_str(), Some("Box<dyn Any>"));
+}
+
+#[test]
+fn should_insert_panic_metadata_under_custom_key() {
+    let _lock = lock_hook();
+    let (test_writer, reader) = MemoryWriter::new();
+    let (_layer, guard) = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                               .with_metadata_key("meta")
+                                                               .layer_guarded()
+                                                               .expect("Create layer");
+    tracing_fluentd::install_panic_hook(&guard);
+
+    let result = std::panic::catch_unwind(|| panic!("boom"));
+    assert!(result.is_err());
+    drop(guard);
+
+    let records = reader.records();
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!(record["message"].as_str(), Some("boom"));
+    assert!(record.as_map().expect("record").iter().all(|(key, _)| key.as_str() != Some("metadata")));
+    assert_eq!(record["meta"]["level"].as_str(), Some("ERROR"));
+    assert_eq!(record["meta"]["module"].as_str(), Some("panic"));
+}
+
+#[test]
+fn should_create_panic_record_according_to_layer_options() {
+    let _lock = lock_hook();
+    let clock = TestClock::new();
+    clock.set_system_time(std::time::UNIX_EPOCH + core::time::Duration::from_secs(1_000_000));
+    let (test_writer, reader) = MemoryWriter::new();
+    let (_layer, guard) = tracing_fluentd::Builder::new("rust").with_writer(test_writer)
+                                                               .with_clock(clock)
+                                                               .with_lowercase_level()
+                                                               .layer_guarded()
+                                                               .expect("Create layer");
+    tracing_fluentd::install_panic_hook(&guard);
+
+    let result = std::panic::catch_unwind(|| panic!("boom"));
+    assert!(result.is_err());
+    drop(guard);
+
+    let entries = reader.entries();
+    assert_eq!(entries.len(), 1);
+    let (time, record) = &entries[0];
+    assert_eq!(header_time(time), core::time::Duration::from_secs(1_000_000));
+    assert_eq!(record["metadata"]["level"].as_str(), Some("error"));
+}
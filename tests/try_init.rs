@@ -0,0 +1,88 @@
+//!Global subscriber can be set only once per process, hence every case is run within its own
+//!process, spawned from this test binary.
+use tracing_fluentd::testing::MemoryWriter;
+
+const CASE_ENV: &str = "TRACING_FLUENTD_TRY_INIT_CASE";
+
+//Runs test `name` within new process, failing if it fails.
+fn run_in_process(name: &str) {
+    let output = std::process::Command::new(std::env::current_exe().expect("current exe"))
+        .args(["--exact", name, "--nocapture", "--test-threads", "1"])
+        .env(CASE_ENV, name)
+        .output()
+        .expect("spawn test process");
+    assert!(output.status.success(), "{} failed:\n{}\n{}", name, String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+}
+
+//Returns whether test is run within its own process.
+fn is_in_process(name: &str) -> bool {
+    match std::env::var(CASE_ENV) {
+        Ok(case) => case == name,
+        Err(_) => {
+            run_in_process(name);
+            false
+        },
+    }
+}
+
+#[test]
+fn should_init_global_subscriber() {
+    if !is_in_process("should_init_global_subscriber") {
+        return;
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let guard = tracing_fluentd::Builder::new("rust").with_writer(test_writer).try_init().expect("init");
+    tracing::info!(idx = 1, "first");
+    tracing::info!(idx = 2, "second");
+
+    let (test_writer, _) = MemoryWriter::new();
+    match tracing_fluentd::Builder::new("rust").with_writer(test_writer).try_init() {
+        Err(tracing_fluentd::InitError::AlreadySet(_)) => (),
+        result => panic!("Unexpected result: {:?}", result.map(|_| ())),
+    }
+
+    //Remaining records are sent once guard is dropped.
+    drop(guard);
+    let records = reader.records();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["message"].as_str(), Some("first"));
+    assert_eq!(records[1]["message"].as_str(), Some("second"));
+}
+
+#[test]
+fn should_init_global_subscriber_with_extra_layer() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static EVENTS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counter;
+
+    impl<S: tracing_core::Subscriber> tracing_subscriber::Layer<S> for Counter {
+        fn on_event(&self, _: &tracing_core::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+            EVENTS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    if !is_in_process("should_init_global_subscriber_with_extra_layer") {
+        return;
+    }
+
+    let (test_writer, reader) = MemoryWriter::new();
+    let guard = tracing_fluentd::Builder::new("rust").with_writer(test_writer).flatten().try_init_with(Counter).expect("init");
+    tracing::info_span!("request", user = "admin").in_scope(|| {
+        tracing::info!("composed");
+    });
+
+    match tracing_fluentd::Builder::new("rust").try_init_with(Counter) {
+        Err(tracing_fluentd::InitError::AlreadySet(_)) => (),
+        result => panic!("Unexpected result: {:?}", result.map(|_| ())),
+    }
+
+    drop(guard);
+    assert_eq!(EVENTS.load(Ordering::SeqCst), 1);
+    let records = reader.records();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["message"].as_str(), Some("composed"));
+    assert_eq!(records[0]["user"].as_str(), Some("admin"));
+}
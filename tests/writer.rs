@@ -0,0 +1,1626 @@
+use tracing_subscriber::Registry;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_fluentd::MakeWriter;
+use tracing_fluentd::testing::{MemoryWriter, MockFluentd, Fault, TestClock};
+
+use std::fs;
+use std::io::{self, Write};
+
+fn failing_writer() -> io::Result<MemoryWriter> {
+    Err(io::Error::other("failing writer"))
+}
+
+struct BrokenWrite;
+
+impl Write for BrokenWrite {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn should_tee_records_into_both_writers() {
+    let (primary, primary_reader) = MemoryWriter::new();
+    let (secondary, secondary_reader) = MemoryWriter::new();
+
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(tracing_fluentd::writer::tee(primary, secondary)).layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    for idx in 0..25 {
+        tracing::info!(idx, "tee");
+    }
+    drop(guard);
+
+    let primary = primary_reader.bytes();
+    let secondary = secondary_reader.bytes();
+    assert!(!primary.is_empty());
+    assert_eq!(primary, secondary);
+}
+
+#[test]
+fn should_tee_fail_on_secondary_error_unless_best_effort() {
+    let (primary, primary_reader) = MemoryWriter::new();
+
+    let strict = tracing_fluentd::writer::tee(primary, failing_writer as fn() -> io::Result<MemoryWriter>);
+    assert!(strict.make().is_err());
+
+    let best_effort = strict.best_effort();
+    let mut writer = best_effort.make().expect("make writer");
+    writer.write_all(b"data").expect("write");
+    drop(writer);
+    assert_eq!(primary_reader.bytes(), b"data");
+
+    let (primary, _) = MemoryWriter::new();
+    let strict = tracing_fluentd::writer::tee(primary, || Ok(BrokenWrite));
+    let mut writer = strict.make().expect("make writer");
+    assert!(writer.write_all(b"data").is_err());
+
+    let (primary, primary_reader) = MemoryWriter::new();
+    let best_effort = tracing_fluentd::writer::tee(primary, || Ok(BrokenWrite)).best_effort();
+    let mut writer = best_effort.make().expect("make writer");
+    writer.write_all(b"data").expect("write");
+    writer.write_all(b"more").expect("write");
+    drop(writer);
+    assert_eq!(primary_reader.bytes(), b"datamore");
+}
+
+fn dead_addr() -> std::net::SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+    listener.local_addr().expect("local addr")
+}
+
+#[test]
+fn should_connect_to_live_address_from_vec_and_slice() {
+    let fluentd = MockFluentd::start().expect("start fluentd");
+    let live = fluentd.addr();
+    let dead = dead_addr();
+
+    let addrs = vec![dead, live];
+    let socket = addrs.make().expect("connect");
+    assert_eq!(socket.peer_addr().expect("peer"), live);
+
+    let addrs: &'static [std::net::SocketAddr] = Box::leak(vec![dead, live].into_boxed_slice());
+    let socket = addrs.make().expect("connect");
+    assert_eq!(socket.peer_addr().expect("peer"), live);
+
+    let addrs = vec![dead];
+    assert_eq!(addrs.make().expect_err("dead address").kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn should_fail_to_make_with_empty_vec() {
+    let addrs: Vec<std::net::SocketAddr> = Vec::new();
+    let error = addrs.make().expect_err("empty vec");
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn should_connect_using_owned_string_addresses() {
+    let fluentd = MockFluentd::start().expect("start fluentd");
+    let live = fluentd.addr();
+
+    let addr = format!("127.0.0.1:{}", live.port());
+    assert_eq!(addr.make().expect("connect").peer_addr().expect("peer"), live);
+
+    let addr = ("127.0.0.1".to_owned(), live.port());
+    assert_eq!(addr.make().expect("connect").peer_addr().expect("peer"), live);
+
+    let addr: std::borrow::Cow<'static, str> = format!("127.0.0.1:{}", live.port()).into();
+    assert_eq!(addr.make().expect("connect").peer_addr().expect("peer"), live);
+
+    let dead = format!("127.0.0.1:{}", dead_addr().port());
+    assert_eq!(dead.make().expect_err("dead").kind(), io::ErrorKind::NotFound);
+
+    let invalid = "not an address".to_owned();
+    assert_eq!(invalid.make().expect_err("invalid").kind(), io::ErrorKind::NotFound);
+}
+
+struct RetryWriter {
+    writer: MemoryWriter,
+    contexts: std::sync::Arc<std::sync::Mutex<Vec<tracing_fluentd::MakeContext>>>,
+}
+
+impl MakeWriter for RetryWriter {
+    type Writer = MemoryWriter;
+
+    fn make(&self) -> io::Result<Self::Writer> {
+        unreachable!("Worker should use make_with")
+    }
+
+    fn make_with(&self, ctx: &tracing_fluentd::MakeContext) -> io::Result<Self::Writer> {
+        self.contexts.lock().expect("lock").push(*ctx);
+        if ctx.attempt > 0 {
+            self.writer.make()
+        } else {
+            Err(io::Error::new(io::ErrorKind::ConnectionRefused, "first attempt"))
+        }
+    }
+}
+
+#[test]
+fn should_pass_attempt_context_to_make_with() {
+    let contexts = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let (writer, reader) = MemoryWriter::new();
+    let writer = RetryWriter {
+        writer,
+        contexts: contexts.clone(),
+    };
+
+    let clock = TestClock::new();
+
+    let layer = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                     .with_writer(writer)
+                                                     .with_clock(clock.clone())
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("retry");
+
+    //Worker waits before retrying, until time is advanced.
+    clock.wait_for_sleepers(1, core::time::Duration::from_secs(5));
+    assert_eq!(contexts.lock().expect("lock").len(), 1);
+    clock.advance(core::time::Duration::from_secs(1));
+    drop(guard);
+
+    assert_eq!(reader.records().len(), 1);
+    let contexts = contexts.lock().expect("lock");
+    assert_eq!(contexts.len(), 2);
+    assert_eq!(contexts[0].attempt, 0);
+    assert!(!contexts[0].previous_failed);
+    assert_eq!(contexts[0].last_error, None);
+    assert_eq!(contexts[1].attempt, 1);
+    assert!(contexts[1].previous_failed);
+    assert_eq!(contexts[1].last_error, Some(io::ErrorKind::ConnectionRefused));
+}
+
+#[test]
+fn should_fallback_and_return_to_primary() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let primary_up = Arc::new(AtomicBool::new(false));
+    let (primary_buf, primary_reader) = MemoryWriter::new();
+    let (secondary_buf, secondary_reader) = MemoryWriter::new();
+
+    let primary = {
+        let primary_up = primary_up.clone();
+        move || match primary_up.load(Ordering::SeqCst) {
+            true => Ok(primary_buf.clone()),
+            false => Err(io::Error::new(io::ErrorKind::ConnectionRefused, "primary down")),
+        }
+    };
+    let secondary = secondary_buf;
+
+    let probe_interval = core::time::Duration::from_millis(100);
+    let fallback = tracing_fluentd::writer::fallback(primary, secondary).probe_interval(probe_interval);
+
+    //Primary is down, so fallback is used
+    let mut writer = fallback.make().expect("make writer");
+    assert!(!writer.is_primary());
+    writer.write_all(b"first").expect("write");
+    writer.flush().expect("flush");
+
+    //Primary is back, but probe interval is not yet passed
+    primary_up.store(true, Ordering::SeqCst);
+    writer.write_all(b"second").expect("write");
+    writer.flush().expect("flush");
+    assert!(!writer.is_primary());
+    assert!(!fallback.make().expect("make writer").is_primary());
+
+    //Once interval passes, traffic returns to primary, even for already created writer
+    std::thread::sleep(probe_interval);
+    writer.write_all(b"third").expect("write");
+    writer.flush().expect("flush");
+    assert!(writer.is_primary());
+    writer.write_all(b"fourth").expect("write");
+    writer.flush().expect("flush");
+    assert!(fallback.make().expect("make writer").is_primary());
+
+    assert_eq!(secondary_reader.bytes(), b"firstsecondthird");
+    assert_eq!(primary_reader.bytes(), b"fourth");
+
+    //Primary goes down again
+    primary_up.store(false, Ordering::SeqCst);
+    assert!(!fallback.make().expect("make writer").is_primary());
+}
+
+#[test]
+fn should_rotate_files_on_message_boundary() {
+    let dir = std::env::temp_dir().join(format!("tracing-fluentd-rotate-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("create dir");
+
+    let writer = tracing_fluentd::writer::RotatingFile::new(&dir, "records.fluentd").max_size(256)
+                                                                                    .max_files(core::num::NonZeroUsize::new(3).unwrap());
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(writer).layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    for idx in 0..200 {
+        tracing::info!(idx, padding = "rotation padding to make records bigger", "rotate");
+    }
+    drop(guard);
+
+    let mut files = fs::read_dir(&dir).expect("read dir").map(|entry| entry.expect("entry").file_name().into_string().expect("utf-8")).collect::<Vec<_>>();
+    files.sort();
+    assert!(files.len() <= 3, "Too many files: {:?}", files);
+    assert!(files.iter().any(|name| name == "records.fluentd.1"), "No rotation: {:?}", files);
+    assert!(files.iter().all(|name| name.starts_with("records.fluentd")));
+
+    for name in files {
+        let data = fs::read(dir.join(&name)).expect("read file");
+        let mut cursor = io::Cursor::new(data.as_slice());
+        while (cursor.position() as usize) < data.len() {
+            let frame = rmpv::decode::read_value(&mut cursor).unwrap_or_else(|error| panic!("{}: invalid frame: {}", name, error));
+            let frame = frame.as_array().expect("frame array");
+            assert_eq!(frame[0].as_str(), Some("rust"));
+            assert!(!frame[1].as_array().expect("entries").is_empty());
+        }
+    }
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn should_connect_localhost_over_ipv6() {
+    let listener = match std::net::TcpListener::bind("[::1]:24224") {
+        Ok(listener) => listener,
+        //No IPv6 or port is busy
+        Err(_) => return,
+    };
+
+    let socket = tracing_fluentd::localhost().expect("connect over IPv6");
+    assert!(socket.peer_addr().expect("peer addr").is_ipv6());
+    let (_, addr) = listener.accept().expect("accept");
+    assert!(addr.is_ipv6());
+}
+
+#[test]
+fn should_cache_resolved_addresses() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let fluentd = MockFluentd::start().expect("start fluentd");
+    let live = fluentd.addr();
+    let dead = dead_addr();
+
+    let lookups = Arc::new(AtomicUsize::new(0));
+    let counting_lookup = |result: Vec<std::net::SocketAddr>| {
+        let lookups = lookups.clone();
+        move |addr: &str| {
+            assert_eq!(addr, "fluentd.internal:24224");
+            lookups.fetch_add(1, Ordering::SeqCst);
+            Ok(result.clone())
+        }
+    };
+
+    //Cached within TTL and rotates to live address
+    let resolver = tracing_fluentd::writer::resolve("fluentd.internal:24224").ttl(core::time::Duration::from_secs(60))
+                                                                          .with_lookup(counting_lookup(vec![dead, live]));
+    for _ in 0..3 {
+        assert_eq!(resolver.make().expect("connect").peer_addr().expect("peer"), live);
+    }
+    assert_eq!(lookups.swap(0, Ordering::SeqCst), 1);
+
+    //Expired TTL forces lookup on every attempt
+    let resolver = tracing_fluentd::writer::resolve("fluentd.internal:24224").ttl(core::time::Duration::from_secs(0))
+                                                                          .with_lookup(counting_lookup(vec![live]));
+    for _ in 0..3 {
+        resolver.make().expect("connect");
+    }
+    assert_eq!(lookups.swap(0, Ordering::SeqCst), 3);
+
+    //Consecutive failures force lookup
+    let resolver = tracing_fluentd::writer::resolve("fluentd.internal:24224").max_failures(core::num::NonZeroUsize::new(2).unwrap())
+                                                                          .with_lookup(counting_lookup(vec![dead]));
+    for _ in 0..2 {
+        assert_eq!(resolver.make().expect_err("dead").kind(), io::ErrorKind::NotFound);
+    }
+    assert_eq!(lookups.load(Ordering::SeqCst), 1);
+    resolver.make().expect_err("dead");
+    assert_eq!(lookups.load(Ordering::SeqCst), 2);
+}
+
+//Returns target and data received by proxy
+#[cfg(feature = "proxy")]
+type ProxyHandle = std::thread::JoinHandle<(Vec<u8>, Vec<u8>)>;
+
+#[cfg(feature = "proxy")]
+fn spawn_socks5_proxy(reply: u8) -> (std::net::SocketAddr, ProxyHandle) {
+    use std::io::Read;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    let handle = std::thread::spawn(move || {
+        let (mut socket, _) = listener.accept().expect("accept");
+
+        let mut greeting = [0u8; 3];
+        socket.read_exact(&mut greeting).expect("read greeting");
+        assert_eq!(greeting, [5, 1, 0]);
+        socket.write_all(&[5, 0]).expect("write method");
+
+        let mut request = [0u8; 5];
+        socket.read_exact(&mut request).expect("read request");
+        assert_eq!(request[..4], [5, 1, 0, 3]);
+        let mut target = vec![0u8; request[4] as usize + 2];
+        socket.read_exact(&mut target).expect("read target");
+        socket.write_all(&[5, reply, 0, 1, 127, 0, 0, 1, 0, 0]).expect("write reply");
+
+        let mut data = Vec::new();
+        let _ = socket.read_to_end(&mut data);
+        (target, data)
+    });
+
+    (addr, handle)
+}
+
+#[cfg(feature = "proxy")]
+#[test]
+fn should_connect_via_socks5_proxy() {
+    let (proxy, handle) = spawn_socks5_proxy(0);
+
+    let writer = tracing_fluentd::proxy::Proxy::socks5(proxy.to_string(), "fluentd.internal:24224");
+    let mut socket = writer.make().expect("connect via proxy");
+    socket.write_all(b"payload").expect("write");
+    drop(socket);
+
+    let (target, data) = handle.join().expect("proxy thread");
+    assert_eq!(&target[..target.len() - 2], b"fluentd.internal");
+    assert_eq!(target[target.len() - 2..], 24224u16.to_be_bytes());
+    assert_eq!(data, b"payload");
+
+    let (proxy, handle) = spawn_socks5_proxy(5);
+    let writer = tracing_fluentd::proxy::Proxy::socks5(proxy.to_string(), "fluentd.internal:24224");
+    assert_eq!(writer.make().expect_err("refused by proxy").kind(), io::ErrorKind::ConnectionRefused);
+    handle.join().expect("proxy thread");
+}
+
+fn boxed_builder(use_memory: bool, memory: MemoryWriter, dead: std::net::SocketAddr) -> tracing_fluentd::Builder<tracing_fluentd::NestedFmt, tracing_fluentd::writer::BoxMakeWriter> {
+    let builder = tracing_fluentd::Builder::new("rust");
+    match use_memory {
+        true => builder.with_boxed_writer(memory),
+        false => builder.with_boxed_writer(dead),
+    }
+}
+
+#[test]
+fn should_select_boxed_writer_at_runtime() {
+    let dead = dead_addr();
+    let (memory, reader) = MemoryWriter::new();
+
+    let tcp = boxed_builder(false, memory.clone(), dead);
+    drop(tcp);
+
+    let layer = boxed_builder(true, memory, dead).layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("boxed");
+    drop(guard);
+
+    assert_eq!(reader.records().len(), 1);
+
+    let boxed = tracing_fluentd::writer::BoxMakeWriter::new(vec![dead]);
+    assert_eq!(boxed.make().err().expect("dead").kind(), io::ErrorKind::NotFound);
+}
+
+#[derive(Clone)]
+struct CountingWrite {
+    writer: MemoryWriter,
+    writes: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    fail: bool,
+}
+
+impl Write for CountingWrite {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        match self.fail {
+            true => Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken")),
+            false => self.writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn should_coalesce_writes_of_buffered_writer() {
+    use std::sync::atomic::Ordering;
+
+    let (memory, reader) = MemoryWriter::new();
+    let counting = CountingWrite {
+        writer: memory,
+        writes: Default::default(),
+        fail: false,
+    };
+    let writes = counting.writes.clone();
+
+    let writer = {
+        let counting = counting.clone();
+        move || Ok(counting.clone())
+    };
+    let layer = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                     .with_writer(tracing_fluentd::writer::buffered(writer))
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    for idx in 0..10 {
+        tracing::info!(idx, "buffered");
+    }
+    drop(guard);
+
+    assert_eq!(reader.records().len(), 10);
+    assert_eq!(writes.load(Ordering::SeqCst), reader.frames().len());
+
+    //Exceeding threshold writes without flush
+    let buffered = tracing_fluentd::writer::buffered(move || Ok(counting.clone())).threshold(4);
+    writes.store(0, Ordering::SeqCst);
+    let mut writer = buffered.make().expect("make writer");
+    writer.write_all(b"12").expect("write");
+    assert_eq!(writes.load(Ordering::SeqCst), 0);
+    writer.write_all(b"34").expect("write");
+    assert_eq!(writes.load(Ordering::SeqCst), 1);
+
+    //Failure is reported on flush
+    let failing = CountingWrite {
+        writer: MemoryWriter::new().0,
+        writes: Default::default(),
+        fail: true,
+    };
+    let buffered = tracing_fluentd::writer::buffered(move || Ok(failing.clone()));
+    let mut writer = buffered.make().expect("make writer");
+    writer.write_all(b"data").expect("write");
+    assert_eq!(writer.flush().expect_err("broken").kind(), io::ErrorKind::BrokenPipe);
+    writer.flush().expect("buffer is discarded");
+}
+
+#[test]
+fn should_retry_connection_with_tcp_config() {
+    let addr = dead_addr();
+
+    //Listener becomes available only after the first attempt
+    let listener = std::thread::spawn(move || {
+        std::thread::sleep(core::time::Duration::from_millis(100));
+        let listener = std::net::TcpListener::bind(addr).expect("bind");
+        listener.accept().expect("accept");
+    });
+
+    let single = tracing_fluentd::writer::TcpConfig::new(addr);
+    assert_eq!(single.make().expect_err("refused").kind(), io::ErrorKind::NotFound);
+
+    let retry = tracing_fluentd::writer::TcpConfig::new(addr).attempts(core::num::NonZeroUsize::new(2).unwrap())
+                                                              .delay(core::time::Duration::from_millis(500));
+    let socket = retry.make().expect("connect on second attempt");
+    assert_eq!(socket.peer_addr().expect("peer"), addr);
+    listener.join().expect("listener");
+}
+
+fn sha512_hex(parts: &[&[u8]]) -> String {
+    use sha2::Digest;
+
+    let mut hasher = sha2::Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+//Server side of forward protocol handshake, returning data received after handshake.
+fn spawn_secure_fluentd(shared_key: &'static str, connections: usize) -> (std::net::SocketAddr, std::thread::JoinHandle<Vec<u8>>) {
+    use std::io::Read;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    let handle = std::thread::spawn(move || {
+        let mut data = Vec::new();
+        for _ in 0..connections {
+            let (mut socket, _) = listener.accept().expect("accept");
+            let nonce = "server-nonce";
+            let helo = rmpv::Value::Array(vec!["HELO".into(), rmpv::Value::Map(vec![
+                ("nonce".into(), rmpv::Value::Binary(nonce.as_bytes().to_vec())),
+                ("auth".into(), "".into()),
+                ("keepalive".into(), true.into()),
+            ])]);
+            rmpv::encode::write_value(&mut socket, &helo).expect("write HELO");
+
+            let ping = rmpv::decode::read_value(&mut socket).expect("read PING");
+            let ping = ping.as_array().expect("PING array");
+            assert_eq!(ping[0].as_str(), Some("PING"));
+            let hostname = ping[1].as_str().expect("hostname");
+            let salt = ping[2].as_str().expect("salt");
+            let expected = sha512_hex(&[salt.as_bytes(), hostname.as_bytes(), nonce.as_bytes(), shared_key.as_bytes()]);
+
+            let pong = match ping[3].as_str() == Some(expected.as_str()) {
+                true => rmpv::Value::Array(vec!["PONG".into(), true.into(), "".into(), "server".into(), sha512_hex(&[salt.as_bytes(), b"server", nonce.as_bytes(), shared_key.as_bytes()]).into()]),
+                false => rmpv::Value::Array(vec!["PONG".into(), false.into(), "shared_key mismatch".into(), "".into(), "".into()]),
+            };
+            rmpv::encode::write_value(&mut socket, &pong).expect("write PONG");
+            let _ = socket.read_to_end(&mut data);
+        }
+        data
+    });
+
+    (addr, handle)
+}
+
+#[test]
+fn should_perform_shared_key_handshake() {
+    let (addr, server) = spawn_secure_fluentd("secret", 1);
+
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(addr)
+                                                     .with_shared_key("secret")
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("secure");
+    drop(guard);
+
+    let data = server.join().expect("server");
+    let frame = rmpv::decode::read_value(&mut data.as_slice()).expect("frame");
+    assert_eq!(frame[0].as_str(), Some("rust"));
+
+    let (addr, server) = spawn_secure_fluentd("secret", 1);
+    let writer = tracing_fluentd::writer::Handshake::new(addr, "wrong");
+    assert_eq!(writer.make().expect_err("wrong key").kind(), io::ErrorKind::PermissionDenied);
+    server.join().expect("server");
+}
+
+#[test]
+fn should_report_handshake_failure_to_error_handler() {
+    let (addr, server) = spawn_secure_fluentd("secret", 1);
+    let (sender, errors) = std::sync::mpsc::channel();
+    let sender = std::sync::Mutex::new(sender);
+
+    let layer = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                     .with_writer(addr)
+                                                     .with_shared_key("wrong")
+                                                     .with_error_handler(move |error| {
+                                                         let _ = sender.lock().expect("lock").send(error.kind());
+                                                     })
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("secure");
+
+    let error = errors.recv_timeout(core::time::Duration::from_secs(5)).expect("error is reported");
+    assert_eq!(error, io::ErrorKind::PermissionDenied);
+    server.join().expect("server");
+    drop(guard);
+}
+
+#[cfg(feature = "tls")]
+struct TestPki {
+    ca: String,
+    server: rustls::ServerConfig,
+    client_cert: String,
+    client_key: String,
+}
+
+#[cfg(feature = "tls")]
+fn create_test_pki() -> TestPki {
+    use std::sync::Arc;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let ca_key = rcgen::KeyPair::generate().expect("CA key");
+    let mut ca_params = rcgen::CertificateParams::new(Vec::<String>::new()).expect("CA params");
+    ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key).expect("CA cert");
+
+    let server_key = rcgen::KeyPair::generate().expect("server key");
+    let server_cert = rcgen::CertificateParams::new(vec!["localhost".to_owned()]).expect("server params")
+                                                                             .signed_by(&server_key, &ca, &ca_key)
+                                                                             .expect("server cert");
+    let client_key = rcgen::KeyPair::generate().expect("client key");
+    let client_cert = rcgen::CertificateParams::new(vec!["client".to_owned()]).expect("client params")
+                                                                          .signed_by(&client_key, &ca, &ca_key)
+                                                                          .expect("client cert");
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(ca.der().clone()).expect("add CA");
+    let verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build().expect("client verifier");
+    let server_key = rustls::pki_types::PrivateKeyDer::Pkcs8(server_key.serialize_der().into());
+    let server = rustls::ServerConfig::builder_with_provider(provider).with_safe_default_protocol_versions()
+                                                                      .expect("protocol versions")
+                                                                      .with_client_cert_verifier(verifier)
+                                                                      .with_single_cert(vec![server_cert.der().clone()], server_key)
+                                                                      .expect("server config");
+    //Unread tickets would cause client to reset connection on close, before server reads all data
+    let mut server = server;
+    server.send_tls13_tickets = 0;
+
+    TestPki {
+        ca: ca.pem(),
+        server,
+        client_cert: client_cert.pem(),
+        client_key: client_key.serialize_pem(),
+    }
+}
+
+//Returns data received after handshake or error.
+#[cfg(feature = "tls")]
+fn spawn_tls_fluentd(config: rustls::ServerConfig) -> (std::net::SocketAddr, std::thread::JoinHandle<io::Result<Vec<u8>>>) {
+    use std::io::Read;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    let handle = std::thread::spawn(move || {
+        let (socket, _) = listener.accept().expect("accept");
+        let session = rustls::ServerConnection::new(std::sync::Arc::new(config)).expect("server session");
+        let mut stream = rustls::StreamOwned::new(session, socket);
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).map(|_| data)
+    });
+
+    (addr, handle)
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn should_authenticate_with_client_certificate() {
+    use std::io::Read;
+    use tracing_fluentd::tls::TlsBuilder;
+
+    let pki = create_test_pki();
+
+    //Success with client identity
+    let (addr, server) = spawn_tls_fluentd(pki.server.clone());
+    let writer = TlsBuilder::new("localhost").add_root_pem(pki.ca.as_bytes())
+                                             .expect("add root")
+                                             .client_identity_pem(pki.client_cert.as_bytes(), pki.client_key.as_bytes())
+                                             .expect("client identity")
+                                             .build(addr)
+                                             .expect("build tls");
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(writer).layer().expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("mtls");
+    drop(guard);
+
+    let data = server.join().expect("server").expect("receive data");
+    let frame = rmpv::decode::read_value(&mut data.as_slice()).expect("frame");
+    assert_eq!(frame[0].as_str(), Some("rust"));
+
+    //Rejection without client identity
+    let (addr, server) = spawn_tls_fluentd(pki.server.clone());
+    let writer = TlsBuilder::new("localhost").add_root_pem(pki.ca.as_bytes()).expect("add root").build(addr).expect("build tls");
+    let result = writer.make().and_then(|mut stream| {
+        stream.write_all(b"data")?;
+        stream.flush()?;
+        stream.read(&mut [0u8; 1])
+    });
+    assert!(result.is_err());
+    assert!(server.join().expect("server").is_err());
+
+    //Server name override
+    let (addr, server) = spawn_tls_fluentd(pki.server);
+    let writer = TlsBuilder::new("fluentd.internal").server_name("localhost")
+                                                    .add_root_pem(pki.ca.as_bytes())
+                                                    .expect("add root")
+                                                    .client_identity_pem(pki.client_cert.as_bytes(), pki.client_key.as_bytes())
+                                                    .expect("client identity")
+                                                    .build(addr)
+                                                    .expect("build tls");
+    drop(writer.make().expect("connect"));
+    server.join().expect("server").expect("receive data");
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn should_reject_invalid_client_identity_on_build() {
+    use tracing_fluentd::tls::TlsBuilder;
+
+    let pki = create_test_pki();
+    let error = TlsBuilder::new("localhost").client_identity_pem(pki.client_cert.as_bytes(), b"garbage").err().expect("invalid key");
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+    let error = TlsBuilder::new("localhost").client_identity_pem(b"garbage", pki.client_key.as_bytes()).err().expect("invalid cert");
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+    let error = TlsBuilder::new("localhost").add_root_pem(b"garbage").err().expect("invalid root");
+    assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn should_probe_cached_connection_before_reuse() {
+    let fluentd = MockFluentd::start().expect("start fluentd");
+    let layer = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                     //Single write per message, so closed connection is not detected by write
+                                                     .with_writer(tracing_fluentd::writer::buffered(fluentd.addr()))
+                                                     .with_connection_probe()
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!(idx = 1, "probe");
+    //Server closes first connection once the first message is received
+    fluentd.wait_for_records(1, core::time::Duration::from_secs(5));
+    fluentd.disconnect();
+    //Let FIN reach client
+    std::thread::sleep(core::time::Duration::from_millis(100));
+    tracing::info!(idx = 2, "probe");
+    drop(guard);
+
+    let records = fluentd.wait_for_records(2, core::time::Duration::from_secs(5));
+    fluentd.assert_record_count(2);
+    assert_eq!(fluentd.connections(), 2);
+    let idx = records[1].as_map().expect("record").iter().find(|(key, _)| key.as_str() == Some("idx")).map(|(_, value)| value.as_u64());
+    assert_eq!(idx, Some(Some(2)));
+}
+
+#[test]
+fn should_write_whole_message_at_once_and_retry_it_after_failure() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let (memory, reader) = MemoryWriter::new();
+    let writes = std::sync::Arc::new(AtomicUsize::new(0));
+    let connections = std::sync::Arc::new(AtomicUsize::new(0));
+
+    let writer = {
+        let writes = writes.clone();
+        let connections = connections.clone();
+        move || Ok(CountingWrite {
+            writer: memory.clone(),
+            writes: writes.clone(),
+            //Only first connection is broken
+            fail: connections.fetch_add(1, Ordering::SeqCst) == 0,
+        })
+    };
+    let layer = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                     .with_writer(writer)
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    for idx in 0..5 {
+        tracing::info!(idx, "retry");
+    }
+    drop(guard);
+
+    //Failed connection is dropped, and message is re-sent in full over new one.
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+    let frames = reader.frames();
+    assert_eq!(writes.load(Ordering::SeqCst), frames.len() + 1);
+
+    let records = reader.records();
+    assert_eq!(records.len(), 5);
+    for (idx, record) in records.iter().enumerate() {
+        let value = record.as_map().expect("map").iter().find(|(key, _)| key.as_str() == Some("idx")).map(|(_, value)| value);
+        assert_eq!(value.and_then(rmpv::Value::as_u64), Some(idx as u64));
+    }
+}
+
+//Tcp listener with UDP heartbeat responder on the same port, which stops responding once flag is set.
+#[cfg(feature = "heartbeat")]
+fn spawn_heartbeat_responder() -> (std::net::TcpListener, std::sync::Arc<std::sync::atomic::AtomicBool>) {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind tcp");
+    let udp = std::net::UdpSocket::bind(listener.local_addr().expect("local addr")).expect("bind udp");
+    udp.set_read_timeout(Some(core::time::Duration::from_millis(20))).expect("set timeout");
+    let stopped = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+    let is_stopped = stopped.clone();
+    std::thread::spawn(move || {
+        let mut buffer = [0u8; 8];
+        while !is_stopped.load(std::sync::atomic::Ordering::SeqCst) {
+            if let Ok((_, peer)) = udp.recv_from(&mut buffer) {
+                let _ = udp.send_to(&[0], peer);
+            }
+        }
+    });
+
+    (listener, stopped)
+}
+
+#[cfg(feature = "heartbeat")]
+#[test]
+fn should_prefer_servers_responding_to_heartbeat() {
+    use core::time::Duration;
+
+    fn wait_alive(writer: &tracing_fluentd::writer::Heartbeat, expected: &[std::net::SocketAddr]) {
+        for _ in 0..100 {
+            if writer.alive() == expected {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        panic!("alive addresses {:?} != {:?}", writer.alive(), expected);
+    }
+
+    let (first, first_stopped) = spawn_heartbeat_responder();
+    let (second, _second_stopped) = spawn_heartbeat_responder();
+    let first = first.local_addr().expect("local addr");
+    let second = second.local_addr().expect("local addr");
+
+    let writer = tracing_fluentd::writer::heartbeat(vec![first, second]).interval(Duration::from_millis(25))
+                                                                        .timeout(Duration::from_millis(150));
+    writer.start();
+    wait_alive(&writer, &[first, second]);
+    assert_eq!(writer.make().expect("connect").peer_addr().expect("peer"), first);
+
+    first_stopped.store(true, std::sync::atomic::Ordering::SeqCst);
+    wait_alive(&writer, &[second]);
+    assert_eq!(writer.make().expect("connect").peer_addr().expect("peer"), second);
+}
+
+#[test]
+fn should_inject_faults_into_mock_fluentd() {
+    use core::time::Duration;
+    use std::io::Read;
+
+    let fluentd = MockFluentd::start().expect("start fluentd");
+    let addr = fluentd.addr();
+
+    fluentd.set_fault(Fault::Refuse);
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(addr.make().expect_err("refused").kind(), io::ErrorKind::NotFound);
+
+    fluentd.set_fault(Fault::Close);
+    std::thread::sleep(Duration::from_millis(50));
+    let mut socket = addr.make().expect("connect");
+    socket.set_read_timeout(Some(Duration::from_secs(5))).expect("set timeout");
+    assert_eq!(socket.read(&mut [0u8; 1]).expect("closed"), 0);
+
+    //Stalled server reads data only after fault is cleared
+    fluentd.set_fault(Fault::Stall);
+    let layer = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                     .with_writer(addr)
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("stall");
+    std::thread::sleep(Duration::from_millis(100));
+    fluentd.assert_record_count(0);
+    fluentd.set_fault(Fault::None);
+    drop(guard);
+    let records = fluentd.wait_for_records(1, Duration::from_secs(5));
+    assert_eq!(records.len(), 1);
+
+    //Ack is sent for messages with chunk option
+    fluentd.set_ack(true);
+    let mut socket = addr.make().expect("connect");
+    socket.set_read_timeout(Some(Duration::from_secs(5))).expect("set timeout");
+    let message = rmpv::Value::Array(vec![
+        "ack".into(),
+        1u64.into(),
+        rmpv::Value::Map(vec![("message".into(), "ack".into())]),
+        rmpv::Value::Map(vec![("chunk".into(), "chunk-id".into())]),
+    ]);
+    rmpv::encode::write_value(&mut socket, &message).expect("write");
+    let ack = rmpv::decode::read_value(&mut socket).expect("ack");
+    assert_eq!(ack, rmpv::Value::Map(vec![("ack".into(), "chunk-id".into())]));
+
+    let frames = fluentd.frames();
+    let frame = frames.last().expect("frame");
+    assert_eq!(frame.tag, "ack");
+    assert_eq!(frame.entries[0].time, Duration::from_secs(1));
+    fluentd.assert_record_count(2);
+}
+
+#[test]
+fn should_switch_writer_target_at_runtime() {
+    let first = MockFluentd::start().expect("start fluentd");
+    let second = MockFluentd::start().expect("start fluentd");
+    let dead = dead_addr();
+
+    let builder = tracing_fluentd::Builder::new("rust").with_writer(tracing_fluentd::writer::switchable(dead))
+                                                       .with_eager_flush_level(tracing::Level::INFO);
+    let handle = builder.writer().handle();
+    let layer = builder.layer().expect("Create layer");
+
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info!("queued 1");
+        tracing::info!("queued 2");
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        handle.set_target(first.addr());
+        tracing::info!("first");
+        first.wait_for_records(3, std::time::Duration::from_secs(5));
+
+        handle.set_target(second.addr());
+        tracing::info!("second");
+        second.wait_for_records(1, std::time::Duration::from_secs(5));
+    });
+
+    let messages = |fluentd: &MockFluentd| fluentd.records().iter().map(|record| record["message"].as_str().expect("message").to_owned()).collect::<Vec<_>>();
+    assert_eq!(messages(&first), ["queued 1", "queued 2", "first"]);
+    assert_eq!(messages(&second), ["second"]);
+}
+
+#[test]
+fn should_send_stats_periodically() {
+    use core::time::Duration;
+
+    let fluentd = MockFluentd::start().expect("start fluentd");
+    let clock = TestClock::new();
+
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(fluentd.addr())
+                                                     .with_stats_interval(Duration::from_secs(1))
+                                                     .with_clock(clock.clone())
+                                                     .layer()
+                                                     .expect("Create layer");
+
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info!("first");
+        tracing::info!("second");
+
+        //Records are sent with stats, without filling batch.
+        clock.advance(Duration::from_secs(1));
+        fluentd.wait_for_records(3, Duration::from_secs(5));
+        clock.advance(Duration::from_secs(1));
+        fluentd.wait_for_records(4, Duration::from_secs(5));
+    });
+
+    let frames = fluentd.frames();
+    let records = frames.iter().filter(|frame| frame.tag == "rust").flat_map(|frame| frame.entries.iter()).count();
+    assert_eq!(records, 2);
+
+    let stats = frames.iter().filter(|frame| frame.tag == "rust.stats").flat_map(|frame| frame.entries.iter().map(|entry| entry.record.clone())).collect::<Vec<_>>();
+    assert_eq!(stats.len(), 2);
+    assert_eq!(stats[0]["enqueued"].as_u64(), Some(2));
+    assert_eq!(stats[0]["delivered"].as_u64(), Some(0));
+    assert_eq!(stats[0]["uptime"].as_u64(), Some(1));
+
+    assert_eq!(stats[1]["enqueued"].as_u64(), Some(2));
+    assert_eq!(stats[1]["delivered"].as_u64(), Some(2));
+    assert_eq!(stats[1]["dropped"].as_u64(), Some(0));
+    assert_eq!(stats[1]["queue_depth"].as_u64(), Some(0));
+    assert_eq!(stats[1]["reconnects"].as_u64(), Some(0));
+    assert!(stats[1]["last_error"].is_nil());
+    assert_eq!(stats[1]["uptime"].as_u64(), Some(2));
+}
+
+#[test]
+fn should_limit_memory_of_undelivered_records() {
+    use core::time::Duration;
+    use std::sync::{Arc, Mutex};
+    use tracing_fluentd::OverflowPolicy;
+
+    fn run(policy: OverflowPolicy) -> (Vec<u64>, usize) {
+        let clock = TestClock::new();
+        let (writer, reader) = MemoryWriter::new();
+        let writer = RetryWriter {
+            writer,
+            contexts: Arc::new(Mutex::new(Vec::new())),
+        };
+        let dropped = Arc::new(Mutex::new(0));
+        let on_drop = dropped.clone();
+
+        let layer = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                         .with_writer(writer)
+                                                         .with_clock(clock.clone())
+                                                         .with_memory_budget(core::num::NonZeroUsize::new(10_000).unwrap(), policy)
+                                                         .on_drop(move |reason, count| {
+                                                             assert_eq!(reason, tracing_fluentd::DropReason::QueueFull);
+                                                             *on_drop.lock().expect("lock") += count;
+                                                         })
+                                                         .layer()
+                                                         .expect("Create layer");
+
+        let payload = "x".repeat(4_000);
+        let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+        //Worker holds the first record, while it waits to retry stalled writer.
+        tracing::info!(idx = 0, payload = payload.as_str());
+        clock.wait_for_sleepers(1, Duration::from_secs(5));
+        for idx in 1..10 {
+            tracing::info!(idx, payload = payload.as_str());
+        }
+        assert_eq!(*dropped.lock().expect("lock"), 8);
+
+        clock.advance(Duration::from_secs(1));
+        drop(guard);
+
+        let delivered = reader.records().iter().map(|record| record["idx"].as_u64().expect("idx")).collect();
+        let dropped = *dropped.lock().expect("lock");
+        (delivered, dropped)
+    }
+
+    assert_eq!(run(OverflowPolicy::DropNewest), (vec![0, 1], 8));
+    assert_eq!(run(OverflowPolicy::DropOldest), (vec![0, 9], 8));
+}
+
+#[test]
+fn should_discard_records_once_ring_queue_is_full() {
+    use core::time::Duration;
+    use std::sync::{Arc, Mutex};
+
+    let clock = TestClock::new();
+    let (writer, reader) = MemoryWriter::new();
+    let writer = RetryWriter {
+        writer,
+        contexts: Arc::new(Mutex::new(Vec::new())),
+    };
+    let dropped = Arc::new(Mutex::new(0));
+    let on_drop = dropped.clone();
+
+    let layer = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                     .with_writer(writer)
+                                                     .with_clock(clock.clone())
+                                                     .with_queue(tracing_fluentd::QueueKind::Ring(core::num::NonZeroUsize::new(2).unwrap()))
+                                                     .on_drop(move |reason, count| {
+                                                         assert_eq!(reason, tracing_fluentd::DropReason::QueueFull);
+                                                         *on_drop.lock().expect("lock") += count;
+                                                     })
+                                                     .layer()
+                                                     .expect("Create layer");
+
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    //Worker holds the first record, while it waits to retry stalled writer.
+    tracing::info!(idx = 0);
+    clock.wait_for_sleepers(1, Duration::from_secs(5));
+    for idx in 1..10 {
+        tracing::info!(idx);
+    }
+    assert_eq!(*dropped.lock().expect("lock"), 7);
+
+    clock.advance(Duration::from_secs(1));
+    drop(guard);
+
+    let delivered = reader.records().iter().map(|record| record["idx"].as_u64().expect("idx")).collect::<Vec<_>>();
+    assert_eq!(delivered, [0, 1, 2]);
+}
+
+#[test]
+fn should_send_drop_markers_after_queue_overflow() {
+    use core::time::Duration;
+    use std::sync::{Arc, Mutex};
+
+    let clock = TestClock::new();
+    let (writer, reader) = MemoryWriter::new();
+    let writer = RetryWriter {
+        writer,
+        contexts: Arc::new(Mutex::new(Vec::new())),
+    };
+    let dropped = Arc::new(Mutex::new(0));
+    let on_drop = dropped.clone();
+
+    let layer = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                     .with_writer(writer)
+                                                     .with_clock(clock.clone())
+                                                     .with_queue(tracing_fluentd::QueueKind::Ring(core::num::NonZeroUsize::new(2).unwrap()))
+                                                     .with_drop_markers("dropped")
+                                                     .on_drop(move |_, count| *on_drop.lock().expect("lock") += count)
+                                                     .layer()
+                                                     .expect("Create layer");
+
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    //Worker holds the first record, while it waits to retry stalled writer.
+    tracing::info!(idx = 0);
+    clock.wait_for_sleepers(1, Duration::from_secs(5));
+    for idx in 1..10 {
+        tracing::info!(idx);
+    }
+
+    clock.advance(Duration::from_secs(1));
+    drop(guard);
+
+    let dropped = *dropped.lock().expect("lock");
+    assert_eq!(dropped, 7);
+
+    let mut records = Vec::new();
+    let mut markers = Vec::new();
+    for frame in reader.frames() {
+        let entries = frame[1].as_array().expect("entries").iter().map(|entry| entry[1].clone());
+        match frame[0].as_str() {
+            Some("rust") => records.extend(entries),
+            Some("rust.dropped") => markers.extend(entries),
+            tag => panic!("Unexpected tag {:?}", tag),
+        }
+    }
+    let delivered = records.iter().map(|record| record["idx"].as_u64().expect("idx")).collect::<Vec<_>>();
+    assert_eq!(delivered, [0, 1, 2]);
+
+    assert_eq!(markers.len(), 1);
+    assert_eq!(markers[0]["reason"].as_str(), Some("queue_full"));
+    assert_eq!(markers[0]["count"].as_u64(), Some(dropped as u64));
+    assert!(markers[0]["from"].as_u64() <= markers[0]["to"].as_u64());
+}
+
+#[test]
+fn should_fail_to_create_checked_layer_for_unreachable_address() {
+    let error = tracing_fluentd::Builder::new("rust").with_writer(dead_addr())
+                                                     .layer_checked()
+                                                     .err()
+                                                     .expect("unreachable address");
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+
+    let error = tracing_fluentd::Builder::new("rust").with_writer(dead_addr())
+                                                     .layer_guarded_checked()
+                                                     .err()
+                                                     .expect("unreachable address");
+    assert_eq!(error.kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn should_reuse_connection_of_checked_layer() {
+    let fluentd = MockFluentd::start().expect("start fluentd");
+    let (layer, guard) = tracing_fluentd::Builder::new("rust").with_writer(fluentd.addr())
+                                                              .layer_guarded_checked()
+                                                              .expect("Create layer");
+    tracing::subscriber::with_default(Registry::default().with(layer), || {
+        tracing::info!(idx = 1, "checked");
+    });
+    drop(guard);
+
+    let records = fluentd.wait_for_records(1, core::time::Duration::from_secs(5));
+    assert_eq!(records.len(), 1);
+    assert_eq!(fluentd.connections(), 1);
+}
+
+#[test]
+fn should_share_connection_between_layers() {
+    let fluentd = MockFluentd::start().expect("start fluentd");
+    let connection = tracing_fluentd::writer::Connection::new(fluentd.addr());
+    let (http_layer, http_guard) = tracing_fluentd::Builder::new("http").with_shared_connection(&connection)
+                                                                        .layer_guarded()
+                                                                        .expect("Create layer");
+    let (db_layer, db_guard) = tracing_fluentd::Builder::new("db").with_shared_connection(&connection)
+                                                                  .flatten()
+                                                                  .layer_guarded()
+                                                                  .expect("Create layer");
+
+    tracing::subscriber::with_default(Registry::default().with(http_layer), || {
+        tracing::info!(idx = 1, "request");
+    });
+    tracing::subscriber::with_default(Registry::default().with(db_layer), || {
+        tracing::info!(idx = 2, "query");
+    });
+    drop(http_guard);
+    drop(db_guard);
+
+    let records = fluentd.wait_for_records(2, core::time::Duration::from_secs(5));
+    assert_eq!(records.len(), 2);
+    let mut tags = fluentd.frames().into_iter().map(|frame| frame.tag).collect::<Vec<_>>();
+    tags.sort();
+    assert_eq!(tags, ["db", "http"]);
+    assert_eq!(fluentd.connections(), 1);
+
+    assert_eq!(fluentd.connections(), 1);
+}
+
+#[test]
+fn should_write_into_existing_writer_shared_by_layers() {
+    let shared = tracing_fluentd::writer::Shared::new(Vec::new());
+    let (http_layer, http_guard) = tracing_fluentd::Builder::new("http").with_writer(shared.clone())
+                                                                        .layer_guarded()
+                                                                        .expect("Create layer");
+    let (db_layer, db_guard) = tracing_fluentd::Builder::new("db").with_writer(shared.clone())
+                                                                  .layer_guarded()
+                                                                  .expect("Create layer");
+
+    tracing::subscriber::with_default(Registry::default().with(http_layer).with(db_layer), || {
+        for idx in 0..10 {
+            tracing::info!(idx, "shared");
+        }
+    });
+    drop(http_guard);
+    drop(db_guard);
+
+    let bytes = shared.get().lock().expect("lock").clone();
+    let (frames, error) = tracing_fluentd::fluent::decode::read_frames(bytes.as_slice());
+    assert!(error.is_none(), "{:?}", error);
+    for tag in ["http", "db"].iter() {
+        let records = frames.iter().filter(|frame| frame.message.tag() == *tag).map(|frame| frame.message.len()).sum::<usize>();
+        assert_eq!(records, 10);
+    }
+}
+
+#[test]
+fn should_report_poisoned_shared_writer_as_error() {
+    let shared = tracing_fluentd::writer::Shared::new(Vec::new());
+    let mut writer = shared.make().expect("make");
+    writer.write_all(b"before").expect("write");
+
+    let poisoned = shared.get().clone();
+    let _ = std::thread::spawn(move || {
+        let _guard = poisoned.lock().expect("lock");
+        panic!("poison shared writer");
+    }).join();
+
+    assert!(writer.write_all(b"after").is_err());
+    assert!(writer.flush().is_err());
+    assert!(shared.make().is_ok());
+}
+
+#[test]
+fn should_recreate_shared_connection_for_all_layers_on_failure() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    #[derive(Clone)]
+    struct Flaky {
+        inner: MemoryWriter,
+        fail: Arc<AtomicBool>,
+        made: Arc<AtomicUsize>,
+    }
+
+    impl tracing_fluentd::MakeWriter for Flaky {
+        type Writer = Self;
+
+        fn make(&self) -> std::io::Result<Self::Writer> {
+            self.made.fetch_add(1, Ordering::SeqCst);
+            Ok(self.clone())
+        }
+    }
+
+    impl std::io::Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            match self.fail.swap(false, Ordering::SeqCst) {
+                true => Err(std::io::ErrorKind::ConnectionReset.into()),
+                false => self.inner.write(buf),
+            }
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let (inner, reader) = MemoryWriter::new();
+    let flaky = Flaky {
+        inner,
+        fail: Arc::new(AtomicBool::new(false)),
+        made: Arc::new(AtomicUsize::new(0)),
+    };
+    let connection = tracing_fluentd::writer::Connection::new(flaky.clone());
+    let (http_layer, http_guard) = tracing_fluentd::Builder::new("http").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                                        .with_shared_connection(&connection)
+                                                                        .layer_guarded()
+                                                                        .expect("Create layer");
+    let (db_layer, db_guard) = tracing_fluentd::Builder::new("db").with_shared_connection(&connection)
+                                                                  .layer_guarded()
+                                                                  .expect("Create layer");
+    let http = tracing::Dispatch::new(Registry::default().with(http_layer));
+    let db = tracing::Dispatch::new(Registry::default().with(db_layer));
+
+    tracing::dispatcher::with_default(&http, || tracing::info!(idx = 1, "request"));
+    for _ in 0..250 {
+        if !reader.bytes().is_empty() {
+            break;
+        }
+        std::thread::sleep(core::time::Duration::from_millis(20));
+    }
+    assert_eq!(reader.records().len(), 1);
+
+    flaky.fail.store(true, Ordering::SeqCst);
+    tracing::dispatcher::with_default(&db, || tracing::info!(idx = 2, "query"));
+    drop(db);
+    drop(db_guard);
+
+    tracing::dispatcher::with_default(&http, || tracing::info!(idx = 3, "request"));
+    drop(http);
+    drop(http_guard);
+
+    let records = reader.records();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[1]["idx"].as_u64(), Some(2));
+    assert_eq!(records[2]["idx"].as_u64(), Some(3));
+    assert_eq!(flaky.made.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn should_reconnect_immediately_when_server_closes_connection() {
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().expect("local addr");
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let server = std::thread::spawn({
+        let received = received.clone();
+        move || {
+            //Reads one frame, then closes connection.
+            let (mut conn, _) = listener.accept().expect("accept");
+            let mut buffer = [0u8; 1024];
+            let size = conn.read(&mut buffer).expect("read");
+            assert!(size > 0);
+            drop(conn);
+
+            let (mut conn, _) = listener.accept().expect("accept");
+            loop {
+                match conn.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(size) => received.lock().unwrap().extend_from_slice(&buffer[..size]),
+                }
+            }
+        }
+    });
+
+    let layer = tracing_fluentd::Builder::new("rust").with_writer(addr)
+                                                     .with_eager_flush_level(tracing::Level::INFO)
+                                                     .layer()
+                                                     .expect("Create layer");
+    let dispatch = tracing::Dispatch::new(Registry::default().with(layer));
+
+    tracing::dispatcher::with_default(&dispatch, || tracing::info!("first"));
+    std::thread::sleep(core::time::Duration::from_millis(200));
+    //Written into closed connection, so server responds with reset.
+    tracing::dispatcher::with_default(&dispatch, || tracing::info!("second"));
+    std::thread::sleep(core::time::Duration::from_millis(200));
+    //Fails with broken connection, hence new connection is made right away.
+    tracing::dispatcher::with_default(&dispatch, || tracing::info!("third"));
+
+    let contains = |text: &[u8]| received.lock().unwrap().windows(text.len()).any(|window| window == text);
+    for _ in 0..100 {
+        if contains(b"third") {
+            break;
+        }
+        std::thread::sleep(core::time::Duration::from_millis(20));
+    }
+    //Record is sent without waiting for next event.
+    assert!(contains(b"third"));
+
+    drop(dispatch);
+    server.join().expect("join server");
+}
+
+//Writer accepting up to `chunk` bytes at once, then failing once with `WouldBlock` or
+//`Interrupted` if it `pauses`, or failing with `WouldBlock` forever after `limit` bytes.
+struct NonBlockingWrite {
+    writer: MemoryWriter,
+    chunk: usize,
+    limit: usize,
+    written: usize,
+    is_ready: bool,
+    pauses: bool,
+}
+
+impl Write for NonBlockingWrite {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written >= self.limit {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        if !self.is_ready {
+            self.is_ready = true;
+            return match self.written % 2 {
+                0 => Err(io::ErrorKind::WouldBlock.into()),
+                _ => Err(io::ErrorKind::Interrupted.into()),
+            };
+        }
+
+        self.is_ready = !self.pauses;
+        let len = buf.len().min(self.chunk).min(self.limit - self.written);
+        self.written += len;
+        self.writer.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn should_retry_partial_writes_of_nonblocking_writer() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let (memory, reader) = MemoryWriter::new();
+    let connections = std::sync::Arc::new(AtomicUsize::new(0));
+    let writer = {
+        let connections = connections.clone();
+        move || {
+            connections.fetch_add(1, Ordering::SeqCst);
+            Ok(NonBlockingWrite {
+                writer: memory.clone(),
+                chunk: 7,
+                limit: usize::MAX,
+                written: 0,
+                is_ready: false,
+                pauses: true,
+            })
+        }
+    };
+    let layer = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(2).unwrap())
+                                                     .with_writer(writer)
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    for idx in 0..6 {
+        tracing::info!(idx, "nonblocking");
+    }
+    drop(guard);
+
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+    let records = reader.records();
+    assert_eq!(records.len(), 6);
+    for (idx, record) in records.iter().enumerate() {
+        let value = record.as_map().expect("map").iter().find(|(key, _)| key.as_str() == Some("idx")).map(|(_, value)| value);
+        assert_eq!(value.and_then(rmpv::Value::as_u64), Some(idx as u64));
+    }
+}
+
+#[test]
+fn should_drop_stalled_nonblocking_writer_after_partial_write() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let clock = TestClock::new();
+    let (stalled, stalled_reader) = MemoryWriter::new();
+    let (memory, reader) = MemoryWriter::new();
+    let connections = std::sync::Arc::new(AtomicUsize::new(0));
+    let writer = {
+        let connections = connections.clone();
+        move || Ok(match connections.fetch_add(1, Ordering::SeqCst) {
+            //First connection stalls in the middle of message.
+            0 => NonBlockingWrite {
+                writer: stalled.clone(),
+                chunk: 7,
+                limit: 10,
+                written: 0,
+                is_ready: true,
+                pauses: true,
+            },
+            //Replacement always accepts whole write.
+            _ => NonBlockingWrite {
+                writer: memory.clone(),
+                chunk: usize::MAX,
+                limit: usize::MAX,
+                written: 0,
+                is_ready: true,
+                pauses: false,
+            },
+        })
+    };
+    let layer = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                     .with_writer(writer)
+                                                     .with_clock(clock.clone())
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    tracing::info!("stalled");
+
+    //Worker waits for stalled writer, until timeout.
+    clock.wait_for_sleepers(1, core::time::Duration::from_secs(5));
+    assert_eq!(stalled_reader.bytes().len(), 10);
+    clock.advance(core::time::Duration::from_secs(10));
+    tracing::info!("next");
+    drop(guard);
+
+    //Stalled connection is not reused and whole message is re-sent over new one.
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+    assert_eq!(stalled_reader.bytes().len(), 10);
+    let records = reader.records();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].as_map().expect("map").iter().find(|(key, _)| key.as_str() == Some("message")).and_then(|(_, value)| value.as_str()), Some("stalled"));
+    assert_eq!(records[1].as_map().expect("map").iter().find(|(key, _)| key.as_str() == Some("message")).and_then(|(_, value)| value.as_str()), Some("next"));
+}
+
+//Endpoint recording connection attempts, which writers fail if endpoint is flapping.
+struct FailoverEndpoint {
+    idx: usize,
+    memory: MemoryWriter,
+    is_alive: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    is_flapping: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    attempts: std::sync::Arc<std::sync::Mutex<Vec<usize>>>,
+}
+
+impl MakeWriter for FailoverEndpoint {
+    type Writer = CountingWrite;
+
+    fn make(&self) -> io::Result<Self::Writer> {
+        use std::sync::atomic::Ordering;
+
+        self.attempts.lock().unwrap().push(self.idx);
+        match self.is_alive.load(Ordering::SeqCst) {
+            true => Ok(CountingWrite {
+                writer: self.memory.clone(),
+                writes: Default::default(),
+                fail: self.is_flapping.load(Ordering::SeqCst),
+            }),
+            false => Err(io::Error::new(io::ErrorKind::ConnectionRefused, "dead")),
+        }
+    }
+}
+
+struct FailoverEndpoints {
+    memory: Vec<tracing_fluentd::testing::MemoryReader>,
+    is_alive: Vec<std::sync::Arc<std::sync::atomic::AtomicBool>>,
+    is_flapping: Vec<std::sync::Arc<std::sync::atomic::AtomicBool>>,
+    attempts: std::sync::Arc<std::sync::Mutex<Vec<usize>>>,
+}
+
+impl FailoverEndpoints {
+    fn take_attempts(&self) -> Vec<usize> {
+        core::mem::take(&mut *self.attempts.lock().unwrap())
+    }
+}
+
+fn failover_endpoints(len: usize) -> (Vec<FailoverEndpoint>, FailoverEndpoints) {
+    let attempts = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut result = FailoverEndpoints {
+        memory: Vec::new(),
+        is_alive: Vec::new(),
+        is_flapping: Vec::new(),
+        attempts: attempts.clone(),
+    };
+    let endpoints = (0..len).map(|idx| {
+        let (memory, reader) = MemoryWriter::new();
+        let endpoint = FailoverEndpoint {
+            idx,
+            memory,
+            is_alive: std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true)),
+            is_flapping: Default::default(),
+            attempts: attempts.clone(),
+        };
+        result.memory.push(reader);
+        result.is_alive.push(endpoint.is_alive.clone());
+        result.is_flapping.push(endpoint.is_flapping.clone());
+        endpoint
+    }).collect();
+    (endpoints, result)
+}
+
+#[test]
+fn should_stick_to_failover_endpoint_until_primary_recovers() {
+    use std::sync::atomic::Ordering;
+
+    let (endpoints, state) = failover_endpoints(3);
+    let failover = tracing_fluentd::writer::failover(endpoints).probe_interval(core::time::Duration::from_millis(100));
+
+    let writer = failover.make().expect("make");
+    assert_eq!(writer.endpoint(), 0);
+    assert!(!failover.is_outdated(&writer));
+    assert_eq!(state.take_attempts(), [0]);
+
+    //Primary dies, so the next endpoint is used and kept.
+    state.is_alive[0].store(false, Ordering::SeqCst);
+    let writer = failover.make().expect("make");
+    assert_eq!(writer.endpoint(), 1);
+    assert_eq!(state.take_attempts(), [0, 1]);
+    let writer = failover.make().expect("make");
+    assert_eq!(writer.endpoint(), 1);
+    assert_eq!(state.take_attempts(), [1]);
+
+    //Second endpoint dies too, primary is not probed until interval passes.
+    state.is_alive[1].store(false, Ordering::SeqCst);
+    let writer = failover.make().expect("make");
+    assert_eq!(writer.endpoint(), 2);
+    assert_eq!(state.take_attempts(), [1, 2]);
+
+    //Primary is probed once interval passes, but is still dead.
+    std::thread::sleep(core::time::Duration::from_millis(150));
+    assert!(failover.is_outdated(&writer));
+    let writer = failover.make().expect("make");
+    assert_eq!(writer.endpoint(), 2);
+    assert_eq!(state.take_attempts(), [0, 2]);
+    assert!(!failover.is_outdated(&writer));
+
+    //Primary recovers, so that writer of failover endpoint becomes outdated after interval.
+    state.is_alive[0].store(true, Ordering::SeqCst);
+    state.is_alive[1].store(true, Ordering::SeqCst);
+    assert!(!failover.is_outdated(&writer));
+    std::thread::sleep(core::time::Duration::from_millis(150));
+    assert!(failover.is_outdated(&writer));
+    let writer = failover.make().expect("make");
+    assert_eq!(writer.endpoint(), 0);
+    assert_eq!(state.take_attempts(), [0]);
+    assert!(!failover.is_outdated(&writer));
+}
+
+#[test]
+fn should_demote_flapping_failover_endpoint() {
+    use std::sync::atomic::Ordering;
+
+    let (endpoints, state) = failover_endpoints(2);
+    state.is_flapping[0].store(true, Ordering::SeqCst);
+    let failover = tracing_fluentd::writer::failover(endpoints).max_failures(core::num::NonZeroUsize::new(2).unwrap())
+                                                               .probe_interval(core::time::Duration::from_secs(3600));
+
+    let layer = tracing_fluentd::Builder::new("rust").with_max_msg_record(core::num::NonZeroUsize::new(1).unwrap())
+                                                     .with_writer(failover)
+                                                     .layer()
+                                                     .expect("Create layer");
+    let guard = tracing::subscriber::set_default(Registry::default().with(layer));
+    for idx in 0..5 {
+        tracing::info!(idx, "failover");
+        //Every record is sent as separate message.
+        std::thread::sleep(core::time::Duration::from_millis(20));
+    }
+    drop(guard);
+
+    //Primary accepts connections, but fails to write, until it is demoted after 2 failures.
+    //Then failover endpoint is kept, as primary is not probed again within interval.
+    assert_eq!(state.take_attempts(), [0, 0, 1]);
+    assert!(state.memory[0].bytes().is_empty());
+    let records = state.memory[1].records();
+    assert_eq!(records.len(), 5);
+    for (idx, record) in records.iter().enumerate() {
+        let value = record.as_map().expect("map").iter().find(|(key, _)| key.as_str() == Some("idx")).map(|(_, value)| value);
+        assert_eq!(value.and_then(rmpv::Value::as_u64), Some(idx as u64));
+    }
+}